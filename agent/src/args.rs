@@ -7,6 +7,9 @@ static BRIEF_LICENCE: &str = "This program is licensed under the Mozilla Public
 pub struct Args {
     pub config_path: Option<String>,
     pub daemon: bool,
+    pub check_config: bool,
+    pub print_config: bool,
+    pub init: bool,
 }
 
 impl Args {
@@ -18,10 +21,10 @@ impl Args {
         raw.next(&mut cursor);
         let mut config_path = None;
         let mut daemon = false;
-        loop {
-            let Some(arg) = raw.next(&mut cursor) else {
-                break;
-            };
+        let mut check_config = false;
+        let mut print_config = false;
+        let mut init = false;
+        while let Some(arg) = raw.next(&mut cursor) {
             if let Some((long, value)) = arg.to_long() {
                 match long {
                     Ok("config") => {
@@ -38,6 +41,14 @@ impl Args {
                         daemon = true;
                         continue;
                     }
+                    Ok("check-config") => {
+                        check_config = true;
+                        continue;
+                    }
+                    Ok("print-config") => {
+                        print_config = true;
+                        continue;
+                    }
                     Ok("help") => {
                         print!("{}", HELP);
                         process::exit(0x0);
@@ -76,6 +87,9 @@ impl Args {
                     }
                 }
                 continue;
+            } else if arg.to_value_os() == "init" {
+                init = true;
+                continue;
             }
 
             break;
@@ -84,6 +98,9 @@ impl Args {
         Ok(Self {
             config_path,
             daemon,
+            check_config,
+            print_config,
+            init,
         })
     }
 }
@@ -1,8 +1,36 @@
-use narrowlink_types::ServiceType;
-use serde::{Deserialize, Serialize};
-use std::{env, fs::File, io::Read, path::PathBuf};
+use base64::Engine;
+use narrowlink_network::{
+    transport::{ClientIdentity, DialOptions, IpFamily, Socks5Proxy, TrustRoots},
+    Compression,
+};
+use narrowlink_types::{
+    generic::{decode_pre_shared_key, KeyDerivation},
+    publish::{ClientAccess, PublishHost, ServiceAccess},
+    token::{AgentPublishToken, AgentToken},
+    ServiceType,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::error::AgentError;
+use tracing::{debug, warn};
+
+use crate::{
+    destination::{Destination, DestinationFilter},
+    error::AgentError,
+    reverse::ReverseBindRule,
+};
+
+mod toml;
+
+static DEFAULT_YAML: &str = include_str!("../default_agent.yaml");
 
 #[derive(Deserialize, Serialize, Default, PartialEq, Clone, Copy)]
 pub enum KeyPolicy {
@@ -10,32 +38,410 @@ pub enum KeyPolicy {
     Lax,
     Strict,
 }
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct SelfHosted {
     pub gateway: String,
+    #[serde(default)]
     pub token: String,
-    pub publish: Option<Vec<String>>,
+    pub token_file: Option<PathBuf>, // read instead of token, keeps the token out of the config file
+    pub refresh_token: Option<String>, // exchanged at the gateway for a new token before the current one expires
+    pub publish: Option<Vec<Publish>>,
     #[serde(default = "ServiceType::default")]
     pub protocol: ServiceType,
+    pub acme: Option<Acme>,
+    pub proxy: Option<String>, // socks5://[user:pass@]host:port
+    #[serde(default)]
+    pub proxy_fallback: bool,
+    #[serde(default = "_default_keepalive_secs")]
+    pub keepalive_secs: u64, // 0 disables the heartbeat
+    #[serde(default = "_default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64, // 0 waits for the gateway forever
+    #[serde(default)]
+    pub ip_family: IpFamily, // Any races IPv6 against IPv4, V4 or V6 forces one
+    #[serde(default)]
+    pub multiplex: bool, // Ws and Wss tunnels share one connection as streams, as they do over QUIC
+    pub gateway_cert_pin: Option<String>, // base64 SHA-256 of the gateway's SubjectPublicKeyInfo
+    pub client_cert: Option<PathBuf>, // PEM chain for gateways requiring mTLS, together with client_key
+    pub client_key: Option<PathBuf>,
+    #[serde(skip)]
+    pub client_identity: Option<ClientIdentity>, // read from client_cert and client_key at load
+    pub ca_bundle: Option<PathBuf>, // PEM CA certificates the gateway is verified against
+    #[serde(default)]
+    pub ca_bundle_extends_roots: bool, // trust the bundle next to the webpki roots instead of only it
+    #[serde(skip)]
+    pub trust_roots: Option<TrustRoots>, // read from ca_bundle at load
+}
+
+impl SelfHosted {
+    pub fn proxy(&self) -> Option<Socks5Proxy> {
+        self.proxy
+            .as_deref()
+            .and_then(|url| Socks5Proxy::from_url(url, self.proxy_fallback))
+    }
+    pub fn cert_pin(&self) -> Option<[u8; 32]> {
+        let pin = base64::engine::general_purpose::STANDARD
+            .decode(self.gateway_cert_pin.as_deref()?.trim())
+            .ok()?;
+        pin.try_into().ok()
+    }
+    // seconds since the epoch, unverified as only the gateway holds the secret
+    pub fn token_expiry(&self) -> Option<u64> {
+        decode_token::<AgentToken>(&self.token).map(|token| token.exp as u64)
+    }
+    pub fn dial_options(&self) -> DialOptions {
+        DialOptions {
+            proxy: self.proxy(),
+            ip_family: self.ip_family,
+            cert_pin: self.cert_pin(),
+            client_identity: self.client_identity.clone(),
+            trust_roots: self.trust_roots.clone(),
+        }
+    }
+    // everything but the publish list, which can be updated on a live connection,
+    // the client access lists are only sent on connect
+    pub fn connection_eq(&self, other: &Self) -> bool {
+        self.gateway == other.gateway
+            // a refreshed token replaces the one of the config
+            && (self.token == other.token
+                || self.refresh_token.is_some() && self.refresh_token == other.refresh_token)
+            && self.protocol == other.protocol
+            && self.acme == other.acme
+            && self.proxy == other.proxy
+            && self.proxy_fallback == other.proxy_fallback
+            && self.keepalive_secs == other.keepalive_secs
+            && self.idle_timeout_secs == other.idle_timeout_secs
+            && self.ip_family == other.ip_family
+            && self.multiplex == other.multiplex
+            && self.gateway_cert_pin == other.gateway_cert_pin
+            && self.client_identity == other.client_identity
+            && self.trust_roots == other.trust_roots
+            && client_access(self.publish.as_deref().unwrap_or_default())
+                == client_access(other.publish.as_deref().unwrap_or_default())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Publish {
+    Token(String),
+    Service {
+        token: String,
+        e2ee: Option<String>, // name of the E2EE policy for the published services
+        rate_limit: Option<RateLimit>,
+        compression: Option<Compression>, // of the data channels to the gateway, negotiated per connection
+        clients: Option<ClientAccess>, // clients allowed to reach the published services, enforced by the gateway
+        #[serde(default)]
+        proxy_protocol: bool, // prepend a PROXY protocol v2 header with the client address to TCP backends
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    #[serde(deserialize_with = "at_least_one_byte")]
+    pub bytes_per_sec: u64, // the limiter divides by it
+    pub burst: Option<u64>, // bytes, defaults to one second of traffic
+}
+
+impl RateLimit {
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.bytes_per_sec).max(1)
+    }
+}
+
+fn at_least_one_byte<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "rate limit must allow at least one byte per second",
+        )),
+        bytes_per_sec => Ok(bytes_per_sec),
+    }
+}
+
+impl Publish {
+    pub fn token(&self) -> &str {
+        match self {
+            Publish::Token(token) | Publish::Service { token, .. } => token,
+        }
+    }
+    fn token_mut(&mut self) -> &mut String {
+        match self {
+            Publish::Token(token) | Publish::Service { token, .. } => token,
+        }
+    }
+    pub fn e2ee(&self) -> Option<&str> {
+        match self {
+            Publish::Token(_) => None,
+            Publish::Service { e2ee, .. } => e2ee.as_deref(),
+        }
+    }
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        match self {
+            Publish::Token(_) => None,
+            Publish::Service { rate_limit, .. } => *rate_limit,
+        }
+    }
+    pub fn compression(&self) -> Option<Compression> {
+        match self {
+            Publish::Token(_) => None,
+            Publish::Service { compression, .. } => *compression,
+        }
+    }
+    pub fn clients(&self) -> Option<&ClientAccess> {
+        match self {
+            Publish::Token(_) => None,
+            Publish::Service { clients, .. } => clients.as_ref(),
+        }
+    }
+    pub fn proxy_protocol(&self) -> bool {
+        match self {
+            Publish::Token(_) => false,
+            Publish::Service { proxy_protocol, .. } => *proxy_protocol,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Acme {
+    pub email: String, // the gateway registers a separate ACME account with this email for the published domains
+    pub challenge_type: Option<AcmeChallengeType>, // replaces the gateway's challenge type for the published domains
+    pub directory: Option<String>, // directory url, one of those the gateway is configured with
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum AcmeChallengeType {
+    Http01,
+    TlsAlpn01,
+    Dns01,
+}
+
+impl AcmeChallengeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AcmeChallengeType::Http01 => "Http01",
+            AcmeChallengeType::TlsAlpn01 => "TlsAlpn01",
+            AcmeChallengeType::Dns01 => "Dns01",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Platform {
+    pub control_plane: String, // host:port of the control plane, the gateway and token are discovered from it
+    pub api_key: String,
+    pub publish: Option<Vec<Publish>>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub enum Endpoint {
-    // Platform(Platform),
+    Platform(Platform),
     // Cloud(Cloud),
-    SelfHosted(SelfHosted),
+    SelfHosted(Box<SelfHosted>),
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct PassPhrase {
+    pub name: Option<String>, // named policies only apply to the publish entries referencing them
     pub phrase: String,
+    #[serde(default)]
+    pub previous: Vec<String>, // still accepted while peers move to the new phrase
     #[serde(default = "KeyPolicy::default")]
     pub policy: KeyPolicy,
+    #[serde(default = "_default_min_length")]
+    pub min_length: usize, // in characters, rejected under Strict and warned about under Lax
+    #[serde(default)]
+    pub kdf: KeyDerivation, // clients have to use the same one
+}
+
+fn _default_min_length() -> usize {
+    16
+}
+
+pub fn _default_keepalive_secs() -> u64 {
+    15
+}
+
+pub fn _default_drain_timeout_secs() -> u64 {
+    30
+}
+
+pub fn _default_idle_timeout_secs() -> u64 {
+    60
+}
+
+pub fn _default_dns_cache_secs() -> u64 {
+    30
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub enum E2EE {
     PassPhrase(PassPhrase),
+    EphemeralPassPhrase(PassPhrase), // also requires an X25519 exchange per connection
+    PreSharedKey(PreSharedKey),
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PreSharedKey {
+    pub name: Option<String>,
+    pub key: String, // 32 bytes as 64 hex digits or base64, used without key derivation
+    #[serde(default)]
+    pub previous: Vec<String>,
+    #[serde(default = "KeyPolicy::default")]
+    pub policy: KeyPolicy,
+}
+
+pub struct E2EEKey {
+    pub phrases: Vec<String>,      // accepted phrases, the current one first
+    pub pre_shared: Vec<[u8; 32]>, // accepted raw keys, the current one first
+    pub policy: KeyPolicy,
+    pub kdf: KeyDerivation,
+    pub ephemeral: bool, // requests without a key exchange are refused
+}
+
+impl E2EE {
+    pub fn passphrase(&self) -> Option<&PassPhrase> {
+        match self {
+            E2EE::PassPhrase(passphrase) | E2EE::EphemeralPassPhrase(passphrase) => {
+                Some(passphrase)
+            }
+            E2EE::PreSharedKey(_) => None,
+        }
+    }
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            E2EE::PassPhrase(passphrase) | E2EE::EphemeralPassPhrase(passphrase) => {
+                passphrase.name.as_deref()
+            }
+            E2EE::PreSharedKey(pre_shared_key) => pre_shared_key.name.as_deref(),
+        }
+    }
+    pub fn key(&self) -> E2EEKey {
+        match self {
+            E2EE::PassPhrase(passphrase) | E2EE::EphemeralPassPhrase(passphrase) => E2EEKey {
+                phrases: [passphrase.phrase.to_owned()]
+                    .into_iter()
+                    .chain(passphrase.previous.iter().cloned())
+                    .collect(),
+                pre_shared: Vec::new(),
+                policy: passphrase.policy,
+                kdf: passphrase.kdf,
+                ephemeral: matches!(self, E2EE::EphemeralPassPhrase(_)),
+            },
+            // decoded by Config::load already, clients do not send a key derivation for these
+            E2EE::PreSharedKey(pre_shared_key) => E2EEKey {
+                phrases: Vec::new(),
+                pre_shared: [&pre_shared_key.key]
+                    .into_iter()
+                    .chain(pre_shared_key.previous.iter())
+                    .filter_map(|key| decode_pre_shared_key(key).ok())
+                    .collect(),
+                policy: pre_shared_key.policy,
+                kdf: KeyDerivation::default(),
+                ephemeral: false,
+            },
+        }
+    }
+}
+
+// key for a connection to a published service, the policy named by its publish entry,
+// otherwise the first unnamed policy, a named policy that is missing is an error rather
+// than an unencrypted connection
+pub fn e2ee_key(
+    publish: &[Publish],
+    e2ee: &[E2EE],
+    host: &str,
+    port: u16,
+) -> Result<Option<E2EEKey>, AgentError> {
+    let name = publish.iter().find_map(|publish| {
+        let name = publish.e2ee()?;
+        decode_token::<AgentPublishToken>(publish.token())?
+            .publish_hosts
+            .iter()
+            .any(|publish_host| {
+                publish_host.connect.host == host && publish_host.connect.port == port
+            })
+            .then_some(name)
+    });
+    match e2ee.iter().find(|e2ee| e2ee.name() == name) {
+        Some(e2ee) => Ok(Some(e2ee.key())),
+        None => match name {
+            Some(name) => Err(AgentError::E2EENotFound(name.to_owned())),
+            None => Ok(None),
+        },
+    }
+}
+
+// every published host of the publish entries, entries with invalid tokens are skipped
+pub fn publish_hosts(publish: &[Publish]) -> Vec<PublishHost> {
+    publish
+        .iter()
+        .filter_map(|publish| decode_token::<AgentPublishToken>(publish.token()))
+        .flat_map(|token| token.publish_hosts)
+        .collect()
+}
+
+// bandwidth limit of a published service, from the first publish entry serving it with one
+pub fn rate_limit(publish: &[Publish], host: &str, port: u16) -> Option<RateLimit> {
+    publish.iter().find_map(|publish| {
+        let rate_limit = publish.rate_limit()?;
+        decode_token::<AgentPublishToken>(publish.token())?
+            .publish_hosts
+            .iter()
+            .any(|publish_host| {
+                publish_host.connect.host == host && publish_host.connect.port == port
+            })
+            .then_some(rate_limit)
+    })
+}
+
+// access lists of the services the publish entries connect to
+pub fn client_access(publish: &[Publish]) -> Vec<ServiceAccess> {
+    publish
+        .iter()
+        .filter_map(|publish| {
+            let access = publish.clients()?;
+            let token = decode_token::<AgentPublishToken>(publish.token())?;
+            Some(
+                token
+                    .publish_hosts
+                    .into_iter()
+                    .map(|publish_host| ServiceAccess {
+                        host: publish_host.connect.host,
+                        port: publish_host.connect.port,
+                        access: access.clone(),
+                    }),
+            )
+        })
+        .flatten()
+        .collect()
+}
+
+// whether any publish entry serving the service asks for the PROXY protocol
+pub fn proxy_protocol(publish: &[Publish], host: &str, port: u16) -> bool {
+    publish.iter().any(|publish| {
+        publish.proxy_protocol()
+            && decode_token::<AgentPublishToken>(publish.token()).is_some_and(|token| {
+                token.publish_hosts.iter().any(|publish_host| {
+                    publish_host.connect.host == host && publish_host.connect.port == port
+                })
+            })
+    })
+}
+
+// compression of a published service, from the first publish entry serving it with one
+pub fn compression(publish: &[Publish], host: &str, port: u16) -> Compression {
+    publish
+        .iter()
+        .find_map(|publish| {
+            let compression = publish.compression()?;
+            decode_token::<AgentPublishToken>(publish.token())?
+                .publish_hosts
+                .iter()
+                .any(|publish_host| {
+                    publish_host.connect.host == host && publish_host.connect.port == port
+                })
+                .then_some(compression)
+        })
+        .unwrap_or_default()
 }
 
 #[derive(Deserialize, Serialize)]
@@ -43,70 +449,752 @@ pub struct Config {
     pub endpoints: Vec<Endpoint>,
     #[serde(default = "Vec::new")]
     pub e2ee: Vec<E2EE>,
+    pub control_socket: Option<PathBuf>, // unix socket, or named pipe on Windows
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default = "_default_drain_timeout_secs")]
+    pub drain_timeout: u64, // seconds active connections may take to finish on shutdown
+    #[serde(default)]
+    pub destinations: Vec<Destination>, // hosts clients may reach through the agent, any host if empty
+    #[serde(skip)]
+    pub destination_filter: Arc<DestinationFilter>, // compiled from destinations at load
+    #[serde(default)]
+    pub unix_sockets: Vec<PathBuf>, // unix socket destinations clients may reach, none if empty
+    #[serde(default)]
+    pub dns: Dns,
+    #[serde(default)]
+    pub reverse_binds: Vec<ReverseBindRule>, // addresses clients may listen on through the agent, none if empty
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Dns {
+    pub resolver: Option<String>, // ip[:port] queried over UDP instead of the system resolver
+    #[serde(default = "_default_dns_cache_secs")]
+    pub cache_secs: u64, // upper bound of the answer TTLs, 0 disables the cache
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Self {
+            resolver: None,
+            cache_secs: _default_dns_cache_secs(),
+        }
+    }
+}
+
+impl Dns {
+    pub fn resolver(&self) -> Option<SocketAddr> {
+        let resolver = self.resolver.as_deref()?.trim();
+        resolver
+            .parse()
+            .ok()
+            .or_else(|| resolver.parse().ok().map(|ip| SocketAddr::new(ip, 53)))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json, // one JSON object per line
 }
 
 impl Config {
     pub fn load(path: Option<String>) -> Result<Self, AgentError> {
-        let custom_path = if let Some(path) = path {
-            let path = PathBuf::from(path);
-            Some(
-                if let Some(stripped_path) = path.strip_prefix("~/").ok().filter(|_| !cfg!(windows))
-                {
-                    let home_dir = dirs::home_dir().ok_or(AgentError::InvalidConfigPath)?;
-                    home_dir.join(stripped_path)
-                } else {
-                    path
-                },
-            )
+        Self::load_with_source(path).map(|(config, _)| config)
+    }
+
+    // also returns the config file that was read, as several search locations may have one
+    pub fn load_with_source(path: Option<String>) -> Result<(Self, PathBuf), AgentError> {
+        let path = Self::path(path)?;
+        debug!("Loading config from {}", path.display());
+        let format = ConfigFormat::from_path(&path);
+        let configuration_data = read_file(&path)?;
+        let value = format.parse(&configuration_data)?;
+        // merged from several files, the errors can not point into one of them
+        let mut config: Self = if value.get("include").is_some() {
+            serde_json::from_value(with_includes(&path, value, &mut Vec::new())?)
+                .map_err(|e| AgentError::ConfigParse(format!("{} {}", format.name(), e)))?
         } else {
-            None
+            format.deserialize(&configuration_data, value)?
         };
+        config.expand_env()?;
+        config.control_socket = config.control_socket.map(expand_home).transpose()?;
+        config.read_token_files()?;
+        config.check_api_keys()?;
+        config.check_proxies()?;
+        config.check_cert_pins()?;
+        config.check_resolver()?;
+        config.read_client_identities()?;
+        config.read_ca_bundles()?;
+        config.check_passphrases()?;
+        config.check_pre_shared_keys()?;
+        config.check_e2ee_names()?;
+        config.destination_filter = Arc::new(DestinationFilter::new(
+            &config.destinations,
+            &config.unix_sockets,
+        )?);
+        Ok((config, path))
+    }
 
-        let current_dir = env::current_dir()
-            .map(|mut d| {
-                d.push("agent");
-                d.set_extension("yaml");
-                d
-            })
-            .ok()
-            .filter(|f| f.is_file());
-        let config_dir = dirs::config_dir()
-            .map(|mut d| {
-                d.push("narrowlink");
-                d.push("agent");
-                d.set_extension("yaml");
-                d
-            })
-            .filter(|f| f.is_file());
-
-        let home_dir = dirs::home_dir()
-            .map(|mut d| {
-                d.push(".narrowlink");
-                d.push("agent");
-                d.set_extension("yaml");
-                d
+    // the custom path if given, otherwise the first config file found in the search locations
+    pub fn path(path: Option<String>) -> Result<PathBuf, AgentError> {
+        let custom_path = path.map(PathBuf::from).map(expand_home).transpose()?;
+
+        custom_path
+            .or_else(|| {
+                search_paths().into_iter().find_map(|base| {
+                    ConfigFormat::EXTENSIONS.iter().find_map(|extension| {
+                        Some(base.with_extension(extension)).filter(|f| f.is_file())
+                    })
+                })
             })
-            .filter(|f| f.is_file());
+            .ok_or(AgentError::ConfigNotFound)
+    }
+
+    pub fn default_yaml() -> &'static str {
+        DEFAULT_YAML
+    }
 
-        let etc = if cfg!(target_os = "linux") {
-            Some(PathBuf::from("/etc/narrowlink/agent.yaml"))
+    // writes the default config to the custom path or the first writable search location
+    pub fn init(path: Option<String>) -> Result<PathBuf, AgentError> {
+        let candidates = if let Some(path) = path {
+            vec![expand_home(PathBuf::from(path))?]
         } else {
-            None
+            if let Ok(existing) = Self::path(None) {
+                return Err(AgentError::ConfigAlreadyExists(existing));
+            }
+            search_paths()
+                .into_iter()
+                .map(|base| base.with_extension("yaml"))
+                .collect()
+        };
+        for candidate in candidates {
+            if !matches!(ConfigFormat::from_path(&candidate), ConfigFormat::Yaml) {
+                return Err(AgentError::InvalidConfigPath);
+            }
+            if let Some(parent) = candidate.parent().filter(|p| !p.as_os_str().is_empty()) {
+                if fs::create_dir_all(parent).is_err() {
+                    continue;
+                }
+            }
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&candidate)
+            {
+                Ok(mut file) => {
+                    file.write_all(Self::default_yaml().as_bytes())?;
+                    return Ok(candidate);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    return Err(AgentError::ConfigAlreadyExists(candidate));
+                }
+                Err(_) => continue,
+            }
+        }
+        Err(AgentError::InvalidConfigPath)
+    }
+
+    // the config as loaded, includes merged and variables expanded, with tokens, api keys, proxy
+    // credentials and E2EE secrets masked, used by --print-config
+    pub fn dump(&self) -> Result<String, AgentError> {
+        let mut value =
+            serde_json::to_value(self).map_err(|e| AgentError::ConfigParse(e.to_string()))?;
+        for endpoint in value["endpoints"].as_array_mut().into_iter().flatten() {
+            for endpoint in endpoint
+                .as_object_mut()
+                .into_iter()
+                .flat_map(|m| m.values_mut())
+            {
+                redact(endpoint, &["token", "refresh_token", "api_key"]);
+                if let Some(proxy) = endpoint.get_mut("proxy") {
+                    redact_url_credentials(proxy);
+                }
+                for publish in endpoint["publish"].as_array_mut().into_iter().flatten() {
+                    match publish {
+                        Value::String(_) => redact_value(publish),
+                        publish => redact(publish, &["token"]),
+                    }
+                }
+            }
+        }
+        for e2ee in value["e2ee"].as_array_mut().into_iter().flatten() {
+            for e2ee in e2ee
+                .as_object_mut()
+                .into_iter()
+                .flat_map(|m| m.values_mut())
+            {
+                redact(e2ee, &["phrase", "key", "previous"]);
+            }
+        }
+        serde_yaml::to_string(&value).map_err(|e| AgentError::ConfigParse(e.to_string()))
+    }
+
+    // semantic checks that do not need the gateway, used by --check-config
+    pub fn validate(&self) -> Result<(), AgentError> {
+        if self.endpoints.is_empty() {
+            return Err(AgentError::EndpointNotFound);
+        }
+        let validate_publish = |publish: &Option<Vec<Publish>>| {
+            for publish in publish.iter().flatten() {
+                decode_token::<AgentPublishToken>(publish.token())
+                    .ok_or(AgentError::InvalidPublishToken)?;
+            }
+            Ok::<_, AgentError>(())
+        };
+        for endpoint in self.endpoints.iter() {
+            match endpoint {
+                Endpoint::Platform(platform) => {
+                    if !is_valid_address(&platform.control_plane) {
+                        return Err(AgentError::InvalidGatewayAddress(
+                            platform.control_plane.clone(),
+                        ));
+                    }
+                    validate_publish(&platform.publish)?;
+                }
+                Endpoint::SelfHosted(self_hosted) => {
+                    if !is_valid_address(&self_hosted.gateway) {
+                        return Err(AgentError::InvalidGatewayAddress(
+                            self_hosted.gateway.clone(),
+                        ));
+                    }
+                    // the token is requested on start with a refresh token
+                    if !(self_hosted.token.is_empty() && self_hosted.refresh_token.is_some()) {
+                        decode_token::<AgentToken>(&self_hosted.token)
+                            .ok_or(AgentError::InvalidToken)?;
+                    }
+                    validate_publish(&self_hosted.publish)?;
+                }
+            }
+        }
+        for passphrase in self.e2ee.iter().filter_map(E2EE::passphrase) {
+            if passphrase.phrase.is_empty()
+                || passphrase.previous.iter().any(|phrase| phrase.is_empty())
+            {
+                return Err(AgentError::InvalidPassPhrase);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_api_keys(&self) -> Result<(), AgentError> {
+        for endpoint in self.endpoints.iter() {
+            if let Endpoint::Platform(platform) = endpoint {
+                if !is_valid_api_key(&platform.api_key) {
+                    return Err(AgentError::InvalidApiKey);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_proxies(&self) -> Result<(), AgentError> {
+        for endpoint in self.endpoints.iter() {
+            if let Endpoint::SelfHosted(self_hosted) = endpoint {
+                if let Some(proxy) = &self_hosted.proxy {
+                    if self_hosted.proxy().is_none() {
+                        return Err(AgentError::InvalidProxy(proxy.clone()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_resolver(&self) -> Result<(), AgentError> {
+        match &self.dns.resolver {
+            Some(resolver) if self.dns.resolver().is_none() => {
+                Err(AgentError::InvalidResolver(resolver.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_cert_pins(&self) -> Result<(), AgentError> {
+        for endpoint in self.endpoints.iter() {
+            if let Endpoint::SelfHosted(self_hosted) = endpoint {
+                if let Some(pin) = &self_hosted.gateway_cert_pin {
+                    if self_hosted.cert_pin().is_none() {
+                        return Err(AgentError::InvalidCertPin(pin.clone()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_client_identities(&mut self) -> Result<(), AgentError> {
+        for endpoint in self.endpoints.iter_mut() {
+            if let Endpoint::SelfHosted(self_hosted) = endpoint {
+                match (&self_hosted.client_cert, &self_hosted.client_key) {
+                    (Some(cert), Some(key)) => {
+                        let cert = expand_home(cert.to_owned())?;
+                        let identity = ClientIdentity::from_pem(
+                            &std::fs::read(&cert)?,
+                            &std::fs::read(expand_home(key.to_owned())?)?,
+                        )
+                        .map_err(|_| AgentError::InvalidClientCert(cert))?;
+                        self_hosted.client_identity = Some(identity);
+                    }
+                    (None, None) => {}
+                    _ => {
+                        return Err(AgentError::InvalidConfig(
+                            "client_cert and client_key must be set together",
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_ca_bundles(&mut self) -> Result<(), AgentError> {
+        for endpoint in self.endpoints.iter_mut() {
+            if let Endpoint::SelfHosted(self_hosted) = endpoint {
+                let Some(ca_bundle) = &self_hosted.ca_bundle else {
+                    continue;
+                };
+                let ca_bundle = expand_home(ca_bundle.to_owned())?;
+                let bundle = std::fs::read(&ca_bundle)
+                    .map_err(|e| AgentError::InvalidCaBundle(ca_bundle.clone(), e.to_string()))?;
+                let trust_roots =
+                    TrustRoots::from_pem(&bundle, self_hosted.ca_bundle_extends_roots)
+                        .map_err(|e| AgentError::InvalidCaBundle(ca_bundle, e.to_string()))?;
+                self_hosted.trust_roots = Some(trust_roots);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_token_files(&mut self) -> Result<(), AgentError> {
+        for endpoint in self.endpoints.iter_mut() {
+            match endpoint {
+                Endpoint::Platform(_) => {}
+                Endpoint::SelfHosted(self_hosted) => {
+                    match (self_hosted.token.is_empty(), &self_hosted.token_file) {
+                        (true, Some(token_file)) => {
+                            let mut token = String::new();
+                            File::open(expand_home(token_file.to_owned())?)?
+                                .read_to_string(&mut token)?;
+                            self_hosted.token = token.trim().to_owned();
+                        }
+                        (false, Some(_)) => {
+                            return Err(AgentError::InvalidConfig(
+                                "token and token_file are mutually exclusive",
+                            ))
+                        }
+                        (true, None) if self_hosted.refresh_token.is_none() => {
+                            return Err(AgentError::InvalidConfig(
+                                "token, token_file or refresh_token is required",
+                            ))
+                        }
+                        (_, None) => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_passphrases(&self) -> Result<(), AgentError> {
+        for passphrase in self.e2ee.iter().filter_map(E2EE::passphrase) {
+            // no key could be derived, every encrypted connection would be refused
+            if passphrase.kdf == (KeyDerivation::Pbkdf2Sha3 { iterations: 0 }) {
+                return Err(AgentError::InvalidKeyDerivation);
+            }
+            if passphrase.phrase.chars().count() >= passphrase.min_length {
+                continue;
+            }
+            if passphrase.policy == KeyPolicy::Strict {
+                return Err(AgentError::InvalidConfig(
+                    "passphrase is shorter than min_length under the Strict policy",
+                ));
+            }
+            warn!(
+                "E2EE passphrase is shorter than {} characters",
+                passphrase.min_length
+            );
+        }
+        Ok(())
+    }
+
+    // publish entries naming a policy that does not exist would otherwise run unencrypted
+    fn check_e2ee_names(&self) -> Result<(), AgentError> {
+        for endpoint in self.endpoints.iter() {
+            let publish = match endpoint {
+                Endpoint::Platform(platform) => &platform.publish,
+                Endpoint::SelfHosted(self_hosted) => &self_hosted.publish,
+            };
+            for name in publish.iter().flatten().filter_map(Publish::e2ee) {
+                if !self.e2ee.iter().any(|e2ee| e2ee.name() == Some(name)) {
+                    return Err(AgentError::E2EENotFound(name.to_owned()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_pre_shared_keys(&self) -> Result<(), AgentError> {
+        for e2ee in self.e2ee.iter() {
+            if let E2EE::PreSharedKey(pre_shared_key) = e2ee {
+                for key in [&pre_shared_key.key]
+                    .into_iter()
+                    .chain(pre_shared_key.previous.iter())
+                {
+                    decode_pre_shared_key(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn expand_env(&mut self) -> Result<(), AgentError> {
+        for endpoint in self.endpoints.iter_mut() {
+            match endpoint {
+                Endpoint::Platform(platform) => {
+                    expand_env(&mut platform.control_plane)?;
+                    expand_env(&mut platform.api_key)?;
+                    for publish in platform.publish.iter_mut().flatten() {
+                        expand_env(publish.token_mut())?;
+                    }
+                }
+                Endpoint::SelfHosted(self_hosted) => {
+                    expand_env(&mut self_hosted.gateway)?;
+                    if let Some(proxy) = self_hosted.proxy.as_mut() {
+                        expand_env(proxy)?;
+                    }
+                    expand_env(&mut self_hosted.token)?;
+                    if let Some(refresh_token) = self_hosted.refresh_token.as_mut() {
+                        expand_env(refresh_token)?;
+                    }
+                    for publish in self_hosted.publish.iter_mut().flatten() {
+                        expand_env(publish.token_mut())?;
+                    }
+                    if let Some(acme) = self_hosted.acme.as_mut() {
+                        expand_env(&mut acme.email)?;
+                    }
+                }
+            }
+        }
+        for e2ee in self.e2ee.iter_mut() {
+            match e2ee {
+                E2EE::PassPhrase(passphrase) | E2EE::EphemeralPassPhrase(passphrase) => {
+                    expand_env(&mut passphrase.phrase)?;
+                    for phrase in passphrase.previous.iter_mut() {
+                        expand_env(phrase)?;
+                    }
+                }
+                E2EE::PreSharedKey(pre_shared_key) => {
+                    expand_env(&mut pre_shared_key.key)?;
+                    for key in pre_shared_key.previous.iter_mut() {
+                        expand_env(key)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// config file locations without extension, in search order
+fn search_paths() -> Vec<PathBuf> {
+    let current_dir = env::current_dir()
+        .map(|mut d| {
+            d.push("agent");
+            d
+        })
+        .ok();
+    let config_dir = dirs::config_dir().map(|mut d| {
+        d.push("narrowlink");
+        d.push("agent");
+        d
+    });
+
+    let home_dir = dirs::home_dir().map(|mut d| {
+        d.push(".narrowlink");
+        d.push("agent");
+        d
+    });
+
+    let etc = if cfg!(target_os = "linux") {
+        Some(PathBuf::from("/etc/narrowlink/agent"))
+    } else {
+        None
+    };
+    [current_dir, config_dir, home_dir, etc]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+// prefixes are matched by component, so on windows `~\` is stripped as well as `~/`
+fn expand_home(path: PathBuf) -> Result<PathBuf, AgentError> {
+    if let Ok(stripped_path) = path.strip_prefix("~/") {
+        let home_dir = dirs::home_dir().ok_or(AgentError::InvalidConfigPath)?;
+        Ok(home_dir.join(stripped_path))
+    } else {
+        Ok(path)
+    }
+}
+
+// host:port, as expected by the transport
+fn is_valid_address(address: &str) -> bool {
+    address.rsplit_once(':').is_some_and(|(host, port)| {
+        !host.is_empty() && !host.contains('/') && port.parse::<u16>().is_ok_and(|port| port != 0)
+    })
+}
+
+fn is_valid_api_key(api_key: &str) -> bool {
+    api_key.len() >= 32
+        && api_key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// reads the claims without verifying the signature, only the gateway holds the secret
+fn decode_token<T: DeserializeOwned>(token: &str) -> Option<T> {
+    token
+        .split('.')
+        .nth(1)
+        .and_then(|claims| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(claims)
+                .ok()
+        })
+        .and_then(|claims| serde_json::from_slice(&claims).ok())
+}
+
+const REDACTED: &str = "<redacted>";
+
+// masks the strings of the fields, lists are masked entry by entry to keep their length
+fn redact(value: &mut Value, fields: &[&str]) {
+    let Some(map) = value.as_object_mut() else {
+        return;
+    };
+    for field in fields {
+        match map.get_mut(*field) {
+            Some(Value::Array(values)) => values.iter_mut().for_each(redact_value),
+            Some(value) => redact_value(value),
+            None => {}
+        }
+    }
+}
+
+// unset and empty values are kept, they are what debugging usually looks for
+fn redact_value(value: &mut Value) {
+    if value.as_str().is_some_and(|value| !value.is_empty()) {
+        *value = Value::String(REDACTED.to_owned());
+    }
+}
+
+fn redact_url_credentials(value: &mut Value) {
+    let Some(url) = value.as_str() else {
+        return;
+    };
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return;
+    };
+    if let Some((_, host)) = rest.rsplit_once('@') {
+        *value = Value::String(format!("{}://{}@{}", scheme, REDACTED, host));
+    }
+}
+
+// replaces ${NAME} with the value of the environment variable NAME, $${ is a literal ${
+fn expand_env(value: &mut String) -> Result<(), AgentError> {
+    let mut res = String::with_capacity(value.len());
+    let mut rest = value.as_str();
+    while let Some(start) = rest.find("${") {
+        if let Some(before) = rest[..start].strip_suffix('$') {
+            res.push_str(before);
+            res.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        res.push_str(&rest[..start]);
+        res.push_str(
+            &env::var(name).map_err(|_| AgentError::EnvironmentVariableNotSet(name.to_owned()))?,
+        );
+        rest = &rest[start + 3 + len..];
+    }
+    res.push_str(rest);
+    *value = res;
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    const EXTENSIONS: [&'static str; 4] = ["yaml", "yml", "toml", "json"];
+
+    // anything other than .toml and .json is read as yaml
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::Toml,
+            Some("json") => Self::Json,
+            _ => Self::Yaml,
         }
-        .filter(|f| f.is_file());
+    }
 
-        let path = custom_path
-            .or(current_dir)
-            .or(config_dir)
-            .or(home_dir)
-            .or(etc)
-            .ok_or(AgentError::ConfigNotFound)?;
+    // every message carries the line and column of the syntax error
+    fn parse(self, data: &str) -> Result<Value, AgentError> {
+        let error =
+            |e: &dyn std::fmt::Display| AgentError::ConfigParse(format!("{} {}", self.name(), e));
+        match self {
+            Self::Yaml => serde_yaml::from_str(data)
+                .map_err(|e| error(&e))
+                .and_then(|value| json_value(value).ok_or(error(&"value out of range"))),
+            Self::Toml => toml::parse(data).map_err(|e| error(&e)),
+            Self::Json => serde_json::from_str(data).map_err(|e| error(&e)),
+        }
+    }
 
-        let mut file = File::open(path)?;
-        let mut configuration_data = String::new();
-        file.read_to_string(&mut configuration_data)?;
-        serde_yaml::from_str(&configuration_data).or(Err(AgentError::InvalidConfig))
+    // straight from the text, so type errors also carry their line and column, the parsed
+    // value is still read when that fails as YAML may spell variants as single key maps too
+    fn deserialize<T: DeserializeOwned>(self, data: &str, value: Value) -> Result<T, AgentError> {
+        let error =
+            |e: &dyn std::fmt::Display| AgentError::ConfigParse(format!("{} {}", self.name(), e));
+        match self {
+            Self::Yaml => serde_yaml::from_str(data)
+                .or_else(|e| serde_json::from_value(value).map_err(|_| e))
+                .map_err(|e| error(&e)),
+            Self::Toml => serde_json::from_value(value).map_err(|e| error(&e)),
+            Self::Json => serde_json::from_str(data).map_err(|e| error(&e)),
+        }
     }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Yaml => "YAML",
+            Self::Toml => "TOML",
+            Self::Json => "JSON",
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, AgentError> {
+    let mut configuration_data = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut configuration_data))
+        .map_err(AgentError::ConfigIo)?;
+    Ok(configuration_data)
+}
+
+// reads the file and merges the files listed in its `include` on top of it, in order;
+// `stack` holds the files being read to reject cyclic includes
+fn read_with_includes(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Value, AgentError> {
+    let value = ConfigFormat::from_path(path).parse(&read_file(path)?)?;
+    with_includes(path, value, stack)
+}
+
+fn with_includes(
+    path: &Path,
+    mut value: Value,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Value, AgentError> {
+    let canonical_path = fs::canonicalize(path).map_err(AgentError::ConfigIo)?;
+    if stack.contains(&canonical_path) {
+        return Err(AgentError::CyclicInclude(canonical_path));
+    }
+    let includes = match value.as_object_mut().and_then(|m| m.remove("include")) {
+        Some(Value::Array(includes)) => includes,
+        Some(Value::String(include)) => vec![Value::String(include)],
+        Some(_) => return Err(AgentError::InvalidConfig("include must be a list of paths")),
+        None => return Ok(value),
+    };
+    stack.push(canonical_path);
+    for include in includes {
+        let Value::String(include) = include else {
+            return Err(AgentError::InvalidConfig("include must be a list of paths"));
+        };
+        let include = expand_home(PathBuf::from(include))?;
+        // relative to the including file
+        let include = path
+            .parent()
+            .map(|dir| dir.join(&include))
+            .unwrap_or(include);
+        merge(&mut value, read_with_includes(&include, stack)?, None);
+    }
+    stack.pop();
+    Ok(value)
+}
+
+// mappings merge recursively, publish and e2ee lists are concatenated, other lists merge
+// element by element and anything else is replaced by the later value
+fn merge(base: &mut Value, other: Value, key: Option<&str>) {
+    match (base, other) {
+        (Value::Object(base), Value::Object(other)) => {
+            for (k, v) in other {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v, Some(&k)),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(other)) if matches!(key, Some("publish" | "e2ee")) => {
+            base.extend(other)
+        }
+        (Value::Array(base), Value::Array(other)) => {
+            for (i, v) in other.into_iter().enumerate() {
+                match base.get_mut(i) {
+                    Some(b) => merge(b, v, None),
+                    None => base.push(v),
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+// YAML tags become single key maps, the way JSON and TOML spell enum variants
+fn json_value(value: serde_yaml::Value) -> Option<Value> {
+    Some(match value {
+        serde_yaml::Value::Null => Value::Null,
+        serde_yaml::Value::Bool(b) => Value::Bool(b),
+        serde_yaml::Value::Number(n) => {
+            if let Some(n) = n.as_i64() {
+                Value::from(n)
+            } else if let Some(n) = n.as_u64() {
+                Value::from(n)
+            } else {
+                Value::Number(serde_json::Number::from_f64(n.as_f64()?)?)
+            }
+        }
+        serde_yaml::Value::String(s) => Value::String(s),
+        serde_yaml::Value::Sequence(sequence) => Value::Array(
+            sequence
+                .into_iter()
+                .map(json_value)
+                .collect::<Option<_>>()?,
+        ),
+        serde_yaml::Value::Mapping(mapping) => Value::Object(
+            mapping
+                .into_iter()
+                .map(|(k, v)| {
+                    let k = match k {
+                        serde_yaml::Value::String(k) => k,
+                        k => serde_yaml::to_string(&k).ok()?.trim().to_owned(),
+                    };
+                    Some((k, json_value(v)?))
+                })
+                .collect::<Option<_>>()?,
+        ),
+        serde_yaml::Value::Tagged(tagged) => Value::Object(serde_json::Map::from_iter([(
+            tagged.tag.to_string().trim_start_matches('!').to_owned(),
+            json_value(tagged.value)?,
+        )])),
+    })
 }
 
 // impl SelfHosted {
@@ -124,3 +1212,192 @@ impl Config {
 //             .map(|t| t.name)
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(value: &str) -> String {
+        let mut value = value.to_owned();
+        expand_env(&mut value).expect("expanded");
+        value
+    }
+
+    #[test]
+    fn env_references_expand_unless_escaped() {
+        env::set_var("NARROWLINK_EXPAND_ENV_TEST", "secret");
+        assert_eq!(expanded("a${NARROWLINK_EXPAND_ENV_TEST}b"), "asecretb");
+        assert_eq!(
+            expanded("a$${NARROWLINK_EXPAND_ENV_TEST}b"),
+            "a${NARROWLINK_EXPAND_ENV_TEST}b"
+        );
+        assert_eq!(
+            expanded("$${literal} ${NARROWLINK_EXPAND_ENV_TEST}"),
+            "${literal} secret"
+        );
+        assert_eq!(
+            expanded("price: $5 ${unterminated"),
+            "price: $5 ${unterminated"
+        );
+        assert!(matches!(
+            expand_env(&mut "${NARROWLINK_EXPAND_ENV_UNSET}".to_owned()),
+            Err(AgentError::EnvironmentVariableNotSet(_))
+        ));
+    }
+
+    #[test]
+    fn rate_limits_below_one_byte_per_second_are_rejected() {
+        let rate_limit = |bytes_per_sec| {
+            serde_json::from_value::<RateLimit>(
+                serde_json::json!({ "bytes_per_sec": bytes_per_sec }),
+            )
+        };
+        assert!(rate_limit(0).is_err());
+        assert!(rate_limit(1).is_ok_and(|limit| limit.bytes_per_sec == 1 && limit.burst() == 1));
+    }
+
+    fn check_passphrase(phrase: &str, policy: &str) -> Result<(), AgentError> {
+        serde_json::from_value::<Config>(serde_json::json!({
+            "endpoints": [],
+            "e2ee": [{ "PassPhrase": { "phrase": phrase, "policy": policy } }],
+        }))
+        .expect("config")
+        .check_passphrases()
+    }
+
+    #[test]
+    fn passphrases_shorter_than_min_length_fail_only_under_strict() {
+        assert!(check_passphrase(&"a".repeat(16), "Strict").is_ok());
+        assert!(matches!(
+            check_passphrase(&"a".repeat(15), "Strict"),
+            Err(AgentError::InvalidConfig(_))
+        ));
+        assert!(check_passphrase(&"a".repeat(15), "Lax").is_ok());
+        assert!(check_passphrase("", "Lax").is_ok());
+    }
+
+    #[test]
+    fn zero_pbkdf2_iterations_are_rejected_at_load() {
+        let kdf = |iterations| {
+            serde_json::from_value::<Config>(serde_json::json!({
+                "endpoints": [],
+                "e2ee": [{ "PassPhrase": {
+                    "phrase": "a".repeat(16),
+                    "kdf": { "Pbkdf2Sha3": { "iterations": iterations } },
+                }}],
+            }))
+            .expect("config")
+            .check_passphrases()
+        };
+        assert!(matches!(kdf(0), Err(AgentError::InvalidKeyDerivation)));
+        assert!(kdf(1).is_ok());
+    }
+
+    #[test]
+    fn passphrase_lengths_count_characters_not_bytes() {
+        assert!(check_passphrase(&"é".repeat(16), "Strict").is_ok());
+        assert!(check_passphrase(&"é".repeat(15), "Strict").is_err());
+        assert!(check_passphrase(&"🔑".repeat(16), "Strict").is_ok());
+        assert!(check_passphrase(&"🔑".repeat(15), "Strict").is_err()); // 60 bytes
+
+        // a combining accent is a character of its own
+        assert!(check_passphrase(&"e\u{301}".repeat(8), "Strict").is_ok());
+    }
+
+    fn from_yaml(yaml: &str) -> Result<Config, AgentError> {
+        ConfigFormat::Yaml.deserialize(yaml, ConfigFormat::Yaml.parse(yaml)?)
+    }
+
+    #[test]
+    fn yaml_type_errors_carry_their_line_and_column() {
+        let yaml =
+            "endpoints: []\ne2ee:\n  - !PassPhrase\n    phrase: key\n    min_length: sixteen\n";
+        let Err(AgentError::ConfigParse(error)) = from_yaml(yaml) else {
+            panic!("type error expected");
+        };
+        assert!(error.contains("line 5 column 17"), "{error}");
+        assert!(from_yaml("endpoints: []\ne2ee:\n  - PassPhrase:\n      phrase: key\n").is_ok());
+    }
+
+    #[test]
+    fn yaml_deserializes_as_through_json_values() {
+        for yaml in [
+            include_str!("../sample_agent.yaml"),
+            include_str!("../default_agent.yaml"),
+        ] {
+            let direct: Config = serde_yaml::from_str(yaml).expect("direct");
+            let through_value: Config =
+                serde_json::from_value(ConfigFormat::Yaml.parse(yaml).expect("value"))
+                    .expect("through value");
+            assert_eq!(
+                serde_json::to_value(direct).expect("direct"),
+                serde_json::to_value(through_value).expect("through value")
+            );
+        }
+    }
+
+    fn publish(e2ee: &str) -> Publish {
+        let claims = serde_json::json!({
+            "uid": "00000000-0000-0000-0000-000000000000",
+            "name": "agent",
+            "exp": 0,
+            "publish_hosts": [{
+                "host": "web.example.com",
+                "port": 443,
+                "connect": {"host": "127.0.0.1", "port": 8080, "protocol": "TCP", "cryptography": null, "sign": null},
+            }],
+        });
+        let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(claims.to_string());
+        serde_json::from_value(
+            serde_json::json!({ "token": format!("header.{}.signature", claims), "e2ee": e2ee }),
+        )
+        .expect("publish")
+    }
+
+    #[test]
+    fn unknown_e2ee_policy_names_are_rejected() {
+        let e2ee: Vec<E2EE> = serde_json::from_value(serde_json::json!([
+            { "PassPhrase": { "name": "sensitive", "phrase": "a".repeat(16) } },
+            { "PassPhrase": { "phrase": "b".repeat(16) } },
+        ]))
+        .expect("e2ee");
+        let key = |name| e2ee_key(&[publish(name)], &e2ee, "127.0.0.1", 8080);
+        assert!(key("sensitive").is_ok_and(|key| key.is_some()));
+        assert!(matches!(key("sensitve"), Err(AgentError::E2EENotFound(_))));
+        assert!(e2ee_key(&[publish("sensitve")], &e2ee, "127.0.0.1", 8081)
+            .is_ok_and(|key| key.is_some()));
+
+        let config = |name| {
+            serde_json::from_value::<Config>(serde_json::json!({
+                "endpoints": [{ "SelfHosted": {
+                    "gateway": "gateway.example.com:443",
+                    "token": "token",
+                    "publish": [publish(name)],
+                }}],
+                "e2ee": e2ee,
+            }))
+            .expect("config")
+        };
+        assert!(config("sensitive").check_e2ee_names().is_ok());
+        assert!(matches!(
+            config("sensitve").check_e2ee_names(),
+            Err(AgentError::E2EENotFound(name)) if name == "sensitve"
+        ));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn tilde_paths_expand_to_the_profile_on_windows() {
+        let home_dir = dirs::home_dir().expect("home dir");
+        for path in ["~\\narrowlink\\agent.yaml", "~/narrowlink/agent.yaml"] {
+            assert_eq!(
+                expand_home(PathBuf::from(path)).expect("expanded"),
+                home_dir.join("narrowlink").join("agent.yaml")
+            );
+        }
+        assert_eq!(
+            expand_home(PathBuf::from("C:\\narrowlink\\agent.yaml")).expect("unchanged"),
+            PathBuf::from("C:\\narrowlink\\agent.yaml")
+        );
+    }
+}
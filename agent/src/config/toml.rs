@@ -0,0 +1,451 @@
+// Minimal TOML reader for the agent configuration. It covers tables, arrays of tables,
+// dotted and quoted keys, single line strings, integers, floats, booleans, arrays and
+// inline tables, which is everything the configuration format uses.
+use std::fmt;
+
+use serde_json::{Map, Number, Value};
+
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    message: &'static str,
+    line: usize,   // from 1
+    column: usize, // from 1, in characters
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {} column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+type Parsed<T> = Result<T, &'static str>;
+
+pub fn parse(input: &str) -> Result<Value, Error> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+    };
+    let mut root = Map::new();
+    let mut current = Vec::new(); // path of the table the following keys belong to
+    loop {
+        parser.skip_blank();
+        let start = parser.pos;
+        if parser.peek().is_none() {
+            return Ok(Value::Object(root));
+        }
+        parser
+            .statement(&mut root, &mut current)
+            .map_err(|message| {
+                // a key defined twice is reported where its line starts
+                let pos = if message == DUPLICATE_KEY {
+                    start
+                } else {
+                    parser.pos
+                };
+                parser.error(message, pos)
+            })?;
+    }
+}
+
+const DUPLICATE_KEY: &str = "duplicate key";
+
+// walks down the path, creating missing tables and descending into the last element of arrays of tables
+fn table<'a>(
+    mut table: &'a mut Map<String, Value>,
+    path: &[String],
+) -> Parsed<&'a mut Map<String, Value>> {
+    for key in path {
+        let value = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        let value = match value {
+            Value::Array(tables) => tables.last_mut().ok_or("key is not a table")?,
+            value => value,
+        };
+        table = value.as_object_mut().ok_or("key is not a table")?;
+    }
+    Ok(table)
+}
+
+fn insert(root: &mut Map<String, Value>, key: &[String], value: Value) -> Parsed<()> {
+    let (last, parent) = key.split_last().ok_or("expected a key")?;
+    let parent = table(root, parent)?;
+    if parent.contains_key(last) {
+        return Err(DUPLICATE_KEY);
+    }
+    parent.insert(last.clone(), value);
+    Ok(())
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &'static str, pos: usize) -> Error {
+        let before = &self.chars[..pos.min(self.chars.len())];
+        let line_start = before
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |newline| newline + 1);
+        Error {
+            message,
+            line: before.iter().filter(|c| **c == '\n').count() + 1,
+            column: before.len() - line_start + 1,
+        }
+    }
+
+    // a table header or a key/value pair, with the rest of its line
+    fn statement(
+        &mut self,
+        root: &mut Map<String, Value>,
+        current: &mut Vec<String>,
+    ) -> Parsed<()> {
+        if self.eat('[') {
+            let array = self.eat('[');
+            let key = self.key()?;
+            if !self.eat(']') || (array && !self.eat(']')) {
+                return Err("expected ] after the table name");
+            }
+            self.end_of_line()?;
+            let (last, parent) = key.split_last().ok_or("expected a key")?;
+            let parent = table(root, parent)?;
+            if array {
+                let Value::Array(tables) = parent
+                    .entry(last.clone())
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                else {
+                    return Err("key is not an array of tables");
+                };
+                tables.push(Value::Object(Map::new()));
+            } else {
+                table(parent, std::slice::from_ref(last))?;
+            }
+            *current = key;
+        } else {
+            let key = self.key()?;
+            if !self.eat('=') {
+                return Err("expected = after the key");
+            }
+            self.skip_whitespace();
+            let value = self.value()?;
+            self.end_of_line()?;
+            insert(table(root, current)?, &key, value)?;
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Parsed<char> {
+        let c = self.peek().ok_or("unexpected end of file")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.pos += 1;
+        }
+    }
+
+    // whitespace, newlines and comments
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('\n' | '\r') => self.pos += 1,
+                Some('#') => self.skip_comment(),
+                _ => return,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Parsed<()> {
+        self.skip_whitespace();
+        if self.peek() == Some('#') {
+            self.skip_comment();
+        }
+        self.eat('\r');
+        if self.eat('\n') || self.peek().is_none() {
+            Ok(())
+        } else {
+            Err("expected the end of the line")
+        }
+    }
+
+    fn key(&mut self) -> Parsed<Vec<String>> {
+        let mut key = Vec::new();
+        loop {
+            self.skip_whitespace();
+            key.push(match self.peek().ok_or("expected a key")? {
+                '"' => self.basic_string()?,
+                '\'' => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err("expected a key");
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            });
+            self.skip_whitespace();
+            if !self.eat('.') {
+                return Ok(key);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Parsed<Value> {
+        match self.peek().ok_or("expected a value")? {
+            '"' => self.basic_string().map(Value::String),
+            '\'' => self.literal_string().map(Value::String),
+            '[' => {
+                self.pos += 1;
+                let mut array = Vec::new();
+                loop {
+                    self.skip_blank();
+                    if self.eat(']') {
+                        return Ok(Value::Array(array));
+                    }
+                    array.push(self.value()?);
+                    self.skip_blank();
+                    if !self.eat(',') {
+                        return if self.eat(']') {
+                            Ok(Value::Array(array))
+                        } else {
+                            Err("expected , or ] in the array")
+                        };
+                    }
+                }
+            }
+            '{' => {
+                self.pos += 1;
+                let mut table = Map::new();
+                self.skip_whitespace();
+                if self.eat('}') {
+                    return Ok(Value::Object(table));
+                }
+                loop {
+                    let key = self.key()?;
+                    if !self.eat('=') {
+                        return Err("expected = after the key");
+                    }
+                    self.skip_whitespace();
+                    let value = self.value()?;
+                    insert(&mut table, &key, value)?;
+                    self.skip_whitespace();
+                    if self.eat('}') {
+                        return Ok(Value::Object(table));
+                    }
+                    if !self.eat(',') {
+                        return Err("expected , or } in the inline table");
+                    }
+                }
+            }
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.'))
+                {
+                    self.pos += 1;
+                }
+                let token: String = self.chars[start..self.pos]
+                    .iter()
+                    .filter(|c| **c != '_')
+                    .collect();
+                let value = match token.as_str() {
+                    "true" => Some(Value::Bool(true)),
+                    "false" => Some(Value::Bool(false)),
+                    _ => token.parse::<i64>().ok().map(Value::from).or_else(|| {
+                        token
+                            .parse::<f64>()
+                            .ok()
+                            .and_then(Number::from_f64)
+                            .map(Value::Number)
+                    }),
+                };
+                // reported where the value starts
+                value.ok_or_else(|| {
+                    self.pos = start;
+                    "expected a string, number, boolean, array or inline table"
+                })
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Parsed<String> {
+        self.pos += 1;
+        let mut res = String::new();
+        loop {
+            let c = match self.next().map_err(|_| "unterminated string")? {
+                '"' => return Ok(res),
+                '\n' => {
+                    self.pos -= 1; // reported at the end of its line
+                    return Err("unterminated string");
+                }
+                '\\' => match self.next()? {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    '"' => '"',
+                    '\\' => '\\',
+                    'u' => self.unicode(4)?,
+                    'U' => self.unicode(8)?,
+                    _ => {
+                        self.pos -= 2; // reported at the backslash
+                        return Err("invalid escape sequence");
+                    }
+                },
+                c => c,
+            };
+            res.push(c);
+        }
+    }
+
+    fn unicode(&mut self, len: usize) -> Parsed<char> {
+        let hex: String = self
+            .chars
+            .get(self.pos..self.pos + len)
+            .ok_or("invalid unicode escape")?
+            .iter()
+            .collect();
+        let c = u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or("invalid unicode escape")?;
+        self.pos += len;
+        Ok(c)
+    }
+
+    fn literal_string(&mut self) -> Parsed<String> {
+        self.pos += 1;
+        let mut res = String::new();
+        loop {
+            match self.next().map_err(|_| "unterminated string")? {
+                '\'' => return Ok(res),
+                '\n' => {
+                    self.pos -= 1; // reported at the end of its line
+                    return Err("unterminated string");
+                }
+                c => res.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn error(input: &str) -> String {
+        parse(input).expect_err("invalid toml").to_string()
+    }
+
+    #[test]
+    fn config_shaped_documents_parse() {
+        let input = r#"
+# agent
+name = "home" # trailing comment
+gateway = 'gateway.example.com:443'
+"quoted.key" = 1_000
+ratio = 0.5
+enabled = true
+labels = [ "a",
+  "b", ]
+inline = { port = 8080, nested.key = "\u00e9\t" }
+
+[[endpoints]]
+kind.Direct = {}
+
+[[endpoints]]
+kind = "Relay"
+
+[logging]
+level = -1
+"#;
+        assert_eq!(
+            parse(input).expect("valid toml"),
+            json!({
+                "name": "home",
+                "gateway": "gateway.example.com:443",
+                "quoted.key": 1000,
+                "ratio": 0.5,
+                "enabled": true,
+                "labels": ["a", "b"],
+                "inline": { "port": 8080, "nested": { "key": "\u{e9}\t" } },
+                "endpoints": [{ "kind": { "Direct": {} } }, { "kind": "Relay" }],
+                "logging": { "level": -1 },
+            })
+        );
+    }
+
+    #[test]
+    fn errors_carry_the_line_and_column() {
+        assert_eq!(
+            error("name = \"home\"\ngateway = \"unterminated\n"),
+            "unterminated string at line 2 column 24"
+        );
+        assert_eq!(
+            error("name = \"home\"\nname = \"work\""),
+            "duplicate key at line 2 column 1"
+        );
+        assert_eq!(
+            error("[table]\n  key value"),
+            "expected = after the key at line 2 column 7"
+        );
+        assert_eq!(
+            error("port = 80 80"),
+            "expected the end of the line at line 1 column 11"
+        );
+        assert_eq!(
+            error("port = eighty"),
+            "expected a string, number, boolean, array or inline table at line 1 column 8"
+        );
+        assert_eq!(
+            error("path = \"C:\\temp\\x\""),
+            "invalid escape sequence at line 1 column 16"
+        );
+        assert_eq!(
+            error("labels = [\"a\" \"b\"]"),
+            "expected , or ] in the array at line 1 column 15"
+        );
+        assert_eq!(
+            error("[table\nkey = 1"),
+            "expected ] after the table name at line 1 column 7"
+        );
+        assert_eq!(
+            error("name = 1\n[name]"),
+            "key is not a table at line 2 column 7"
+        );
+    }
+}
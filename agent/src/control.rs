@@ -0,0 +1,79 @@
+use std::{io, path::PathBuf};
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, info, warn};
+
+use crate::stats;
+
+// one JSON object per line, e.g. {"command":"services"}, answered with one JSON line
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    Status,
+    Services,
+}
+
+pub fn spawn(path: PathBuf) {
+    tokio::spawn(async move {
+        if let Err(e) = serve(&path).await {
+            warn!("Control socket {} stopped: {}", path.display(), e);
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn serve(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    // a socket left behind by a previous run, anything else at the path is kept
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(handle(stream));
+    }
+}
+
+#[cfg(windows)]
+async fn serve(path: &std::path::Path) -> io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(path)?;
+    info!("Control pipe listening on {}", path.display());
+    loop {
+        server.connect().await?;
+        let client = server;
+        server = ServerOptions::new().create(path)?;
+        tokio::spawn(handle(client));
+    }
+}
+
+async fn handle(stream: impl AsyncRead + AsyncWrite) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Status) => serde_json::to_string(&stats::status()),
+            Ok(Request::Services) => serde_json::to_string(&stats::services()),
+            Err(e) => {
+                debug!("Invalid control request: {}", e);
+                serde_json::to_string(&serde_json::json!({ "error": e.to_string() }))
+            }
+        };
+        let Ok(mut response) = response else {
+            break;
+        };
+        response.push('\n');
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use narrowlink_types::{generic::Connect, policy::Policy};
+use regex_lite::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use wildmatch::WildMatch;
+
+use crate::error::AgentError;
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub enum Destination {
+    Wildcard(String), // *.internal.corp, ? matches a single character
+    Regex(String),    // has to match the whole host, case-insensitively
+}
+
+enum Rule {
+    Wildcard(WildMatch),
+    Regex(Regex),
+}
+
+// hosts clients may reach through the agent, checked before dialing, any host if there are no rules,
+// unix sockets only if listed
+#[derive(Default)]
+pub struct DestinationFilter {
+    rules: Vec<Rule>,
+    unix_sockets: Vec<PathBuf>,
+}
+
+impl DestinationFilter {
+    pub fn new(destinations: &[Destination], unix_sockets: &[PathBuf]) -> Result<Self, AgentError> {
+        let rules = destinations
+            .iter()
+            .map(|destination| match destination {
+                Destination::Wildcard(pattern) => Ok(Rule::Wildcard(WildMatch::new(
+                    &pattern.to_ascii_lowercase(),
+                ))),
+                Destination::Regex(pattern) => RegexBuilder::new(&format!("^(?:{})$", pattern))
+                    .case_insensitive(true)
+                    .build()
+                    .map(Rule::Regex)
+                    .map_err(|_| AgentError::InvalidDestination(pattern.clone())),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            rules,
+            unix_sockets: unix_sockets.to_vec(),
+        })
+    }
+
+    pub fn permit(&self, host: &str) -> bool {
+        if let Some(path) = host.strip_prefix("unix:") {
+            return self
+                .unix_sockets
+                .iter()
+                .any(|unix_socket| unix_socket == Path::new(path));
+        }
+        if self.rules.is_empty() {
+            return true;
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.rules.iter().any(|rule| match rule {
+            Rule::Wildcard(pattern) => pattern.matches(&host),
+            Rule::Regex(pattern) => pattern.is_match(&host),
+        })
+    }
+}
+
+// checked against the IP policies of the client, address is None for a unix socket, which the
+// policies can't name, so clients restricted by any are refused it
+pub fn ip_permit(connect: &Connect, address: Option<IpAddr>, ip_policies: &[Policy]) -> bool {
+    let Some(address) = address else {
+        return ip_policies.is_empty();
+    };
+    let mut connect = connect.clone();
+    connect.host = address.to_string();
+    ip_policies.is_empty() || ip_policies.iter().any(|p| p.permit(&connect))
+}
+
+#[cfg(test)]
+mod tests {
+    use narrowlink_types::generic::Protocol;
+
+    use super::*;
+
+    fn connect(host: &str) -> Connect {
+        Connect {
+            host: host.to_owned(),
+            port: 80,
+            protocol: Protocol::TCP,
+            cryptography: None,
+            sign: None,
+            kdf: None,
+            exchange: None,
+            reverse: None,
+            source: None,
+        }
+    }
+
+    fn blacklist(network: &str) -> Policy {
+        serde_json::from_value(serde_json::json!({
+            "type": "BlackList",
+            "policies": [{"Ip": ["Any", network, 0, "TCP"]}],
+        }))
+        .expect("policy")
+    }
+
+    #[test]
+    fn patterns_match_hosts_regardless_of_case() {
+        let filter = DestinationFilter::new(
+            &[
+                Destination::Regex(r"API\.Internal\.corp".to_owned()),
+                Destination::Regex(r"[A-Z]+\.example\.com".to_owned()),
+                Destination::Wildcard("*.Lab.corp".to_owned()),
+            ],
+            &[],
+        )
+        .expect("destination filter");
+        assert!(filter.permit("api.internal.corp"));
+        assert!(filter.permit("API.INTERNAL.CORP."));
+        assert!(filter.permit("www.example.com"));
+        assert!(filter.permit("host.lab.CORP"));
+        assert!(!filter.permit("api.internal.corp.evil"));
+        assert!(!filter.permit("www1.example.com"));
+    }
+
+    #[test]
+    fn unix_sockets_are_denied_unless_listed() {
+        let filter = DestinationFilter::new(&[], &[PathBuf::from("/run/app.sock")])
+            .expect("destination filter");
+        assert!(filter.permit("unix:/run/app.sock"));
+        assert!(!filter.permit("unix:/run/other.sock"));
+        assert!(filter.permit("example.com"));
+        let filter = DestinationFilter::new(&[], &[]).expect("destination filter");
+        assert!(!filter.permit("unix:/run/app.sock"));
+    }
+
+    #[test]
+    fn blacklisted_clients_are_refused_unix_sockets() {
+        let unix = connect("unix:/run/app.sock");
+        assert!(ip_permit(&unix, None, &[]));
+        let policies = [blacklist("10.0.0.0/8")];
+        assert!(!ip_permit(&unix, None, &policies));
+        let tcp = connect("example.com");
+        assert!(!ip_permit(&tcp, Some([10, 0, 0, 1].into()), &policies));
+        assert!(ip_permit(&tcp, Some([192, 168, 0, 1].into()), &policies));
+    }
+}
@@ -1,4 +1,5 @@
 use narrowlink_network::error::NetworkError;
+use narrowlink_types::error::PreSharedKeyError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,8 +22,72 @@ pub enum AgentError {
     KeyNotFound,
     #[error("Config Not Found")]
     ConfigNotFound,
-    #[error("Invalid Config")]
-    InvalidConfig,
-    #[error("Unable To Resolve")]
-    UnableToResolve,
+    #[error("Unable To Read Config: {0}")]
+    ConfigIo(std::io::Error),
+    #[error("Unable To Parse Config: {0}")]
+    ConfigParse(String),
+    #[error("Config Already Exists: {}", .0.display())]
+    ConfigAlreadyExists(std::path::PathBuf),
+    #[error("Cyclic Config Include: {}", .0.display())]
+    CyclicInclude(std::path::PathBuf),
+    #[error("Invalid Config: {0}")]
+    InvalidConfig(&'static str),
+    #[error("Endpoint Not Found")]
+    EndpointNotFound,
+    #[error("Invalid Gateway Address: {0}, expected host:port")]
+    InvalidGatewayAddress(String),
+    #[error("Invalid Proxy: {0}, expected socks5://[user:pass@]host:port")]
+    InvalidProxy(String),
+    #[error("Invalid Gateway Certificate Pin: {0}, expected the base64 SHA-256 of the SubjectPublicKeyInfo")]
+    InvalidCertPin(String),
+    #[error("Invalid Client Certificate: {}, expected a PEM certificate chain and its private key", .0.display())]
+    InvalidClientCert(std::path::PathBuf),
+    #[error("Invalid CA Bundle: {}, {1}", .0.display())]
+    InvalidCaBundle(std::path::PathBuf, String),
+    #[error("Certificate Pin Mismatch: {0} presented a certificate whose public key is not pinned, refusing to connect")]
+    CertificatePinMismatch(String),
+    #[error("Invalid API Key, expected at least 32 letters, digits, '-' or '_'")]
+    InvalidApiKey,
+    #[error("Platform Discovery Failed: {0}")]
+    PlatformDiscovery(&'static str),
+    #[error("Gateway {0} does not support {1}, which the config requires")]
+    MissingCapability(String, narrowlink_types::capability::Capability),
+    #[error("Invalid Token")]
+    InvalidToken,
+    #[error("Token Expired: the token for {0} expired, set a refresh_token or replace the token")]
+    TokenExpired(String),
+    #[error("Unable To Refresh Token: {0}")]
+    TokenRefresh(&'static str),
+    #[error("Invalid Publish Token")]
+    InvalidPublishToken,
+    #[error("Invalid Passphrase, it can not be empty")]
+    InvalidPassPhrase,
+    #[error("Invalid key derivation, iterations can not be zero")]
+    InvalidKeyDerivation,
+    #[error("Invalid Pre-Shared Key: {0}")]
+    InvalidPreSharedKey(#[from] PreSharedKeyError),
+    #[error("E2EE Policy {0} Not Found")]
+    E2EENotFound(String),
+    #[error("Environment Variable {0} Is Not Set")]
+    EnvironmentVariableNotSet(String),
+    #[error("Unable To Resolve {0}")]
+    UnableToResolve(String),
+    #[error("Invalid DNS Resolver: {0}, expected ip or ip:port")]
+    InvalidResolver(String),
+    #[error("Invalid Destination Regex: {0}")]
+    InvalidDestination(String),
+    #[error("Destination Not Allowed: {0}")]
+    DestinationDenied(String),
+    #[error("Unix Socket Not Found: {}", .0.display())]
+    UnixSocketNotFound(std::path::PathBuf),
+    #[error("Not A Unix Socket: {}", .0.display())]
+    NotAUnixSocket(std::path::PathBuf),
+    #[error("Unix Sockets Are Not Supported On This Platform: {0}")]
+    UnixSocketUnsupported(String),
+    #[error("Reverse Connection Not Found")]
+    ReverseConnectionNotFound,
+    #[error("Invalid Reverse Bind Ports: {0}, expected a port or a range such as 8000-8099")]
+    InvalidReverseBind(String),
+    #[error("Reverse Bind Not Allowed: {0}")]
+    ReverseBindDenied(String),
 }
@@ -2,43 +2,54 @@ use std::{
     collections::HashMap,
     env,
     io::{self, IsTerminal},
-    net::SocketAddr,
+    path::PathBuf,
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 mod args;
 use args::Args;
-use config::KeyPolicy;
+use config::{E2EEKey, KeyPolicy, LogFormat};
 use error::AgentError;
+use futures_channel::{mpsc, oneshot};
 use futures_util::{SinkExt, StreamExt};
 use hmac::Mac;
 use narrowlink_network::{
     async_forward,
     error::NetworkError,
     event::NarrowEvent,
+    mux::MuxTransport,
     p2p::QuicStream,
-    transport::{StreamType, TlsConfiguration, UnifiedSocket},
+    quic::QuicTransport,
+    transport::{connect_tcp_addrs, DialOptions, StreamType, TlsConfiguration, UnifiedSocket},
     ws::{WsConnection, WsConnectionBinary},
-    AsyncSocket, AsyncSocketCrypt,
+    AsyncSocket, AsyncSocketCompress, AsyncSocketCrypt, Compression, EphemeralKey,
+    IntegrityMonitor,
 };
 use narrowlink_types::{
     agent::{
         ConstSystemInfo, DynSystemInfo, EventInBound as AgentEventInBound,
         EventOutBound as AgentEventOutBound, EventRequest as AgentEventRequest,
     },
-    generic::{self, Connect},
+    capability::{Capabilities, Capability},
+    generic::{self, Connect, KeyExchange},
     policy::Policy,
     ServiceType,
 };
+use rate_limit::{RateLimited, TokenBucket};
+use resolver::Resolver;
 use sha3::{Digest, Sha3_256};
 use tokio::{
-    net::{lookup_host, TcpStream},
-    time,
+    io::AsyncWriteExt,
+    time::{self, Instant, MissedTickBehavior},
 };
 use tracing::{debug, error, info, trace};
 use tracing::{warn, Level};
 use tracing_subscriber::{
-    filter::{LevelFilter, Targets},
+    filter::{filter_fn, FilterExt, LevelFilter, Targets},
     fmt::writer::MakeWriterExt,
     prelude::__tracing_subscriber_SubscriberExt,
     util::SubscriberInitExt,
@@ -48,27 +59,71 @@ use udp_stream::UdpStream;
 use uuid::Uuid;
 
 mod config;
+mod control;
+mod destination;
 mod error;
+mod platform;
+mod proxy_protocol;
+mod rate_limit;
+mod refresh;
+mod resolver;
+mod reverse;
+mod stats;
+
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(10);
+const FAILBACK_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+const FAILBACK_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const FAILBACK_STABILITY_WINDOW: Duration = Duration::from_secs(300);
+const SYSINFO_INTERVAL: Duration = Duration::from_secs(40); // used when heartbeats are disabled
+const QUIC_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const MUX_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+const RECONNECT_ATTEMPTS: u32 = 10; // failed rounds over all endpoints before giving up
+const CONNECTION_STABILITY_THRESHOLD: Duration = Duration::from_secs(30); // uptime that resets the backoff
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+// NARROWLINK_LOG_FORMAT (json or text) takes precedence over the config
+fn set_log_format(log_format: LogFormat) {
+    let log_format = match env::var("NARROWLINK_LOG_FORMAT") {
+        Ok(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+        Ok(_) => LogFormat::Text,
+        Err(_) => log_format,
+    };
+    JSON_LOGS.store(log_format == LogFormat::Json, Ordering::Relaxed);
+}
 
 fn main() -> Result<(), AgentError> {
     let (stdout, _stdout_guard) = tracing_appender::non_blocking(io::stdout());
     let (stderr, _stderr_guard) = tracing_appender::non_blocking(io::stderr());
 
+    let writer = stdout
+        .with_min_level(Level::WARN)
+        .and(stderr.with_max_level(Level::ERROR));
+    let targets = env::var("RUST_LOG")
+        .ok()
+        .and_then(|e| e.parse::<Targets>().ok())
+        .unwrap_or(Targets::new().with_default(LevelFilter::INFO));
+    set_log_format(LogFormat::default());
+
     let cmd = tracing_subscriber::fmt::layer()
         .with_ansi(io::stdout().is_terminal() && io::stderr().is_terminal())
         .compact()
         // .with_target(false)
-        .with_writer(
-            stdout
-                .with_min_level(Level::WARN)
-                .and(stderr.with_max_level(Level::ERROR)),
-        )
+        .with_writer(writer.clone())
         .with_filter(
-            env::var("RUST_LOG")
-                .ok()
-                .and_then(|e| e.parse::<Targets>().ok())
-                .unwrap_or(Targets::new().with_default(LevelFilter::INFO)),
+            targets
+                .clone()
+                .and(filter_fn(|_| !JSON_LOGS.load(Ordering::Relaxed))),
         );
+    // one object per line, the fields of the enclosing spans are listed under `spans`
+    let json = tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_writer(writer)
+        .with_filter(targets.and(filter_fn(|_| JSON_LOGS.load(Ordering::Relaxed))));
 
     // let debug_file =
     //     tracing_appender::rolling::minutely("log", "debug").with_min_level(Level::DEBUG);
@@ -81,11 +136,52 @@ fn main() -> Result<(), AgentError> {
 
     tracing_subscriber::registry()
         .with(cmd)
+        .with(json)
         // .with(file)
         .init();
 
     let args = Args::parse(env::args())?;
 
+    if args.init {
+        match config::Config::init(args.config_path) {
+            Ok(path) => info!("Default config written to {}", path.display()),
+            Err(e) => {
+                error!("Unable to write config: {}", e.to_string());
+                drop((_stdout_guard, _stderr_guard));
+                std::process::exit(0x1);
+            }
+        }
+        return Ok(());
+    }
+
+    if args.check_config {
+        if let Err(e) = config::Config::load_with_source(args.config_path).and_then(|(c, path)| {
+            info!("Checking config {}", path.display());
+            c.validate()
+        }) {
+            error!("Invalid config: {}", e.to_string());
+            drop((_stdout_guard, _stderr_guard));
+            std::process::exit(0x1);
+        }
+        info!("Config is valid");
+        return Ok(());
+    }
+
+    if args.print_config {
+        // the logs share stdout, the source is a comment to keep the output valid YAML
+        match config::Config::load_with_source(args.config_path)
+            .and_then(|(c, path)| Ok((c.dump()?, path)))
+        {
+            Ok((dump, path)) => print!("# {}\n{}", path.display(), dump),
+            Err(e) => {
+                error!("Invalid config: {}", e.to_string());
+                drop((_stdout_guard, _stderr_guard));
+                std::process::exit(0x1);
+            }
+        }
+        return Ok(());
+    }
+
     #[cfg(unix)]
     if args.daemon {
         use daemonize::Daemonize;
@@ -106,44 +202,155 @@ fn main() -> Result<(), AgentError> {
 
 #[tokio::main]
 async fn start(args: Args) -> Result<(), AgentError> {
-    let mut conf = match config::Config::load(args.config_path) {
+    let (conf, config_path) = match config::Config::load_with_source(args.config_path.clone()) {
         Ok(c) => c,
         Err(e) => {
             error!("Unable to load config: {}", e.to_string());
             return Ok(());
         }
     };
+    set_log_format(conf.log_format);
+    let mut reload_receiver = config_watcher(Some(config_path));
+    if let Some(control_socket) = conf.control_socket {
+        control::spawn(control_socket);
+    }
 
-    let Some(config::Endpoint::SelfHosted(self_hosted_config)) = conf.endpoints.pop() else {
+    // ordered by priority, the first endpoint is the primary
+    let mut endpoints = event_endpoints(resolve_endpoints(conf.endpoints).await);
+    let mut e2ee = conf.e2ee;
+    let mut destinations = conf.destination_filter;
+    let mut resolver = Arc::new(Resolver::new(&conf.dns));
+    if endpoints.is_empty() {
         error!("Invalid config, endpoint not found");
         return Ok(());
-    };
-    let service_type = &self_hosted_config.protocol;
-    let token = &self_hosted_config.token;
-    let mut event_headers = HashMap::from([("NL-TOKEN", token.clone())]);
-    if let Some(publish_token) = self_hosted_config
-        .publish
-        .and_then(|p| serde_json::to_string(&p).ok())
-    {
-        event_headers.insert("NL-PUBLISH", publish_token.to_owned());
     }
+    let mut active = 0;
+    let mut failed_attempts = 0;
+    let mut primary_healthy_since: Option<Instant> = None;
+    let mut failback_probe = time::interval_at(
+        Instant::now() + FAILBACK_PROBE_INTERVAL,
+        FAILBACK_PROBE_INTERVAL,
+    );
+    failback_probe.set_missed_tick_behavior(MissedTickBehavior::Delay);
     let mut event_connection = None;
-    let mut sleep_time = 0;
+    let mut quic = None;
+    let mut mux = None;
+    let mut heartbeat = None;
+    let mut rate_limits = HashMap::new(); // service -> bucket shared by its connections
+    let mut reverse_binds = reverse::ReverseBinds::new(conf.reverse_binds);
+    let mut backoff = Backoff::default();
+    let mut connected_since: Option<Instant> = None; // None after intentional reconnects
+    let mut drain_timeout = Duration::from_secs(conf.drain_timeout);
+    let mut shutdown = Box::pin(shutdown_signal());
+    let mut refresh_not_before: Option<Instant> = None; // attempts are spaced out, successful or not
     loop {
+        // a connection attempt with a token about to expire would be refused or be short-lived
+        if event_connection.is_none()
+            && refresh_not_before.map_or(true, |not_before| not_before <= Instant::now())
+            && refresh::due_in(&endpoints[active].0).is_some_and(|due| due.is_zero())
+        {
+            refresh_not_before = Some(Instant::now() + refresh::REFRESH_RETRY_INTERVAL);
+            refresh_token(&mut endpoints[active]).await;
+        }
+        let (self_hosted_config, event_headers) = &endpoints[active];
+        let service_type = &self_hosted_config.protocol;
         let Some(event) = event_connection.as_mut() else {
+            stats::gateway_disconnected();
+            reverse_binds.clear();
+            if let Some(since) = connected_since.take() {
+                if since.elapsed() >= CONNECTION_STABILITY_THRESHOLD {
+                    backoff.reset();
+                } else {
+                    // a connection that keeps dropping right away must not turn into a tight loop
+                    let delay = backoff.next_delay();
+                    info!(
+                        "Connection was unstable, reconnecting in {:.1} secs",
+                        delay.as_secs_f64()
+                    );
+                    tokio::select! {
+                        _ = time::sleep(delay) => {}
+                        _ = &mut shutdown => break,
+                    }
+                }
+            }
             info!("Connecting to gateway: {}", self_hosted_config.gateway);
-            match WsConnection::new(&self_hosted_config.gateway, &event_headers, service_type).await
-            {
+            quic = quic_transport(self_hosted_config).await;
+            mux = match quic {
+                Some(_) => None,
+                None => mux_transport(self_hosted_config).await,
+            };
+            let event_stream = match (&quic, &mux) {
+                (Some(quic), _) => {
+                    WsConnection::with_quic(quic, &self_hosted_config.gateway, event_headers).await
+                }
+                (None, Some(mux)) => {
+                    WsConnection::with_mux(mux, &self_hosted_config.gateway, event_headers).await
+                }
+                (None, None) => {
+                    WsConnection::with_options(
+                        &self_hosted_config.gateway,
+                        event_headers,
+                        service_type,
+                        &self_hosted_config.dial_options(),
+                    )
+                    .await
+                }
+            };
+            match event_stream {
                 Ok(event_stream) => {
-                    sleep_time = 0;
+                    let required = required_capabilities(event_headers);
+                    match event_stream
+                        .get_header("NL-CAPABILITIES")
+                        .and_then(|c| Capabilities::from_str(c).ok())
+                    {
+                        Some(capabilities) => {
+                            info!(
+                                "Gateway version {}",
+                                event_stream.get_header("NL-VERSION").unwrap_or("unknown")
+                            );
+                            debug!("Negotiated capabilities: {}", capabilities);
+                            if let Some(missing) = capabilities.missing(&required) {
+                                error!(
+                                    "{}",
+                                    AgentError::MissingCapability(
+                                        self_hosted_config.gateway.clone(),
+                                        missing
+                                    )
+                                );
+                                // another endpoint may support it, reconnecting to this one will not
+                                failed_attempts += 1;
+                                if failed_attempts >= endpoints.len() {
+                                    break;
+                                }
+                                active = (active + 1) % endpoints.len();
+                                continue;
+                            }
+                        }
+                        None if required != Capabilities::default() => warn!(
+                            "Gateway {} does not advertise its capabilities, it may ignore: {}",
+                            self_hosted_config.gateway, required
+                        ),
+                        None => {}
+                    }
+                    connected_since = Some(Instant::now());
+                    failed_attempts = 0;
+                    primary_healthy_since = None;
                     let local_addr = event_stream.local_addr();
                     let event: NarrowEvent<AgentEventOutBound, AgentEventInBound> =
                         NarrowEvent::new(event_stream);
                     let req = event.get_request();
                     event_connection = Some(event);
                     info!("Connection successful");
+                    stats::gateway_connected(&self_hosted_config.gateway);
+                    stats::set_published(config::publish_hosts(
+                        self_hosted_config.publish.as_deref().unwrap_or_default(),
+                    ));
+                    let (heartbeat_sender, heartbeat_receiver) = oneshot::channel();
+                    heartbeat = Some(heartbeat_receiver);
+                    let keepalive = Duration::from_secs(self_hosted_config.keepalive_secs);
+                    let idle_timeout = Duration::from_secs(self_hosted_config.idle_timeout_secs);
                     tokio::spawn(async move {
-                        let mut s = sysinfo::System::new_all();
+                        let s = sysinfo::System::new_all();
                         let _ = req
                             .request(AgentEventOutBound::Request(
                                 0,
@@ -153,76 +360,271 @@ async fn start(args: Args) -> Result<(), AgentError> {
                                 }),
                             ))
                             .await;
+                        // the system info update doubles as the heartbeat, the gateway answers every update
                         loop {
-                            s.refresh_all();
-                            let _ = req
-                                .request(AgentEventOutBound::Request(
-                                    0,
-                                    AgentEventRequest::UpdateDynamicSysInfo(DynSystemInfo {
-                                        loadavg: sysinfo::System::load_average().one,
-                                    }),
-                                ))
-                                .await;
-                            trace!("SysInfo Update");
-                            time::sleep(Duration::from_secs(40)).await;
+                            let update = req.request(AgentEventOutBound::Request(
+                                0,
+                                AgentEventRequest::UpdateDynamicSysInfo(DynSystemInfo {
+                                    loadavg: sysinfo::System::load_average().one,
+                                }),
+                            ));
+                            let res = if idle_timeout.is_zero() {
+                                Ok(update.await)
+                            } else {
+                                time::timeout(idle_timeout, update).await
+                            };
+                            match res {
+                                Ok(Ok(_)) => trace!("SysInfo Update"),
+                                Ok(Err(_)) => break, // the connection is gone
+                                Err(_) => {
+                                    let _ = heartbeat_sender.send(());
+                                    break;
+                                }
+                            }
+                            time::sleep(if keepalive.is_zero() {
+                                SYSINFO_INTERVAL
+                            } else {
+                                keepalive
+                            })
+                            .await;
                         }
                     });
                 }
                 Err(e) => {
-                    if let NetworkError::UnableToUpgrade(status) = e {
-                        match status {
+                    match e {
+                        NetworkError::UnableToUpgrade(status) => match status {
+                            401 if refresh::is_expired(self_hosted_config) => {
+                                error!(
+                                    "{}",
+                                    AgentError::TokenExpired(self_hosted_config.gateway.clone())
+                                );
+                                if endpoints.len() == 1 {
+                                    break;
+                                }
+                            }
                             401 => {
                                 error!("Authentication failed");
-                                break;
+                                if endpoints.len() == 1 {
+                                    break;
+                                }
                             }
                             403 => {
                                 error!("Access denied");
                             }
                             _ => {}
-                        }
+                        },
+                        NetworkError::CertificatePinMismatch => error!(
+                            "{}",
+                            AgentError::CertificatePinMismatch(self_hosted_config.gateway.clone())
+                        ),
+                        _ => {}
                     };
                     error!("Unable to connect to the gateway: {}", e.to_string());
-                    if sleep_time == 0 {
-                        info!("Try again");
-                    } else if sleep_time == 70 {
+                    active = (active + 1) % endpoints.len();
+                    failed_attempts += 1;
+                    if failed_attempts < endpoints.len() {
+                        // try the next endpoint before backing off
+                        continue;
+                    }
+                    failed_attempts = 0;
+                    if backoff.attempt == RECONNECT_ATTEMPTS {
                         error!("Unable to connect");
                         info!("Exit");
                         break;
-                    } else {
-                        info!("Try again in {} secs", sleep_time);
                     }
-                    time::sleep(Duration::from_secs(sleep_time)).await;
-                    sleep_time += 10;
+                    let delay = backoff.next_delay();
+                    info!(
+                        "Reconnect attempt {} in {:.1} secs",
+                        backoff.attempt,
+                        delay.as_secs_f64()
+                    );
+                    tokio::select! {
+                        _ = time::sleep(delay) => {}
+                        _ = &mut shutdown => break,
+                    }
                 }
             };
             continue;
         };
 
-        let service_type = service_type.clone();
         let event_sender = event.get_sender();
-        let token = token.clone();
-        let gateway = self_hosted_config.gateway.clone();
-        let key = if let Some(config::E2EE::PassPhrase(e2ee)) = conf.e2ee.first() {
-            Some((e2ee.phrase.to_owned(), e2ee.policy))
-        } else {
-            None
-        };
-        // let key = conf.e2ee.clone().map(|k| (k, k.policy));
-        let service_type = service_type.clone();
+        let gateway = self_hosted_config.clone();
         trace!("Waiting for event");
-        match event.next().await {
+        let next = tokio::select! {
+            next = event.next() => Wake::Event(next),
+            _ = failback_probe.tick(), if active != 0 => Wake::FailbackProbe,
+            Some(()) = reload_receiver.next() => Wake::Reload,
+            _ = heartbeat_lost(&mut heartbeat) => Wake::HeartbeatLost,
+            _ = token_refresh_due(self_hosted_config, refresh_not_before) => Wake::TokenRefresh,
+            _ = &mut shutdown => Wake::Shutdown,
+        };
+        let next = match next {
+            Wake::Event(next) => next,
+            Wake::Shutdown => {
+                info!("Shutting down, new connections are no longer accepted");
+                break;
+            }
+            Wake::HeartbeatLost => {
+                error!(
+                    "Gateway did not answer within {} secs, reconnecting",
+                    self_hosted_config.idle_timeout_secs
+                );
+                event_connection = None;
+                heartbeat = None;
+                active = (active + 1) % endpoints.len();
+                continue;
+            }
+            Wake::TokenRefresh => {
+                // the event connection stays, the new token authenticates the next connections
+                refresh_not_before = Some(Instant::now() + refresh::REFRESH_RETRY_INTERVAL);
+                refresh_token(&mut endpoints[active]).await;
+                continue;
+            }
+            Wake::FailbackProbe => {
+                let (primary, _) = &endpoints[0];
+                if !is_gateway_reachable(primary).await {
+                    primary_healthy_since = None;
+                    continue;
+                }
+                // the primary has to stay reachable for the whole window to avoid flapping between gateways
+                let healthy_since = *primary_healthy_since.get_or_insert_with(Instant::now);
+                if healthy_since.elapsed() >= FAILBACK_STABILITY_WINDOW {
+                    info!(
+                        "Primary gateway is stable, failing back: {}",
+                        primary.gateway
+                    );
+                    event_connection = None;
+                    connected_since = None;
+                    active = 0;
+                }
+                continue;
+            }
+            Wake::Reload => {
+                let conf = match config::Config::load(args.config_path.clone()) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Unable to reload config: {}", e.to_string());
+                        continue;
+                    }
+                };
+                set_log_format(conf.log_format);
+                drain_timeout = Duration::from_secs(conf.drain_timeout);
+                let reloaded_endpoints = event_endpoints(resolve_endpoints(conf.endpoints).await);
+                if reloaded_endpoints.is_empty() {
+                    error!("Invalid config, endpoint not found");
+                    continue;
+                }
+                // E2EE and rate limit changes apply to the next tunnels
+                e2ee = conf.e2ee;
+                destinations = conf.destination_filter;
+                resolver = Arc::new(Resolver::new(&conf.dns));
+                reverse_binds.set_rules(conf.reverse_binds);
+                rate_limits.clear();
+                if endpoints.len() == reloaded_endpoints.len()
+                    && endpoints
+                        .iter()
+                        .zip(reloaded_endpoints.iter())
+                        .all(|((current, _), (reloaded, _))| current.connection_eq(reloaded))
+                {
+                    let publish = reloaded_endpoints[active].0.publish.clone();
+                    if endpoints[active].0.publish != publish {
+                        info!("Publish list changed, updating the gateway");
+                        stats::set_published(config::publish_hosts(
+                            publish.as_deref().unwrap_or_default(),
+                        ));
+                        if let Err(e) = event
+                            .send(AgentEventOutBound::Request(
+                                0,
+                                AgentEventRequest::UpdatePublish(
+                                    publish
+                                        .iter()
+                                        .flatten()
+                                        .map(|p| p.token().to_owned())
+                                        .collect(),
+                                ),
+                            ))
+                            .await
+                        {
+                            error!("Gateway connection dropped: {}", e.to_string());
+                            event_connection = None;
+                        }
+                    }
+                    // refreshed tokens outlive the reload, the config only has the initial one
+                    let mut reloaded_endpoints = reloaded_endpoints;
+                    for ((current, _), reloaded) in
+                        endpoints.iter().zip(reloaded_endpoints.iter_mut())
+                    {
+                        if current.refresh_token.is_some() && current.token != reloaded.0.token {
+                            reloaded.1.insert("NL-TOKEN", current.token.clone());
+                            reloaded.0.token = current.token.clone();
+                        }
+                    }
+                    endpoints = reloaded_endpoints;
+                } else {
+                    info!("Endpoints changed, reconnecting");
+                    endpoints = reloaded_endpoints;
+                    event_connection = None;
+                    connected_since = None;
+                    active = 0;
+                }
+                continue;
+            }
+        };
+        match next {
             Some(Ok(AgentEventInBound::Connect(connection, connect, ip_policies))) => {
                 debug!("Connection to {:?} received", connect);
+                // reverse connections were accepted by the agent itself, there is nothing to dial
+                if connect.reverse.is_none() && !destinations.permit(&connect.host) {
+                    let destination = format!("{}:{}", connect.host, connect.port);
+                    warn!(
+                        "Destination {} denied for connection {}",
+                        destination, connection
+                    );
+                    let _ = event_sender.send(AgentEventOutBound::Error(
+                        connection,
+                        AgentError::DestinationDenied(destination).to_string(),
+                    ));
+                    continue;
+                }
+                let publish = self_hosted_config.publish.as_deref().unwrap_or_default();
+                let key = match config::e2ee_key(publish, &e2ee, &connect.host, connect.port) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        warn!("Connection {} refused: {}", connection, e);
+                        let _ =
+                            event_sender.send(AgentEventOutBound::Error(connection, e.to_string()));
+                        continue;
+                    }
+                };
+                let bucket =
+                    config::rate_limit(publish, &connect.host, connect.port).map(|limit| {
+                        let service = format!("{}:{}", connect.host, connect.port);
+                        rate_limits
+                            .entry(service.clone())
+                            .or_insert_with(|| Arc::new(TokenBucket::new(service, limit)))
+                            .clone()
+                    });
+                let compression = config::compression(publish, &connect.host, connect.port);
+                let proxy_protocol = config::proxy_protocol(publish, &connect.host, connect.port);
+                let quic = quic.clone();
+                let mux = mux.clone();
+                let resolver = resolver.clone();
+                let reverse_pending = reverse_binds.pending();
                 tokio::spawn(async move {
                     if let Err(e) = data_connect(
                         &gateway,
-                        token.clone(),
+                        quic,
+                        mux,
+                        &resolver,
+                        &reverse_pending,
                         // session,
                         connection,
                         connect,
                         ip_policies,
                         key.as_ref(),
-                        service_type,
+                        bucket,
+                        compression,
+                        proxy_protocol,
                     )
                     .await
                     {
@@ -233,9 +635,13 @@ async fn start(args: Args) -> Result<(), AgentError> {
                 continue;
             }
             Some(Ok(AgentEventInBound::Peer2Peer(p2p))) => {
+                let publish = self_hosted_config.publish.clone().unwrap_or_default();
+                let e2ee = e2ee.clone();
+                let destinations = destinations.clone();
+                let resolver = resolver.clone();
                 tokio::spawn({
                     async move {
-                        let (socket, _) = match narrowlink_network::p2p::udp_punched_socket(
+                        let (socket, peer) = match narrowlink_network::p2p::udp_punched_socket(
                             (&p2p).into(),
                             &Sha3_256::digest(&p2p.cert)[0..6],
                             false,
@@ -269,7 +675,12 @@ async fn start(args: Args) -> Result<(), AgentError> {
                                     break;
                                 }
                             };
-                            let key = key.clone();
+                            let (publish, e2ee, destinations, resolver) = (
+                                publish.clone(),
+                                e2ee.clone(),
+                                destinations.clone(),
+                                resolver.clone(),
+                            );
                             tokio::spawn(async move {
                                 let Ok(r) = narrowlink_network::p2p::Request::read(&mut s).await
                                 else {
@@ -277,7 +688,40 @@ async fn start(args: Args) -> Result<(), AgentError> {
                                     return;
                                 };
                                 let con = Into::<Connect>::into(&r);
+                                let key =
+                                    match config::e2ee_key(&publish, &e2ee, &con.host, con.port) {
+                                        Ok(key) => key,
+                                        Err(e) => {
+                                            warn!("Peer {} refused: {}", p2p.peer_ip, e);
+                                            if narrowlink_network::p2p::Response::write(
+                                                &narrowlink_network::p2p::Response::AccessDenied,
+                                                &mut s,
+                                            )
+                                            .await
+                                            .is_err()
+                                            {
+                                                warn!("Unable to write response");
+                                            }
+                                            return;
+                                        }
+                                    };
 
+                                if !destinations.permit(&con.host) {
+                                    warn!(
+                                        "Destination {}:{} denied, peer: {}",
+                                        con.host, con.port, p2p.peer_ip
+                                    );
+                                    if narrowlink_network::p2p::Response::write(
+                                        &narrowlink_network::p2p::Response::AccessDenied,
+                                        &mut s,
+                                    )
+                                    .await
+                                    .is_err()
+                                    {
+                                        warn!("Unable to write response");
+                                    }
+                                    return;
+                                }
                                 if !policies.is_empty()
                                     && !policies.into_iter().any(|p| p.permit(&con))
                                 {
@@ -302,7 +746,7 @@ async fn start(args: Args) -> Result<(), AgentError> {
                                     || nonce.is_none()
                                         && key
                                             .as_ref()
-                                            .filter(|(_, policy)| &KeyPolicy::Strict == policy)
+                                            .filter(|key| key.policy == KeyPolicy::Strict)
                                             .is_some()
                                 {
                                     warn!(
@@ -321,50 +765,15 @@ async fn start(args: Args) -> Result<(), AgentError> {
                                     return;
                                 }
 
+                                let service = format!("{}:{}", con.host, con.port);
+                                let monitor = integrity_monitor(
+                                    format!("{} from peer {}", service, p2p.peer_ip),
+                                    service,
+                                );
                                 let (k, n): (Option<[u8; 32]>, Option<[u8; 24]>) =
-                                    if let (Some((k, _)), Some(n)) = (key.as_ref(), nonce) {
-                                        let k = Sha3_256::digest(
-                                            k.as_bytes()
-                                                .iter()
-                                                .zip(n.iter().cycle())
-                                                .map(|(n, s)| n ^ s)
-                                                .collect::<Vec<u8>>(),
-                                        );
-                                        let Ok(mut mac) = generic::HmacSha256::new_from_slice(&k)
-                                        else {
-                                            warn!("Unable to create HMAC");
-                                            if narrowlink_network::p2p::Response::write(
-                                                &narrowlink_network::p2p::Response::AccessDenied,
-                                                &mut s,
-                                            )
-                                            .await
-                                            .is_err()
-                                            {
-                                                warn!("Unable to write response");
-                                            }
-                                            return;
-                                        };
-                                        mac.update(
-                                            &[
-                                                format!(
-                                                    "{}:{}:{}",
-                                                    &con.host,
-                                                    &con.port,
-                                                    con.protocol.clone() as u32
-                                                )
-                                                .as_bytes(),
-                                                &n,
-                                            ]
-                                            .concat(),
-                                        );
-
-                                        if (con.get_sign().and_then(|s| mac.verify_slice(&s).ok()))
-                                            .is_none()
-                                        {
-                                            warn!(
-                                                "Request signature verification failed, peer: {}",
-                                                p2p.peer_ip
-                                            );
+                                    if let (Some(key), Some(n)) = (key.as_ref(), nonce) {
+                                        let Some(k) = session_key(key, &con, &n) else {
+                                            monitor.key_mismatch();
                                             if narrowlink_network::p2p::Response::write(
                                                 &narrowlink_network::p2p::Response::AccessDenied,
                                                 &mut s,
@@ -376,18 +785,13 @@ async fn start(args: Args) -> Result<(), AgentError> {
                                             }
                                             return;
                                         };
-
-                                        (Some(k.into()), Some(n))
+                                        (Some(k), Some(n))
                                     } else {
                                         (None, None)
                                     };
                                 // dbg!(&con);
                                 trace!("Connecting to {}", con.host);
-                                let Some(remote_addr) =
-                                    tokio::net::lookup_host(&format!("{}:{}", con.host, con.port))
-                                        .await
-                                        .ok()
-                                        .and_then(|mut s| s.next())
+                                let Ok(remote_addrs) = resolver.resolve(&con.host, con.port).await
                                 else {
                                     if narrowlink_network::p2p::Response::write(
                                         &narrowlink_network::p2p::Response::UnableToResolve,
@@ -404,13 +808,25 @@ async fn start(args: Args) -> Result<(), AgentError> {
                                 };
 
                                 let socket = if matches!(con.protocol, generic::Protocol::UDP) {
-                                    UdpStream::connect(remote_addr)
+                                    connect_udp_addrs(remote_addrs)
                                         .await
                                         .map(|s| Box::new(s) as Box<dyn AsyncSocket>)
                                 } else {
-                                    TcpStream::connect(remote_addr)
-                                        .await
-                                        .map(|s| Box::new(s) as Box<dyn AsyncSocket>)
+                                    let proxy_protocol =
+                                        config::proxy_protocol(&publish, &con.host, con.port);
+                                    match connect_tcp_addrs(remote_addrs).await {
+                                        Ok(mut s) if proxy_protocol => match s.peer_addr() {
+                                            Ok(remote_addr) => s
+                                                .write_all(&proxy_protocol::header(
+                                                    Some(peer),
+                                                    remote_addr,
+                                                ))
+                                                .await
+                                                .map(|_| Box::new(s) as Box<dyn AsyncSocket>),
+                                            Err(e) => Err(e),
+                                        },
+                                        s => s.map(|s| Box::new(s) as Box<dyn AsyncSocket>),
+                                    }
                                 };
 
                                 let stream = match socket {
@@ -441,8 +857,22 @@ async fn start(args: Args) -> Result<(), AgentError> {
                                         return;
                                     }
                                 };
-                                if let (Some(k), Some(n)) = (k, n) {
-                                    s = Box::new(AsyncSocketCrypt::new(k, n, s).await);
+                                if let (Some(mut k), Some(n)) = (k, n) {
+                                    if let Some(exchange) = con.exchange {
+                                        k = match ephemeral_key(&mut s, &k, &exchange).await {
+                                            Ok(k) => k,
+                                            Err(e) => {
+                                                warn!(
+                                                    "{} from peer {}: {}",
+                                                    con.host, p2p.peer_ip, e
+                                                );
+                                                return;
+                                            }
+                                        };
+                                    }
+                                    s = Box::new(
+                                        AsyncSocketCrypt::new(k, n, s).await.with_monitor(monitor),
+                                    );
                                 }
                                 if let Err(_e) = async_forward(s, stream).await {
                                     trace!("Data channel closed: {}", _e.to_string());
@@ -453,17 +883,54 @@ async fn start(args: Args) -> Result<(), AgentError> {
                 });
             }
             Some(Ok(AgentEventInBound::IsReachable(connection, connect))) => {
-                let res = match is_ready(connect).await {
-                    Ok(true) => AgentEventOutBound::Ready(connection),
-                    Ok(false) => AgentEventOutBound::NotSure(connection),
-                    Err(e) => AgentEventOutBound::Error(connection, e.to_string()),
+                let res = if !destinations.permit(&connect.host) {
+                    let destination = format!("{}:{}", connect.host, connect.port);
+                    warn!(
+                        "Destination {} denied for reachability check {}",
+                        destination, connection
+                    );
+                    AgentEventOutBound::Error(
+                        connection,
+                        AgentError::DestinationDenied(destination).to_string(),
+                    )
+                } else {
+                    match is_ready(connect, &resolver).await {
+                        Ok(true) => AgentEventOutBound::Ready(connection),
+                        Ok(false) => AgentEventOutBound::NotSure(connection),
+                        Err(e) => AgentEventOutBound::Error(connection, e.to_string()),
+                    }
                 };
                 if let Err(e) = event.send(res).await {
                     error!("Gateway connection dropped: {}", e.to_string());
                     event_connection = None;
+                    active = (active + 1) % endpoints.len();
                 };
                 continue;
             }
+            Some(Ok(AgentEventInBound::ReverseBind(id, host, port))) => {
+                reverse_binds.bind(id, &host, port, event_sender).await;
+                continue;
+            }
+            Some(Ok(AgentEventInBound::ReverseUnbind(id))) => {
+                reverse_binds.unbind(id);
+                continue;
+            }
+            Some(Ok(AgentEventInBound::ConnectionRejected(connection, reason))) => {
+                warn!("Gateway rejected connection {}: {}", connection, reason);
+                continue;
+            }
+            Some(Ok(AgentEventInBound::ConnectionClosed(connection, reason))) => {
+                info!("Gateway closed connection {}: {}", connection, reason);
+                continue;
+            }
+            Some(Ok(AgentEventInBound::PublishRejected(services, reason))) => {
+                warn!(
+                    "Gateway did not publish {}: {}",
+                    services.join(", "),
+                    reason
+                );
+                continue;
+            }
             Some(Ok(AgentEventInBound::Response(_, _))) => continue,
             Some(Ok(narrowlink_types::agent::EventInBound::Ping(ping_time))) => {
                 let _ = event_sender.send(AgentEventOutBound::Pong(ping_time));
@@ -475,6 +942,7 @@ async fn start(args: Args) -> Result<(), AgentError> {
             Some(Err(e)) => {
                 error!("Gateway connection dropped: {}", e.to_string());
                 event_connection = None;
+                active = (active + 1) % endpoints.len();
                 continue;
             }
             None => {
@@ -482,36 +950,435 @@ async fn start(args: Args) -> Result<(), AgentError> {
             }
         }
     }
+    // tunnels already established keep running without the event connection
+    drop(event_connection);
+    stats::gateway_disconnected();
+    drain(drain_timeout).await;
     Ok(())
 }
 
+// SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    if let Ok(mut terminate) =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+    {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = ctrl_c => {}
+        }
+        return;
+    }
+    ctrl_c.await
+}
+
+// waits up to the timeout for the active connections to finish
+async fn drain(timeout: Duration) {
+    let active = stats::status().active_connections;
+    if active == 0 {
+        return;
+    }
+    info!(
+        "Waiting up to {} secs for {} active connections",
+        timeout.as_secs(),
+        active
+    );
+    let drained = time::timeout(timeout, async {
+        while stats::status().active_connections > 0 {
+            time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "{} connections still active, closing them",
+            stats::status().active_connections
+        );
+    }
+}
+
+// exponential backoff with full jitter, so agents don't reconnect in lockstep when a gateway recovers
+#[derive(Default)]
+struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    fn next_delay(&mut self) -> Duration {
+        let ceiling = RECONNECT_BASE_DELAY
+            .saturating_mul(2_u32.saturating_pow(self.attempt))
+            .min(RECONNECT_MAX_DELAY);
+        self.attempt += 1;
+        ceiling.mul_f64(rand::random::<f64>())
+    }
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+enum Wake<T> {
+    Event(T),
+    FailbackProbe,
+    Reload,
+    HeartbeatLost,
+    TokenRefresh,
+    Shutdown,
+}
+
+// never without a refresh token
+async fn token_refresh_due(self_hosted_config: &config::SelfHosted, not_before: Option<Instant>) {
+    let Some(due) = refresh::due_in(self_hosted_config) else {
+        return std::future::pending().await;
+    };
+    let due = Instant::now() + due;
+    time::sleep_until(not_before.map_or(due, |not_before| due.max(not_before))).await
+}
+
+// replaces the token of the endpoint by a refreshed one, the current one is kept on failure
+async fn refresh_token(endpoint: &mut (config::SelfHosted, HashMap<&'static str, String>)) {
+    let (self_hosted_config, event_headers) = endpoint;
+    match refresh::refresh(self_hosted_config).await {
+        Ok(token) => {
+            info!("Token refreshed at {}", self_hosted_config.gateway);
+            event_headers.insert("NL-TOKEN", token.clone());
+            self_hosted_config.token = token;
+        }
+        Err(e) if refresh::is_expired(self_hosted_config) => error!(
+            "{}, {}",
+            AgentError::TokenExpired(self_hosted_config.gateway.clone()),
+            e
+        ),
+        Err(e) => warn!("{}", e),
+    }
+}
+
+// platform endpoints are resolved to the gateway their control plane assigns
+async fn resolve_endpoints(endpoints: Vec<config::Endpoint>) -> Vec<config::SelfHosted> {
+    let mut res = Vec::with_capacity(endpoints.len());
+    for endpoint in endpoints {
+        match endpoint {
+            config::Endpoint::Platform(platform) => match platform::discover(&platform).await {
+                Ok(self_hosted) => res.push(self_hosted),
+                Err(e) => warn!(
+                    "Unable to discover gateway from {}: {}",
+                    platform.control_plane, e
+                ),
+            },
+            config::Endpoint::SelfHosted(self_hosted) => res.push(*self_hosted),
+        }
+    }
+    res
+}
+
+fn event_endpoints(
+    endpoints: Vec<config::SelfHosted>,
+) -> Vec<(config::SelfHosted, HashMap<&'static str, String>)> {
+    endpoints
+        .into_iter()
+        .map(|self_hosted_config| {
+            let mut event_headers = HashMap::from([
+                ("NL-TOKEN", self_hosted_config.token.clone()),
+                ("NL-CAPABILITIES", agent_capabilities().to_string()),
+            ]);
+            if let Some(publish_token) = self_hosted_config
+                .publish
+                .as_ref()
+                .map(|p| p.iter().map(|p| p.token()).collect::<Vec<_>>())
+                .and_then(|p| serde_json::to_string(&p).ok())
+            {
+                event_headers.insert("NL-PUBLISH", publish_token);
+            }
+            let client_access =
+                config::client_access(self_hosted_config.publish.as_deref().unwrap_or_default());
+            if let Some(client_access) = Some(client_access)
+                .filter(|c| !c.is_empty())
+                .and_then(|c| serde_json::to_string(&c).ok())
+            {
+                event_headers.insert("NL-CLIENT-ACL", client_access);
+            }
+            if let Some(acme) = &self_hosted_config.acme {
+                event_headers.insert("NL-ACME-EMAIL", acme.email.clone());
+                if let Some(challenge_type) = acme.challenge_type {
+                    event_headers.insert("NL-ACME-CHALLENGE", challenge_type.as_str().to_owned());
+                }
+                if let Some(directory) = &acme.directory {
+                    event_headers.insert("NL-ACME-DIRECTORY", directory.clone());
+                }
+            }
+            (self_hosted_config, event_headers)
+        })
+        .collect()
+}
+
+fn agent_capabilities() -> Capabilities {
+    Capabilities::new(&[
+        Capability::Quic,
+        Capability::Compression,
+        Capability::Multiplex,
+        Capability::ClientAcl,
+        Capability::AcmeAccount,
+        Capability::ReverseForward,
+        Capability::ConnectionEvents,
+        Capability::PublishEvents,
+        Capability::AcmeDirectory,
+    ])
+}
+
+// a gateway ignoring these headers would leave services public or without their own ACME account
+fn required_capabilities(event_headers: &HashMap<&'static str, String>) -> Capabilities {
+    let mut required = Capabilities::default();
+    if event_headers.contains_key("NL-CLIENT-ACL") {
+        required.insert(Capability::ClientAcl);
+    }
+    if event_headers.contains_key("NL-ACME-EMAIL") {
+        required.insert(Capability::AcmeAccount);
+    }
+    if event_headers.contains_key("NL-ACME-DIRECTORY") {
+        required.insert(Capability::AcmeDirectory);
+    }
+    required
+}
+
+// notifies on SIGHUP, and when the modification time of the config file changes
+fn config_watcher(path: Option<PathBuf>) -> mpsc::UnboundedReceiver<()> {
+    let (sender, receiver) = mpsc::unbounded();
+    tokio::spawn(async move {
+        let modified_time = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        };
+        let mut modified = modified_time(&path);
+        let mut interval = time::interval(CONFIG_WATCH_INTERVAL);
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .map_err(|e| warn!("Unable to watch SIGHUP: {}", e))
+            .ok();
+        #[cfg(not(unix))]
+        let mut hangup: Option<()> = None;
+        loop {
+            tokio::select! {
+                _ = hangup_received(&mut hangup) => {
+                    info!("SIGHUP received, reloading config");
+                }
+                _ = interval.tick() => {
+                    let current = modified_time(&path);
+                    if current == modified {
+                        continue;
+                    }
+                    modified = current;
+                    info!("Config file changed, reloading config");
+                }
+            }
+            if sender.unbounded_send(()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(unix)]
+async fn hangup_received(hangup: &mut Option<tokio::signal::unix::Signal>) {
+    match hangup {
+        Some(hangup) => {
+            hangup.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn hangup_received(_hangup: &mut Option<()>) {
+    std::future::pending().await
+}
+
+// resolves only when the heartbeat task reports an unanswered heartbeat
+async fn heartbeat_lost(heartbeat: &mut Option<oneshot::Receiver<()>>) {
+    if let Some(receiver) = heartbeat.as_mut() {
+        if receiver.await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+// counts the failures of the service, a chunk failing verification ends its stream
+fn integrity_monitor(label: String, service: String) -> IntegrityMonitor {
+    let (failures, mismatches) = stats::e2ee_counters(service);
+    IntegrityMonitor::new(label).counters(failures, mismatches)
+}
+
+// derives the session key from the first accepted phrase the request is signed with,
+// pre-shared keys are used as they are,
+// requests naming another key derivation than the configured one are refused
+fn session_key(key: &E2EEKey, connect: &Connect, nonce: &[u8; 24]) -> Option<[u8; 32]> {
+    let sign = connect.get_sign()?;
+    let kdf = key.kdf;
+    if key.ephemeral && connect.exchange.is_none() {
+        debug!("Key exchange required, but not offered");
+        return None;
+    }
+    if connect.get_key_derivation() != kdf {
+        debug!(
+            "Key derivation version {} requested, {} configured",
+            connect.get_key_derivation().version(),
+            kdf.version()
+        );
+        return None;
+    }
+    key.phrases
+        .iter()
+        .filter_map(|phrase| kdf.derive(phrase, nonce))
+        .chain(key.pre_shared.iter().copied())
+        .find(|k| {
+            generic::HmacSha256::new_from_slice(k).is_ok_and(|mut mac| {
+                mac.update(&connect.signed_data(nonce));
+                mac.verify_slice(&sign).is_ok()
+            })
+        })
+}
+
+// answers the client's X25519 public key, the session key replaces the passphrase key
+async fn ephemeral_key(
+    socket: &mut Box<dyn AsyncSocket>,
+    key: &[u8; 32],
+    exchange: &KeyExchange,
+) -> Result<[u8; 32], NetworkError> {
+    EphemeralKey::generate()?
+        .respond(socket, key, exchange)
+        .await
+}
+
+async fn is_gateway_reachable(self_hosted_config: &config::SelfHosted) -> bool {
+    let gateway = &self_hosted_config.gateway;
+    let transport_type = if let ServiceType::Wss | ServiceType::Quic = self_hosted_config.protocol {
+        StreamType::Tls(TlsConfiguration {
+            sni: gateway.split(':').next().unwrap_or(gateway).to_owned(),
+        })
+    } else {
+        StreamType::Tcp
+    };
+    time::timeout(
+        FAILBACK_PROBE_TIMEOUT,
+        UnifiedSocket::with_options(gateway, transport_type, &self_hosted_config.dial_options()),
+    )
+    .await
+    .is_ok_and(|stream| stream.is_ok())
+}
+
+// None unless the gateway is reachable over QUIC, the caller falls back to Wss
+async fn quic_transport(self_hosted_config: &config::SelfHosted) -> Option<Arc<QuicTransport>> {
+    if self_hosted_config.protocol != ServiceType::Quic {
+        return None;
+    }
+    let gateway = &self_hosted_config.gateway;
+    if self_hosted_config.proxy.is_some() {
+        debug!("QUIC can't be sent through a SOCKS5 proxy, using Wss");
+        return None;
+    }
+    let sni = gateway.split(':').next().unwrap_or(gateway);
+    match time::timeout(
+        QUIC_HANDSHAKE_TIMEOUT,
+        QuicTransport::connect(gateway, sni, &self_hosted_config.dial_options()),
+    )
+    .await
+    {
+        Ok(Ok(quic)) => {
+            debug!("QUIC connection to {} established", gateway);
+            Some(Arc::new(quic))
+        }
+        Ok(Err(e)) => {
+            warn!("QUIC to {} failed ({}), falling back to Wss", gateway, e);
+            None
+        }
+        Err(_) => {
+            warn!("QUIC to {} timed out, falling back to Wss", gateway);
+            None
+        }
+    }
+}
+
+// None unless multiplexing is enabled and the gateway supports it, the caller then opens a
+// connection per tunnel
+async fn mux_transport(self_hosted_config: &config::SelfHosted) -> Option<Arc<MuxTransport>> {
+    if !self_hosted_config.multiplex {
+        return None;
+    }
+    let gateway = &self_hosted_config.gateway;
+    match time::timeout(
+        MUX_HANDSHAKE_TIMEOUT,
+        MuxTransport::connect(
+            gateway,
+            &self_hosted_config.protocol,
+            &self_hosted_config.dial_options(),
+        ),
+    )
+    .await
+    {
+        Ok(Ok(mux)) => {
+            debug!("Multiplexed connection to {} established", gateway);
+            Some(Arc::new(mux))
+        }
+        Ok(Err(e)) => {
+            warn!(
+                "Multiplexing to {} failed ({}), using a connection per tunnel",
+                gateway, e
+            );
+            None
+        }
+        Err(_) => {
+            warn!(
+                "Multiplexing to {} timed out, using a connection per tunnel",
+                gateway
+            );
+            None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn data_connect(
-    gateway_addr: &str,
-    token: String,
+    gateway: &config::SelfHosted,
+    quic: Option<Arc<QuicTransport>>,
+    mux: Option<Arc<MuxTransport>>,
+    resolver: &Resolver,
+    reverse_pending: &reverse::PendingConnections,
     // session: Uuid,
     connection: Uuid,
     req: generic::Connect,
     ip_policies: Vec<Policy>,
-    key: Option<&(String, KeyPolicy)>,
-    service_type: ServiceType,
+    key: Option<&E2EEKey>,
+    bucket: Option<Arc<TokenBucket>>,
+    compression: Compression,
+    proxy_protocol: bool,
 ) -> Result<(), AgentError> {
     let addr = format!("{}:{}", req.host, req.port);
-    let address = match SocketAddr::from_str(&addr) {
-        Ok(addr) => addr,
-        Err(_e) => match lookup_host(&addr).await?.next() {
-            Some(addr) => addr,
-            None => return Err(AgentError::UnableToResolve),
-        },
+    // unix sockets are checked when dialing, like the services only started after publishing
+    let addresses = match unix_socket_path(&req.host) {
+        Some(_) => None,
+        None => Some(resolver.resolve(&req.host, req.port).await?),
     };
 
-    let mut connect = req.clone();
-    connect.host = address.ip().to_string();
-    connect.port = req.port;
-
-    if !ip_policies.is_empty() && !ip_policies.into_iter().any(|p| p.permit(&connect)) {
+    // every resolved address is checked on its own, the denied ones are not dialed
+    let permitted = match addresses {
+        Some(addresses) => {
+            let addresses = addresses
+                .into_iter()
+                .filter(|address| destination::ip_permit(&req, Some(address.ip()), &ip_policies))
+                .collect::<Vec<_>>();
+            (!addresses.is_empty()).then_some(Some(addresses))
+        }
+        None => destination::ip_permit(&req, None, &ip_policies).then_some(None),
+    };
+    let Some(addresses) = permitted else {
         trace!("IP policies denied");
         return Err(AgentError::AccessDenied);
-    }
+    };
 
     let protocol = req.protocol.clone();
 
@@ -522,69 +1389,70 @@ async fn data_connect(
     } else if nonce.is_none()
         && key
             .as_ref()
-            .filter(|(_, policy)| &KeyPolicy::Strict == policy)
+            .filter(|key| key.policy == KeyPolicy::Strict)
             .is_some()
     {
         trace!("Encryption is enforced, but request is not encrypted");
         return Err(AgentError::AccessDenied);
     }
 
-    let (k, n): (Option<[u8; 32]>, Option<[u8; 24]>) = if let (Some((k, _)), Some(n)) = (key, nonce)
-    {
-        let k = Sha3_256::digest(
-            k.as_bytes()
-                .iter()
-                .zip(n.iter().cycle())
-                .map(|(n, s)| n ^ s)
-                .collect::<Vec<u8>>(),
-        );
-        let Ok(mut mac) = generic::HmacSha256::new_from_slice(&k) else {
-            trace!("Unable to create HMAC");
-            return Err(AgentError::AccessDenied);
-        };
-        mac.update(
-            &[
-                format!(
-                    "{}:{}:{}",
-                    &req.host,
-                    &req.port,
-                    req.protocol.clone() as u32
-                )
-                .as_bytes(),
-                &n,
-            ]
-            .concat(),
-        );
-
-        if (req.get_sign().and_then(|s| mac.verify_slice(&s).ok())).is_none() {
-            trace!("Request signature verification failed");
+    let monitor = integrity_monitor(
+        format!("{} via connection {}", addr, connection),
+        addr.clone(),
+    );
+    let (k, n): (Option<[u8; 32]>, Option<[u8; 24]>) = if let (Some(key), Some(n)) = (key, nonce) {
+        let Some(k) = session_key(key, &req, &n) else {
+            monitor.key_mismatch();
             return Err(AgentError::AccessDenied);
         };
-
-        (Some(k.into()), Some(n))
+        (Some(k), Some(n))
     } else {
         (None, None)
     };
 
-    let (socket, peer_address): (Box<dyn AsyncSocket>, Option<String>) = match protocol {
-        generic::Protocol::HTTP | generic::Protocol::TCP => {
-            trace!("Connecting to {} (TCP)", address);
-            let stream = TcpStream::connect(address).await?;
+    let (socket, peer_address): (Box<dyn AsyncSocket>, Option<String>) = match (protocol, addresses)
+    {
+        _ if req.reverse.is_some() => {
+            let stream = req
+                .reverse
+                .and_then(|connection| reverse_pending.take(connection))
+                .ok_or(AgentError::ReverseConnectionNotFound)?;
             let peer_address = stream.peer_addr().map(|sa| format!("TCP://{}", sa)).ok();
             (Box::new(stream), peer_address)
         }
-        generic::Protocol::UDP | generic::Protocol::QUIC | generic::Protocol::DTLS => {
-            trace!("Connecting to {} (UDP)", address);
-            let stream = UdpStream::connect(address).await?;
+        (_, None) => {
+            trace!("Connecting to {} (Unix)", req.host);
+            unix_connect(&req.host).await?
+        }
+        (generic::Protocol::HTTP | generic::Protocol::TCP, Some(addresses)) => {
+            trace!("Connecting to {} (TCP)", addr);
+            let mut stream = connect_tcp_addrs(addresses).await?;
+            if proxy_protocol {
+                stream
+                    .write_all(&proxy_protocol::header(req.source, stream.peer_addr()?))
+                    .await?;
+            }
+            let peer_address = stream.peer_addr().map(|sa| format!("TCP://{}", sa)).ok();
+            (Box::new(stream), peer_address)
+        }
+        (
+            generic::Protocol::UDP | generic::Protocol::QUIC | generic::Protocol::DTLS,
+            Some(addresses),
+        ) => {
+            trace!("Connecting to {} (UDP)", addr);
+            let stream = connect_udp_addrs(addresses).await?;
 
             let peer_address = stream.peer_addr().map(|sa| format!("UDP://{}", sa)).ok();
             (Box::new(stream), peer_address)
         }
-        generic::Protocol::TLS | generic::Protocol::HTTPS => {
-            trace!("Connecting to {} (TLS)", address);
-            let stream = UnifiedSocket::new(
-                &addr,
-                StreamType::Tls(TlsConfiguration { sni: addr.clone() }),
+        (generic::Protocol::TLS | generic::Protocol::HTTPS, Some(addresses)) => {
+            trace!("Connecting to {} (TLS)", addr);
+            let stream = UnifiedSocket::with_tls(
+                connect_tcp_addrs(addresses).await?,
+                &TlsConfiguration {
+                    sni: req.host.clone(),
+                },
+                &DialOptions::default(),
             )
             .await?;
             let peer_address = Some(format!("TLS://{}", stream.peer_addr()));
@@ -593,19 +1461,65 @@ async fn data_connect(
         }
     };
 
+    let socket: Box<dyn AsyncSocket> = match bucket {
+        Some(bucket) => Box::new(RateLimited::new(socket, bucket)),
+        None => socket,
+    };
+    let socket = stats::Counted::new(socket, addr.clone());
+
     let mut headers = HashMap::from([
-        ("NL-TOKEN", token),
+        ("NL-TOKEN", gateway.token.clone()),
         ("NL-CONNECTION", connection.to_string()),
     ]);
     if let Some(peer_address) = peer_address {
         headers.insert("NL-CONNECTING-ADDRESS", peer_address);
     }
-    trace!("Connecting to gateway for Data channel: {}", gateway_addr);
-    let mut data_stream: Box<dyn AsyncSocket> =
-        Box::new(WsConnectionBinary::new(gateway_addr, headers, &service_type).await?);
+    // encrypted traffic does not compress, gateways without support leave the header unanswered
+    let compression = (k.is_none() && compression != Compression::None).then_some(compression);
+    if let Some(compression) = compression {
+        headers.insert("NL-COMPRESSION", compression.as_str().to_owned());
+    }
+    trace!(
+        "Connecting to gateway for Data channel: {}",
+        gateway.gateway
+    );
+    let quic = quic.filter(|quic| !quic.is_closed());
+    let mux = mux.filter(|mux| !mux.is_closed());
+    let ws_stream = match (quic, mux) {
+        // a stream of its own on the shared QUIC connection
+        (Some(quic), _) => WsConnectionBinary::with_quic(&quic, &gateway.gateway, headers).await?,
+        (None, Some(mux)) => WsConnectionBinary::with_mux(&mux, &gateway.gateway, headers).await?,
+        (None, None) => WsConnectionBinary::with_options(
+            &gateway.gateway,
+            headers,
+            &gateway.protocol,
+            &gateway.dial_options(),
+        )
+        .await
+        .map_err(|e| match e {
+            NetworkError::CertificatePinMismatch => {
+                AgentError::CertificatePinMismatch(gateway.gateway.clone())
+            }
+            e => e.into(),
+        })?,
+    };
     trace!("Connected to gateway for Data channel");
-    if let (Some(k), Some(n)) = (k, n) {
-        data_stream = Box::new(AsyncSocketCrypt::new(k, n, data_stream).await);
+    let compressed = compression.is_some_and(|compression| {
+        ws_stream.get_header("NL-COMPRESSION") == Some(compression.as_str())
+    });
+    let mut data_stream: Box<dyn AsyncSocket> = Box::new(ws_stream);
+    if compressed {
+        data_stream = Box::new(AsyncSocketCompress::new(data_stream));
+    }
+    if let (Some(mut k), Some(n)) = (k, n) {
+        if let Some(exchange) = req.exchange {
+            k = ephemeral_key(&mut data_stream, &k, &exchange).await?;
+        }
+        data_stream = Box::new(
+            AsyncSocketCrypt::new(k, n, data_stream)
+                .await
+                .with_monitor(monitor),
+        );
     }
 
     if let Err(_e) = async_forward(data_stream, socket).await {
@@ -616,17 +1530,64 @@ async fn data_connect(
     Ok(())
 }
 
-pub async fn is_ready(command: generic::Connect) -> Result<bool, std::io::Error> {
+// datagrams can't be raced, the first address a socket connects to is used, an unreachable
+// family falls through to the next one
+async fn connect_udp_addrs(addresses: Vec<std::net::SocketAddr>) -> io::Result<UdpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+    for address in addresses {
+        match UdpStream::connect(address).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!("connecting to {} failed: {}", address, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+// destinations of the form unix:/path/to.sock
+fn unix_socket_path(host: &str) -> Option<&std::path::Path> {
+    host.strip_prefix("unix:").map(std::path::Path::new)
+}
+
+#[cfg(unix)]
+async fn unix_connect(host: &str) -> Result<(Box<dyn AsyncSocket>, Option<String>), AgentError> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let path = unix_socket_path(host).ok_or(AgentError::UnableToResolve(host.to_owned()))?;
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        Ok(_) => return Err(AgentError::NotAUnixSocket(path.to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(AgentError::UnixSocketNotFound(path.to_owned()))
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    let peer_address = format!("UNIX://{}", path.display());
+    Ok((Box::new(stream), Some(peer_address)))
+}
+
+#[cfg(not(unix))]
+async fn unix_connect(host: &str) -> Result<(Box<dyn AsyncSocket>, Option<String>), AgentError> {
+    Err(AgentError::UnixSocketUnsupported(host.to_owned()))
+}
+
+pub async fn is_ready(command: generic::Connect, resolver: &Resolver) -> Result<bool, AgentError> {
     debug!("Checking if {:?} is ready", command);
+    if unix_socket_path(&command.host).is_some() {
+        return unix_connect(&command.host).await.map(|_| true);
+    }
     match command.protocol {
         generic::Protocol::HTTPS
         | generic::Protocol::TLS
         | generic::Protocol::TCP
         | generic::Protocol::HTTP => {
-            let addr = format!("{}:{}", command.host, command.port);
-            match TcpStream::connect(addr).await {
+            let addresses = resolver.resolve(&command.host, command.port).await?;
+            match connect_tcp_addrs(addresses).await {
                 Ok(_) => Ok(true),
-                Err(e) => Err(e),
+                Err(e) => Err(e.into()),
             }
         }
         generic::Protocol::QUIC | generic::Protocol::DTLS | generic::Protocol::UDP => Ok(false),
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use hyper::{
+    client::conn,
+    header::{self, HeaderName},
+    Body, Request, StatusCode,
+};
+use narrowlink_network::transport::{StreamType, TlsConfiguration, UnifiedSocket};
+use narrowlink_types::ServiceType;
+use serde::Deserialize;
+use tokio::time;
+use tracing::debug;
+
+use crate::{
+    config::{self, Platform, SelfHosted},
+    error::AgentError,
+};
+
+const DISCOVERY_PATH: &str = "/v1/agent/endpoint";
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct PlatformEndpoint {
+    gateway: String,
+    token: String,
+    #[serde(default)]
+    protocol: ServiceType,
+}
+
+// asks the control plane which gateway to use, authenticated with the API key
+pub async fn discover(platform: &Platform) -> Result<SelfHosted, AgentError> {
+    let control_plane = &platform.control_plane;
+    let host = control_plane.split(':').next().unwrap_or(control_plane);
+    debug!("Discovering gateway from {}", control_plane);
+    let stream = time::timeout(
+        DISCOVERY_TIMEOUT,
+        UnifiedSocket::new(
+            control_plane,
+            StreamType::Tls(TlsConfiguration {
+                sni: host.to_owned(),
+            }),
+        ),
+    )
+    .await
+    .or(Err(AgentError::PlatformDiscovery("connection timeout")))??;
+    let authorization = format!("Bearer {}", platform.api_key);
+    let response = get(
+        stream,
+        host,
+        DISCOVERY_PATH,
+        &[
+            (header::AUTHORIZATION, authorization.as_str()),
+            (header::ACCEPT, "application/json"),
+        ],
+        DISCOVERY_TIMEOUT,
+        AgentError::PlatformDiscovery,
+    )
+    .await?;
+    let endpoint: PlatformEndpoint = serde_json::from_slice(&response)
+        .or(Err(AgentError::PlatformDiscovery("invalid response")))?;
+    debug!("Discovered gateway {}", endpoint.gateway);
+    Ok(SelfHosted {
+        gateway: endpoint.gateway,
+        token: endpoint.token,
+        token_file: None,
+        refresh_token: None,
+        publish: platform.publish.clone(),
+        protocol: endpoint.protocol,
+        acme: None,
+        proxy: None,
+        proxy_fallback: false,
+        keepalive_secs: config::_default_keepalive_secs(),
+        idle_timeout_secs: config::_default_idle_timeout_secs(),
+        ip_family: Default::default(),
+        multiplex: false,
+        gateway_cert_pin: None,
+        client_cert: None,
+        client_key: None,
+        client_identity: None,
+        ca_bundle: None,
+        ca_bundle_extends_roots: false,
+        trust_roots: None,
+    })
+}
+
+// the body of a successful GET over the stream, error is the failure of the request
+pub async fn get(
+    stream: UnifiedSocket,
+    host: &str,
+    path: &str,
+    headers: &[(HeaderName, &str)],
+    timeout: Duration,
+    error: fn(&'static str) -> AgentError,
+) -> Result<Vec<u8>, AgentError> {
+    let (mut request_sender, connection) = conn::handshake(stream)
+        .await
+        .or(Err(error("invalid response")))?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("HTTP connection closed: {}", e);
+        }
+    });
+    let mut request = Request::get(path).header(header::HOST, host);
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    let request = request
+        .body(Body::empty())
+        .or(Err(error("invalid request")))?;
+    let (status, body) = time::timeout(timeout, async {
+        let response = request_sender.send_request(request).await?;
+        let status = response.status();
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .map(|body| (status, body))
+    })
+    .await
+    .or(Err(error("response timeout")))?
+    .or(Err(error("invalid response")))?;
+    match status {
+        StatusCode::OK => Ok(body.to_vec()),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(AgentError::AccessDenied),
+        _ => Err(error("unexpected status")),
+    }
+}
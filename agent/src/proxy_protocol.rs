@@ -0,0 +1,41 @@
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY: u8 = 0x21; // version 2, the connection was relayed on behalf of the source
+const LOCAL: u8 = 0x20; // version 2, the backend uses the addresses of the connection itself
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+// PROXY protocol v2 header for a TCP backend connection, LOCAL if the source is unknown,
+// IPv4 addresses are mapped when only one side is IPv6
+pub fn header(source: Option<SocketAddr>, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    let Some(source) = source else {
+        header.extend_from_slice(&[LOCAL, 0, 0, 0]);
+        return header;
+    };
+    let addresses = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(PROXY);
+            header.push(TCP_OVER_IPV4);
+            [src.octets().to_vec(), dst.octets().to_vec()].concat()
+        }
+        (src, dst) => {
+            header.push(PROXY);
+            header.push(TCP_OVER_IPV6);
+            [ipv6(src).to_vec(), ipv6(dst).to_vec()].concat()
+        }
+    };
+    header.extend_from_slice(&(addresses.len() as u16 + 4).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+fn ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use narrowlink_network::AsyncSocket;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+use tracing::info;
+
+use crate::config::RateLimit;
+
+const THROTTLE_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    throttle_logged: Option<Instant>,
+}
+
+// shared by every connection of a published service, reads and writes draw from the same bucket
+pub struct TokenBucket {
+    service: String,
+    limit: RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    pub fn new(service: String, limit: RateLimit) -> Self {
+        Self {
+            service,
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: limit.burst() as f64,
+                refilled: Instant::now(),
+                throttle_logged: None,
+            }),
+        }
+    }
+
+    // grants up to `wanted` bytes, or the time until a byte is available
+    fn take(&self, wanted: usize) -> Result<usize, Duration> {
+        let Ok(mut bucket) = self.bucket.lock() else {
+            return Ok(wanted);
+        };
+        let now = Instant::now();
+        let refill =
+            now.duration_since(bucket.refilled).as_secs_f64() * self.limit.bytes_per_sec as f64;
+        bucket.tokens = (bucket.tokens + refill).min(self.limit.burst() as f64);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            let granted = wanted.min(bucket.tokens as usize);
+            bucket.tokens -= granted as f64;
+            return Ok(granted);
+        }
+        if bucket
+            .throttle_logged
+            .map_or(true, |logged| logged.elapsed() >= THROTTLE_LOG_INTERVAL)
+        {
+            bucket.throttle_logged = Some(now);
+            info!(
+                "Service {} is throttled to {} bytes/s",
+                self.service, self.limit.bytes_per_sec
+            );
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.limit.bytes_per_sec as f64,
+        ))
+    }
+
+    fn refund(&self, unused: usize) {
+        if let Ok(mut bucket) = self.bucket.lock() {
+            bucket.tokens = (bucket.tokens + unused as f64).min(self.limit.burst() as f64);
+        }
+    }
+}
+
+// backpressures the wrapped socket instead of dropping data once the bucket is empty
+pub struct RateLimited {
+    inner: Box<dyn AsyncSocket>,
+    bucket: Arc<TokenBucket>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+    scratch: Vec<u8>,
+}
+
+impl RateLimited {
+    pub fn new(inner: Box<dyn AsyncSocket>, bucket: Arc<TokenBucket>) -> Self {
+        Self {
+            inner,
+            bucket,
+            read_delay: None,
+            write_delay: None,
+            scratch: Vec::new(),
+        }
+    }
+}
+
+// waits out the delay, then takes tokens or schedules the next delay
+fn poll_tokens(
+    bucket: &TokenBucket,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+    wanted: usize,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = delay.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *delay = None;
+        }
+        match bucket.take(wanted) {
+            Ok(granted) => return Poll::Ready(granted),
+            Err(wait) => *delay = Some(Box::pin(tokio::time::sleep(wait))),
+        }
+    }
+}
+
+impl AsyncRead for RateLimited {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let granted = match poll_tokens(&this.bucket, &mut this.read_delay, cx, buf.remaining()) {
+            Poll::Ready(granted) => granted,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.scratch.resize(granted, 0);
+        let mut limited = ReadBuf::new(&mut this.scratch);
+        let res = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let filled = limited.filled().len();
+        buf.put_slice(limited.filled());
+        this.bucket.refund(granted - filled);
+        res
+    }
+}
+
+impl AsyncWrite for RateLimited {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let granted = match poll_tokens(&this.bucket, &mut this.write_delay, cx, buf.len()) {
+            Poll::Ready(granted) => granted,
+            Poll::Pending => return Poll::Pending,
+        };
+        let res = Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]);
+        let written = match &res {
+            Poll::Ready(Ok(written)) => *written,
+            _ => 0,
+        };
+        this.bucket.refund(granted - written);
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
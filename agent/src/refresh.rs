@@ -0,0 +1,84 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::HeaderName;
+use narrowlink_network::transport::{StreamType, TlsConfiguration, UnifiedSocket};
+use narrowlink_types::ServiceType;
+use tokio::time;
+use tracing::debug;
+
+use crate::{config::SelfHosted, error::AgentError, platform::get};
+
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(10);
+const REFRESH_BEFORE_EXPIRY: Duration = Duration::from_secs(60); // below the shortest lifetime the gateway issues
+pub const REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// how long until the token has to be refreshed, None without a refresh token, a token that
+// can't be read is refreshed right away
+pub fn due_in(self_hosted: &SelfHosted) -> Option<Duration> {
+    self_hosted.refresh_token.as_ref()?;
+    let Some(exp) = self_hosted.token_expiry() else {
+        return Some(Duration::ZERO);
+    };
+    Some(
+        Duration::from_secs(exp)
+            .saturating_sub(now())
+            .saturating_sub(REFRESH_BEFORE_EXPIRY),
+    )
+}
+
+pub fn is_expired(self_hosted: &SelfHosted) -> bool {
+    self_hosted
+        .token_expiry()
+        .is_some_and(|exp| Duration::from_secs(exp) <= now())
+}
+
+// exchanges the refresh token for a new token at the gateway, over the transport and with the
+// dial options of the gateway's event connection
+pub async fn refresh(self_hosted: &SelfHosted) -> Result<String, AgentError> {
+    let Some(refresh_token) = self_hosted.refresh_token.as_deref() else {
+        return Err(AgentError::TokenRefresh("no refresh token"));
+    };
+    let gateway = &self_hosted.gateway;
+    let host = gateway.split(':').next().unwrap_or(gateway);
+    let transport_type = if let ServiceType::Wss | ServiceType::Quic = self_hosted.protocol {
+        StreamType::Tls(TlsConfiguration {
+            sni: host.to_owned(),
+        })
+    } else {
+        StreamType::Tcp
+    };
+    debug!("Refreshing token at {}", gateway);
+    let stream = time::timeout(
+        REFRESH_TIMEOUT,
+        UnifiedSocket::with_options(gateway, transport_type, &self_hosted.dial_options()),
+    )
+    .await
+    .or(Err(AgentError::TokenRefresh("connection timeout")))??;
+    let token = match get(
+        stream,
+        host,
+        "/",
+        &[(HeaderName::from_static("nl-refresh-token"), refresh_token)],
+        REFRESH_TIMEOUT,
+        AgentError::TokenRefresh,
+    )
+    .await
+    {
+        Ok(token) => token,
+        Err(AgentError::AccessDenied) => {
+            return Err(AgentError::TokenRefresh("refresh token rejected"))
+        }
+        Err(e) => return Err(e),
+    };
+    String::from_utf8(token)
+        .ok()
+        .map(|token| token.trim().to_owned())
+        .filter(|token| !token.is_empty())
+        .ok_or(AgentError::TokenRefresh("invalid response"))
+}
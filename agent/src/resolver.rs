@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+    time,
+};
+use tracing::debug;
+
+use crate::{config::Dns, error::AgentError};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const QUERY_ATTEMPTS: usize = 2;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+// the system resolver reports no TTL, its answers are only kept briefly since it usually caches
+// them itself, within cache_secs
+const SYSTEM_CACHE_SECS: u64 = 5;
+
+// resolves the destinations clients request in the agent's network, through the pinned resolver
+// if one is set, answers are cached for their TTL capped by cache_secs, every address is returned
+// so the connection can race them
+pub struct Resolver {
+    server: Option<SocketAddr>,
+    cache_secs: u64,
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>, // host, addresses and their expiry
+}
+
+impl Resolver {
+    pub fn new(dns: &Dns) -> Self {
+        Self {
+            server: dns.resolver(),
+            cache_secs: dns.cache_secs,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // never empty
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, AgentError> {
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addresses) = self.cached(&host) {
+            return Ok(with_port(&addresses, port));
+        }
+        let (addresses, ttl) = match self.server {
+            Some(server) => query(server, &host).await,
+            None => lookup_host((host.as_str(), port))
+                .await
+                .map(|addresses| (addresses.map(|a| a.ip()).collect(), SYSTEM_CACHE_SECS))
+                .map_err(|e| e.to_string()),
+        }
+        .and_then(|(addresses, ttl): (Vec<IpAddr>, u64)| {
+            if addresses.is_empty() {
+                Err("no address found".to_owned())
+            } else {
+                Ok((addresses, ttl))
+            }
+        })
+        .map_err(|e| {
+            debug!("Unable to resolve {}: {}", host, e);
+            AgentError::UnableToResolve(host.clone())
+        })?;
+        let resolved = with_port(&addresses, port);
+        let ttl = ttl.min(self.cache_secs);
+        if ttl > 0 {
+            if let Ok(mut cache) = self.cache.lock() {
+                let now = Instant::now();
+                cache.retain(|_, (_, expiry)| *expiry > now);
+                cache.insert(host, (addresses, now + Duration::from_secs(ttl)));
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let cache = self.cache.lock().ok()?;
+        let (addresses, expiry) = cache.get(host)?;
+        (*expiry > Instant::now()).then(|| addresses.clone())
+    }
+}
+
+fn with_port(addresses: &[IpAddr], port: u16) -> Vec<SocketAddr> {
+    addresses
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, port))
+        .collect()
+}
+
+// IPv4 addresses first, the TTL is the lowest of the answers
+async fn query(server: SocketAddr, host: &str) -> Result<(Vec<IpAddr>, u64), String> {
+    let (v4, v6) = tokio::join!(
+        query_type(server, host, TYPE_A),
+        query_type(server, host, TYPE_AAAA)
+    );
+    match (v4, v6) {
+        (Err(e), Err(_)) => Err(e),
+        (v4, v6) => {
+            let answers = v4
+                .unwrap_or_default()
+                .into_iter()
+                .chain(v6.unwrap_or_default())
+                .collect::<Vec<_>>();
+            let ttl = answers.iter().map(|(_, ttl)| *ttl).min().unwrap_or(0);
+            Ok((answers.into_iter().map(|(ip, _)| ip).collect(), ttl as u64))
+        }
+    }
+}
+
+async fn query_type(
+    server: SocketAddr,
+    host: &str,
+    record_type: u16,
+) -> Result<Vec<(IpAddr, u32)>, String> {
+    let bind: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await.map_err(|e| e.to_string())?;
+    socket.connect(server).await.map_err(|e| e.to_string())?;
+    let id = rand::random::<u16>();
+    let request = request(id, host, record_type)?;
+    let mut response = [0; 1232];
+    for _ in 0..QUERY_ATTEMPTS {
+        socket.send(&request).await.map_err(|e| e.to_string())?;
+        let deadline = time::Instant::now() + QUERY_TIMEOUT;
+        // answers of other ids are late replies to an earlier attempt
+        while let Ok(len) = time::timeout_at(deadline, socket.recv(&mut response)).await {
+            let len = len.map_err(|e| e.to_string())?;
+            match answers(&response[..len], id, record_type)? {
+                Reply::Answers(answers) => return Ok(answers),
+                Reply::Truncated => return query_tcp(server, &request, id, record_type).await,
+                Reply::Other => continue,
+            }
+        }
+    }
+    Err(format!("{} did not answer", server))
+}
+
+// the answer did not fit in a datagram, the query is sent again over TCP
+async fn query_tcp(
+    server: SocketAddr,
+    request: &[u8],
+    id: u16,
+    record_type: u16,
+) -> Result<Vec<(IpAddr, u32)>, String> {
+    time::timeout(QUERY_TIMEOUT, async {
+        let mut stream = TcpStream::connect(server).await?;
+        let mut message = (request.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(request);
+        stream.write_all(&message).await?;
+        let len = stream.read_u16().await?;
+        let mut response = vec![0; len as usize];
+        stream.read_exact(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    })
+    .await
+    .map_err(|_| format!("{} did not answer over tcp", server))?
+    .map_err(|e| e.to_string())
+    .and_then(|response| match answers(&response, id, record_type)? {
+        Reply::Answers(answers) => Ok(answers),
+        Reply::Truncated | Reply::Other => Err("invalid answer over tcp".to_owned()),
+    })
+}
+
+fn request(id: u16, host: &str, record_type: u16) -> Result<Vec<u8>, String> {
+    let mut request = Vec::with_capacity(18 + host.len());
+    request.extend_from_slice(&id.to_be_bytes());
+    request.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]); // recursion desired, one question
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err("invalid host name".to_owned());
+        }
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+    request.push(0);
+    request.extend_from_slice(&record_type.to_be_bytes());
+    request.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(request)
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Answers(Vec<(IpAddr, u32)>),
+    Truncated, // the TC bit is set, the records are incomplete
+    Other,     // not the answer to the query
+}
+
+fn answers(message: &[u8], id: u16, record_type: u16) -> Result<Reply, String> {
+    let invalid = || "invalid answer".to_owned();
+    let read_u16 = |at: usize| {
+        message
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(invalid)
+    };
+    if message.len() < 12 || read_u16(0)? != id || message[2] & 0x80 == 0 {
+        return Ok(Reply::Other);
+    }
+    if message[2] & 0x02 != 0 {
+        return Ok(Reply::Truncated);
+    }
+    match read_u16(2)? & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => return Err("no such host".to_owned()),
+        rcode => return Err(format!("server failure ({})", rcode)),
+    }
+    let (questions, records) = (read_u16(4)?, read_u16(6)?);
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(message, at).ok_or_else(invalid)? + 4;
+    }
+    let mut answers = Vec::new();
+    for _ in 0..records {
+        at = skip_name(message, at).ok_or_else(invalid)?;
+        let (answer_type, class) = (read_u16(at)?, read_u16(at + 2)?);
+        let ttl = message
+            .get(at + 4..at + 8)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(invalid)?;
+        let data_len = read_u16(at + 8)? as usize;
+        let data = message
+            .get(at + 10..at + 10 + data_len)
+            .ok_or_else(invalid)?;
+        at += 10 + data_len;
+        // CNAME records are followed by the records of their target
+        if answer_type != record_type || class != CLASS_IN {
+            continue;
+        }
+        let ip = match data.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(data).map_err(|_| invalid())?),
+            16 => IpAddr::from(<[u8; 16]>::try_from(data).map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+        answers.push((ip, ttl));
+    }
+    Ok(Reply::Answers(answers))
+}
+
+// offset after the name, compressed names end at their first pointer
+fn skip_name(message: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(at)?;
+        match len {
+            0 => return Some(at + 1),
+            len if len & 0xc0 == 0xc0 => return Some(at + 2),
+            len => at += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+    const ANSWER: u16 = 0x8180; // response, recursion desired and available
+    const TRUNCATED: u16 = 0x8380;
+    const NXDOMAIN: u16 = 0x8183;
+
+    // example.com A, answered with a CNAME to www.example.com and its address, both names
+    // compressed
+    fn response(flags: u16) -> Vec<u8> {
+        let mut message = ID.to_be_bytes().to_vec();
+        message.extend_from_slice(&flags.to_be_bytes());
+        message.extend_from_slice(&[0, 1, 0, 2, 0, 0, 0, 0]);
+        message.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        // CNAME www.example.com, the target points back at the question
+        message.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x01\x2c\x00\x06");
+        message.extend_from_slice(b"\x03www\xc0\x0c");
+        // A of www.example.com, a label followed by a pointer
+        message.extend_from_slice(b"\x03www\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04");
+        message.extend_from_slice(&[93, 184, 216, 34]);
+        message
+    }
+
+    #[test]
+    fn answers_follow_compressed_names() {
+        assert_eq!(
+            answers(&response(ANSWER), ID, TYPE_A),
+            Ok(Reply::Answers(vec![(IpAddr::from([93, 184, 216, 34]), 60)]))
+        );
+        assert_eq!(
+            answers(&response(ANSWER), ID, TYPE_AAAA),
+            Ok(Reply::Answers(Vec::new()))
+        );
+        assert_eq!(answers(&response(ANSWER), ID + 1, TYPE_A), Ok(Reply::Other));
+        assert!(answers(&response(NXDOMAIN), ID, TYPE_A).is_err());
+    }
+
+    #[test]
+    fn truncated_messages_are_never_read_as_answers() {
+        assert_eq!(
+            answers(&response(TRUNCATED), ID, TYPE_A),
+            Ok(Reply::Truncated)
+        );
+        // cut short without the TC bit, e.g. by a middlebox
+        let message = response(ANSWER);
+        for len in 12..message.len() {
+            assert!(
+                answers(&message[..len], ID, TYPE_A).is_err(),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn names_end_at_their_root_or_first_pointer() {
+        let message = response(ANSWER);
+        assert_eq!(skip_name(&message, 12), Some(25));
+        assert_eq!(skip_name(&message, 29), Some(31));
+        assert_eq!(skip_name(&message, 41), Some(47));
+        assert_eq!(skip_name(b"\x07example\x03co", 0), None);
+        assert_eq!(skip_name(b"\x07example", 0), None);
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_asked_again_over_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.expect("udp socket");
+        let server = udp.local_addr().expect("udp address");
+        let tcp = tokio::net::TcpListener::bind(server)
+            .await
+            .expect("tcp listener on the same port");
+        tokio::spawn(async move {
+            let mut query = [0; 512];
+            let (len, peer) = udp.recv_from(&mut query).await.expect("udp query");
+            let mut truncated = response(TRUNCATED);
+            truncated[..2].copy_from_slice(&query[..2]);
+            truncated.truncate(25); // the header and the question
+            udp.send_to(&truncated, peer).await.expect("udp answer");
+
+            let (mut stream, _) = tcp.accept().await.expect("tcp connection");
+            let len_tcp = stream.read_u16().await.expect("tcp query length") as usize;
+            let mut query_tcp = vec![0; len_tcp];
+            stream.read_exact(&mut query_tcp).await.expect("tcp query");
+            assert_eq!(query_tcp, query[..len]);
+            let mut answer = response(ANSWER);
+            answer[..2].copy_from_slice(&query[..2]);
+            stream
+                .write_all(&(answer.len() as u16).to_be_bytes())
+                .await
+                .expect("tcp answer length");
+            stream.write_all(&answer).await.expect("tcp answer");
+        });
+        assert_eq!(
+            query_type(server, "example.com", TYPE_A).await,
+            Ok(vec![(IpAddr::from([93, 184, 216, 34]), 60)])
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use narrowlink_types::agent::EventOutBound as AgentEventOutBound;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::UnboundedSender,
+    task::JoinHandle,
+    time,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::error::AgentError;
+
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(10); // for the client to open the data channel
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortsSpec {
+    Port(u16),
+    Range(String),
+}
+
+// a port or an inclusive range such as 8000-8099
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "PortsSpec", into = "String")]
+pub struct Ports(u16, u16);
+
+impl TryFrom<PortsSpec> for Ports {
+    type Error = AgentError;
+
+    fn try_from(ports: PortsSpec) -> Result<Self, Self::Error> {
+        let range = match ports {
+            PortsSpec::Port(port) => return Ok(Self(port, port)),
+            PortsSpec::Range(range) => range,
+        };
+        let invalid = || AgentError::InvalidReverseBind(range.clone());
+        let (start, end) = range.split_once('-').unwrap_or((&range, &range));
+        let start = start.trim().parse().map_err(|_| invalid())?;
+        let end = end.trim().parse().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        Ok(Self(start, end))
+    }
+}
+
+impl From<Ports> for String {
+    fn from(Ports(start, end): Ports) -> Self {
+        if start == end {
+            start.to_string()
+        } else {
+            format!("{}-{}", start, end)
+        }
+    }
+}
+
+// an address clients may listen on through the agent
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct ReverseBindRule {
+    pub host: String, // compared with the requested host as it is, 0.0.0.0 only matches itself
+    pub ports: Ports,
+}
+
+impl ReverseBindRule {
+    fn permit(&self, host: &str, port: u16) -> bool {
+        self.host.eq_ignore_ascii_case(host) && (self.ports.0..=self.ports.1).contains(&port)
+    }
+}
+
+// accepted connections not yet claimed by a data connection of the client
+#[derive(Clone, Default)]
+pub struct PendingConnections(Arc<Mutex<HashMap<Uuid, TcpStream>>>);
+
+impl PendingConnections {
+    fn insert(&self, connection: Uuid, stream: TcpStream) {
+        if let Ok(mut pending) = self.0.lock() {
+            pending.insert(connection, stream);
+        }
+    }
+
+    pub fn take(&self, connection: Uuid) -> Option<TcpStream> {
+        self.0.lock().ok()?.remove(&connection)
+    }
+}
+
+// listeners of the reverse forwards clients registered through the gateway, every accepted
+// connection waits until the client opens a data connection for it, only the addresses of the
+// rules may be listened on
+pub struct ReverseBinds {
+    rules: Vec<ReverseBindRule>,
+    listeners: HashMap<Uuid, (String, u16, JoinHandle<()>)>,
+    pending: PendingConnections,
+}
+
+impl ReverseBinds {
+    pub fn new(rules: Vec<ReverseBindRule>) -> Self {
+        Self {
+            rules,
+            listeners: HashMap::new(),
+            pending: PendingConnections::default(),
+        }
+    }
+
+    pub fn pending(&self) -> PendingConnections {
+        self.pending.clone()
+    }
+
+    fn permit(&self, host: &str, port: u16) -> bool {
+        self.rules.iter().any(|rule| rule.permit(host, port))
+    }
+
+    // listeners the new rules no longer permit are closed
+    pub fn set_rules(&mut self, rules: Vec<ReverseBindRule>) {
+        self.rules = rules;
+        let denied = self
+            .listeners
+            .iter()
+            .filter(|(_, (host, port, _))| !self.permit(host, *port))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in denied {
+            self.unbind(id);
+        }
+    }
+
+    pub async fn bind(
+        &mut self,
+        id: Uuid,
+        host: &str,
+        port: u16,
+        event_sender: UnboundedSender<AgentEventOutBound>,
+    ) {
+        self.unbind(id);
+        if !self.permit(host, port) {
+            let address = format!("{}:{}", host, port);
+            warn!("Reverse forward on {} denied", address);
+            let _ = event_sender.send(AgentEventOutBound::Error(
+                id,
+                AgentError::ReverseBindDenied(address).to_string(),
+            ));
+            return;
+        }
+        let listener = match TcpListener::bind((host, port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!(
+                    "Unable to listen on {}:{} for reverse forward: {}",
+                    host, port, e
+                );
+                let _ = event_sender.send(AgentEventOutBound::Error(id, e.to_string()));
+                return;
+            }
+        };
+        info!("Reverse forward listening on {}:{}", host, port);
+        let pending = self.pending.clone();
+        let listener = tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let connection = Uuid::new_v4();
+                debug!("Reverse forward connection {} from {}", connection, peer);
+                pending.insert(connection, stream);
+                if event_sender
+                    .send(AgentEventOutBound::ReverseConnection(id, connection))
+                    .is_err()
+                {
+                    pending.take(connection);
+                    break;
+                }
+                let pending = pending.clone();
+                tokio::spawn(async move {
+                    time::sleep(CLAIM_TIMEOUT).await;
+                    if pending.take(connection).is_some() {
+                        debug!("Reverse forward connection {} was not claimed", connection);
+                    }
+                });
+            }
+        });
+        self.listeners.insert(id, (host.to_owned(), port, listener));
+    }
+
+    pub fn unbind(&mut self, id: Uuid) {
+        if let Some((_, _, listener)) = self.listeners.remove(&id) {
+            info!("Reverse forward {} closed", id);
+            listener.abort();
+        }
+    }
+
+    // the binds belong to the gateway connection, the gateway restores them on reconnect
+    pub fn clear(&mut self) {
+        for (_, (_, _, listener)) in self.listeners.drain() {
+            listener.abort();
+        }
+    }
+}
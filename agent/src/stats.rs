@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use narrowlink_network::AsyncSocket;
+use narrowlink_types::publish::PublishHost;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+static SERVICES: Mutex<Option<HashMap<String, Arc<Counters>>>> = Mutex::new(None); // host:port -> counters
+static PUBLISHED: Mutex<Vec<PublishHost>> = Mutex::new(Vec::new());
+static GATEWAY: Mutex<Option<(String, u64)>> = Mutex::new(None); // address, connected since
+
+#[derive(Default)]
+struct Counters {
+    bytes_received: AtomicU64, // from the service, towards the gateway
+    bytes_sent: AtomicU64,     // from the gateway, towards the service
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    integrity_failures: Arc<AtomicU64>, // E2EE chunks failing verification
+    key_mismatches: Arc<AtomicU64>,     // E2EE requests signed with another key
+}
+
+#[derive(Serialize)]
+pub struct ServiceStats {
+    pub service: String,        // host:port the agent connects to
+    pub published: Vec<String>, // host:port published on the gateway, empty for client connections
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub active_connections: u64,
+    pub total_connections: u64,
+    pub integrity_failures: u64,
+    pub key_mismatches: u64,
+}
+
+#[derive(Serialize)]
+pub struct GatewayStatus {
+    pub connected: bool,
+    pub gateway: Option<String>,
+    pub since: Option<u64>, // seconds since epoch
+    pub active_connections: u64,
+}
+
+pub fn set_published(publish_hosts: Vec<PublishHost>) {
+    if let Ok(mut published) = PUBLISHED.lock() {
+        *published = publish_hosts;
+    }
+}
+
+pub fn gateway_connected(gateway: &str) {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if let Ok(mut current) = GATEWAY.lock() {
+        *current = Some((gateway.to_owned(), since));
+    }
+}
+
+pub fn gateway_disconnected() {
+    if let Ok(mut current) = GATEWAY.lock() {
+        *current = None;
+    }
+}
+
+pub fn status() -> GatewayStatus {
+    let gateway = GATEWAY.lock().ok().and_then(|g| g.clone());
+    let active_connections = SERVICES
+        .lock()
+        .map(|services| {
+            services
+                .iter()
+                .flatten()
+                .map(|(_, c)| c.active_connections.load(Ordering::Relaxed))
+                .sum()
+        })
+        .unwrap_or(0);
+    GatewayStatus {
+        connected: gateway.is_some(),
+        since: gateway.as_ref().map(|(_, since)| *since),
+        gateway: gateway.map(|(gateway, _)| gateway),
+        active_connections,
+    }
+}
+
+// published services first, then the other destinations with traffic
+pub fn services() -> Vec<ServiceStats> {
+    let mut published: Vec<(String, Vec<String>)> = Vec::new();
+    if let Ok(publish_hosts) = PUBLISHED.lock() {
+        for publish_host in publish_hosts.iter() {
+            let service = format!(
+                "{}:{}",
+                publish_host.connect.host, publish_host.connect.port
+            );
+            let public = format!("{}:{}", publish_host.host, publish_host.port);
+            match published.iter_mut().find(|(s, _)| s == &service) {
+                Some((_, public_hosts)) => public_hosts.push(public),
+                None => published.push((service, vec![public])),
+            }
+        }
+    }
+    let counters = SERVICES
+        .lock()
+        .ok()
+        .and_then(|services| services.clone())
+        .unwrap_or_default();
+    let mut res: Vec<ServiceStats> = published
+        .into_iter()
+        .map(|(service, public_hosts)| stats(&service, public_hosts, counters.get(&service)))
+        .collect();
+    let mut unpublished: Vec<_> = counters
+        .iter()
+        .filter(|(service, _)| !res.iter().any(|s| &&s.service == service))
+        .map(|(service, c)| stats(service, Vec::new(), Some(c)))
+        .collect();
+    unpublished.sort_by(|l, r| l.service.cmp(&r.service));
+    res.extend(unpublished);
+    res
+}
+
+fn stats(service: &str, published: Vec<String>, counters: Option<&Arc<Counters>>) -> ServiceStats {
+    let load = |counter: fn(&Counters) -> &AtomicU64| {
+        counters.map_or(0, |c| counter(c).load(Ordering::Relaxed))
+    };
+    ServiceStats {
+        service: service.to_owned(),
+        published,
+        bytes_received: load(|c| &c.bytes_received),
+        bytes_sent: load(|c| &c.bytes_sent),
+        active_connections: load(|c| &c.active_connections),
+        total_connections: load(|c| &c.total_connections),
+        integrity_failures: load(|c| &c.integrity_failures),
+        key_mismatches: load(|c| &c.key_mismatches),
+    }
+}
+
+// counts the traffic of one connection to a service
+pub struct Counted {
+    inner: Box<dyn AsyncSocket>,
+    counters: Arc<Counters>,
+}
+
+fn counters(service: String) -> Arc<Counters> {
+    SERVICES
+        .lock()
+        .map(|mut services| {
+            services
+                .get_or_insert_with(HashMap::new)
+                .entry(service)
+                .or_default()
+                .clone()
+        })
+        .unwrap_or_default()
+}
+
+// integrity failures and key mismatches of a service
+pub fn e2ee_counters(service: String) -> (Arc<AtomicU64>, Arc<AtomicU64>) {
+    let counters = counters(service);
+    (
+        counters.integrity_failures.clone(),
+        counters.key_mismatches.clone(),
+    )
+}
+
+impl Counted {
+    pub fn new(inner: Box<dyn AsyncSocket>, service: String) -> Self {
+        let counters = counters(service);
+        counters.active_connections.fetch_add(1, Ordering::Relaxed);
+        counters.total_connections.fetch_add(1, Ordering::Relaxed);
+        Self { inner, counters }
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.counters
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.counters
+            .bytes_received
+            .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &res {
+            self.counters
+                .bytes_sent
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
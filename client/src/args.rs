@@ -47,13 +47,41 @@ pub struct ListArgs {
 
 #[derive(Debug, Clone)]
 pub struct ForwardArgs {
-    pub direct: bool,                 //d direct
-    pub relay: bool,                  //r relay
-    pub udp: bool,                    //u udp
-    pub agent_name: String,           //i name
-    pub cryptography: Option<String>, //k key
-    pub local_addr: SocketAddr,       //l local
-    pub remote_addr: (String, u16),   //<Remote>
+    pub direct: bool,                                    //d direct
+    pub relay: bool,                                     //r relay
+    pub udp: bool,                                       //u udp
+    pub agent_name: String,                              //i name
+    pub cryptography: Option<String>,                    //k key
+    pub local_addr: SocketAddr,                          //l local
+    pub remote_addr: (String, u16),                      //<Remote>
+    pub specs: Vec<(SocketAddr, (String, u16))>,         //L local-forward
+    pub reverse_specs: Vec<(SocketAddr, (String, u16))>, //R remote-forward
+}
+
+pub type ForwardSpec = (SocketAddr, (String, u16)); // bind, endpoint
+
+// [bind_address:]local_port:remote_host:remote_port as ssh -L takes it, the bind address
+// defaults to 127.0.0.1 and IPv6 addresses are written in brackets
+pub fn forward_spec(spec: &str) -> Result<(SocketAddr, (String, u16)), ClientError> {
+    let invalid = || ClientError::InvalidForwardSpec(spec.to_owned());
+    let (first, rest) = match spec.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once("]:").ok_or_else(invalid)?,
+        None => spec.split_once(':').ok_or_else(invalid)?,
+    };
+    let (bind, local_port, remote) = match first.parse::<u16>() {
+        Ok(port) if !spec.starts_with('[') => (IpAddr::V4(Ipv4Addr::LOCALHOST), port, rest),
+        _ => {
+            let bind = match first {
+                "localhost" => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                "*" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                ip => ip.parse::<IpAddr>().map_err(|_| invalid())?,
+            };
+            let (port, remote) = rest.split_once(':').ok_or_else(invalid)?;
+            (bind, port.parse::<u16>().map_err(|_| invalid())?, remote)
+        }
+    };
+    let remote = extract_addr(remote, false).map_err(|_| invalid())?;
+    Ok((SocketAddr::new(bind, local_port), remote))
 }
 
 #[derive(Debug, Clone)]
@@ -64,6 +92,7 @@ pub struct ProxyArgs {
     pub cryptography: Option<String>,       //k key
     pub local_addr: SocketAddr,             //<Local>
     pub map_addr: Option<(String, String)>, //m map
+    pub auth: Option<(String, String)>,     //a auth
 }
 
 #[derive(Debug, Clone)]
@@ -448,6 +477,8 @@ impl Args {
                         direct: false,
                         relay: false,
                         remote_addr: ("".to_string(), 0),
+                        specs: Vec::new(),
+                        reverse_specs: Vec::new(),
                     };
                     while let Some(arg) = raw.next(&mut cursor) {
                         if let Some((long, value)) = arg.to_long() {
@@ -484,6 +515,22 @@ impl Args {
                                             .to_string(),
                                     );
                                 }
+                                Ok("local-forward") => {
+                                    sub.specs.push(forward_spec(
+                                        value
+                                            .ok_or(ClientError::RequiredValue("local-forward"))?
+                                            .to_str()
+                                            .ok_or(ClientError::Encoding)?,
+                                    )?);
+                                }
+                                Ok("remote-forward") => {
+                                    sub.reverse_specs.push(forward_spec(
+                                        value
+                                            .ok_or(ClientError::RequiredValue("remote-forward"))?
+                                            .to_str()
+                                            .ok_or(ClientError::Encoding)?,
+                                    )?);
+                                }
                                 Ok("help") => {
                                     print!("{}", FORWARD_HELP);
                                     process::exit(0x0);
@@ -560,6 +607,42 @@ impl Args {
                                                 .to_string(),
                                         );
                                     }
+                                    Ok('L') => {
+                                        let next_value = if let Some(v) = shorts.next_value_os() {
+                                            v.to_str()
+                                        } else if let Some(v) = raw.next_os(&mut cursor) {
+                                            v.to_str().and_then(|v| {
+                                                if v.is_empty() || v.find('-') == Some(0) {
+                                                    None
+                                                } else {
+                                                    Some(v)
+                                                }
+                                            })
+                                        } else {
+                                            None
+                                        };
+                                        sub.specs.push(forward_spec(next_value.ok_or(
+                                            ClientError::RequiredValue("local-forward"),
+                                        )?)?);
+                                    }
+                                    Ok('R') => {
+                                        let next_value = if let Some(v) = shorts.next_value_os() {
+                                            v.to_str()
+                                        } else if let Some(v) = raw.next_os(&mut cursor) {
+                                            v.to_str().and_then(|v| {
+                                                if v.is_empty() || v.find('-') == Some(0) {
+                                                    None
+                                                } else {
+                                                    Some(v)
+                                                }
+                                            })
+                                        } else {
+                                            None
+                                        };
+                                        sub.reverse_specs.push(forward_spec(next_value.ok_or(
+                                            ClientError::RequiredValue("remote-forward"),
+                                        )?)?);
+                                    }
 
                                     Ok('h') => {
                                         print!("{}", FORWARD_HELP);
@@ -586,7 +669,10 @@ impl Args {
                     }
                     if sub.agent_name.is_empty() {
                         Err(ClientError::RequiredValue("name"))
-                    } else if sub.remote_addr.0.is_empty() {
+                    } else if sub.remote_addr.0.is_empty()
+                        && sub.specs.is_empty()
+                        && sub.reverse_specs.is_empty()
+                    {
                         Err(ClientError::RequiredValue("remote"))
                     } else {
                         Ok(ArgCommands::Forward(sub))
@@ -613,14 +699,12 @@ impl Args {
                                 Ok("relay") => {
                                     sub.relay = true;
                                 }
-                                Ok("name") => {
-                                    if sub.agent_name.is_empty() {
-                                        sub.agent_name = value
-                                            .ok_or(ClientError::RequiredValue("name"))?
-                                            .to_str()
-                                            .ok_or(ClientError::Encoding)?
-                                            .to_string();
-                                    }
+                                Ok("name") if sub.agent_name.is_empty() => {
+                                    sub.agent_name = value
+                                        .ok_or(ClientError::RequiredValue("name"))?
+                                        .to_str()
+                                        .ok_or(ClientError::Encoding)?
+                                        .to_string();
                                 }
                                 Ok("key") => {
                                     sub.cryptography = Some(
@@ -727,18 +811,17 @@ impl Args {
                         direct: false,
                         local_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1080),
                         map_addr: None,
+                        auth: None,
                     };
                     while let Some(arg) = raw.next(&mut cursor) {
                         if let Some((long, value)) = arg.to_long() {
                             match long {
-                                Ok("name") => {
-                                    if sub.agent_name.is_empty() {
-                                        sub.agent_name = value
-                                            .ok_or(ClientError::RequiredValue("name"))?
-                                            .to_str()
-                                            .ok_or(ClientError::Encoding)?
-                                            .to_string();
-                                    }
+                                Ok("name") if sub.agent_name.is_empty() => {
+                                    sub.agent_name = value
+                                        .ok_or(ClientError::RequiredValue("name"))?
+                                        .to_str()
+                                        .ok_or(ClientError::Encoding)?
+                                        .to_string();
                                 }
                                 Ok("direct") => {
                                     sub.direct = true;
@@ -768,6 +851,14 @@ impl Args {
                                     }
                                     sub.map_addr = map;
                                 }
+                                Ok("auth") => {
+                                    sub.auth = Some(socks_auth(
+                                        value
+                                            .ok_or(ClientError::RequiredValue("auth"))?
+                                            .to_str()
+                                            .ok_or(ClientError::Encoding)?,
+                                    )?);
+                                }
                                 Ok("help") => {
                                     print!("{}", PROXY_HELP);
                                     process::exit(0x0);
@@ -845,6 +936,25 @@ impl Args {
                                         }
                                         sub.map_addr = map;
                                     }
+                                    Ok('a') => {
+                                        let next_value = if let Some(v) = shorts.next_value_os() {
+                                            v.to_str()
+                                        } else if let Some(v) = raw.next_os(&mut cursor) {
+                                            v.to_str().and_then(|v| {
+                                                if v.is_empty() || v.find('-') == Some(0) {
+                                                    None
+                                                } else {
+                                                    Some(v)
+                                                }
+                                            })
+                                        } else {
+                                            None
+                                        };
+
+                                        sub.auth = Some(socks_auth(
+                                            next_value.ok_or(ClientError::RequiredValue("auth"))?,
+                                        )?);
+                                    }
                                     Ok('h') => {
                                         print!("{}", PROXY_HELP);
                                         process::exit(0x0);
@@ -894,3 +1004,75 @@ pub enum ArgCommands {
     #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
     Tun(TunArgs),
 }
+
+// username:password for the local SOCKS5 server, each at most 255 bytes as RFC 1929 allows
+fn socks_auth(value: &str) -> Result<(String, String), ClientError> {
+    value
+        .split_once(':')
+        .filter(|(username, password)| {
+            !username.is_empty() && username.len() <= 255 && password.len() <= 255
+        })
+        .map(|(username, password)| (username.to_owned(), password.to_owned()))
+        .ok_or(ClientError::InvalidSocksAuth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(bind: &str, host: &str, port: u16) -> ForwardSpec {
+        (bind.parse().expect("bind"), (host.to_owned(), port))
+    }
+
+    #[test]
+    fn forward_specs_parse_like_ssh() {
+        let valid = [
+            (
+                "8080:example.com:80",
+                spec("127.0.0.1:8080", "example.com", 80),
+            ),
+            (
+                "0.0.0.0:8080:example.com:80",
+                spec("0.0.0.0:8080", "example.com", 80),
+            ),
+            (
+                "*:8080:example.com:80",
+                spec("0.0.0.0:8080", "example.com", 80),
+            ),
+            (
+                "localhost:22:10.0.0.1:22",
+                spec("127.0.0.1:22", "10.0.0.1", 22),
+            ),
+            (
+                "[::1]:8443:[2001:db8::1]:443",
+                spec("[::1]:8443", "2001:db8::1", 443),
+            ),
+        ];
+        for (input, expected) in valid {
+            assert_eq!(forward_spec(input).ok(), Some(expected), "{input}");
+        }
+    }
+
+    #[test]
+    fn invalid_forward_specs_are_rejected() {
+        let invalid = [
+            "",
+            "8080",
+            "8080:example.com",
+            "8080::80",
+            "8080:example.com:0",
+            "8080:example.com:http",
+            "70000:example.com:80",
+            "gateway:8080:example.com:80",
+            "[::1:8080:example.com:80",
+            "[::1]8080:example.com:80",
+            "8080:exa mple.com:80",
+        ];
+        for input in invalid {
+            assert!(
+                matches!(forward_spec(input), Err(ClientError::InvalidForwardSpec(ref s)) if s == input),
+                "{input}"
+            );
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use narrowlink_types::ServiceType;
+use narrowlink_types::{generic::KeyDerivation, ServiceType};
 use serde::{Deserialize, Serialize};
 use std::{env, fs::File, io::Read, path::PathBuf};
 
@@ -25,6 +25,12 @@ pub enum Endpoint {
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     pub endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    pub kdf: KeyDerivation, // derives the E2EE key from --key, the same as the agent's
+    #[serde(default)]
+    pub ephemeral: bool, // per connection X25519 keys on top of --key, for forward secrecy
+    #[serde(default)]
+    pub pre_shared_key: bool, // --key is 32 raw bytes as hex or base64, for agents with a PreSharedKey
 }
 
 impl Config {
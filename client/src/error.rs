@@ -1,4 +1,5 @@
 use narrowlink_network::error::NetworkError;
+use narrowlink_types::error::PreSharedKeyError;
 use thiserror::Error;
 
 #[allow(dead_code)]
@@ -44,6 +45,20 @@ pub enum ClientError {
     // UnableToCreateNetStack(#[from] netstack_lwip::Error),
     #[error("Network Error: {0}")]
     NetworkError(#[from] NetworkError),
+    #[error(
+        "Invalid Forward Spec: {0}, expected [bind_address:]local_port:remote_host:remote_port"
+    )]
+    InvalidForwardSpec(String),
+    #[error("Unable To Bind {0} On The Agent")]
+    ReverseBindFailed(std::net::SocketAddr),
+    #[error("The Gateway Does Not Support {0}")]
+    MissingCapability(narrowlink_types::capability::Capability),
+    #[error("Invalid SOCKS5 Credentials, expected username:password")]
+    InvalidSocksAuth,
+    #[error("SOCKS5 Authentication Failed")]
+    SocksAuthFailed,
+    #[error("Invalid Pre-Shared Key: {0}")]
+    InvalidPreSharedKey(#[from] PreSharedKeyError),
     #[error("STOP: #{0:#10x}")]
     Unexpected(u32),
     #[error("IO Error: {0}")]
@@ -76,7 +91,7 @@ pub enum ClientError {
     ControlChannelNotConnected,
     #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
     #[error("IpStack Error: {0}")]
-    IpStackError(#[from] ipstack::IpStackError),
+    IpStackError(Box<ipstack::IpStackError>), // boxed to keep results small
     #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
     #[error("Unsupported Tun Protocol")]
     UnsupportedTunProtocol,
@@ -87,3 +102,10 @@ pub enum ClientError {
     #[error("wintun.dll not found, please download from https://www.wintun.net/ and put it in the same directory as narrowlink.exe")]
     WinTunDLLNotFound,
 }
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+impl From<ipstack::IpStackError> for ClientError {
+    fn from(err: ipstack::IpStackError) -> Self {
+        Self::IpStackError(Box::new(err))
+    }
+}
@@ -7,6 +7,7 @@ mod tunnel;
 use args::Args;
 use error::ClientError;
 use manage::{ControlFactory, ControlMsg, Instruction};
+use narrowlink_types::generic;
 use std::{
     env,
     io::{self, IsTerminal},
@@ -83,8 +84,9 @@ pub fn main() -> Result<(), ClientError> {
 async fn start(mut args: Args) -> Result<(), ClientError> {
     let conf = config::Config::load(args.take_conf_path())?;
     let instruction = Instruction::from(&args.arg_commands);
-    let mut control = ControlFactory::new(conf, instruction.is_direct_only())?;
-    let mut transport = TransportFactory::new(instruction.transport);
+    let direct_only = instruction.is_direct_only();
+    let mut transport = TransportFactory::new(instruction.transport, &conf);
+    let mut control = ControlFactory::new(conf, direct_only)?;
     let mut tunnel = TunnelFactory::new(instruction.tunnel);
 
     loop {
@@ -92,7 +94,12 @@ async fn start(mut args: Args) -> Result<(), ClientError> {
             msg = control.accept_msg() => {
                 match msg {
                     Ok(ControlMsg::ConnectionError(connection_id, msg)) => {
-                        debug!("Connection error: {}:{}", connection_id, msg);
+                        if let Some((bind, _)) = control.reverse_target(connection_id) {
+                            warn!("Agent is unable to listen on {}: {}", bind, msg);
+                        } else {
+                            // e.g. the agent could not resolve the destination or it refused
+                            warn!("Connection {} failed: {}", connection_id, msg);
+                        }
                     }
                     Ok(ControlMsg::Peer2Peer(p2p)) => {
                         debug!("Peer2Peer: {:?}", p2p);
@@ -104,6 +111,26 @@ async fn start(mut args: Args) -> Result<(), ClientError> {
                             _ = system_status_sender.send(ControlStatus::P2PRequest(t.create_direct(p2p,direct_tunnel_status).await));
                         });
                     }
+                    Ok(ControlMsg::ReverseConnection(bind_id, connection_id)) => {
+                        let Some((bind, (host, port))) = control.reverse_target(bind_id) else {
+                            continue;
+                        };
+                        let t = transport.clone();
+                        control.add_connection(tokio::spawn(async move{
+                            let socket = tokio::net::TcpStream::connect((host.as_str(), port)).await?;
+                            t.connect(socket, generic::Connect {
+                                host: bind.ip().to_string(),
+                                port: bind.port(),
+                                protocol: generic::Protocol::TCP,
+                                cryptography: None,
+                                sign: None,
+                                kdf: None,
+                                exchange: None,
+                                reverse: Some(connection_id),
+                                source: None,
+                            }).await
+                        }));
+                    }
                     Ok(ControlMsg::Shutdown(err)) => {
                         tunnel.stop().await;
                         return Err(err);
@@ -130,6 +157,14 @@ async fn start(mut args: Args) -> Result<(), ClientError> {
                             }
                             warn!("{}",e);
                         }
+                        if let Some((agent_name, specs)) = &instruction.reverse {
+                            if let Err(e) = control.reverse_bind(agent_name, specs).await {
+                                if matches!(e, ClientError::ReverseBindFailed(_) | ClientError::MissingCapability(_)) {
+                                    return Err(e);
+                                }
+                                warn!("{}",e);
+                            }
+                        }
 
                     }
                 }
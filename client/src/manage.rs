@@ -20,16 +20,17 @@ use narrowlink_network::{
     ws::WsConnection,
 };
 use narrowlink_types::{
+    capability::{Capabilities, Capability},
     client::EventInBound as ClientEventInBound,
     client::EventOutBound as ClientEventOutBound,
     client::EventRequest as ClientEventRequest,
-    client::{Peer2PeerInstruction, Peer2PeerRequest},
+    client::{Peer2PeerInstruction, Peer2PeerRequest, ReverseBindRequest},
     generic::AgentInfo,
     GetResponse, ServiceType,
 };
 
 use crate::{
-    args::{ArgCommands, ListArgs},
+    args::{ArgCommands, ForwardSpec, ListArgs},
     config::{self, Config},
     error::ClientError,
     transport::{DirectTunnelStatus, TransportInstruction},
@@ -39,6 +40,7 @@ use crate::{
 pub enum ControlMsg {
     ConnectionError(Uuid, String),
     Peer2Peer(Peer2PeerInstruction),
+    ReverseConnection(Uuid, Uuid), // bind id, connection waiting on the agent
     Shutdown(ClientError),
 }
 
@@ -58,6 +60,8 @@ pub struct ControlFactory {
     system_status_receiver: tokio::sync::mpsc::UnboundedReceiver<ControlStatus>,
     system_status_sender: tokio::sync::mpsc::UnboundedSender<ControlStatus>,
     is_direct_only: bool,
+    reverse: HashMap<Uuid, ForwardSpec>, // bind id -> (agent bind, local endpoint)
+    capabilities: Option<Capabilities>,  // negotiated, None with gateways predating the exchange
 }
 
 #[derive(Clone)]
@@ -100,6 +104,8 @@ impl ControlFactory {
             system_status_receiver,
             system_status_sender,
             is_direct_only,
+            reverse: HashMap::new(),
+            capabilities: None,
         })
     }
     pub fn get_status_sender(&self) -> tokio::sync::mpsc::UnboundedSender<ControlStatus> {
@@ -111,14 +117,16 @@ impl ControlFactory {
             info!("Connecting to gateway: {}", self.gateway);
         }
 
-        let headers = if let Some(acl) = self.acl.as_ref() {
-            HashMap::from([
-                ("NL-ACL", acl.to_string()),
-                ("NL-TOKEN", self.token.to_string()),
-            ])
-        } else {
-            HashMap::from([("NL-TOKEN", self.token.to_string())])
-        };
+        let mut headers = HashMap::from([
+            ("NL-TOKEN", self.token.to_string()),
+            (
+                "NL-CAPABILITIES",
+                Capabilities::new(&[Capability::ReverseForward]).to_string(),
+            ),
+        ]);
+        if let Some(acl) = self.acl.as_ref() {
+            headers.insert("NL-ACL", acl.to_string());
+        }
         if let Some(c) = self.control.take() {
             c.task.abort();
         }
@@ -161,6 +169,16 @@ impl ControlFactory {
             .get_header("NL-SESSION")
             .ok_or(ClientError::UnableToConnect)?
             .to_string();
+        self.capabilities = connection
+            .get_header("NL-CAPABILITIES")
+            .and_then(|c| c.parse::<Capabilities>().ok());
+        if let Some(capabilities) = &self.capabilities {
+            debug!(
+                "Gateway version {}, negotiated capabilities: {}",
+                connection.get_header("NL-VERSION").unwrap_or("unknown"),
+                capabilities
+            );
+        }
 
         let local_addr = connection.local_addr();
         let address = connection.peer_addr();
@@ -189,6 +207,14 @@ impl ControlFactory {
                             debug!("Peer2Peer: {:?}", p2p);
                             let _ = msg_sender.send(ControlMsg::Peer2Peer(p2p));
                         }
+                        Ok(narrowlink_types::client::EventInBound::ReverseConnection(
+                            bind_id,
+                            connection_id,
+                        )) => {
+                            debug!("Reverse connection: {}:{}", bind_id, connection_id);
+                            let _ = msg_sender
+                                .send(ControlMsg::ReverseConnection(bind_id, connection_id));
+                        }
                         Ok(_) => {
                             debug!("Unhandled message: {:?}", msg);
                         }
@@ -240,6 +266,49 @@ impl ControlFactory {
             session_id,
         })
     }
+    // registers the reverse forwards on the agent, the gateway drops them with the control connection
+    pub async fn reverse_bind(
+        &mut self,
+        agent_name: &str,
+        specs: &[ForwardSpec],
+    ) -> Result<(), ClientError> {
+        let Some(control) = self.control.as_ref() else {
+            return Err(ClientError::ControlChannelNotConnected);
+        };
+        if self
+            .capabilities
+            .as_ref()
+            .is_some_and(|c| !c.contains(Capability::ReverseForward))
+        {
+            return Err(ClientError::MissingCapability(Capability::ReverseForward));
+        }
+        self.reverse.clear();
+        for (bind, local) in specs {
+            let id = Uuid::new_v4();
+            let response = control
+                .request
+                .request(ClientEventOutBound::Request(
+                    0,
+                    ClientEventRequest::ReverseBind(ReverseBindRequest {
+                        agent_name: agent_name.to_owned(),
+                        id,
+                        host: bind.ip().to_string(),
+                        port: bind.port(),
+                    }),
+                ))
+                .await?
+                .response();
+            if !matches!(response, Some(narrowlink_types::client::EventResponse::Ok)) {
+                return Err(ClientError::ReverseBindFailed(*bind));
+            }
+            info!("Listen on agent: tcp://{} -> {}:{}", bind, local.0, local.1);
+            self.reverse.insert(id, (*bind, local.clone()));
+        }
+        Ok(())
+    }
+    pub fn reverse_target(&self, bind_id: Uuid) -> Option<ForwardSpec> {
+        self.reverse.get(&bind_id).cloned()
+    }
     pub fn add_connection(
         &mut self,
         task: tokio::task::JoinHandle<
@@ -400,6 +469,7 @@ pub struct Instruction {
     pub tunnel: TunnelInstruction,
     pub transport: TransportInstruction,
     pub manage: ManageInstruction,
+    pub reverse: Option<(String, Vec<ForwardSpec>)>, // agent name, (agent bind, local endpoint)
 }
 
 impl Instruction {
@@ -430,7 +500,14 @@ impl From<&ArgCommands> for Instruction {
     fn from(cmd: &ArgCommands) -> Self {
         match cmd {
             ArgCommands::Forward(a) => Self {
-                tunnel: TunnelInstruction::Forward(a.udp, a.local_addr, a.remote_addr.clone()),
+                tunnel: TunnelInstruction::Forward(
+                    a.udp,
+                    Some((a.local_addr, a.remote_addr.clone()))
+                        .filter(|(_, remote)| !remote.0.is_empty())
+                        .into_iter()
+                        .chain(a.specs.iter().cloned())
+                        .collect(),
+                ),
                 transport: TransportInstruction::determine(
                     a.direct,
                     a.relay,
@@ -443,14 +520,21 @@ impl From<&ArgCommands> for Instruction {
                 } else {
                     ManageInstruction::AgentCheck(a.agent_name.clone())
                 },
+                reverse: Some((a.agent_name.clone(), a.reverse_specs.clone()))
+                    .filter(|(_, specs)| !specs.is_empty()),
             },
             ArgCommands::List(ListArgs { verbose }) => Self {
                 tunnel: TunnelInstruction::None,
                 transport: TransportInstruction::None,
                 manage: ManageInstruction::AgentList(*verbose),
+                reverse: None,
             },
             ArgCommands::Proxy(a) => Self {
-                tunnel: TunnelInstruction::Proxy(a.local_addr, a.map_addr.to_owned()),
+                tunnel: TunnelInstruction::Proxy(
+                    a.local_addr,
+                    a.map_addr.to_owned(),
+                    a.auth.to_owned(),
+                ),
                 transport: TransportInstruction::determine(
                     a.direct,
                     a.relay,
@@ -463,6 +547,7 @@ impl From<&ArgCommands> for Instruction {
                 } else {
                     ManageInstruction::AgentCheck(a.agent_name.clone())
                 },
+                reverse: None,
             },
             ArgCommands::Connect(a) => Self {
                 tunnel: TunnelInstruction::Connect(a.udp, a.remote_addr.clone()),
@@ -478,6 +563,7 @@ impl From<&ArgCommands> for Instruction {
                 } else {
                     ManageInstruction::AgentCheck(a.agent_name.clone())
                 },
+                reverse: None,
             },
             #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
             ArgCommands::Tun(a) => Self {
@@ -494,6 +580,7 @@ impl From<&ArgCommands> for Instruction {
                 } else {
                     ManageInstruction::AgentCheck(a.agent_name.clone())
                 },
+                reverse: None,
             },
         }
     }
@@ -1,12 +1,12 @@
 use hmac::Mac;
 use narrowlink_network::{
     async_forward, error::NetworkError, p2p::QuicStream, ws::WsConnectionBinary, AsyncSocket,
-    AsyncSocketCrypt,
+    AsyncSocketCrypt, EphemeralKey, IntegrityMonitor,
 };
 use narrowlink_types::{
     client::DataOutBound as ClientDataOutBound,
     client::Peer2PeerInstruction,
-    generic::{self, Connect},
+    generic::{self, decode_pre_shared_key, Connect, KeyDerivation},
 };
 use std::{
     collections::HashMap,
@@ -20,7 +20,7 @@ use tracing::{error, info, trace};
 
 use sha3::{Digest, Sha3_256};
 
-use crate::{error::ClientError, manage::RelayInfo};
+use crate::{config::Config, error::ClientError, manage::RelayInfo};
 
 pub enum DirectTunnelStatus {
     Uninitialized = 0x0,
@@ -60,12 +60,18 @@ pub struct TransportFactory {
     direct: Arc<RwLock<Option<QuicStream>>>,
     notify_direct: Arc<RwLock<Option<Arc<Notify>>>>,
     relay: Option<RelayInfo>,
+    kdf: KeyDerivation,
+    ephemeral: bool,      // offers an X25519 exchange with every encrypted connection
+    pre_shared_key: bool, // the key is used as it is instead of derived
 }
 
 impl TransportFactory {
-    pub fn new(i: TransportInstruction) -> Self {
+    pub fn new(i: TransportInstruction, conf: &Config) -> Self {
         Self {
             i,
+            kdf: conf.kdf,
+            ephemeral: conf.ephemeral,
+            pre_shared_key: conf.pre_shared_key,
             direct: Arc::new(RwLock::new(None)),
             notify_direct: Arc::new(RwLock::new(Some(Arc::new(Notify::new())))),
             relay: None,
@@ -152,37 +158,30 @@ impl TransportFactory {
                 .replace(Arc::new(Notify::new()));
             return Err(ClientError::UnableToOpenQuicBiStream);
         };
-        let e2ee_params: Option<([u8; 32], [u8; 24])> = match e2ee {
+        let e2ee_params: Option<([u8; 32], [u8; 24], Option<EphemeralKey>)> = match e2ee {
             Some(ck) => {
                 trace!("Cryptography required");
                 let n = rand::random::<[u8; 24]>();
                 connect.set_cryptography_nonce(n);
-                let k = Sha3_256::digest(
-                    ck.as_bytes()
-                        .iter()
-                        .zip(n.iter().cycle())
-                        .map(|(n, s)| n ^ s)
-                        .collect::<Vec<u8>>(),
-                );
+                let ephemeral = self.ephemeral.then(EphemeralKey::generate).transpose()?;
+                connect.exchange = ephemeral.as_ref().map(EphemeralKey::exchange);
+                let k = if self.pre_shared_key {
+                    decode_pre_shared_key(ck)?
+                } else {
+                    connect.set_key_derivation(self.kdf);
+                    let Some(k) = self.kdf.derive(ck, &n) else {
+                        error!("Unable to derive the key"); // unreachable
+                        return Err(ClientError::Unexpected(0));
+                    };
+                    k
+                };
                 let Ok(mut mac) = generic::HmacSha256::new_from_slice(&k) else {
                     error!("Unable to create hmac"); // unreachable
                     return Err(ClientError::Unexpected(0));
                 };
-                mac.update(
-                    &[
-                        format!(
-                            "{}:{}:{}",
-                            &connect.host,
-                            &connect.port,
-                            connect.protocol.clone() as u32
-                        )
-                        .as_bytes(),
-                        &n,
-                    ]
-                    .concat(),
-                );
+                mac.update(&connect.signed_data(&n));
                 connect.set_sign(mac.finalize().into_bytes().into());
-                Some((k.into(), n))
+                Some((k, n, ephemeral))
             }
             None => None,
         };
@@ -227,9 +226,19 @@ impl TransportFactory {
             }
         }
 
-        if let Some((key, nonce)) = e2ee_params {
+        if let Some((mut key, nonce, ephemeral)) = e2ee_params {
+            if let Some(ephemeral) = ephemeral {
+                key = ephemeral.accept(&mut quic_socket, &key).await?;
+            }
             Ok((
-                Box::new(AsyncSocketCrypt::new(key, nonce, Box::new(quic_socket)).await),
+                Box::new(
+                    AsyncSocketCrypt::new(key, nonce, Box::new(quic_socket))
+                        .await
+                        .with_monitor(IntegrityMonitor::new(format!(
+                            "{}:{} over the direct channel",
+                            connect.host, connect.port
+                        ))),
+                ),
                 None,
             ))
         } else {
@@ -249,37 +258,30 @@ impl TransportFactory {
             return Err(ClientError::RelayChannelNotAvailable);
         };
 
-        let e2ee_params: Option<([u8; 32], [u8; 24])> = match e2ee {
+        let e2ee_params: Option<([u8; 32], [u8; 24], Option<EphemeralKey>)> = match e2ee {
             Some(ck) => {
                 trace!("Cryptography required");
                 let n = rand::random::<[u8; 24]>();
                 connect.set_cryptography_nonce(n);
-                let k = Sha3_256::digest(
-                    ck.as_bytes()
-                        .iter()
-                        .zip(n.iter().cycle())
-                        .map(|(n, s)| n ^ s)
-                        .collect::<Vec<u8>>(),
-                );
+                let ephemeral = self.ephemeral.then(EphemeralKey::generate).transpose()?;
+                connect.exchange = ephemeral.as_ref().map(EphemeralKey::exchange);
+                let k = if self.pre_shared_key {
+                    decode_pre_shared_key(ck)?
+                } else {
+                    connect.set_key_derivation(self.kdf);
+                    let Some(k) = self.kdf.derive(ck, &n) else {
+                        error!("Unable to derive the key"); // unreachable
+                        return Err(ClientError::Unexpected(0));
+                    };
+                    k
+                };
                 let Ok(mut mac) = generic::HmacSha256::new_from_slice(&k) else {
                     error!("Unable to create hmac"); // unreachable
                     return Err(ClientError::Unexpected(0));
                 };
-                mac.update(
-                    &[
-                        format!(
-                            "{}:{}:{}",
-                            &connect.host,
-                            &connect.port,
-                            connect.protocol.clone() as u32
-                        )
-                        .as_bytes(),
-                        &n,
-                    ]
-                    .concat(),
-                );
+                mac.update(&connect.signed_data(&n));
                 connect.set_sign(mac.finalize().into_bytes().into());
-                Some((k.into(), n))
+                Some((k, n, ephemeral))
             }
             None => None,
         };
@@ -289,7 +291,7 @@ impl TransportFactory {
             connect.clone(),
         ))
         .map_err(|_| ClientError::Unexpected(0))?;
-        let connection = match WsConnectionBinary::new(
+        let mut connection = match WsConnectionBinary::new(
             &relay.gateway,
             HashMap::from([
                 ("NL-TOKEN", relay.token.to_owned()),
@@ -317,9 +319,19 @@ impl TransportFactory {
             .get_header("NL-CONNECTION")
             .map(|c| c.to_string());
 
-        if let Some((key, nonce)) = e2ee_params {
+        if let Some((mut key, nonce, ephemeral)) = e2ee_params {
+            if let Some(ephemeral) = ephemeral {
+                key = ephemeral.accept(&mut connection, &key).await?;
+            }
             Ok((
-                Box::new(AsyncSocketCrypt::new(key, nonce, Box::new(connection)).await),
+                Box::new(
+                    AsyncSocketCrypt::new(key, nonce, Box::new(connection))
+                        .await
+                        .with_monitor(IntegrityMonitor::new(format!(
+                            "{}:{} on agent {}",
+                            connect.host, connect.port, agent_name
+                        ))),
+                ),
                 connection_id,
             ))
         } else {
@@ -332,6 +344,13 @@ impl TransportFactory {
         connect: Connect,
     ) -> Result<Option<String>, ClientError> {
         let (connection, connection_id): (Box<dyn AsyncSocket>, Option<String>) = match &self.i {
+            // the direct channel has no room for the reverse connection id
+            TransportInstruction::Direct(e2ee, agent_name)
+            | TransportInstruction::Mixed(e2ee, agent_name, _)
+                if connect.reverse.is_some() =>
+            {
+                self.connect_relay(agent_name, connect, e2ee).await?
+            }
             TransportInstruction::Direct(e2ee, agent_name) => {
                 self.connect_direct(agent_name, connect, true, e2ee).await?
             }
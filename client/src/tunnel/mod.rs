@@ -1,4 +1,5 @@
 mod input_stream;
+mod socks_auth;
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
 mod tun;
 use either::Either;
@@ -13,7 +14,7 @@ use narrowlink_network::AsyncSocket;
 use narrowlink_types::generic::{self};
 use proxy_stream::ProxyStream;
 
-use tracing::info;
+use tracing::{info, warn};
 use udp_stream::UdpListener;
 
 use std::{
@@ -31,9 +32,13 @@ use input_stream::InputStream;
 use tun::{RouteCommand, TunListener};
 
 pub enum TunnelInstruction {
-    Connect(bool, (String, u16)),                // udp, endpoint
-    Forward(bool, SocketAddr, (String, u16)),    // udp, local, endpoint
-    Proxy(SocketAddr, Option<(String, String)>), // endpoint, map
+    Connect(bool, (String, u16)),                    // udp, endpoint
+    Forward(bool, Vec<(SocketAddr, (String, u16))>), // udp, (local, endpoint)
+    Proxy(
+        SocketAddr,
+        Option<(String, String)>,
+        Option<(String, String)>,
+    ), // endpoint, map, username and password
     #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
     Tun(bool, IpAddr, Option<(IpAddr, IpAddr)>), // default_gateway, addr, map
     None,
@@ -48,8 +53,12 @@ pub struct TunnelFactory {
 
 pub enum TunnelListener {
     Connect(Once<Ready<InputStream>>, bool, (String, u16)),
-    Forward(Either<TcpListener, UdpListener>, (String, u16)),
-    Proxy(TcpListener, Option<(String, String)>),
+    Forward(Vec<(Either<TcpListener, UdpListener>, (String, u16))>),
+    Proxy(
+        TcpListener,
+        Option<(String, String)>,
+        Option<(String, String)>,
+    ),
     #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
     Tun(TunListener, Option<(IpAddr, IpAddr)>),
 }
@@ -75,29 +84,45 @@ impl TunnelFactory {
                     (dst_addr.clone(), *dst_port),
                 ));
             }
-            TunnelInstruction::Forward(udp, local, endpoint) => {
-                let listener = if *udp {
-                    let listener = UdpListener::bind(*local).await?;
-                    if let Ok(addr) = listener.local_addr() {
-                        info!("Listen on: udp://{}:{}", addr.ip(), addr.port());
-                    }
+            TunnelInstruction::Forward(udp, forwards) => {
+                let mut listeners = Vec::new();
+                for (local, endpoint) in forwards {
+                    let listener = if *udp {
+                        let listener = UdpListener::bind(*local).await?;
+                        if let Ok(addr) = listener.local_addr() {
+                            info!(
+                                "Listen on: udp://{}:{} -> {}:{}",
+                                addr.ip(),
+                                addr.port(),
+                                endpoint.0,
+                                endpoint.1
+                            );
+                        }
 
-                    either::Right(listener)
-                } else {
-                    let listener = TcpListener::bind(*local).await?;
-                    if let Ok(addr) = listener.local_addr() {
-                        info!("Listen on: tcp://{}:{}", addr.ip(), addr.port());
-                    }
-                    either::Left(listener)
-                };
-                self.listener = Some(TunnelListener::Forward(listener, endpoint.clone()));
+                        either::Right(listener)
+                    } else {
+                        let listener = TcpListener::bind(*local).await?;
+                        if let Ok(addr) = listener.local_addr() {
+                            info!(
+                                "Listen on: tcp://{}:{} -> {}:{}",
+                                addr.ip(),
+                                addr.port(),
+                                endpoint.0,
+                                endpoint.1
+                            );
+                        }
+                        either::Left(listener)
+                    };
+                    listeners.push((listener, endpoint.clone()));
+                }
+                self.listener = Some(TunnelListener::Forward(listeners));
             }
-            TunnelInstruction::Proxy(endpoint, map) => {
+            TunnelInstruction::Proxy(endpoint, map, auth) => {
                 let listener = TcpListener::bind(endpoint).await?;
                 if let Ok(addr) = listener.local_addr() {
                     info!("Listen on: socks5://{}:{}", addr.ip(), addr.port());
                 }
-                self.listener = Some(TunnelListener::Proxy(listener, map.clone()));
+                self.listener = Some(TunnelListener::Proxy(listener, map.clone(), auth.clone()));
             }
             #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
             TunnelInstruction::Tun(default_gateway, addr, map) => {
@@ -148,23 +173,52 @@ impl TunnelFactory {
                         },
                         cryptography: None,
                         sign: None,
+                        kdf: None,
+                        exchange: None,
+                        reverse: None,
+                        source: None,
                     },
                 ))
             }
-            Some(TunnelListener::Proxy(l, map)) => {
-                let interrupted_stream = match ProxyStream::new(proxy_stream::ProxyType::SOCKS5)
-                    .accept(l.accept().await?.0)
-                    .await
-                {
-                    Ok(s) => s,
-                    Err(_e) => return Err(ClientError::InvalidSocksRequest),
-                };
-                let mut addr: (String, u16) = interrupted_stream.addr().into();
-                let protocol =
-                    if interrupted_stream.command() == proxy_stream::Command::UdpAssociate {
-                        generic::Protocol::UDP
-                    } else {
-                        generic::Protocol::TCP
+            Some(TunnelListener::Proxy(l, map, auth)) => {
+                let (socket, peer) = l.accept().await?;
+                let (socket, mut addr, protocol): (Box<dyn AsyncSocket>, (String, u16), _) =
+                    match auth {
+                        Some(auth) => match socks_auth::accept(socket, auth).await {
+                            Ok((socket, addr)) => (Box::new(socket), addr, generic::Protocol::TCP),
+                            Err(e) => {
+                                warn!("SOCKS5 client {} rejected: {}", peer, e);
+                                return Err(e);
+                            }
+                        },
+                        None => {
+                            let interrupted_stream =
+                                match ProxyStream::new(proxy_stream::ProxyType::SOCKS5)
+                                    .accept(socket)
+                                    .await
+                                {
+                                    Ok(s) => s,
+                                    Err(_e) => return Err(ClientError::InvalidSocksRequest),
+                                };
+                            let addr: (String, u16) = interrupted_stream.addr().into();
+                            let protocol = if interrupted_stream.command()
+                                == proxy_stream::Command::UdpAssociate
+                            {
+                                generic::Protocol::UDP
+                            } else {
+                                generic::Protocol::TCP
+                            };
+                            (
+                                Box::new(
+                                    interrupted_stream
+                                        .connect()
+                                        .await
+                                        .map_err(|_| ClientError::InvalidSocksRequest)?,
+                                ),
+                                addr,
+                                protocol,
+                            )
+                        }
                     };
                 if let Some(m) = map {
                     if addr.0 == m.0 {
@@ -172,33 +226,43 @@ impl TunnelFactory {
                     }
                 }
                 Ok((
-                    Box::new(
-                        interrupted_stream
-                            .connect()
-                            .await
-                            .map_err(|_| ClientError::InvalidSocksRequest)?,
-                    ),
+                    socket,
                     generic::Connect {
                         host: addr.0,
                         port: addr.1,
                         protocol,
                         cryptography: None,
                         sign: None,
+                        kdf: None,
+                        exchange: None,
+                        reverse: None,
+                        source: None,
                     },
                 ))
             }
-            Some(TunnelListener::Forward(listener, end_point)) => {
-                let (socket, protocol): (Box<dyn AsyncSocket>, generic::Protocol) = match listener {
-                    either::Left(tcp_listener) => {
-                        let (socket, _local_addr) = tcp_listener.accept().await?;
-                        (Box::new(socket), generic::Protocol::TCP)
-                    }
-                    either::Right(udp_listener) => {
-                        let (socket, _local_addr) = udp_listener.accept().await?;
-                        (Box::new(socket), generic::Protocol::UDP)
-                    }
-                };
-                let addr: (String, u16) = end_point.clone();
+            Some(TunnelListener::Forward(listeners)) => {
+                if listeners.is_empty() {
+                    pending::<()>().await; // only reverse forwards, nothing listens locally
+                }
+                // the first connection accepted on any of the forwards
+                let accepts = listeners.iter().map(|(listener, end_point)| {
+                    Box::pin(async move {
+                        let (socket, protocol): (Box<dyn AsyncSocket>, generic::Protocol) =
+                            match listener {
+                                either::Left(tcp_listener) => {
+                                    let (socket, _local_addr) = tcp_listener.accept().await?;
+                                    (Box::new(socket), generic::Protocol::TCP)
+                                }
+                                either::Right(udp_listener) => {
+                                    let (socket, _local_addr) = udp_listener.accept().await?;
+                                    (Box::new(socket), generic::Protocol::UDP)
+                                }
+                            };
+                        Ok::<_, ClientError>((socket, protocol, end_point.clone()))
+                    })
+                });
+                let ((socket, protocol, addr), _) =
+                    futures_util::future::select_ok(accepts).await?;
 
                 Ok((
                     Box::new(socket),
@@ -208,6 +272,10 @@ impl TunnelFactory {
                         protocol,
                         cryptography: None,
                         sign: None,
+                        kdf: None,
+                        exchange: None,
+                        reverse: None,
+                        source: None,
                     },
                 ))
             }
@@ -237,6 +305,10 @@ impl TunnelFactory {
                         },
                         cryptography: None,
                         sign: None,
+                        kdf: None,
+                        exchange: None,
+                        reverse: None,
+                        source: None,
                     },
                 ))
             }
@@ -0,0 +1,91 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::ClientError;
+
+const VERSION: u8 = 0x05;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const AUTH_VERSION: u8 = 0x01; // RFC 1929
+const CONNECT: u8 = 0x01;
+const SUCCEEDED: u8 = 0x00;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+const IPV4: u8 = 0x01;
+const DOMAIN: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+// proxy_stream only negotiates no authentication, so with credentials the whole SOCKS5
+// handshake (RFC 1928) runs here with the username/password method of RFC 1929, only
+// CONNECT is served, as proxy_stream does
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    credentials: &(String, String),
+) -> Result<(S, (String, u16)), ClientError> {
+    let mut header = [0; 2];
+    socket.read_exact(&mut header).await?;
+    let mut methods = vec![0; header[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if header[0] != VERSION || !methods.contains(&USERNAME_PASSWORD) {
+        socket.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(ClientError::SocksAuthFailed);
+    }
+    socket.write_all(&[VERSION, USERNAME_PASSWORD]).await?;
+
+    let version = socket.read_u8().await?;
+    let mut username = vec![0; socket.read_u8().await? as usize];
+    socket.read_exact(&mut username).await?;
+    let mut password = vec![0; socket.read_u8().await? as usize];
+    socket.read_exact(&mut password).await?;
+    if version != AUTH_VERSION
+        || username != credentials.0.as_bytes()
+        || password != credentials.1.as_bytes()
+    {
+        socket.write_all(&[AUTH_VERSION, 0x01]).await?;
+        return Err(ClientError::SocksAuthFailed);
+    }
+    socket.write_all(&[AUTH_VERSION, 0x00]).await?;
+
+    let mut request = [0; 4]; // version, command, reserved and address type
+    socket.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(ClientError::InvalidSocksRequest);
+    }
+    let host = match request[3] {
+        IPV4 => {
+            let mut octets = [0; 4];
+            socket.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        IPV6 => {
+            let mut octets = [0; 16];
+            socket.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        DOMAIN => {
+            let mut domain = vec![0; socket.read_u8().await? as usize];
+            socket.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|_| ClientError::InvalidSocksRequest)?
+        }
+        _ => {
+            reply(&mut socket, ADDRESS_NOT_SUPPORTED).await?;
+            return Err(ClientError::InvalidSocksRequest);
+        }
+    };
+    let port = socket.read_u16().await?;
+    if request[1] != CONNECT {
+        reply(&mut socket, COMMAND_NOT_SUPPORTED).await?;
+        return Err(ClientError::InvalidSocksRequest);
+    }
+    reply(&mut socket, SUCCEEDED).await?;
+    Ok((socket, (host, port)))
+}
+
+// the bound address is left unspecified, clients of a CONNECT don't use it
+async fn reply<S: AsyncWrite + Unpin>(socket: &mut S, status: u8) -> Result<(), ClientError> {
+    socket
+        .write_all(&[VERSION, status, 0x00, IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
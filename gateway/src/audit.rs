@@ -0,0 +1,186 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use futures_util::{Sink, Stream};
+use narrowlink_network::{error::NetworkError, UniversalStream};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::error::GatewayError;
+
+// events of this target only go to the audit log, never to the operational logs
+pub const TARGET: &str = "audit";
+
+static AUDIT_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+pub fn open(path: &Path) -> Result<(), GatewayError> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = AUDIT_LOG.set(Mutex::new(file));
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    AUDIT_LOG.get().is_some()
+}
+
+pub struct Writer;
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match AUDIT_LOG.get().map(|file| file.lock()) {
+            Some(Ok(mut file)) => file.write_all(buf).map(|_| buf.len()),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match AUDIT_LOG.get().map(|file| file.lock()) {
+            Some(Ok(mut file)) => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn writer() -> Writer {
+    Writer
+}
+
+// a request refused by the policies of the client token or the access list of the agent
+pub fn denied(uid: Uuid, client: &str, peer: IpAddr, agent: &str, destination: &str, reason: &str) {
+    info!(
+        target: TARGET,
+        uid = %uid,
+        client,
+        peer = %peer,
+        agent,
+        destination,
+        reason,
+        "Access denied"
+    );
+}
+
+#[derive(Default)]
+pub struct Traffic {
+    upstream: AtomicU64,   // bytes sent to the agent
+    downstream: AtomicU64, // bytes received from the agent
+}
+
+// one record per tunnel, written when it is dropped
+pub struct Tunnel {
+    connection: Uuid,
+    uid: Uuid,
+    client: Option<String>, // name of the client token, None for visitors of published services
+    peer: Option<SocketAddr>,
+    agent: String,
+    destination: String,
+    started: Instant,
+    traffic: Arc<Traffic>,
+    reason: Option<String>,
+}
+
+impl Tunnel {
+    pub fn new(
+        connection: Uuid,
+        uid: Uuid,
+        client: Option<String>,
+        peer: Option<SocketAddr>,
+        agent: String,
+        destination: String,
+    ) -> Self {
+        Self {
+            connection,
+            uid,
+            client,
+            peer,
+            agent,
+            destination,
+            started: Instant::now(),
+            traffic: Arc::default(),
+            reason: None,
+        }
+    }
+    pub fn set_reason(&mut self, reason: String) {
+        self.reason.get_or_insert(reason);
+    }
+    pub fn count(
+        &self,
+        stream: Box<dyn UniversalStream<Vec<u8>, NetworkError>>,
+    ) -> Box<dyn UniversalStream<Vec<u8>, NetworkError>> {
+        Box::new(Counted {
+            stream,
+            traffic: self.traffic.clone(),
+        })
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        info!(
+            target: TARGET,
+            connection = %self.connection,
+            uid = %self.uid,
+            client = self.client.as_deref().unwrap_or_default(),
+            peer = self.peer.map(|peer| peer.to_string()).unwrap_or_default(),
+            agent = self.agent.as_str(),
+            destination = self.destination.as_str(),
+            bytes_up = self.traffic.upstream.load(Ordering::Relaxed),
+            bytes_down = self.traffic.downstream.load(Ordering::Relaxed),
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            reason = self.reason.as_deref().unwrap_or("Closed before the agent connected"),
+            "Tunnel closed"
+        );
+    }
+}
+
+struct Counted {
+    stream: Box<dyn UniversalStream<Vec<u8>, NetworkError>>,
+    traffic: Arc<Traffic>,
+}
+
+impl Stream for Counted {
+    type Item = Result<Vec<u8>, NetworkError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = Pin::new(&mut self.stream).poll_next(cx);
+        if let Poll::Ready(Some(Ok(data))) = &item {
+            self.traffic
+                .downstream
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        item
+    }
+}
+
+impl Sink<Vec<u8>> for Counted {
+    type Error = NetworkError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.stream).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.traffic
+            .upstream
+            .fetch_add(item.len() as u64, Ordering::Relaxed);
+        Pin::new(&mut self.stream).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
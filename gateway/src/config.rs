@@ -3,7 +3,12 @@ use std::{env, fmt::Debug, fs, io::Read, net::SocketAddr, path::PathBuf};
 use tracing::{debug, instrument, trace};
 use validator::{Validate, ValidationError};
 
-use crate::{error::GatewayError, service::certificate::ACMEChallengeType};
+use crate::{
+    error::GatewayError,
+    service::certificate::{
+        cipher_suite, manager::AgentSelection, ACMEChallengeType, KeyType, TlsVersion,
+    },
+};
 
 #[derive(Deserialize, Validate)]
 #[validate(schema(function = "Self::verify"))]
@@ -12,6 +17,18 @@ pub struct Config {
     #[validate(length(min = 8))]
     pub secret: Vec<u8>,
     pub services: Vec<Service>,
+    #[serde(default)]
+    pub connection_limit: ConnectionLimit,
+    #[validate(range(min = 1))]
+    pub publish_limit: Option<usize>, // published services per uid and agent name, unlimited if not set
+    #[serde(default)]
+    pub log_format: LogFormat,
+    pub audit_log: Option<PathBuf>, // JSON line per tunnel, kept out of the operational logs
+    #[serde(default = "_default_drain_timeout")]
+    pub drain_timeout: u64, // seconds
+    #[serde(default = "_default_agent_token_ttl")]
+    #[validate(range(min = 300))]
+    pub agent_token_ttl: u64, // seconds agent tokens issued for refresh tokens are valid
 }
 
 impl Debug for Config {
@@ -20,6 +37,12 @@ impl Debug for Config {
             .field("name", &self.name)
             .field("secret", &"XXXX")
             .field("services", &self.services)
+            .field("connection_limit", &self.connection_limit)
+            .field("publish_limit", &self.publish_limit)
+            .field("log_format", &self.log_format)
+            .field("audit_log", &self.audit_log)
+            .field("drain_timeout", &self.drain_timeout)
+            .field("agent_token_ttl", &self.agent_token_ttl)
             .finish()
     }
 }
@@ -37,11 +60,54 @@ impl Config {
                     if s.listen_addr.port() == 80 {
                         http_port_80 = true;
                     }
+                    Self::verify_idle_timeout(s.idle_timeout)?;
                 }
                 Service::Wss(s) => {
                     debug!("checking wss service: {:?}", s);
+                    Self::verify_idle_timeout(s.idle_timeout)?;
+                    if !s
+                        .tls_config
+                        .tls_policy()
+                        .cipher_suites
+                        .iter()
+                        .all(|name| cipher_suite(name).is_some())
+                    {
+                        return Err(ValidationError::new(
+                            "Unknown TLS cipher suite, use the IANA name of a suite rustls supports",
+                        ));
+                    }
                     if let TlsConfig::Acme(acme) = &s.tls_config {
                         debug!("checking acme config: {:?}", acme);
+                        if acme.validate().is_err() {
+                            return Err(ValidationError::new("Invalid ACME configuration"));
+                        }
+                        if acme.staging
+                            && acme.directory_url != _default_acme_directory_url()
+                            && acme.directory_url != LETS_ENCRYPT_STAGING_DIRECTORY_URL
+                        {
+                            return Err(ValidationError::new(
+                                "ACME staging selects the Let's Encrypt staging directory, remove directory_url",
+                            ));
+                        }
+                        if acme.eab_kid.is_some() != acme.eab_hmac_key.is_some()
+                            || acme
+                                .directories
+                                .iter()
+                                .any(|d| d.eab_kid.is_some() != d.eab_hmac_key.is_some())
+                        {
+                            return Err(ValidationError::new(
+                                "ACME External Account Binding requires both eab_kid and eab_hmac_key",
+                            ));
+                        }
+                        if acme
+                            .directories
+                            .iter()
+                            .any(|d| !validator::validate_url(&d.url) || d.url == acme.directory())
+                        {
+                            return Err(ValidationError::new(
+                                "Invalid ACME directory, its url must be valid and differ from directory_url",
+                            ));
+                        }
                         match acme.challenge_type {
                             ACMEChallengeType::Http01 => {
                                 is_http01_enabled = true;
@@ -53,9 +119,55 @@ impl Config {
                                     ));
                                 }
                             }
+                            ACMEChallengeType::Dns01 => {}
                         }
                     }
                 }
+                Service::Quic(s) => {
+                    debug!("checking quic service: {:?}", s);
+                    if self.tls_config().is_none() {
+                        return Err(ValidationError::new(
+                            "The QUIC service requires a WSS service for its certificates",
+                        ));
+                    }
+                }
+                Service::Tls(s) => {
+                    debug!("checking tls service: {:?}", s);
+                    Self::verify_idle_timeout(s.idle_timeout)?;
+                    if self.tls_config().is_none() {
+                        return Err(ValidationError::new(
+                            "The TLS service requires a WSS service for its certificates",
+                        ));
+                    }
+                }
+                Service::Udp(s) => {
+                    debug!("checking udp service: {:?}", s);
+                    if s.idle_timeout == 0 {
+                        return Err(ValidationError::new(
+                            "UDP idle timeout must be at least one second",
+                        ));
+                    }
+                    if s.max_sessions == 0 || s.max_sessions_per_prefix == 0 {
+                        return Err(ValidationError::new(
+                            "UDP session limits must allow at least one session",
+                        ));
+                    }
+                }
+                Service::Health(s) => {
+                    debug!("checking health service: {:?}", s);
+                }
+                Service::Admin(s) => {
+                    debug!("checking admin service: {:?}", s);
+                    if s.token_env.is_empty() {
+                        return Err(ValidationError::new(
+                            "The admin service requires the name of its token variable",
+                        ));
+                    }
+                }
+                #[cfg(feature = "metrics")]
+                Service::Metrics(s) => {
+                    debug!("checking metrics service: {:?}", s);
+                }
             }
         }
         if is_http01_enabled && !http_port_80 {
@@ -67,6 +179,15 @@ impl Config {
         trace!("config successfully verified");
         Ok(())
     }
+    // unset disables it, zero would close every tunnel right away
+    fn verify_idle_timeout(idle_timeout: Option<u64>) -> Result<(), ValidationError> {
+        if idle_timeout == Some(0) {
+            return Err(ValidationError::new(
+                "Idle timeout must be at least one second, remove it to disable",
+            ));
+        }
+        Ok(())
+    }
     #[instrument(name = "config::load", skip(path))]
     pub fn load(path: Option<String>) -> Result<Self, GatewayError> {
         trace!("loading config");
@@ -165,6 +286,31 @@ impl Config {
     pub fn services(&self) -> &Vec<Service> {
         &self.services
     }
+    // agents have to present a client certificate on every service once a Wss service sets one
+    pub fn client_ca(&self) -> Option<&PathBuf> {
+        self.services.iter().find_map(|service| match service {
+            Service::Wss(s) => s.client_ca.as_ref(),
+            _ => None,
+        })
+    }
+    // addresses published hosts can be bound to
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        let mut listen_addrs = Vec::new();
+        for service in &self.services {
+            match service {
+                Service::Ws(s) => listen_addrs.push(s.listen_addr),
+                Service::Wss(s) => listen_addrs.push(s.listen_addr),
+                Service::Quic(s) => listen_addrs.push(s.listen_addr),
+                Service::Tls(s) => listen_addrs.push(s.listen_addr),
+                Service::Udp(s) => listen_addrs.push(s.listen_addr),
+                Service::Health(_) => {}
+                Service::Admin(_) => {}
+                #[cfg(feature = "metrics")]
+                Service::Metrics(_) => {}
+            }
+        }
+        listen_addrs
+    }
     pub fn tls_config(&self) -> Option<TlsConfig> {
         for service in &self.services {
             if let Service::Wss(s) = service {
@@ -178,25 +324,112 @@ impl Config {
 #[derive(Debug, Deserialize)]
 pub enum Service {
     Ws(WsService),
-    Wss(WsSecureService),
+    Wss(Box<WsSecureService>),
+    Quic(QuicService),
+    Tls(TlsService),
+    Udp(UdpService),
+    Health(HealthService),
+    Admin(AdminService),
+    #[cfg(feature = "metrics")]
+    Metrics(MetricsService),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json, // one JSON object per line
+}
+
+// concurrent connections, unlimited if not set
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+pub struct ConnectionLimit {
+    pub per_agent: Option<usize>, // per uid and agent name
+    pub total: Option<usize>,     // every agent of the gateway
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct WsService {
     pub domains: Vec<String>,
     pub listen_addr: SocketAddr,
+    #[serde(default)]
+    pub http_proxy: bool, // CONNECT requests of clients are tunnelled through their agents
+    pub idle_timeout: Option<u64>, // seconds without traffic before a tunnel to a published service is closed
+}
+
+// served with the certificates of the Wss service
+#[derive(Deserialize, Debug)]
+pub struct QuicService {
+    pub domains: Vec<String>,
+    pub listen_addr: SocketAddr, // udp
+}
+
+// terminates TLS for any TCP protocol with the certificates of the Wss service, the plain stream
+// goes to the agent publishing the SNI
+#[derive(Deserialize, Debug)]
+pub struct TlsService {
+    pub domains: Vec<String>, // every published domain if empty
+    pub listen_addr: SocketAddr,
+    #[serde(default)]
+    pub alpn: Vec<String>, // protocols advertised in the handshake, none by default
+    pub idle_timeout: Option<u64>, // seconds
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UdpService {
+    pub domain: String, // publish host the datagrams are forwarded to
+    pub listen_addr: SocketAddr,
+    #[serde(default = "_default_udp_idle_timeout")]
+    pub idle_timeout: u64, // seconds
+    #[serde(default = "_default_udp_max_sessions")]
+    pub max_sessions: usize, // datagrams of new sources past it are dropped
+    #[serde(default = "_default_udp_max_sessions_per_prefix")]
+    pub max_sessions_per_prefix: usize, // per /24 of IPv4 and /64 of IPv6 sources
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HealthService {
+    pub listen_addr: SocketAddr,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminService {
+    pub listen_addr: SocketAddr,
+    pub token_env: String, // environment variable holding the bearer token, kept out of the config file
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Deserialize, Debug)]
+pub struct MetricsService {
+    pub listen_addr: SocketAddr,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct WsSecureService {
     pub domains: Vec<String>,
     pub listen_addr: SocketAddr,
+    pub alpn: Option<Vec<String>>, // replaces the advertised h2 and http/1.1
     pub tls_config: TlsConfig,
+    pub client_ca: Option<PathBuf>, // PEM bundle agent client certificates are verified against
+    #[serde(default)]
+    pub http_proxy: bool,
+    pub idle_timeout: Option<u64>,     // seconds
+    pub default_cert: Option<PathBuf>, // PEM chain and key for SNIs without a certificate or agent
+    #[serde(default)]
+    pub unknown_sni: UnknownSni,
+}
+
+// what a handshake with the default certificate is followed by, without one it is aborted
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum UnknownSni {
+    #[default]
+    Respond, // HTTP 421 Misdirected Request
+    Reject, // closed right after the handshake
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub enum TlsConfig {
-    Acme(Acme),
+    Acme(Box<Acme>),
     File(File),
 }
 
@@ -209,14 +442,221 @@ pub struct Acme {
     #[serde(default = "_default_acme_directory_url")]
     #[validate(url)]
     pub directory_url: String,
+    #[serde(default)]
+    pub staging: bool, // Let's Encrypt staging directory, its certificates are not trusted
+    #[serde(default)]
+    pub preload: bool, // serve stored certificates on start, before their agents connect
+    #[validate(range(min = 1))]
+    pub max_cached_certificates: Option<usize>, // domains kept in memory, unlimited if unset
+    #[serde(default = "_default_load_concurrency")]
+    #[validate(range(min = 1, max = 256))]
+    pub load_concurrency: usize, // certificates read from storage at once when several are loaded
+    #[serde(default)]
+    pub agent_selection: AgentSelection,
+    #[serde(default)]
+    pub session_resumption: SessionResumption,
+    #[serde(default)]
+    pub tls_policy: TlsPolicy,
+    #[serde(default = "_default_expiry_warning_days")]
+    pub expiry_warning_days: Vec<u64>, // days before expiry a failing renewal is warned about
+    #[validate(range(min = 1))]
+    pub max_connection_age: Option<u64>, // seconds, older connections are drained once their certificate renews
+    #[serde(default = "_default_renew_check_interval")]
+    #[validate(range(min = 60))]
+    pub renew_check_interval: u64, // seconds
+    #[serde(default = "_default_renew_before_days")]
+    #[validate(range(min = 1, max = 60))]
+    pub renew_before_days: u64,
+    #[serde(default = "_default_challenge_poll_tries")]
+    #[validate(range(min = 1, max = 20))]
+    pub challenge_poll_tries: u8,
+    #[serde(default = "_default_challenge_poll_interval")]
+    #[validate(range(min = 1, max = 600))]
+    pub challenge_poll_interval: u64, // seconds before the first poll, doubled after every poll
+    #[serde(default = "_default_max_concurrent_issuances")]
+    #[validate(range(min = 1, max = 50))]
+    pub max_concurrent_issuances: usize,
+    #[serde(default)]
+    pub key_type: KeyType,
+    #[serde(default)]
+    pub storage: CertificateStorage,
+    pub storage_encryption: Option<StorageEncryption>, // private keys are stored in plaintext if unset
+    pub eab_kid: Option<String>,
+    pub eab_hmac_key: Option<String>, // base64url encoded
+    #[serde(default)]
+    pub directories: Vec<AcmeDirectory>, // other directories agents may select, each with its own account
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AcmeDirectory {
+    pub url: String,
+    pub eab_kid: Option<String>,
+    pub eab_hmac_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub enum CertificateStorage {
+    File {
+        path: String,
+    },
+    Redis {
+        address: String,
+        password: Option<String>,
+        #[serde(default = "_default_redis_prefix")]
+        prefix: String,
+    },
+    Memory, // lost on exit, every restart issues again
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SessionResumption {
+    #[serde(default)]
+    pub tickets: bool, // stateless session tickets
+    #[serde(default = "_default_session_cache_size")]
+    pub cache_size: usize, // sessions kept for stateful resumption, 0 disables it
+    #[serde(default = "_default_tls13_tickets")]
+    pub tls13_tickets: usize, // sent after every full TLS 1.3 handshake
+}
+
+impl Default for SessionResumption {
+    fn default() -> Self {
+        Self {
+            tickets: false,
+            cache_size: _default_session_cache_size(),
+            tls13_tickets: _default_tls13_tickets(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsPolicy {
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    #[serde(default)]
+    pub cipher_suites: Vec<String>, // IANA names, every suite of the allowed versions if empty
+    #[serde(default = "_default_alpn")]
+    pub alpn: Vec<String>,
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        Self {
+            min_version: TlsVersion::default(),
+            cipher_suites: Vec::new(),
+            alpn: _default_alpn(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StorageEncryption {
+    pub key_env: String, // environment variable of the base64 encoded 32 byte master key
+    #[serde(default)]
+    pub previous_key_envs: Vec<String>, // keys still read after a rotation, replaced on the next put
+}
+
+impl Default for CertificateStorage {
+    fn default() -> Self {
+        Self::File {
+            path: "./certificates".to_owned(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct File {
     pub domains: Vec<String>,
     pub cert_path: String,
+    #[serde(default)]
+    pub tls_policy: TlsPolicy,
+}
+
+impl TlsConfig {
+    pub fn tls_policy(&self) -> &TlsPolicy {
+        match self {
+            Self::Acme(acme) => &acme.tls_policy,
+            Self::File(file) => &file.tls_policy,
+        }
+    }
+}
+
+impl Acme {
+    pub fn directory(&self) -> String {
+        if self.staging {
+            LETS_ENCRYPT_STAGING_DIRECTORY_URL.to_string()
+        } else {
+            self.directory_url.clone()
+        }
+    }
 }
 
+pub const LETS_ENCRYPT_STAGING_DIRECTORY_URL: &str =
+    "https://acme-staging-v02.api.letsencrypt.org/directory";
+
 pub fn _default_acme_directory_url() -> String {
     "https://acme-v02.api.letsencrypt.org/directory".to_string()
 }
+
+pub fn _default_renew_check_interval() -> u64 {
+    60 * 60 * 6
+}
+
+pub fn _default_challenge_poll_tries() -> u8 {
+    5
+}
+
+pub fn _default_challenge_poll_interval() -> u64 {
+    10
+}
+
+pub fn _default_max_concurrent_issuances() -> usize {
+    2
+}
+
+pub fn _default_load_concurrency() -> usize {
+    8
+}
+
+pub fn _default_expiry_warning_days() -> Vec<u64> {
+    vec![30, 14, 7]
+}
+
+pub fn _default_renew_before_days() -> u64 {
+    7
+}
+
+pub fn _default_drain_timeout() -> u64 {
+    30
+}
+
+pub fn _default_agent_token_ttl() -> u64 {
+    60 * 60 // an hour
+}
+
+pub fn _default_udp_idle_timeout() -> u64 {
+    60
+}
+
+pub fn _default_udp_max_sessions() -> usize {
+    1024
+}
+
+pub fn _default_udp_max_sessions_per_prefix() -> usize {
+    64
+}
+
+pub fn _default_alpn() -> Vec<String> {
+    vec!["h2".to_string(), "http/1.1".to_string()]
+}
+
+pub fn _default_session_cache_size() -> usize {
+    256
+}
+
+pub fn _default_tls13_tickets() -> usize {
+    4
+}
+
+pub fn _default_redis_prefix() -> String {
+    "narrowlink".to_string()
+}
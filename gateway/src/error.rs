@@ -41,20 +41,36 @@ pub enum GatewayError {
     ACMEFailed,
     #[error("ACME Challenge Not Found")]
     ACMEChallengeNotFound,
+    #[error("ACME Challenge Type Mismatch, the pending challenge is {0}")]
+    ACMEChallengeTypeMismatch(&'static str),
+    #[error("ACME Challenge Rate Limited")]
+    ACMEChallengeRateLimited,
     #[error("ACME Order Not Found")]
     ACMEOrderNotAvailable,
+    #[error("ACME Order Already Finalized")]
+    ACMEOrderFinalized,
     #[error("ACME Verification Timeout")]
     ACMEVerificationTimeOut,
     #[error("ACME Verification Failed")]
     ACMEVerificationFailed,
     #[error("ACME Pending")]
     ACMEPending,
+    #[error("ACME Issuance Backing Off")]
+    ACMEBackingOff,
+    #[error("ACME External Account Binding is required by the CA, set eab_kid and eab_hmac_key")]
+    ACMEExternalAccountRequired,
+    #[error("ACME Account Key Change Failed: {0}")]
+    ACMEKeyChangeFailed(String),
+    #[error("Redis Error: {0}")]
+    RedisError(String),
     #[error("Certificate Not Found")]
     CertificateNotFound,
     #[error("Certificate Renewal Required")]
     CertificateRenewalRequired,
     #[error("Invalid {0}")]
     Invalid(&'static str),
+    #[error("Unsupported: {0}")]
+    Unsupported(&'static str),
     #[error("Other: {0}")]
     Other(&'static str),
 }
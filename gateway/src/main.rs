@@ -1,14 +1,17 @@
 use std::{
     env,
     io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 
 use error::GatewayError;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use state::State;
-use tracing::{debug, error, info, span, trace, Instrument, Level};
+use tokio::time;
+use tracing::{debug, error, info, span, trace, warn, Instrument, Level};
 use tracing_subscriber::{
-    filter::{LevelFilter, Targets},
+    filter::{filter_fn, FilterExt, LevelFilter, Targets},
     fmt::writer::MakeWriterExt,
     prelude::__tracing_subscriber_SubscriberExt,
     util::SubscriberInitExt,
@@ -16,35 +19,74 @@ use tracing_subscriber::{
 };
 use validator::Validate;
 
-use crate::{args::Args, service::Service};
+use crate::{args::Args, config::LogFormat, service::Service};
 mod args;
+mod audit;
 mod config;
 mod error;
 mod service;
 mod state;
 
 const CONNECTION_ORIANTED: bool = true;
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+// NARROWLINK_LOG_FORMAT (json or text) takes precedence over the config
+fn set_log_format(log_format: LogFormat) {
+    let log_format = match env::var("NARROWLINK_LOG_FORMAT") {
+        Ok(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+        Ok(_) => LogFormat::Text,
+        Err(_) => log_format,
+    };
+    JSON_LOGS.store(log_format == LogFormat::Json, Ordering::Relaxed);
+}
 
 #[tokio::main]
 async fn main() -> Result<(), GatewayError> {
     let (stdout, _stdout_guard) = tracing_appender::non_blocking(io::stdout());
     let (stderr, _stderr_guard) = tracing_appender::non_blocking(io::stderr());
 
+    let writer = stdout
+        .with_min_level(Level::WARN)
+        .and(stderr.with_max_level(Level::ERROR));
+    let targets = env::var("RUST_LOG")
+        .ok()
+        .and_then(|e| e.parse::<Targets>().ok())
+        .unwrap_or(Targets::new().with_default(LevelFilter::INFO));
+    set_log_format(LogFormat::default());
+
     let cmd = tracing_subscriber::fmt::layer()
         .with_ansi(io::stdout().is_terminal() && io::stderr().is_terminal())
         .compact()
         // .with_target(false)
-        .with_writer(
-            stdout
-                .with_min_level(Level::WARN)
-                .and(stderr.with_max_level(Level::ERROR)),
-        )
+        .with_writer(writer.clone())
         .with_filter(
-            env::var("RUST_LOG")
-                .ok()
-                .and_then(|e| e.parse::<Targets>().ok())
-                .unwrap_or(Targets::new().with_default(LevelFilter::INFO)),
+            targets
+                .clone()
+                .and(filter_fn(|m| m.target() != audit::TARGET))
+                .and(filter_fn(|_| !JSON_LOGS.load(Ordering::Relaxed))),
         );
+    // one object per line, the fields of the enclosing spans are listed under `spans`
+    let json = tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_writer(writer)
+        .with_filter(
+            targets
+                .and(filter_fn(|m| m.target() != audit::TARGET))
+                .and(filter_fn(|_| JSON_LOGS.load(Ordering::Relaxed))),
+        );
+    // written regardless of RUST_LOG once the config names an audit log
+    let audit = tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_span_list(false)
+        .with_writer(audit::writer)
+        .with_filter(filter_fn(|m| {
+            m.target() == audit::TARGET && audit::enabled()
+        }));
 
     // let debug_file =
     //     tracing_appender::rolling::minutely("log", "debug").with_min_level(Level::DEBUG);
@@ -57,12 +99,18 @@ async fn main() -> Result<(), GatewayError> {
 
     tracing_subscriber::registry()
         .with(cmd)
+        .with(json)
+        .with(audit)
         // .with(file)
         .init();
     let span = span!(Level::TRACE, "main");
     let _gaurd = span.enter();
     let args = Args::parse(env::args())?;
     let conf = config::Config::load(args.config_path)?;
+    set_log_format(conf.log_format);
+    if let Some(audit_log) = &conf.audit_log {
+        audit::open(audit_log)?;
+    }
 
     trace!("config successfully read");
     conf.validate()?;
@@ -81,6 +129,11 @@ async fn main() -> Result<(), GatewayError> {
         None
     };
 
+    let client_verifier = match conf.client_ca() {
+        Some(client_ca) => Some(service::wss::client_verifier(client_ca).await?),
+        None => None,
+    };
+
     let mut state = State::from(
         &conf,
         cm.clone().and_then(|cm| match cm {
@@ -95,9 +148,14 @@ async fn main() -> Result<(), GatewayError> {
         match service {
             config::Service::Ws(ws) => {
                 services.push(
-                    service::ws::Ws::from(ws, state.get_sender(), cm.clone())
-                        .run()
-                        .instrument(span.clone()),
+                    service::ws::Ws::from(
+                        ws,
+                        state.get_sender(),
+                        cm.clone(),
+                        client_verifier.is_some(),
+                    )
+                    .run()
+                    .instrument(span.clone()),
                 );
                 span.in_scope(|| {
                     info!("Ws service added: {}", ws.listen_addr);
@@ -107,9 +165,14 @@ async fn main() -> Result<(), GatewayError> {
             config::Service::Wss(wss) => {
                 if let Some(cm) = &cm {
                     services.push(
-                        service::wss::Wss::from(wss, state.get_sender(), cm.clone())
-                            .run()
-                            .instrument(span.clone()),
+                        service::wss::Wss::from(
+                            wss,
+                            state.get_sender(),
+                            cm.clone(),
+                            client_verifier.clone(),
+                        )
+                        .run()
+                        .instrument(span.clone()),
                     );
                     span.in_scope(|| {
                         info!("Wss service added: {}", wss.listen_addr);
@@ -117,15 +180,137 @@ async fn main() -> Result<(), GatewayError> {
                     });
                 }
             }
+            config::Service::Quic(quic) => {
+                if let Some(cm) = &cm {
+                    services.push(
+                        service::quic::Quic::from(
+                            quic,
+                            state.get_sender(),
+                            cm.clone(),
+                            client_verifier.clone(),
+                        )
+                        .run()
+                        .instrument(span.clone()),
+                    );
+                    span.in_scope(|| {
+                        info!("Quic service added: {}", quic.listen_addr);
+                        debug!("Quic service added: {:?}", quic)
+                    });
+                }
+            }
+            config::Service::Tls(tls) => {
+                if let Some(cm) = &cm {
+                    services.push(
+                        service::tls::Tls::from(tls, state.get_sender(), cm.clone())
+                            .run()
+                            .instrument(span.clone()),
+                    );
+                    span.in_scope(|| {
+                        info!("Tls service added: {}", tls.listen_addr);
+                        debug!("Tls service added: {:?}", tls)
+                    });
+                }
+            }
+            config::Service::Udp(udp) => {
+                services.push(
+                    service::udp::Udp::from(udp, state.get_sender())
+                        .run()
+                        .instrument(span.clone()),
+                );
+                span.in_scope(|| {
+                    info!("Udp service added: {}", udp.listen_addr);
+                    debug!("Udp service added: {:?}", udp)
+                });
+            }
+            config::Service::Health(health) => {
+                let acme = cm.clone().and_then(|cm| match cm {
+                    service::wss::TlsEngine::Acme(cm) => Some(cm),
+                    _ => None,
+                });
+                services.push(
+                    service::health::Health::from(health, acme)
+                        .run()
+                        .instrument(span.clone()),
+                );
+                span.in_scope(|| info!("Health service added: {}", health.listen_addr));
+            }
+            config::Service::Admin(admin) => {
+                let acme = cm.clone().and_then(|cm| match cm {
+                    service::wss::TlsEngine::Acme(cm) => Some(cm),
+                    _ => None,
+                });
+                services.push(
+                    service::admin::Admin::from(admin, acme)?
+                        .run()
+                        .instrument(span.clone()),
+                );
+                span.in_scope(|| info!("Admin service added: {}", admin.listen_addr));
+            }
+            #[cfg(feature = "metrics")]
+            config::Service::Metrics(metrics) => {
+                services.push(
+                    service::metrics::Metrics::from(metrics)
+                        .run()
+                        .instrument(span.clone()),
+                );
+                span.in_scope(|| info!("Metrics service added: {}", metrics.listen_addr));
+            }
         }
     }
 
-    tokio::join!(
-        state.run().instrument(span.clone()),
-        services.for_each(|_s| {
-            error!("{:?}", _s);
-            std::future::ready(())
-        })
-    );
+    let connections = state.active_connections();
+    tokio::select! {
+        _ = async {
+            tokio::join!(
+                state.run().instrument(span.clone()),
+                services.for_each(|_s| {
+                    error!("{:?}", _s);
+                    std::future::ready(())
+                })
+            )
+        } => {}
+        _ = shutdown_signal() => {}
+    }
+    // the listeners and the state are dropped, tunnels already established keep running
+    span.in_scope(|| info!("Shutting down, new connections are no longer accepted"));
+    let drain_timeout = Duration::from_secs(conf.drain_timeout);
+    let drained = time::timeout(drain_timeout, async {
+        if let Some(service::wss::TlsEngine::Acme(cm)) = &cm {
+            cm.shutdown().await;
+        }
+        while connections.load(Ordering::Relaxed) > 0 {
+            time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        span.in_scope(|| {
+            warn!(
+                "{} connections still active after {} secs, closing them",
+                connections.load(Ordering::Relaxed),
+                drain_timeout.as_secs()
+            )
+        });
+    }
     Ok(())
 }
+
+// SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    if let Ok(mut terminate) =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+    {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = ctrl_c => {}
+        }
+        return;
+    }
+    ctrl_c.await
+}
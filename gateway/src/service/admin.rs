@@ -0,0 +1,294 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use hyper::{
+    body::HttpBody, header, server::conn::Http, service::service_fn, Body, Method, Request,
+    Response, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tracing::{debug, info, span, trace, warn, Instrument};
+
+use crate::error::GatewayError;
+
+use instant_acme::RevocationReason;
+
+use super::{
+    certificate::manager::{CertificateManager, CertificateServiceMessage},
+    Service,
+};
+
+const MAX_BODY_SIZE: usize = 64 * 1024; // a certificate chain and its key
+
+// body of PUT /certificates/{uid}/{agent_name}
+#[derive(Deserialize)]
+struct Import {
+    domains: Vec<String>,
+    cert_pem: String, // the chain, leaf first
+    key_pem: String,
+}
+
+// certificate and ACME account operations for operators, every request needs `Authorization: Bearer <token>`
+pub struct Admin {
+    listen_addr: SocketAddr,
+    token: Arc<String>,
+    cm: Option<Arc<CertificateManager>>,
+}
+
+impl Admin {
+    pub fn from(
+        admin: &crate::config::AdminService,
+        cm: Option<Arc<CertificateManager>>,
+    ) -> Result<Self, GatewayError> {
+        let token = std::env::var(&admin.token_env)
+            .ok()
+            .map(|token| token.trim().to_owned())
+            .filter(|token| !token.is_empty())
+            .ok_or(GatewayError::Invalid(
+                "admin token, the token_env variable is not set",
+            ))?;
+        Ok(Self {
+            listen_addr: admin.listen_addr,
+            token: Arc::new(token),
+            cm,
+        })
+    }
+}
+
+#[async_trait]
+impl Service for Admin {
+    async fn run(self) -> Result<(), GatewayError> {
+        let span = span!(tracing::Level::TRACE, "admin", listen_addr = %self.listen_addr);
+        let tcp_listener: TcpListener = TcpListener::bind(&self.listen_addr).await?;
+        span.in_scope(|| trace!("tcp listener successfully bound"));
+        loop {
+            let Ok((tcp_stream, peer_addr)) = tcp_listener.accept().await else {
+                span.in_scope(|| warn!("failed to accept tcp connection"));
+                continue;
+            };
+            span.in_scope(|| debug!("new admin connection from {}", peer_addr));
+            let (cm, token) = (self.cm.clone(), self.token.clone());
+            tokio::spawn(
+                async move {
+                    if let Err(http_err) = Http::new()
+                        .serve_connection(
+                            tcp_stream,
+                            service_fn(|req| {
+                                admin_response(req, peer_addr, cm.clone(), token.clone())
+                            }),
+                        )
+                        .await
+                    {
+                        warn!("{}", http_err);
+                    }
+                }
+                .instrument(span.clone()),
+            );
+        }
+    }
+}
+
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .is_some_and(|presented| {
+            ring::constant_time::verify_slices_are_equal(presented.as_bytes(), token.as_bytes())
+                .is_ok()
+        })
+}
+
+fn json(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap_or_default()
+}
+
+fn error(e: GatewayError) -> Response<Body> {
+    let status = match e {
+        GatewayError::CertificateNotFound => StatusCode::NOT_FOUND,
+        GatewayError::ACMEIsDisabled => StatusCode::CONFLICT,
+        GatewayError::Invalid(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    json(status, serde_json::json!({ "error": e.to_string() }))
+}
+
+// /certificates/{uid}/{agent_name}[/{action}], percent-encoded
+fn certificates_path(path: &str) -> Option<(String, String, Option<&str>)> {
+    let mut segments = path.strip_prefix("/certificates/")?.split('/');
+    let (Some(uid), Some(agent_name), action, None) = (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) else {
+        return None;
+    };
+    let decode = |segment| {
+        percent_decode_str(segment)
+            .decode_utf8()
+            .ok()
+            .map(|segment| segment.into_owned())
+            .filter(|segment| !segment.is_empty())
+    };
+    Some((decode(uid)?, decode(agent_name)?, action))
+}
+
+// ?reason= of a revocation, the names of RFC 5280, unspecified if missing
+fn revocation_reason(query: Option<&str>) -> Result<RevocationReason, GatewayError> {
+    let reason = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("reason="));
+    Ok(match reason {
+        None | Some("unspecified") => RevocationReason::Unspecified,
+        Some("keyCompromise") => RevocationReason::KeyCompromise,
+        Some("affiliationChanged") => RevocationReason::AffiliationChanged,
+        Some("superseded") => RevocationReason::Superseded,
+        Some("cessationOfOperation") => RevocationReason::CessationOfOperation,
+        Some(_) => return Err(GatewayError::Invalid("revocation reason")),
+    })
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, GatewayError> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| GatewayError::Invalid("request body"))?;
+        if buf.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(GatewayError::Invalid("request body is too large"));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+async fn admin_response(
+    req: Request<Body>,
+    peer_addr: SocketAddr,
+    cm: Option<Arc<CertificateManager>>,
+    token: Arc<String>,
+) -> Result<Response<Body>, Infallible> {
+    if !authorized(&req, &token) {
+        warn!("unauthorized admin request from {}", peer_addr);
+        return Ok(json(
+            StatusCode::UNAUTHORIZED,
+            serde_json::json!({ "error": "unauthorized" }),
+        ));
+    }
+    let path = req.uri().path().to_owned();
+    if path == "/account/rotate-key" {
+        return Ok(rotate_account_key(req.method(), peer_addr, cm).await);
+    }
+    let Some((uid, agent_name, action)) = certificates_path(&path) else {
+        return Ok(json(
+            StatusCode::NOT_FOUND,
+            serde_json::json!({ "error": "not found" }),
+        ));
+    };
+    let Some(cm) = cm else {
+        return Ok(error(GatewayError::ACMEIsDisabled));
+    };
+    let res = match (req.method().clone(), action) {
+        (Method::PUT, None) => {
+            info!(
+                "import of a certificate for agent {}:{} requested by {}",
+                uid, agent_name, peer_addr
+            );
+            match read_body(req.into_body()).await.and_then(|body| {
+                serde_json::from_slice::<Import>(&body)
+                    .map_err(|_| GatewayError::Invalid("import request"))
+            }) {
+                Ok(import) => {
+                    cm.import(
+                        &uid,
+                        &agent_name,
+                        import.domains,
+                        &import.cert_pem,
+                        &import.key_pem,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            }
+        }
+        (Method::DELETE, None) => {
+            info!(
+                "purge of the certificates of agent {}:{} requested by {}",
+                uid, agent_name, peer_addr
+            );
+            cm.purge(&uid, &agent_name).await
+        }
+        (Method::POST, Some("revoke")) => {
+            info!(
+                "revocation of the certificates of agent {}:{} requested by {}",
+                uid, agent_name, peer_addr
+            );
+            match revocation_reason(req.uri().query()) {
+                Ok(reason) => cm.revoke(&uid, &agent_name, reason).await,
+                Err(e) => Err(e),
+            }
+        }
+        (Method::POST, Some("renew")) => {
+            info!(
+                "renewal of the certificates of agent {}:{} requested by {}",
+                uid, agent_name, peer_addr
+            );
+            // issuances take a while, the outcome is in the log and the certificate events
+            return Ok(
+                match cm
+                    .get_service_sender()
+                    .send(CertificateServiceMessage::Renew(uid, agent_name))
+                {
+                    Ok(()) => json(StatusCode::ACCEPTED, serde_json::json!({ "ok": true })),
+                    Err(_) => error(GatewayError::Other("certificate manager is stopped")),
+                },
+            );
+        }
+        (_, None | Some("revoke" | "renew")) => {
+            return Ok(json(
+                StatusCode::METHOD_NOT_ALLOWED,
+                serde_json::json!({ "error": "method not allowed" }),
+            ))
+        }
+        _ => {
+            return Ok(json(
+                StatusCode::NOT_FOUND,
+                serde_json::json!({ "error": "not found" }),
+            ))
+        }
+    };
+    Ok(match res {
+        Ok(()) => json(StatusCode::OK, serde_json::json!({ "ok": true })),
+        Err(e) => error(e),
+    })
+}
+
+// POST /account/rotate-key, replaces the key of the gateway's ACME account
+async fn rotate_account_key(
+    method: &Method,
+    peer_addr: SocketAddr,
+    cm: Option<Arc<CertificateManager>>,
+) -> Response<Body> {
+    if method != Method::POST {
+        return json(
+            StatusCode::METHOD_NOT_ALLOWED,
+            serde_json::json!({ "error": "method not allowed" }),
+        );
+    }
+    let Some(cm) = cm else {
+        return error(GatewayError::ACMEIsDisabled);
+    };
+    info!(
+        "rotation of the ACME account key requested by {}",
+        peer_addr
+    );
+    match cm.rotate_acme_account_key().await {
+        Ok(()) => json(StatusCode::OK, serde_json::json!({ "ok": true })),
+        Err(e) => error(e),
+    }
+}
@@ -1,17 +1,32 @@
 use std::sync::Arc;
 
+use base64::Engine;
+use hyper::{body, header::CONTENT_TYPE, Body, Request};
 use instant_acme::{
-    Account, AccountCredentials, Authorization, AuthorizationStatus, ChallengeType, Identifier,
-    NewAccount, NewOrder, Order, OrderStatus,
+    Account, AccountCredentials, Authorization, AuthorizationStatus, ChallengeType,
+    ExternalAccountKey, Identifier, NewAccount, NewOrder, Order, OrderStatus, RevocationReason,
+    RevocationRequest,
 };
 use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey},
+    traits::PublicKeyParts,
+};
 use rustls::{PrivateKey, ServerConfig};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::time;
 use tracing::{debug, instrument, trace};
 
 use crate::error::GatewayError;
 
+use super::TlsPolicy;
+
+const JOSE_CONTENT_TYPE: &str = "application/jose+json";
+
 pub struct Acme {
     pub account: Account,
     authorizations: Vec<Authorization>,
@@ -35,30 +50,146 @@ pub struct ChallengeInfo {
     pub challenge: ACMEChallenge,
 }
 #[allow(dead_code)]
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Deserialize, Default)]
 pub enum ACMEChallengeType {
+    #[default]
     Http01,
     TlsAlpn01,
+    Dns01,
 }
 
-impl Default for ACMEChallengeType {
-    fn default() -> Self {
-        Self::Http01
+impl std::str::FromStr for ACMEChallengeType {
+    type Err = GatewayError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Http01" => Ok(Self::Http01),
+            "TlsAlpn01" => Ok(Self::TlsAlpn01),
+            "Dns01" => Ok(Self::Dns01),
+            _ => Err(GatewayError::Invalid("ACME challenge type")),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, Deserialize)]
+pub enum KeyType {
+    #[default]
+    EcdsaP256,
+    EcdsaP384,
+    Rsa2048,
+    Rsa4096,
+}
+
+impl KeyType {
+    // the key of the previous certificate is kept if it is still of this type
+    async fn key_pair(
+        &self,
+        suggested_private_key: Option<&PrivateKey>,
+    ) -> Result<KeyPair, GatewayError> {
+        if let Some(key_pair) = suggested_private_key
+            .filter(|private_key| self.is_type_of(private_key))
+            .and_then(|private_key| KeyPair::from_der(&private_key.0).ok())
+        {
+            return Ok(key_pair);
+        }
+        match self {
+            KeyType::EcdsaP256 => Ok(KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?),
+            KeyType::EcdsaP384 => Ok(KeyPair::generate(&rcgen::PKCS_ECDSA_P384_SHA384)?),
+            // ring only signs with RSA keys, they are generated by the rsa crate, which takes
+            // a while for 4096 bits
+            KeyType::Rsa2048 | KeyType::Rsa4096 => {
+                let bits = self.rsa_bits();
+                let pkcs8 = tokio::task::spawn_blocking(move || {
+                    rsa::RsaPrivateKey::new(&mut rand_core::OsRng, bits)
+                        .and_then(|key| Ok(key.to_pkcs8_der()?))
+                })
+                .await
+                .map_err(|_| GatewayError::Other("unable to generate an RSA key"))?
+                .map_err(|_| GatewayError::Other("unable to generate an RSA key"))?;
+                Ok(KeyPair::from_der(pkcs8.as_bytes())?)
+            }
+        }
+    }
+    fn rsa_bits(&self) -> usize {
+        match self {
+            KeyType::Rsa4096 => 4096,
+            _ => 2048,
+        }
+    }
+    fn is_type_of(&self, private_key: &PrivateKey) -> bool {
+        let ecdsa = |alg| {
+            KeyPair::from_der(&private_key.0).is_ok_and(|key_pair| key_pair.is_compatible(alg))
+        };
+        match self {
+            KeyType::EcdsaP256 => ecdsa(&rcgen::PKCS_ECDSA_P256_SHA256),
+            KeyType::EcdsaP384 => ecdsa(&rcgen::PKCS_ECDSA_P384_SHA384),
+            KeyType::Rsa2048 | KeyType::Rsa4096 => {
+                rsa::RsaPrivateKey::from_pkcs8_der(&private_key.0)
+                    .is_ok_and(|key| key.size() * 8 == self.rsa_bits())
+            }
+        }
+    }
+    async fn certificate_params(
+        &self,
+        domains: Vec<String>,
+        suggested_private_key: Option<&PrivateKey>,
+    ) -> Result<CertificateParams, GatewayError> {
+        let key_pair = self.key_pair(suggested_private_key).await?;
+        let mut params = CertificateParams::new(domains);
+        params.alg = key_pair.algorithm();
+        params.key_pair = Some(key_pair);
+        params.distinguished_name = DistinguishedName::new();
+        Ok(params)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ACMEChallenge {
     Http01(String, String),
-    TlsAlpn01(Arc<ServerConfig>),
-    // Dns01(String),
+    TlsAlpn01(Vec<u8>, Vec<u8>), // (certificate der, private key der)
+    Dns01(String, String),       // (record name, record value)
+}
+
+impl ACMEChallenge {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ACMEChallenge::Http01(..) => "http-01",
+            ACMEChallenge::TlsAlpn01(..) => "tls-alpn-01",
+            ACMEChallenge::Dns01(..) => "dns-01",
+        }
+    }
+    pub fn tls_alpn_server_config(
+        certificate: &[u8],
+        private_key: &[u8],
+        tls_policy: &TlsPolicy,
+    ) -> Result<Arc<ServerConfig>, GatewayError> {
+        let mut server_config = tls_policy
+            .builder()?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(certificate.to_vec())],
+                rustls::PrivateKey(private_key.to_vec()),
+            )?;
+        server_config
+            .alpn_protocols
+            .push(crate::service::certificate::ACME_TLS_ALPN_NAME.to_vec());
+        Ok(Arc::new(server_config))
+    }
 }
 
 impl Acme {
     pub async fn new(
         email: &str,
         directory: &str,
+        eab: Option<(&str, &str)>, // (key id, base64url hmac key)
     ) -> Result<(Self, AccountCredentials), GatewayError> {
+        let external_account = if let Some((kid, hmac_key)) = eab {
+            let hmac_key = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(hmac_key.trim_end_matches('='))
+                .map_err(|_| GatewayError::Invalid("EAB HMAC key"))?;
+            Some(ExternalAccountKey::new(kid.to_owned(), &hmac_key))
+        } else {
+            None
+        };
         let (account, account_credentials) = Account::create(
             &NewAccount {
                 contact: &[&format!("mailto:{}", email)],
@@ -66,9 +197,17 @@ impl Acme {
                 only_return_existing: false,
             },
             directory,
-            None,
+            external_account.as_ref(),
         )
-        .await?;
+        .await
+        .map_err(|e| match e {
+            instant_acme::Error::Api(problem)
+                if problem.r#type == "urn:ietf:params:acme:error:externalAccountRequired" =>
+            {
+                GatewayError::ACMEExternalAccountRequired
+            }
+            e => GatewayError::ACMEError(e),
+        })?;
         Ok((
             Self {
                 account,
@@ -85,11 +224,37 @@ impl Acme {
             order: None,
         })
     }
+    // a certificate the CA already revoked counts as revoked
+    #[instrument(name = "acme::revoke", skip(self, certificate_der))]
+    pub async fn revoke(
+        &self,
+        certificate_der: &[u8],
+        reason: RevocationReason,
+    ) -> Result<(), GatewayError> {
+        match self
+            .account
+            .revoke(&RevocationRequest {
+                certificate: &rustls_pki_types::CertificateDer::from(certificate_der),
+                reason: Some(reason),
+            })
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(instant_acme::Error::Api(problem))
+                if problem.r#type == "urn:ietf:params:acme:error:alreadyRevoked" =>
+            {
+                debug!("certificate already revoked");
+                Ok(())
+            }
+            Err(e) => Err(GatewayError::ACMEError(e)),
+        }
+    }
     #[instrument(name = "acme::new_order", skip(self))]
     pub async fn new_order(
         &mut self,
         domains: Vec<String>,
         suggested_private_key: Option<&PrivateKey>,
+        key_type: KeyType,
     ) -> Result<Option<Vec<pem::Pem>>, GatewayError> {
         debug!("place new acme order for {:?}", &domains);
         let identifiers = domains
@@ -104,7 +269,17 @@ impl Acme {
             })
             .await?;
         debug!("new acme order placed for {:?}", &domains);
-        // let state = order.state();
+        // the CA hands back the still open order of the same identifiers, e.g. one left behind
+        // by an issuance interrupted by a restart, instead of creating a duplicate
+        match order.state().status {
+            OrderStatus::Pending | OrderStatus::Ready => {}
+            OrderStatus::Processing | OrderStatus::Valid => {
+                // finalized with a key that did not survive the interrupted issuance
+                debug!("acme order for {:?} is already finalized", &domains);
+                return Err(GatewayError::ACMEOrderFinalized);
+            }
+            OrderStatus::Invalid => return Err(GatewayError::ACMEFailed),
+        }
         let authorizations = order.authorizations().await?;
         debug!("get acme authorization orders for {:?}", &domains);
         if authorizations.iter().any(|a| {
@@ -116,39 +291,25 @@ impl Acme {
             return Err(GatewayError::ACMEFailed);
         }
         trace!("{:?}", &authorizations);
-        if authorizations
+        // authorizations the CA still considers valid need no challenge, once all of them are
+        // valid the order is ready and can be finalized without any validation
+        let reused = authorizations
             .iter()
-            .any(|authorization| matches!(authorization.status, AuthorizationStatus::Valid))
-        {
-            let mut params = CertificateParams::new(domains);
-            params.key_pair = suggested_private_key
-                .and_then(|private_key| KeyPair::from_der(&private_key.0).ok());
-            params.distinguished_name = DistinguishedName::new();
-            let cert = rcgen::Certificate::from_params(params)?;
-            let csr = cert.serialize_request_der()?;
-            order.finalize(&csr).await?;
-            let cert_chain_pem = loop {
-                // todo
-                match order.certificate().await? {
-                    Some(cert_chain_pem) => break cert_chain_pem,
-                    None => tokio::time::sleep(tokio::time::Duration::from_secs(1)).await,
-                }
-            };
-
-            // let mut certificates = Vec::new();
-            // for pem in x509_parser::prelude::Pem::iter_from_buffer(&cert_chain_pem.as_bytes()) {
-            //     certificates.push(rustls::Certificate(pem?.contents));
-            // }
-            // let private_key = rustls::PrivateKey(cert.get_key_pair().serialize_der());
-            // return Ok(Some((private_key, certificates)));
-            return Ok(Some(pem::parse_many(cert_chain_pem).and_then(
-                |mut c| {
-                    pem::parse(cert.get_key_pair().serialize_pem()).map(|p| {
-                        c.push(p);
-                        c
-                    })
-                },
-            )?));
+            .filter(|authorization| matches!(authorization.status, AuthorizationStatus::Valid))
+            .count();
+        if reused > 0 {
+            debug!(
+                "reusing {} of {} valid acme authorizations for {:?}",
+                reused,
+                authorizations.len(),
+                &domains
+            );
+        }
+        if order.state().status == OrderStatus::Ready || reused == authorizations.len() {
+            trace!("acme order ready, skipping validation");
+            return Ok(Some(
+                Self::finalize(&mut order, domains, suggested_private_key, key_type).await?,
+            ));
         }
 
         self.order = Some(order);
@@ -168,12 +329,7 @@ impl Acme {
         let challenges = self
             .authorizations
             .iter()
-            .filter(|authorization| {
-                matches!(
-                    authorization.status,
-                    AuthorizationStatus::Pending | AuthorizationStatus::Valid
-                )
-            })
+            .filter(|authorization| matches!(authorization.status, AuthorizationStatus::Pending))
             .flat_map(|authorization| {
                 let Identifier::Dns(identifier) = &authorization.identifier;
 
@@ -205,21 +361,13 @@ impl Acme {
             params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(&digest)];
             let cert = rcgen::Certificate::from_params(params)?;
 
-            let mut server_config = rustls::ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(
-                    vec![rustls::Certificate(cert.serialize_der()?)],
-                    rustls::PrivateKey(cert.get_key_pair().serialize_der()),
-                )?;
-            server_config
-                .alpn_protocols
-                .push(crate::service::certificate::ACME_TLS_ALPN_NAME.to_vec());
-
             cert_tuple.push(ChallengeInfo {
                 verification_url: verification_url.to_string(),
                 domain: domain.to_string(),
-                challenge: ACMEChallenge::TlsAlpn01(Arc::new(server_config)),
+                challenge: ACMEChallenge::TlsAlpn01(
+                    cert.serialize_der()?,
+                    cert.get_key_pair().serialize_der(),
+                ),
             });
         }
 
@@ -265,23 +413,71 @@ impl Acme {
         Ok(cert_tuple)
     }
 
+    pub fn get_dns_01_certificate_challenges(&self) -> Result<Vec<ChallengeInfo>, GatewayError> {
+        let order = self
+            .order
+            .as_ref()
+            .ok_or(GatewayError::ACMEOrderNotAvailable)?;
+        let mut cert_tuple = Vec::new();
+        let challenges = self
+            .authorizations
+            .iter()
+            .filter(|authorization| matches!(authorization.status, AuthorizationStatus::Pending))
+            .flat_map(|authorization| {
+                let Identifier::Dns(identifier) = &authorization.identifier;
+                authorization
+                    .challenges
+                    .iter()
+                    .filter(|challenge| challenge.r#type == ChallengeType::Dns01)
+                    .map(move |challenge| {
+                        (
+                            &challenge.url,
+                            identifier,
+                            ACMEChallenge::Dns01(
+                                format!("_acme-challenge.{}", identifier.trim_start_matches("*.")),
+                                order.key_authorization(challenge).dns_value(),
+                            ),
+                        )
+                    })
+            })
+            .collect::<Vec<(&String, &String, ACMEChallenge)>>();
+
+        for (verification_url, domain, challenge) in challenges {
+            cert_tuple.push(ChallengeInfo {
+                verification_url: verification_url.to_string(),
+                domain: domain.to_string(),
+                challenge,
+            });
+        }
+        Ok(cert_tuple)
+    }
+
     pub async fn check_challenge(
         &mut self,
         challenges: Vec<ChallengeInfo>,
         tries: u8,
         delay: u64,
         suggested_private_key: Option<&PrivateKey>,
+        key_type: KeyType,
     ) -> Result<Vec<pem::Pem>, GatewayError> {
         let order = self
             .order
             .as_mut()
             .ok_or(GatewayError::ACMEOrderNotAvailable)?;
-        let mut domain = Vec::new();
         for challenge in challenges {
+            // already triggered for a resumed order, the CA only needs to be polled again
+            let triggered = self
+                .authorizations
+                .iter()
+                .flat_map(|authorization| authorization.challenges.iter())
+                .any(|c| c.url == challenge.verification_url && !is_pending(&c.status));
+            if triggered {
+                debug!("resuming acme challenge of {}", challenge.domain);
+                continue;
+            }
             order
                 .set_challenge_ready(&challenge.verification_url)
                 .await?;
-            domain.push(challenge.domain.clone());
         }
         let mut tries_counter = 1;
         let mut delay = std::time::Duration::from_millis(delay);
@@ -308,10 +504,27 @@ impl Acme {
             return Err(GatewayError::ACMEVerificationFailed);
         }
         trace!("acme verification successful");
-        let mut params = CertificateParams::new(domain);
-        params.key_pair =
-            suggested_private_key.and_then(|private_key| KeyPair::from_der(&private_key.0).ok());
-        params.distinguished_name = DistinguishedName::new();
+        // every identifier of the order, including the ones with reused authorizations
+        let domains = self
+            .authorizations
+            .iter()
+            .map(|authorization| {
+                let Identifier::Dns(identifier) = &authorization.identifier;
+                identifier.to_owned()
+            })
+            .collect();
+        Self::finalize(order, domains, suggested_private_key, key_type).await
+    }
+
+    async fn finalize(
+        order: &mut Order,
+        domains: Vec<String>,
+        suggested_private_key: Option<&PrivateKey>,
+        key_type: KeyType,
+    ) -> Result<Vec<pem::Pem>, GatewayError> {
+        let params = key_type
+            .certificate_params(domains, suggested_private_key)
+            .await?;
         let cert = rcgen::Certificate::from_params(params)?;
         let csr = cert.serialize_request_der()?;
         order.finalize(&csr).await?;
@@ -330,10 +543,249 @@ impl Acme {
                 c
             })
         })?)
-        // for pem in x509_parser::prelude::Pem::iter_from_buffer(&cert_chain_pem.as_bytes()) {
-        //     certificates.push(rustls::Certificate(pem?.contents));
-        // }
-        // let private_key = rustls::PrivateKey(cert.get_key_pair().serialize_der());
-        // Ok((private_key, certificates))
+    }
+}
+
+// instant-acme does not export its challenge status type, the pending variant is obtained by
+// deserializing its RFC 8555 name into the same type
+fn is_pending<S: serde::de::DeserializeOwned>(status: &S) -> bool {
+    serde_json::from_str::<S>("\"pending\"")
+        .is_ok_and(|pending| std::mem::discriminant(&pending) == std::mem::discriminant(status))
+}
+
+// RFC 8555 7.3.5, the new key signs the inner JWS and the current one the outer, the account
+// url stays the same, instant-acme keeps the key private so the request is built here
+#[instrument(name = "acme::change_account_key", skip(credentials))]
+pub async fn change_account_key(
+    credentials: &AccountCredentials,
+) -> Result<AccountCredentials, GatewayError> {
+    let stored = serde_json::to_value(credentials)?;
+    let (Some(account_url), Some(key_pkcs8), Some(directory_url)) = (
+        stored["id"].as_str(),
+        stored["key_pkcs8"].as_str(),
+        stored["directory"].as_str(),
+    ) else {
+        return Err(GatewayError::Invalid("ACME account credentials"));
+    };
+    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let rng = SystemRandom::new();
+    let old_key = b64
+        .decode(key_pkcs8)
+        .ok()
+        .and_then(|key| EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key, &rng).ok())
+        .ok_or(GatewayError::Invalid("ACME account key"))?;
+    let new_pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+        .map_err(|_| GatewayError::Other("unable to generate an ACME account key"))?;
+    let new_key =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, new_pkcs8.as_ref(), &rng)
+            .map_err(|_| GatewayError::Other("unable to generate an ACME account key"))?;
+
+    let client = hyper::Client::builder().build::<_, Body>(
+        hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_only()
+            .enable_http1()
+            .build(),
+    );
+    let directory_uri = directory_url
+        .parse::<hyper::Uri>()
+        .map_err(|_| GatewayError::Invalid("ACME directory url"))?;
+    let directory: serde_json::Value = serde_json::from_slice(
+        &body::to_bytes(client.get(directory_uri).await?.into_body()).await?,
+    )?;
+    let (Some(key_change_url), Some(new_nonce_url)) = (
+        directory["keyChange"].as_str(),
+        directory["newNonce"].as_str(),
+    ) else {
+        return Err(GatewayError::Unsupported("ACME account key change"));
+    };
+    let nonce_request = Request::head(new_nonce_url)
+        .body(Body::empty())
+        .map_err(|_| GatewayError::Invalid("ACME directory url"))?;
+    let nonce = client
+        .request(nonce_request)
+        .await?
+        .headers()
+        .get("replay-nonce")
+        .and_then(|nonce| nonce.to_str().ok())
+        .map(|nonce| nonce.to_owned())
+        .ok_or(GatewayError::ACMEKeyChangeFailed(
+            "no replay nonce".to_owned(),
+        ))?;
+
+    let inner = jws(
+        &new_key,
+        &rng,
+        serde_json::json!({ "alg": "ES256", "jwk": jwk(&new_key), "url": key_change_url }),
+        &serde_json::json!({ "account": account_url, "oldKey": jwk(&old_key) }),
+    )?;
+    let outer = jws(
+        &old_key,
+        &rng,
+        serde_json::json!({ "alg": "ES256", "kid": account_url, "nonce": nonce, "url": key_change_url }),
+        &inner,
+    )?;
+    trace!("request account key change");
+    let key_change_request = Request::post(key_change_url)
+        .header(CONTENT_TYPE, JOSE_CONTENT_TYPE)
+        .body(Body::from(serde_json::to_vec(&outer)?))
+        .map_err(|_| GatewayError::Invalid("ACME directory url"))?;
+    let response = client.request(key_change_request).await?;
+    if !response.status().is_success() {
+        let problem = body::to_bytes(response.into_body()).await?;
+        return Err(GatewayError::ACMEKeyChangeFailed(
+            String::from_utf8_lossy(&problem).into_owned(),
+        ));
+    }
+    Ok(serde_json::from_value(serde_json::json!({
+        "id": account_url,
+        "key_pkcs8": b64.encode(new_pkcs8.as_ref()),
+        "directory": directory_url,
+    }))?)
+}
+
+fn jwk(key: &EcdsaKeyPair) -> serde_json::Value {
+    // uncompressed point, 0x04 | x | y
+    let point = key.public_key().as_ref();
+    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    serde_json::json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": b64.encode(&point[1..33]),
+        "y": b64.encode(&point[33..]),
+    })
+}
+
+fn jws(
+    key: &EcdsaKeyPair,
+    rng: &SystemRandom,
+    protected: serde_json::Value,
+    payload: &serde_json::Value,
+) -> Result<serde_json::Value, GatewayError> {
+    let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let protected = b64.encode(serde_json::to_vec(&protected)?);
+    let payload = b64.encode(serde_json::to_vec(payload)?);
+    let signature = key
+        .sign(rng, format!("{}.{}", protected, payload).as_bytes())
+        .map_err(|_| GatewayError::Other("unable to sign the ACME request"))?;
+    Ok(serde_json::json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": b64.encode(signature.as_ref()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use instant_acme::Challenge;
+
+    use super::*;
+
+    fn challenge(status: &str) -> Challenge {
+        serde_json::from_value(serde_json::json!({
+            "type": "http-01",
+            "url": "https://acme.example.com/challenge/1",
+            "token": "token",
+            "status": status,
+        }))
+        .expect("valid challenge")
+    }
+
+    fn decode(part: &serde_json::Value) -> serde_json::Value {
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let part = b64
+            .decode(part.as_str().expect("base64 part"))
+            .expect("base64");
+        serde_json::from_slice(&part).expect("json part")
+    }
+
+    fn verified(key: &EcdsaKeyPair, jws: &serde_json::Value) -> bool {
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let (Some(protected), Some(payload), Some(signature)) = (
+            jws["protected"].as_str(),
+            jws["payload"].as_str(),
+            jws["signature"].as_str(),
+        ) else {
+            return false;
+        };
+        b64.decode(signature).is_ok_and(|signature| {
+            ring::signature::UnparsedPublicKey::new(
+                &ring::signature::ECDSA_P256_SHA256_FIXED,
+                key.public_key().as_ref(),
+            )
+            .verify(format!("{}.{}", protected, payload).as_bytes(), &signature)
+            .is_ok()
+        })
+    }
+
+    #[test]
+    fn key_change_nests_the_new_key_inside_the_old_one() {
+        let rng = SystemRandom::new();
+        let key = || {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .expect("pkcs8");
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .expect("key")
+        };
+        let (old_key, new_key) = (key(), key());
+        let (account, url) = (
+            "https://acme.example.com/acct/1",
+            "https://acme.example.com/key-change",
+        );
+        let inner = jws(
+            &new_key,
+            &rng,
+            serde_json::json!({ "alg": "ES256", "jwk": jwk(&new_key), "url": url }),
+            &serde_json::json!({ "account": account, "oldKey": jwk(&old_key) }),
+        )
+        .expect("inner jws");
+        let outer = jws(
+            &old_key,
+            &rng,
+            serde_json::json!({ "alg": "ES256", "kid": account, "nonce": "nonce", "url": url }),
+            &inner,
+        )
+        .expect("outer jws");
+
+        assert!(verified(&old_key, &outer));
+        assert!(!verified(&new_key, &outer));
+        assert_eq!(decode(&outer["payload"]), inner);
+        assert!(verified(&new_key, &inner));
+        assert_eq!(decode(&inner["protected"])["jwk"], jwk(&new_key));
+        assert_eq!(decode(&inner["payload"])["oldKey"], jwk(&old_key));
+    }
+
+    #[tokio::test]
+    async fn key_types_generate_their_own_keys() {
+        let domains = vec!["example.com".to_owned()];
+        let ecdsa = KeyType::EcdsaP256
+            .certificate_params(domains.clone(), None)
+            .await
+            .expect("ecdsa params");
+        let ecdsa_key = PrivateKey(ecdsa.key_pair.as_ref().expect("key").serialize_der());
+        assert!(KeyType::EcdsaP256.is_type_of(&ecdsa_key));
+        assert!(!KeyType::EcdsaP384.is_type_of(&ecdsa_key));
+        assert!(!KeyType::Rsa2048.is_type_of(&ecdsa_key));
+
+        // the suggested ECDSA key does not fit, a new RSA one is generated
+        let rsa = KeyType::Rsa2048
+            .certificate_params(domains, Some(&ecdsa_key))
+            .await
+            .expect("rsa params");
+        assert_eq!(rsa.alg, &rcgen::PKCS_RSA_SHA256);
+        let rsa_key = PrivateKey(rsa.key_pair.as_ref().expect("key").serialize_der());
+        assert!(KeyType::Rsa2048.is_type_of(&rsa_key));
+        assert!(!KeyType::Rsa4096.is_type_of(&rsa_key));
+        assert!(rcgen::Certificate::from_params(rsa)
+            .and_then(|cert| cert.serialize_request_der())
+            .is_ok());
+    }
+
+    #[test]
+    fn only_pending_challenges_are_pending() {
+        assert!(is_pending(&challenge("pending").status));
+        for status in ["processing", "valid", "invalid"] {
+            assert!(!is_pending(&challenge(status).status));
+        }
     }
 }
@@ -0,0 +1,47 @@
+use std::{fmt::Debug, time::SystemTime};
+#[cfg(test)]
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// the time renewal decisions are made against, so they can be driven without waiting
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// stands still until advanced, for exercising renewal without real certificates expiring
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<Mutex<SystemTime>>);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self(Arc::new(Mutex::new(now)))
+    }
+    pub fn advance(&self, duration: Duration) {
+        if let Ok(mut now) = self.0.lock() {
+            *now += duration;
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.0
+            .lock()
+            .map(|now| *now)
+            .unwrap_or_else(|_| SystemTime::now())
+    }
+}
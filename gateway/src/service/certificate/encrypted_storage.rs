@@ -0,0 +1,283 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use base64::Engine;
+use instant_acme::AccountCredentials;
+use pem::Pem;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use sha3::{Digest, Sha3_256};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::error::GatewayError;
+
+use super::{ACMEChallenge, CertificateStorage};
+
+const ENCRYPTED_PEM_TAG_PREFIX: &str = "NARROWLINK ENCRYPTED "; // followed by the tag of the key
+const SEALED_MAGIC: &[u8] = b"NLE1"; // sealed data starts with it, followed by key id and nonce
+const KEY_ID_LEN: usize = 8;
+
+struct MasterKey {
+    id: [u8; KEY_ID_LEN], // leading bytes of the key digest
+    key: LessSafeKey,
+}
+
+// encrypts the private keys of certificates and ACME accounts before they reach the wrapped
+// storage, certificates stay readable, the first master key encrypts and the others only
+// decrypt what was put before a rotation until it is put again, plaintext keys of an
+// unencrypted storage are read as they are
+pub struct EncryptedCertificateStorage {
+    inner: Arc<dyn CertificateStorage + Sync + Send>,
+    keys: Vec<MasterKey>,
+    rng: SystemRandom,
+}
+
+impl EncryptedCertificateStorage {
+    pub fn new(
+        inner: Arc<dyn CertificateStorage + Sync + Send>,
+        keys: Vec<Vec<u8>>, // 32 bytes each, the current one first
+    ) -> Result<Self, GatewayError> {
+        if keys.is_empty() {
+            return Err(GatewayError::Invalid("storage encryption key"));
+        }
+        let keys = keys
+            .iter()
+            .map(|key| {
+                let mut id = [0; KEY_ID_LEN];
+                id.copy_from_slice(&Sha3_256::digest(key)[..KEY_ID_LEN]);
+                UnboundKey::new(&AES_256_GCM, key)
+                    .map(|key| MasterKey {
+                        id,
+                        key: LessSafeKey::new(key),
+                    })
+                    .map_err(|_| GatewayError::Invalid("storage encryption key"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            inner,
+            keys,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    // each variable holds a base64 encoded key
+    pub fn from_env(
+        inner: Arc<dyn CertificateStorage + Sync + Send>,
+        key_env: &str,
+        previous_key_envs: &[String],
+    ) -> Result<Self, GatewayError> {
+        let keys = std::iter::once(key_env)
+            .chain(previous_key_envs.iter().map(String::as_str))
+            .map(|name| {
+                std::env::var(name)
+                    .ok()
+                    .and_then(|key| {
+                        base64::engine::general_purpose::STANDARD
+                            .decode(key.trim())
+                            .ok()
+                    })
+                    .ok_or(GatewayError::Invalid("storage encryption key"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(inner, keys)
+    }
+
+    fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, GatewayError> {
+        let master_key = &self.keys[0];
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| GatewayError::Other("unable to generate nonce"))?;
+        let mut in_out = plaintext.to_vec();
+        master_key
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad),
+                &mut in_out,
+            )
+            .map_err(|_| GatewayError::Other("unable to encrypt private key"))?;
+        Ok([SEALED_MAGIC, &master_key.id, &nonce, &in_out].concat())
+    }
+
+    fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, GatewayError> {
+        let Some(sealed) = sealed.strip_prefix(SEALED_MAGIC) else {
+            return Ok(sealed.to_vec());
+        };
+        if sealed.len() < KEY_ID_LEN + NONCE_LEN {
+            return Err(GatewayError::Invalid("encrypted private key"));
+        }
+        let (id, sealed) = sealed.split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let master_key = self
+            .keys
+            .iter()
+            .find(|master_key| master_key.id == id)
+            .ok_or(GatewayError::Invalid(
+                "storage encryption key, unknown key id",
+            ))?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|_| GatewayError::Invalid("encrypted private key"))?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = master_key
+            .key
+            .open_in_place(nonce, Aad::from(aad), &mut in_out)
+            .map_err(|_| GatewayError::Invalid("encrypted private key"))?;
+        Ok(plaintext.to_vec())
+    }
+
+    fn encrypt_pems(&self, pems: Vec<Pem>) -> Result<Vec<Pem>, GatewayError> {
+        pems.into_iter()
+            .map(|pem| match pem.tag() {
+                "PRIVATE KEY" | "RSA PRIVATE KEY" | "EC PRIVATE KEY" => Ok(Pem::new(
+                    format!("{}{}", ENCRYPTED_PEM_TAG_PREFIX, pem.tag()),
+                    self.seal(pem.tag().as_bytes(), pem.contents())?,
+                )),
+                _ => Ok(pem),
+            })
+            .collect()
+    }
+
+    fn decrypt_pems(&self, pems: Vec<Pem>) -> Result<Vec<Pem>, GatewayError> {
+        pems.into_iter()
+            .map(
+                |pem| match pem.tag().strip_prefix(ENCRYPTED_PEM_TAG_PREFIX) {
+                    Some(tag) => Ok(Pem::new(tag, self.open(tag.as_bytes(), pem.contents())?)),
+                    None => Ok(pem),
+                },
+            )
+            .collect()
+    }
+
+    // the credentials are opaque, only their serialized key is replaced
+    fn map_credentials_key(
+        account: AccountCredentials,
+        f: impl FnOnce(&[u8]) -> Result<Vec<u8>, GatewayError>,
+    ) -> Result<AccountCredentials, GatewayError> {
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let mut value = serde_json::to_value(account)?;
+        let key = value
+            .get("key_pkcs8")
+            .and_then(|key| key.as_str())
+            .and_then(|key| engine.decode(key).ok())
+            .ok_or(GatewayError::Invalid("account credentials"))?;
+        value["key_pkcs8"] = serde_json::Value::String(engine.encode(f(&key)?));
+        Ok(serde_json::from_value(value)?)
+    }
+
+    fn encrypt_credentials(
+        &self,
+        account: AccountCredentials,
+    ) -> Result<AccountCredentials, GatewayError> {
+        Self::map_credentials_key(account, |key| self.seal(b"key_pkcs8", key))
+    }
+
+    fn decrypt_credentials(
+        &self,
+        account: AccountCredentials,
+    ) -> Result<AccountCredentials, GatewayError> {
+        Self::map_credentials_key(account, |key| self.open(b"key_pkcs8", key))
+    }
+}
+
+#[async_trait]
+impl CertificateStorage for EncryptedCertificateStorage {
+    async fn set_account_credentials(
+        &self,
+        directory: &str,
+        account: AccountCredentials,
+    ) -> Result<(), GatewayError> {
+        self.inner
+            .set_account_credentials(directory, self.encrypt_credentials(account)?)
+            .await
+    }
+    async fn get_account_credentials(
+        &self,
+        directory: &str,
+    ) -> Result<AccountCredentials, GatewayError> {
+        self.decrypt_credentials(self.inner.get_account_credentials(directory).await?)
+    }
+    async fn get_default_account_credentials(&self) -> Result<AccountCredentials, GatewayError> {
+        self.decrypt_credentials(self.inner.get_default_account_credentials().await?)
+    }
+    async fn put(
+        &self,
+        account: &str,
+        domain: &str,
+        acme_account: Option<AccountCredentials>,
+        pems: Vec<Pem>,
+    ) -> Result<(), GatewayError> {
+        let acme_account = acme_account
+            .map(|acme_account| self.encrypt_credentials(acme_account))
+            .transpose()?;
+        self.inner
+            .put(account, domain, acme_account, self.encrypt_pems(pems)?)
+            .await
+    }
+    async fn get_pems(&self, account: &str, domain: &str) -> Result<Vec<Pem>, GatewayError> {
+        self.decrypt_pems(self.inner.get_pems(account, domain).await?)
+    }
+    async fn delete(
+        &self,
+        uid: &str,
+        agent_name: &str,
+        domains: &[String],
+    ) -> Result<(), GatewayError> {
+        self.inner.delete(uid, agent_name, domains).await
+    }
+    async fn get_acme_account_credentials(
+        &self,
+        account: &str,
+        domain: &str,
+    ) -> Option<AccountCredentials> {
+        let acme_account = self
+            .inner
+            .get_acme_account_credentials(account, domain)
+            .await?;
+        self.decrypt_credentials(acme_account).ok()
+    }
+    async fn set_failed(
+        &self,
+        account: &str,
+        domain: &str,
+        retry_after: Duration,
+    ) -> Result<(), GatewayError> {
+        self.inner.set_failed(account, domain, retry_after).await
+    }
+    async fn is_failed(&self, account: &str, domain: &str) -> bool {
+        self.inner.is_failed(account, domain).await
+    }
+    async fn set_pending(&self, account: &str, domain: &str) -> Result<(), GatewayError> {
+        self.inner.set_pending(account, domain).await
+    }
+    async fn is_pending(&self, account: &str, domain: &str) -> bool {
+        self.inner.is_pending(account, domain).await
+    }
+    async fn put_challenge(
+        &self,
+        domain: &str,
+        challenge: &ACMEChallenge,
+    ) -> Result<(), GatewayError> {
+        self.inner.put_challenge(domain, challenge).await
+    }
+    async fn get_challenge(&self, domain: &str) -> Result<ACMEChallenge, GatewayError> {
+        self.inner.get_challenge(domain).await
+    }
+    async fn remove_challenge(&self, domain: &str) -> Result<(), GatewayError> {
+        self.inner.remove_challenge(domain).await
+    }
+    async fn get_challenges(&self) -> Result<Vec<(String, ACMEChallenge)>, GatewayError> {
+        self.inner.get_challenges().await
+    }
+    async fn health(&self) -> Result<(), GatewayError> {
+        self.inner.health().await
+    }
+    async fn list(&self) -> Result<Vec<(String, String, Vec<String>)>, GatewayError> {
+        self.inner.list().await
+    }
+    async fn watch(&self) -> Option<UnboundedReceiver<(String, String)>> {
+        self.inner.watch().await
+    }
+}
@@ -1,7 +1,7 @@
 use std::{
     fmt::Write,
     io::{BufReader, BufWriter},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use askama::Result;
@@ -13,7 +13,7 @@ use tokio::{fs, io::AsyncWriteExt};
 
 use crate::error::GatewayError;
 
-use super::{Certificate, CertificateStorage};
+use super::{ACMEChallenge, Certificate, CertificateStorage};
 
 pub struct CertificateFileStorage {
     path: String,
@@ -25,6 +25,15 @@ impl CertificateFileStorage {
     }
 }
 
+fn domain_hash(domain: &str) -> String {
+    Sha3_256::digest(domain.as_bytes())
+        .iter()
+        .fold(String::new(), |mut acc, x| {
+            let _ = write!(acc, "{:02x}", x);
+            acc
+        })
+}
+
 #[async_trait]
 impl CertificateStorage for CertificateFileStorage {
     async fn get_default_account_credentials(&self) -> Result<AccountCredentials, GatewayError> {
@@ -32,15 +41,25 @@ impl CertificateStorage for CertificateFileStorage {
         let defaul_account_file = std::fs::File::open(default_account_path)?;
         serde_json::de::from_reader(BufReader::new(defaul_account_file)).map_err(|e| e.into())
     }
-    async fn set_default_account_credentials(
+    async fn get_account_credentials(
+        &self,
+        directory: &str,
+    ) -> Result<AccountCredentials, GatewayError> {
+        let account_path = format!("{}/accounts/{}.account", self.path, domain_hash(directory));
+        let account_file = std::fs::File::open(account_path)?;
+        serde_json::de::from_reader(BufReader::new(account_file)).map_err(|e| e.into())
+    }
+    async fn set_account_credentials(
         &self,
+        directory: &str,
         account: AccountCredentials,
     ) -> Result<(), GatewayError> {
-        fs::create_dir_all(&self.path).await?;
-        let default_account_path = format!("{}/default.account", self.path);
+        let accounts_path = format!("{}/accounts", self.path);
+        fs::create_dir_all(&accounts_path).await?;
+        let account_path = format!("{}/{}.account", accounts_path, domain_hash(directory));
 
         Ok(serde_json::ser::to_writer(
-            BufWriter::new(std::fs::File::create(default_account_path)?),
+            BufWriter::new(std::fs::File::create(account_path)?),
             &account,
         )?)
     }
@@ -53,13 +72,7 @@ impl CertificateStorage for CertificateFileStorage {
     ) -> Result<(), GatewayError> {
         let base_path = format!("{}/{}", self.path, account);
         fs::create_dir_all(&base_path).await?;
-        let domain_hash =
-            Sha3_256::digest(domain.as_bytes())
-                .iter()
-                .fold(String::new(), |mut acc, x| {
-                    let _ = write!(acc, "{:02x}", x);
-                    acc
-                });
+        let domain_hash = domain_hash(domain);
         if let Some(acme_account_credentials) = acme_account_credentials {
             let acme_account_path = format!("{}/{}.account", base_path, domain_hash);
 
@@ -67,7 +80,7 @@ impl CertificateStorage for CertificateFileStorage {
                 BufWriter::new(std::fs::File::create(acme_account_path)?),
                 &acme_account_credentials,
             )
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
         }
         let pem_path = format!("{}/{}.pem", base_path, domain_hash);
 
@@ -84,76 +97,87 @@ impl CertificateStorage for CertificateFileStorage {
 
         Ok(())
     }
-    async fn get(
+    async fn get_pems(&self, account: &str, domain: &str) -> Result<Vec<Pem>, GatewayError> {
+        let pem_path = format!("{}/{}/{}.pem", self.path, account, domain_hash(domain));
+        Ok(pem::parse_many(fs::read_to_string(pem_path).await?)?)
+    }
+    async fn delete(
         &self,
-        account: &str,
-        domain: &str,
-    ) -> Result<(Certificate, Option<AccountCredentials>), GatewayError> {
-        let domain_hash =
-            Sha3_256::digest(domain.as_bytes())
-                .iter()
-                .fold(String::new(), |mut acc, x| {
-                    let _ = write!(acc, "{:02x}", x);
-                    acc
-                });
-        let base_path = format!("{}/{}", self.path, account);
-        let acme_account_path = format!("{}/{}.account", base_path, domain_hash);
-        let pem_path = format!("{}/{}.pem", base_path, domain_hash);
-
-        let cert =
-            Certificate::from_pem_vec(pem::parse_many(fs::read_to_string(pem_path).await?)?)?;
-
-        let acme_account = if cert.renew_needed() {
-            if let Ok(acme_account_file) = std::fs::File::open(acme_account_path) {
-                serde_json::de::from_reader(BufReader::new(acme_account_file)).ok()
-            } else {
-                None
+        uid: &str,
+        _agent_name: &str,
+        domains: &[String],
+    ) -> Result<(), GatewayError> {
+        let base_path = format!("{}/{}", self.path, uid);
+        // every file is first renamed into a tombstone, which is atomic within the directory, a
+        // failed rename moves the ones before it back so the domains stay as they were
+        let tombstone = format!("{}/.deleted-{}", base_path, uuid::Uuid::new_v4());
+        fs::create_dir_all(&tombstone).await?;
+        let mut moved = Vec::new();
+        let mut found = false;
+        let mut res = Ok(());
+        'domains: for domain in domains {
+            let domain_hash = domain_hash(domain);
+            for kind in ["pem", "account", "failed", "pending"] {
+                let file_name = format!("{}.{}", domain_hash, kind);
+                let from = format!("{}/{}", base_path, file_name);
+                let to = format!("{}/{}", tombstone, file_name);
+                match fs::rename(&from, &to).await {
+                    Ok(()) => {
+                        found |= kind == "pem";
+                        moved.push((from, to));
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        res = Err(e);
+                        break 'domains;
+                    }
+                }
             }
-        } else {
-            None
-        };
-
-        Ok((cert, acme_account))
+        }
+        if let Err(e) = res {
+            for (from, to) in moved.iter().rev() {
+                _ = fs::rename(to, from).await;
+            }
+            _ = fs::remove_dir_all(&tombstone).await;
+            return Err(e.into());
+        }
+        // nothing refers to the tombstone anymore, a leftover is only disk space
+        _ = fs::remove_dir_all(&tombstone).await;
+        found.then_some(()).ok_or(GatewayError::CertificateNotFound)
     }
     async fn get_acme_account_credentials(
         &self,
         account: &str,
         domain: &str,
     ) -> Option<AccountCredentials> {
-        let domain_hash =
-            Sha3_256::digest(domain.as_bytes())
-                .iter()
-                .fold(String::new(), |mut acc, x| {
-                    let _ = write!(acc, "{:02x}", x);
-                    acc
-                });
+        let domain_hash = domain_hash(domain);
         let acme_account_path = format!("{}/{}/{}.account", self.path, account, domain_hash);
         std::fs::File::open(acme_account_path)
             .ok()
             .and_then(|f| serde_json::de::from_reader(BufReader::new(f)).ok())
     }
-    async fn set_failed(&self, account: &str, domain: &str) -> Result<(), GatewayError> {
-        let domain_hash =
-            Sha3_256::digest(domain.as_bytes())
-                .iter()
-                .fold(String::new(), |mut acc, x| {
-                    let _ = write!(acc, "{:02x}", x);
-                    acc
-                });
+    async fn set_failed(
+        &self,
+        account: &str,
+        domain: &str,
+        retry_after: Duration,
+    ) -> Result<(), GatewayError> {
+        let domain_hash = domain_hash(domain);
         let base_path = format!("{}/{}", self.path, account);
         fs::create_dir_all(&base_path).await?;
         let failed_path = format!("{}/{}.failed", base_path, domain_hash);
         let pending_path = format!("{}/{}.pending", base_path, domain_hash);
-        Ok(fs::rename(pending_path, failed_path).await.map(|_| ())?)
+        let retry_ts = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_add(retry_after)
+            .as_secs();
+        fs::write(failed_path, retry_ts.to_string()).await?;
+        _ = fs::remove_file(pending_path).await;
+        Ok(())
     }
     async fn is_failed(&self, account: &str, domain: &str) -> bool {
-        let domain_hash =
-            Sha3_256::digest(domain.as_bytes())
-                .iter()
-                .fold(String::new(), |mut acc, x| {
-                    let _ = write!(acc, "{:02x}", x);
-                    acc
-                });
+        let domain_hash = domain_hash(domain);
         let failed_path = format!("{}/{}/{}.failed", self.path, account, domain_hash);
         let ts = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -162,17 +186,11 @@ impl CertificateStorage for CertificateFileStorage {
         std::fs::read_to_string(failed_path)
             .ok()
             .and_then(|v| v.parse::<u64>().ok())
-            .filter(|v| *v + 60 * 60 > ts) // 1 hour
+            .filter(|v| *v > ts) // retry time not reached
             .is_some()
     }
     async fn set_pending(&self, account: &str, domain: &str) -> Result<(), GatewayError> {
-        let domain_hash =
-            Sha3_256::digest(domain.as_bytes())
-                .iter()
-                .fold(String::new(), |mut acc, x| {
-                    let _ = write!(acc, "{:02x}", x);
-                    acc
-                });
+        let domain_hash = domain_hash(domain);
         let base_path = format!("{}/{}", self.path, account);
         let pending_path = format!("{}/{}.pending", base_path, domain_hash);
         let ts = SystemTime::now()
@@ -183,13 +201,7 @@ impl CertificateStorage for CertificateFileStorage {
         Ok(fs::write(pending_path, ts.to_string()).await.map(|_| ())?)
     }
     async fn is_pending(&self, account: &str, domain: &str) -> bool {
-        let domain_hash =
-            Sha3_256::digest(domain.as_bytes())
-                .iter()
-                .fold(String::new(), |mut acc, x| {
-                    let _ = write!(acc, "{:02x}", x);
-                    acc
-                });
+        let domain_hash = domain_hash(domain);
         let pending_path = format!("{}/{}/{}.pending", self.path, account, domain_hash);
         let ts = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -201,4 +213,92 @@ impl CertificateStorage for CertificateFileStorage {
             .filter(|v| *v + 120 > ts) // 120 seconds
             .is_some()
     }
+    async fn put_challenge(
+        &self,
+        domain: &str,
+        challenge: &ACMEChallenge,
+    ) -> Result<(), GatewayError> {
+        let domain_hash = domain_hash(domain);
+        let base_path = format!("{}/challenges", self.path);
+        fs::create_dir_all(&base_path).await?;
+        let challenge_path = format!("{}/{}.challenge", base_path, domain_hash);
+        Ok(fs::write(challenge_path, serde_json::to_vec(&(domain, challenge))?).await?)
+    }
+    async fn get_challenge(&self, domain: &str) -> Result<ACMEChallenge, GatewayError> {
+        let domain_hash = domain_hash(domain);
+        let challenge_path = format!("{}/challenges/{}.challenge", self.path, domain_hash);
+        let (_, challenge): (String, ACMEChallenge) =
+            serde_json::from_slice(&fs::read(challenge_path).await?)?;
+        Ok(challenge)
+    }
+    async fn remove_challenge(&self, domain: &str) -> Result<(), GatewayError> {
+        let domain_hash = domain_hash(domain);
+        let challenge_path = format!("{}/challenges/{}.challenge", self.path, domain_hash);
+        Ok(fs::remove_file(challenge_path).await?)
+    }
+    async fn get_challenges(&self) -> Result<Vec<(String, ACMEChallenge)>, GatewayError> {
+        let mut challenges = Vec::new();
+        let Ok(mut entries) = fs::read_dir(format!("{}/challenges", self.path)).await else {
+            return Ok(challenges);
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if entry
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "challenge")
+            {
+                if let Ok(challenge) = serde_json::from_slice(&fs::read(entry.path()).await?) {
+                    challenges.push(challenge);
+                }
+            }
+        }
+        Ok(challenges)
+    }
+    async fn list(&self) -> Result<Vec<(String, String, Vec<String>)>, GatewayError> {
+        let mut certificates = Vec::new();
+        let Ok(mut accounts) = fs::read_dir(&self.path).await else {
+            return Ok(certificates);
+        };
+        while let Some(account) = accounts.next_entry().await? {
+            let Some(account_name) = account.file_name().to_str().map(|name| name.to_owned())
+            else {
+                continue;
+            };
+            if account_name == "challenges"
+                || account_name == "accounts"
+                || !account.file_type().await?.is_dir()
+            {
+                continue;
+            }
+            let mut entries = fs::read_dir(account.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension().map_or(true, |ext| ext != "pem") {
+                    continue;
+                }
+                let Ok(info) = Certificate::info_from_pem_vec(&pem::parse_many(
+                    fs::read_to_string(&path).await?,
+                )?) else {
+                    continue;
+                };
+                let domains = info.domains;
+                // files are named by the hash of the domain they were put for
+                let Some(domain) = domains.iter().find(|domain| {
+                    path.file_stem()
+                        .is_some_and(|stem| stem.to_str() == Some(&domain_hash(domain)))
+                }) else {
+                    continue;
+                };
+                certificates.push((account_name.clone(), domain.to_owned(), domains.clone()));
+            }
+        }
+        Ok(certificates)
+    }
+    async fn health(&self) -> Result<(), GatewayError> {
+        fs::create_dir_all(&self.path).await?;
+        if fs::metadata(&self.path).await?.permissions().readonly() {
+            return Err(GatewayError::Other("Certificate storage is read only"));
+        }
+        Ok(())
+    }
 }
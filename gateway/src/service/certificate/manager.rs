@@ -1,42 +1,234 @@
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
-    time::Duration,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use instant_acme::Account;
+use instant_acme::{Account, AccountCredentials, RevocationReason};
+use ring::rand::{SecureRandom, SystemRandom};
 use rustls::{PrivateKey, ServerConfig};
-use tracing::{debug, error, instrument, span, trace, warn, Instrument, Span};
+use serde::Deserialize;
+use tracing::{debug, error, info, instrument, span, trace, warn, Instrument, Span};
 
 use tokio::{
     sync::{
+        broadcast,
         mpsc::{self, UnboundedSender},
-        RwLock,
+        RwLock, Semaphore,
     },
+    task::JoinSet,
     time,
 };
 
 use super::{
     acme::{ACMEChallenge, Acme},
-    ACMEChallengeType, Certificate, CertificateStorage,
+    clock::{Clock, SystemClock},
+    ocsp, ACMEChallengeType, Certificate, CertificateInfo, CertificateStorage, DnsProvider,
+    HttpChallengeHandler, KeyType, SessionResumption, TlsPolicy, DEFAULT_RENEW_BEFORE_EXPIRY,
 };
 use crate::error::GatewayError;
 
+const HTTP_CHALLENGE_REQUESTS: u32 = 20; // per ip and window
+const HTTP_CHALLENGE_WINDOW: Duration = Duration::from_secs(10);
+const HTTP_CHALLENGE_TRACKED_IPS: usize = 1024; // expired windows are pruned past this
+const PRELOADED_AGENT_NAME: &str = "<preloaded>"; // holds stored certificates until an agent loads them
+
+// the uid and agent name the certificates of the gateway's own domains are kept under
+pub const GATEWAY_UID: &str = "main";
+pub const GATEWAY_AGENT_NAME: &str = "self";
+
 pub enum CertificateServiceMessage {
-    Load(String, String, Vec<String>),
+    Load(String, String, Vec<String>, Option<KeyType>), // (uid, agent_name, domains, key type override)
     Unload(String, String),
+    UnloadDomains(String, String, Vec<String>), // (uid, agent_name, domains)
+    Renew(String, String),                      // (uid, agent_name)
+    Account(String, String, String),            // (uid, agent_name, acme email)
+    Directory(String, String, String),          // (uid, agent_name, acme directory url)
+    ChallengeType(String, String, ACMEChallengeType), // (uid, agent_name, challenge type override)
+    Issued(String, String, String),             // (uid, agent_name, domain), sent by issuance tasks
+    IssuancePending(String, String, String),    // (uid, agent_name, domain), sent by issuance tasks
+    Shutdown,
+}
+
+#[derive(Debug, Clone)]
+pub enum CertificateEvent {
+    Loaded(String, String, Vec<String>), // (uid, agent_name, domains), as are the others
+    Renewed(String, String, Vec<String>),
+    IssuanceFailed(String, String, Vec<String>),
+    Unloaded(String, String, Vec<String>),
+    ImportedExpiring(String, String, Vec<String>), // imported certificates are never renewed
+    ExpiryWarning(String, String, Vec<String>, i64), // renewal keeps failing, days until expiry
+}
+
+#[derive(Debug, Clone)]
+pub struct CertificateManagerConfig {
+    pub renew_check_interval: Duration,
+    pub renew_before_expiry: Duration,
+    pub issue_retry_base: Duration,
+    pub issue_retry_cap: Duration,
+    pub ocsp_refresh_interval: Duration,
+    pub challenge_poll_tries: u8,
+    pub challenge_poll_interval: Duration, // doubles after every poll
+    pub max_concurrent_issuances: usize, // further orders queue to stay within the CA's rate limits
+    pub key_type: KeyType,
+    pub staging: bool,
+    pub preload: bool, // load every stored certificate on start, before agents connect
+    pub max_cached: Option<usize>, // domains kept in memory, least recently served ones are evicted
+    pub load_concurrency: usize, // certificates read from storage at once by a preload or a load of several domains
+    pub agent_selection: AgentSelection,
+    pub session_resumption: SessionResumption, // applied to the config of every domain
+    pub tls_policy: TlsPolicy,                 // every config served, challenges included
+    pub expiry_warning_days: Vec<u64>, // warned once each while a due renewal has not succeeded
+    pub max_connection_age: Option<Duration>, // connections this old are drained once their certificate renews
+    pub directories: Vec<(String, Option<(String, String)>)>, // agents may select, (directory url, (eab kid, eab hmac key))
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for CertificateManagerConfig {
+    fn default() -> Self {
+        Self {
+            renew_check_interval: Duration::from_secs(60 * 60 * 6), // every six hours
+            renew_before_expiry: DEFAULT_RENEW_BEFORE_EXPIRY,
+            issue_retry_base: Duration::from_secs(60), // one minute
+            issue_retry_cap: Duration::from_secs(60 * 60), // one hour
+            ocsp_refresh_interval: Duration::from_secs(60 * 10), // ten minutes
+            challenge_poll_tries: 5,
+            challenge_poll_interval: Duration::from_secs(10),
+            max_concurrent_issuances: 2,
+            key_type: KeyType::default(),
+            staging: false,
+            preload: false,
+            max_cached: None,
+            load_concurrency: 8,
+            agent_selection: AgentSelection::default(),
+            session_resumption: SessionResumption::default(),
+            tls_policy: TlsPolicy::default(),
+            expiry_warning_days: vec![30, 14, 7],
+            max_connection_age: None,
+            directories: Vec::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+// whose certificate is served when agents of several accounts publish the same domain, the
+// agents of one account share theirs
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum AgentSelection {
+    #[default]
+    Primary, // the earliest loaded, the next one takes over once it is unloaded
+    RoundRobin,
+    Random,
+}
+
+struct AgentAccount {
+    email: String,
+    account: Option<(Account, String, String)>, // (account, credentials json, directory url), registered on first issuance
+}
+
+struct IssuanceBackoff {
+    attempts: u32,
+    retry_at: time::Instant,
+    domains: HashSet<String>,
+}
+
+// the certificates of a domain, at most one from ACME and imported ones of other key types,
+// their config lets rustls pick the key type per handshake
+struct DomainCertificates {
+    certificates: Vec<Certificate>,
+    config: Arc<ServerConfig>,
+    loaded: u64, // tick of the serve counter when first loaded, orders the selection
+    last_served: AtomicU64, // tick of the store's serve counter
+}
+
+impl DomainCertificates {
+    fn new(
+        certificate: Certificate,
+        tick: u64,
+        session_resumption: &SessionResumption,
+        tls_policy: &TlsPolicy,
+    ) -> Result<Self, GatewayError> {
+        Ok(Self {
+            config: session_resumption.applied(certificate.get_config(tls_policy)?),
+            certificates: vec![certificate],
+            loaded: tick,
+            last_served: AtomicU64::new(tick),
+        })
+    }
+    // replaces the certificate of the same key type. ACME certificates are stored one per domain
+    // and issued with the configured key_type, so one of another type replaces the previous one
+    // too, only imported certificates are served next to a certificate of another key type
+    fn put(
+        &mut self,
+        certificate: Certificate,
+        session_resumption: &SessionResumption,
+        tls_policy: &TlsPolicy,
+    ) {
+        let mut certificates = std::mem::take(&mut self.certificates);
+        certificates.retain(|current| {
+            current.algorithm() != certificate.algorithm()
+                && (current.is_imported() || certificate.is_imported())
+        });
+        certificates.push(certificate);
+        self.set(certificates, session_resumption, tls_policy);
+    }
+    fn set(
+        &mut self,
+        mut certificates: Vec<Certificate>,
+        session_resumption: &SessionResumption,
+        tls_policy: &TlsPolicy,
+    ) {
+        // ECDSA is preferred whenever the client supports it
+        certificates.sort_by_key(|certificate| match certificate.algorithm() {
+            rustls::SignatureAlgorithm::ECDSA => 0,
+            rustls::SignatureAlgorithm::ED25519 => 1,
+            _ => 2,
+        });
+        if let Ok(config) = Certificate::negotiated_config(&certificates, tls_policy) {
+            self.config = session_resumption.applied(config);
+        }
+        self.certificates = certificates;
+    }
 }
 
 pub struct CertificateStore {
-    certificates: HashMap<(String, String), Certificate>, // (uid, domain) -> certificate
-    domain_map: HashMap<String, HashSet<(String, String)>>, // domain -> (uid, agent_name)
+    certificates: HashMap<(String, String), DomainCertificates>, // (uid, domain) -> certificates
+    domain_map: HashMap<String, HashSet<(String, String)>>,      // domain -> (uid, agent_name)
+    evicted: HashMap<String, HashSet<(String, String)>>, // domain -> (uid, agent_name), reloaded from storage when served again
+    max_cached: Option<usize>,
+    served: AtomicU64,
+    selection: AgentSelection,
+    selected: AtomicUsize, // round robin counter
+    random: SystemRandom,
+    session_resumption: SessionResumption,
+    tls_policy: TlsPolicy,
+    clock: Arc<dyn Clock>,
 }
 
 impl CertificateStore {
-    pub fn new() -> Self {
+    pub fn new(
+        max_cached: Option<usize>,
+        selection: AgentSelection,
+        session_resumption: SessionResumption,
+        tls_policy: TlsPolicy,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         Self {
             certificates: HashMap::new(),
             domain_map: HashMap::new(),
+            evicted: HashMap::new(),
+            max_cached,
+            served: AtomicU64::new(0),
+            selection,
+            selected: AtomicUsize::new(0),
+            random: SystemRandom::new(),
+            session_resumption,
+            tls_policy,
+            clock,
         }
     }
     pub fn insert(
@@ -45,21 +237,108 @@ impl CertificateStore {
         agent_name: String,
         domain: &str,
         certificate: Certificate,
-    ) {
-        self.certificates
-            .insert((uid.clone(), domain.to_owned()), certificate);
+    ) -> Result<(), GatewayError> {
+        let tick = self.served.fetch_add(1, Ordering::Relaxed);
+        match self.certificates.get_mut(&(uid.clone(), domain.to_owned())) {
+            Some(current) => current.put(certificate, &self.session_resumption, &self.tls_policy),
+            None => {
+                self.certificates.insert(
+                    (uid.clone(), domain.to_owned()),
+                    DomainCertificates::new(
+                        certificate,
+                        tick,
+                        &self.session_resumption,
+                        &self.tls_policy,
+                    )?,
+                );
+            }
+        }
 
-        if let Some(agent_set) = self.domain_map.get_mut(domain) {
-            agent_set.insert((uid.clone(), agent_name.clone()));
-        } else {
-            let mut agent_set = HashSet::new();
-            agent_set.insert((uid.clone(), agent_name.clone()));
-            self.domain_map.insert(domain.to_string(), agent_set);
+        // the other agents of the account sharing an evicted certificate are served again too
+        let mut reloaded = HashSet::new();
+        if let Some(evicted_set) = self.evicted.get_mut(domain) {
+            evicted_set.retain(|(set_uid, set_agent)| {
+                set_uid != &uid || !reloaded.insert((set_uid.clone(), set_agent.clone()))
+            });
+            if evicted_set.is_empty() {
+                self.evicted.remove(domain);
+            }
+        }
+        let agent_set = self.domain_map.entry(domain.to_string()).or_default();
+        agent_set.extend(reloaded);
+        if agent_name != PRELOADED_AGENT_NAME {
+            agent_set.remove(&(uid.clone(), PRELOADED_AGENT_NAME.to_owned()));
+        }
+        agent_set.insert((uid.clone(), agent_name.clone()));
+        self.evict(&(uid, domain.to_owned()));
+        Ok(())
+    }
+    // drops the least recently served domains over the limit, `keep` being the one just loaded
+    fn evict(&mut self, keep: &(String, String)) {
+        let Some(max_cached) = self.max_cached else {
+            return;
+        };
+        while self.certificates.len() > max_cached {
+            let Some(key) = self
+                .certificates
+                .iter()
+                .filter(|(key, _)| *key != keep)
+                .min_by_key(|(_, domain_certificates)| {
+                    domain_certificates.last_served.load(Ordering::Relaxed)
+                })
+                .map(|(key, _)| key.clone())
+            else {
+                return;
+            };
+            let (uid, domain) = &key;
+            self.certificates.remove(&key);
+            if let Some(agent_set) = self.domain_map.get_mut(domain) {
+                let owners = agent_set
+                    .iter()
+                    .filter(|(set_uid, _)| set_uid == uid)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for owner in owners {
+                    agent_set.remove(&owner);
+                    self.evicted
+                        .entry(domain.clone())
+                        .or_default()
+                        .insert(owner);
+                }
+                if agent_set.is_empty() {
+                    self.domain_map.remove(domain);
+                }
+            }
+            debug!("certificate for {} evicted from memory", domain);
         }
     }
-    pub fn remove(&mut self, uid: String, agent_name: String) {
+    // swaps the certificate in place, the domain map is left untouched so lookups never miss
+    pub fn update(
+        &mut self,
+        uid: &str,
+        domain: &str,
+        certificate: Certificate,
+    ) -> Result<(), GatewayError> {
+        let Some(current) = self
+            .certificates
+            .get_mut(&(uid.to_owned(), domain.to_owned()))
+        else {
+            return Err(GatewayError::CertificateNotFound);
+        };
+        current.put(certificate, &self.session_resumption, &self.tls_policy);
+        Ok(())
+    }
+    pub fn remove_domains(&mut self, uid: &str, agent_name: &str, domains: &[String]) {
+        let (uid, agent_name) = (uid.to_owned(), agent_name.to_owned());
+        for (domain, evicted_set) in self.evicted.iter_mut() {
+            if domains.contains(domain) {
+                evicted_set.remove(&(uid.clone(), agent_name.clone()));
+            }
+        }
+        self.evicted.retain(|_, v| !v.is_empty());
         for (domain, agent_set) in self.domain_map.iter_mut() {
-            if agent_set.remove(&(uid.clone(), agent_name.clone()))
+            if domains.contains(domain)
+                && agent_set.remove(&(uid.clone(), agent_name.clone()))
                 && !agent_set.iter().any(|(set_uid, _)| set_uid == &uid)
             {
                 // if agent_set.remove(&(uid.clone(), agent_name.clone())) {
@@ -71,28 +350,169 @@ impl CertificateStore {
         self.domain_map.retain(|_, v| !v.is_empty());
         trace!("domain map: {:?}", self.domain_map);
     }
+    // exact match first, then the wildcard of the immediate parent (`*.example.com` serves
+    // `api.example.com` but not `a.b.example.com`)
     pub fn get_config(&self, domain: &str) -> Option<Arc<ServerConfig>> {
-        Some(
-            self.certificates
-                .get(
-                    &self
-                        .domain_map
-                        .get(domain)?
-                        .iter()
-                        .next()
-                        .map(|(uid, _agent)| (uid.to_owned(), domain.to_string()))?,
-                )?
-                .config
-                .clone(),
-        )
+        self.get_exact_config(domain)
+            .or_else(|| self.get_exact_config(&parent_wildcard(domain)?))
+    }
+    fn get_exact_config(&self, domain: &str) -> Option<Arc<ServerConfig>> {
+        // one entry per account, ordered by when it was loaded
+        let mut candidates = self
+            .domain_map
+            .get(domain)?
+            .iter()
+            .filter_map(|(uid, _agent)| {
+                self.certificates.get(&(uid.to_owned(), domain.to_string()))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|domain_certificates| domain_certificates.loaded);
+        candidates.dedup_by_key(|domain_certificates| domain_certificates.loaded);
+        let index = match self.selection {
+            _ if candidates.len() < 2 => 0,
+            AgentSelection::Primary => 0,
+            AgentSelection::RoundRobin => self.selected.fetch_add(1, Ordering::Relaxed),
+            AgentSelection::Random => {
+                let mut random = [0; 4];
+                self.random.fill(&mut random).ok()?;
+                u32::from_le_bytes(random) as usize
+            }
+        };
+        let domain_certificates = candidates.get(index % candidates.len().max(1))?;
+        domain_certificates.last_served.store(
+            self.served.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        Some(domain_certificates.config.clone())
+    }
+    // the agents whose certificate for the domain, or its wildcard, was evicted
+    pub fn evicted(&self, domain: &str) -> Vec<(String, String, String)> {
+        [Some(domain.to_owned()), parent_wildcard(domain)]
+            .into_iter()
+            .flatten()
+            .filter_map(|domain| Some((self.evicted.get(&domain)?, domain)))
+            .flat_map(|(evicted_set, domain)| {
+                evicted_set.iter().map(move |(uid, agent_name)| {
+                    (uid.to_owned(), agent_name.to_owned(), domain.clone())
+                })
+            })
+            .collect()
+    }
+    // evicted domains included, so unloading an agent also forgets them
+    pub fn domains(&self, uid: &str, agent_name: &str) -> Vec<String> {
+        let agent = (uid.to_owned(), agent_name.to_owned());
+        self.domain_map
+            .iter()
+            .chain(self.evicted.iter())
+            .filter(|(_, agent_set)| agent_set.contains(&agent))
+            .map(|(domain, _)| domain.to_owned())
+            .collect()
+    }
+    pub fn certificate_count(&self) -> usize {
+        self.certificates
+            .values()
+            .map(|domain_certificates| domain_certificates.certificates.len())
+            .sum()
+    }
+    pub fn agents(&self, uid: &str, domain: &str) -> Vec<String> {
+        self.domain_map
+            .get(domain)
+            .map(|agent_set| {
+                agent_set
+                    .iter()
+                    .filter(|(set_uid, _)| set_uid == uid)
+                    .map(|(_, agent_name)| agent_name.to_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    pub fn certificate_info(&self, uid: &str, agent_name: &str) -> Vec<CertificateInfo> {
+        let agent = (uid.to_owned(), agent_name.to_owned());
+        self.domain_map
+            .iter()
+            .filter(|(_, agent_set)| agent_set.contains(&agent))
+            .filter_map(|(domain, _)| self.certificates.get(&(uid.to_owned(), domain.to_owned())))
+            .flat_map(|domain_certificates| domain_certificates.certificates.iter())
+            .map(|cert| cert.info())
+            .collect()
+    }
+    pub fn ocsp_refresh_needed(&self) -> Vec<(String, String, Vec<rustls::Certificate>)> {
+        let now = self.clock.now();
+        let mut list = Vec::new();
+        for ((uid, domain), domain_certificates) in self.certificates.iter() {
+            for cert in domain_certificates.certificates.iter() {
+                if cert.ocsp_refresh_needed(now) {
+                    list.push((
+                        uid.to_owned(),
+                        domain.to_owned(),
+                        cert.certificate_chain().to_vec(),
+                    ));
+                }
+            }
+        }
+        list
+    }
+    // staples the response to the certificate of the domain with the given chain
+    pub fn set_ocsp(
+        &mut self,
+        uid: &str,
+        domain: &str,
+        certificate_chain: &[rustls::Certificate],
+        response: Vec<u8>,
+        next_update: std::time::SystemTime,
+    ) -> Result<(), GatewayError> {
+        let Some(domain_certificates) = self
+            .certificates
+            .get_mut(&(uid.to_owned(), domain.to_owned()))
+        else {
+            return Err(GatewayError::CertificateNotFound);
+        };
+        let Some(cert) = domain_certificates
+            .certificates
+            .iter_mut()
+            .find(|cert| cert.certificate_chain() == certificate_chain)
+        else {
+            return Err(GatewayError::CertificateNotFound);
+        };
+        *cert = cert.with_ocsp(response, next_update)?;
+        let certificates = std::mem::take(&mut domain_certificates.certificates);
+        domain_certificates.set(certificates, &self.session_resumption, &self.tls_policy);
+        Ok(())
+    }
+    // the agents of the certificates expiring within `threshold`
+    pub fn renew_needed(&self, threshold: Duration) -> Vec<(String, String, String)> {
+        self.expiring(false, threshold)
     }
-    pub fn renew_needed(&self) -> Vec<(String, String, String)> {
+    // of the earliest expiring renewable certificate of the domain
+    pub fn days_until_expiry(&self, uid: &str, domain: &str) -> Option<i64> {
+        let now = self.clock.now();
+        self.certificates
+            .get(&(uid.to_owned(), domain.to_owned()))?
+            .certificates
+            .iter()
+            .filter(|cert| !cert.is_imported())
+            .map(|cert| cert.days_until_expiry(now))
+            .min()
+    }
+    pub fn imported_expiring(&self, threshold: Duration) -> Vec<(String, String, String)> {
+        self.expiring(true, threshold)
+    }
+    fn expiring(&self, imported: bool, threshold: Duration) -> Vec<(String, String, String)> {
+        let now = self.clock.now();
         let mut list_of_agents = Vec::new();
-        for ((uid, domain), cert) in self.certificates.iter() {
-            if cert.renew_needed() {
+        for ((uid, domain), domain_certificates) in self.certificates.iter() {
+            if domain_certificates
+                .certificates
+                .iter()
+                .any(|cert| cert.is_imported() == imported && cert.renew_needed_at(now, threshold))
+            {
                 // if let Some(domains) = cert.domains() {
                 if let Some(agents) = self.domain_map.get(domain) {
-                    for (uid, agent_name) in agents.iter().filter(|(u, _)| u == uid) {
+                    // preloaded certificates are renewed once an agent loads them
+                    for (uid, agent_name) in agents
+                        .iter()
+                        .filter(|(u, name)| u == uid && name != PRELOADED_AGENT_NAME)
+                    {
                         list_of_agents.push((
                             uid.to_owned(),
                             agent_name.to_owned(),
@@ -106,14 +526,33 @@ impl CertificateStore {
     }
 }
 
+// (email, challenge type, directory url, (eab kid, eab hmac key))
+pub type AcmeInfo = (String, ACMEChallengeType, String, Option<(String, String)>);
+
 pub struct CertificateManager {
     certificate_store: Arc<RwLock<CertificateStore>>,
     acme_configurations: Arc<RwLock<HashMap<String, ACMEChallenge>>>,
+    http_challenge_requests: Arc<std::sync::Mutex<HashMap<IpAddr, (Instant, u32)>>>, // ip -> (window start, requests)
+    issuance_backoff: Arc<RwLock<HashMap<(String, String), IssuanceBackoff>>>, // (uid, agent_name) -> backoff
+    agent_key_types: Arc<RwLock<HashMap<(String, String), KeyType>>>, // (uid, agent_name) -> key type
+    agent_challenge_types: Arc<RwLock<HashMap<(String, String), ACMEChallengeType>>>, // (uid, agent_name) -> challenge type
+    agent_accounts: Arc<RwLock<HashMap<(String, String), AgentAccount>>>, // (uid, agent_name) -> account
+    agent_directories: Arc<RwLock<HashMap<(String, String), String>>>, // (uid, agent_name) -> directory url
     acme_type: Option<ACMEChallengeType>,
-    acme_account: Option<Account>,
+    acme_email: Option<String>,
+    acme_account: Arc<RwLock<Option<Account>>>, // registered with acme_directory
+    directory_accounts: Arc<RwLock<HashMap<String, Account>>>, // directory url -> account, registered on first issuance
+    key_rotation: Arc<RwLock<()>>, // held by issuances, the key is rotated once none is in progress
+    issuance_permits: Arc<Semaphore>,
+    acme_directory: Option<(String, Option<(String, String)>)>, // (directory url, (eab kid, eab hmac key))
     storage: Arc<dyn CertificateStorage + Sync + Send>,
+    dns_provider: Option<Arc<dyn DnsProvider + Sync + Send>>,
+    http_challenge_handler: Option<Arc<dyn HttpChallengeHandler + Sync + Send>>,
+    config: CertificateManagerConfig,
+    event_sender: Option<UnboundedSender<CertificateEvent>>,
+    renewals: broadcast::Sender<String>, // renewed domains, for draining connections on their old certificates
     sender: UnboundedSender<CertificateServiceMessage>,
-    handler: Option<tokio::task::JoinHandle<()>>,
+    handler: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>, // only set in the instance that spawned it
 }
 
 impl Clone for CertificateManager {
@@ -122,126 +561,388 @@ impl Clone for CertificateManager {
             certificate_store: self.certificate_store.clone(),
             // configurations: self.configurations.clone(),
             acme_configurations: self.acme_configurations.clone(),
+            http_challenge_requests: self.http_challenge_requests.clone(),
+            issuance_backoff: self.issuance_backoff.clone(),
+            agent_key_types: self.agent_key_types.clone(),
+            agent_challenge_types: self.agent_challenge_types.clone(),
+            agent_accounts: self.agent_accounts.clone(),
+            agent_directories: self.agent_directories.clone(),
             acme_type: self.acme_type.clone(),
+            acme_email: self.acme_email.clone(),
             acme_account: self.acme_account.clone(),
+            directory_accounts: self.directory_accounts.clone(),
+            key_rotation: self.key_rotation.clone(),
+            issuance_permits: self.issuance_permits.clone(),
+            acme_directory: self.acme_directory.clone(),
             storage: self.storage.clone(),
+            dns_provider: self.dns_provider.clone(),
+            http_challenge_handler: self.http_challenge_handler.clone(),
+            config: self.config.clone(),
+            event_sender: self.event_sender.clone(),
+            renewals: self.renewals.clone(),
             sender: self.sender.clone(),
-            handler: None,
+            handler: std::sync::Mutex::new(None),
         }
     }
 }
 
 impl CertificateManager {
-    #[instrument(name = "certificate_manager::new", skip(storage))]
+    #[instrument(
+        name = "certificate_manager::new",
+        skip(storage, dns_provider, http_challenge_handler, event_sender)
+    )]
     pub async fn new(
         storage: Arc<dyn CertificateStorage + Sync + Send>,
-        acme_info: Option<(String, ACMEChallengeType, String)>,
+        acme_info: Option<AcmeInfo>,
+        dns_provider: Option<Arc<dyn DnsProvider + Sync + Send>>,
+        http_challenge_handler: Option<Arc<dyn HttpChallengeHandler + Sync + Send>>,
+        config: CertificateManagerConfig,
+        event_sender: Option<UnboundedSender<CertificateEvent>>,
     ) -> Result<Self, GatewayError> {
-        let certificate_store = Arc::new(RwLock::new(CertificateStore::new()));
+        let certificate_store = Arc::new(RwLock::new(CertificateStore::new(
+            config.max_cached,
+            config.agent_selection,
+            config.session_resumption.clone(),
+            config.tls_policy.clone(),
+            config.clock.clone(),
+        )));
         let acme_configurations = Arc::new(RwLock::new(HashMap::new()));
+        let issuance_backoff = Arc::new(RwLock::new(HashMap::new()));
+        let agent_key_types = Arc::new(RwLock::new(HashMap::new()));
+        let agent_challenge_types = Arc::new(RwLock::new(HashMap::new()));
+        let issuance_permits = Arc::new(Semaphore::new(config.max_concurrent_issuances.max(1)));
+        let agent_accounts = Arc::new(RwLock::new(HashMap::new()));
+        let agent_directories = Arc::new(RwLock::new(HashMap::new()));
         let (sender, mut receiver) = mpsc::unbounded_channel::<CertificateServiceMessage>();
+        let (renewals, _) = broadcast::channel(64);
 
         let mut res = if let Some(acme_info) = acme_info {
             if !validator::validate_email(&acme_info.0) {
                 trace!("invalid email");
                 return Err(GatewayError::Invalid("email"));
             }
-            let account = if let Ok(account) = storage.get_default_account().await {
-                trace!("default account found");
+            if config.staging {
+                warn!(
+                    "ACME staging directory {} in use, issued certificates are not trusted by clients",
+                    acme_info.2
+                );
+            }
+            match storage.get_challenges().await {
+                Ok(challenges) => {
+                    trace!("{} outstanding challenges loaded", challenges.len());
+                    acme_configurations.write().await.extend(challenges);
+                }
+                Err(e) => warn!("unable to load outstanding challenges: {}", e),
+            }
+            let account = if let Ok(account) = storage.get_account(&acme_info.2).await {
+                trace!("account found");
                 account
             } else {
                 trace!("crate new ACME account");
-                let (acme, account_credentials) = Acme::new(&acme_info.0, &acme_info.2).await?;
+                let (acme, account_credentials) = Acme::new(
+                    &acme_info.0,
+                    &acme_info.2,
+                    acme_info
+                        .3
+                        .as_ref()
+                        .map(|(kid, hmac_key)| (kid.as_str(), hmac_key.as_str())),
+                )
+                .await?;
                 storage
-                    .set_default_account_credentials(account_credentials)
+                    .set_account_credentials(&acme_info.2, account_credentials)
                     .await?;
                 acme.account
             };
             Self {
                 certificate_store,
                 acme_configurations,
+                http_challenge_requests: Default::default(),
+                issuance_backoff,
+                agent_key_types: agent_key_types.clone(),
+                agent_challenge_types: agent_challenge_types.clone(),
+                agent_accounts: agent_accounts.clone(),
+                agent_directories: agent_directories.clone(),
                 acme_type: Some(acme_info.1),
-                acme_account: Some(account),
+                acme_email: Some(acme_info.0),
+                acme_account: Arc::new(RwLock::new(Some(account))),
+                directory_accounts: Default::default(),
+                key_rotation: Default::default(),
+                issuance_permits,
+                acme_directory: Some((acme_info.2, acme_info.3)),
                 storage,
+                dns_provider,
+                http_challenge_handler: http_challenge_handler.clone(),
+                config,
+                event_sender: event_sender.clone(),
+                renewals: renewals.clone(),
                 sender: sender.clone(),
-                handler: None,
+                handler: std::sync::Mutex::new(None),
             }
         } else {
             Self {
                 certificate_store,
                 acme_configurations,
+                http_challenge_requests: Default::default(),
+                issuance_backoff,
+                agent_key_types,
+                agent_challenge_types,
+                agent_accounts,
+                agent_directories,
                 acme_type: None,
-                acme_account: None,
+                acme_email: None,
+                acme_account: Default::default(),
+                directory_accounts: Default::default(),
+                key_rotation: Default::default(),
+                issuance_permits,
+                acme_directory: None,
                 storage,
+                dns_provider,
+                http_challenge_handler,
+                config,
+                event_sender: event_sender.clone(),
+                renewals: renewals.clone(),
                 sender: sender.clone(),
-                handler: None,
+                handler: std::sync::Mutex::new(None),
             }
         };
+        match res.storage.list().await {
+            Ok(certificates) => {
+                debug!("{} certificates in storage", certificates.len());
+                if res.config.preload {
+                    res.preload(certificates).await;
+                }
+            }
+            Err(GatewayError::Unsupported(_)) if res.config.preload => {
+                warn!("the certificate storage is unable to list certificates to preload")
+            }
+            Err(GatewayError::Unsupported(_)) => {}
+            Err(e) => warn!("unable to list stored certificates: {}", e),
+        }
         let cm = res.clone();
-        res.handler = Some(tokio::spawn(
+        let handler = tokio::spawn(
             async move {
                 let sender: UnboundedSender<CertificateServiceMessage> = sender.clone();
-                let mut interval = time::interval(Duration::from_secs(60 * 60 * 6)); // every six hours
+                let mut interval = time::interval(cm.config.renew_check_interval);
                 let mut pending_interval = time::interval(Duration::from_secs(60)); // every one minute
+                let mut ocsp_interval = time::interval(cm.config.ocsp_refresh_interval);
                 let mut pendings = HashSet::new();
+                let mut loading = HashSet::new(); // agents not unloaded since their last load, certificates issued for the others stay in storage
+                let mut expiry_warned = HashMap::new();
+                let mut storage_updates = cm.storage.watch().await;
                 loop {
                     tokio::select! {
+                        Some((uid, domain)) = async {
+                            match storage_updates.as_mut() {
+                                Some(storage_updates) => storage_updates.recv().await,
+                                None => std::future::pending().await,
+                            }
+                        } => {
+                            let agents = cm.certificate_store.read().await.agents(&uid, &domain);
+                            for agent_name in agents {
+                                trace!("reload updated certificate {:?} in agent {}:{}", &domain, uid, agent_name);
+                                let _ = cm.load_to_memory(&uid, &agent_name, &domain).await;
+                            }
+                        }
                         Some(msg) = receiver.recv() =>{
                             match msg {
-                                CertificateServiceMessage::Load(uid, agent_name, domains) => {
+                                CertificateServiceMessage::Load(uid, agent_name, domains, key_type) => {
                                     let span = span!(tracing::Level::TRACE, "load_certificate", uid = %uid, agent_name = %agent_name, domains = ?domains);
-                                    for domain in &domains {
-                                        if cm
-                                            .load_to_memory(&uid, &agent_name, domain).instrument(span.clone())
-                                            .await
-                                            .is_err()
-                                            && cm.is_acme_enabled()
-                                        {
-                                            if let Err(e) =
-                                                cm.issue(&uid, &agent_name, domain.clone(), None).instrument(span.clone()).await
-                                            {
-                                                if matches!(e,GatewayError::ACMEPending) {
-                                                    warn!("pending acme request for: {:?} : {}", &domain, e.to_string());
-                                                    pendings.insert((uid.clone(),agent_name.clone(),domain.clone()));
-                                                    continue;
+                                    if let Some(key_type) = key_type {
+                                        cm.agent_key_types.write().await.insert((uid.clone(), agent_name.clone()), key_type);
+                                    }
+                                    loading.insert((uid.clone(), agent_name.clone()));
+                                    let loads = domains.iter().map(|domain| (uid.clone(), agent_name.clone(), domain.clone())).collect();
+                                    let results = cm.load_many(loads).instrument(span.clone()).await;
+                                    for (domain, result) in domains.iter().zip(results) {
+                                        let Err(e) = result else {
+                                            continue;
+                                        };
+                                        if !cm.is_acme_enabled() {
+                                            warn!("no valid certificate for {:?} in agent {}:{} and acme is disabled: {}", &domain, uid, agent_name, e);
+                                            continue;
+                                        }
+                                        if cm.is_backing_off(&uid, &agent_name).await {
+                                            debug!("issuance for {:?} is backing off", &domain);
+                                            continue;
+                                        }
+                                        // issued right away instead of on the first handshake, in the background so a slow
+                                        // order never holds up the other messages
+                                        info!("issuing certificate for {:?} in agent {}:{} ({})", &domain, uid, agent_name, e);
+                                        let (cm, sender) = (cm.clone(), sender.clone());
+                                        let (uid, agent_name, domain) = (uid.clone(), agent_name.clone(), domain.clone());
+                                        tokio::spawn(async move {
+                                            match cm.issue(&uid, &agent_name, domain.clone(), None).await {
+                                                Ok(()) => {
+                                                    info!("certificate for {:?} in agent {}:{} issued", &domain, uid, agent_name);
+                                                    let _ = sender.send(CertificateServiceMessage::Issued(uid, agent_name, domain));
+                                                }
+                                                Err(GatewayError::ACMEPending) => {
+                                                    warn!("pending acme request for: {:?}", &domain);
+                                                    let _ = sender.send(CertificateServiceMessage::IssuancePending(uid, agent_name, domain));
+                                                }
+                                                Err(GatewayError::ACMEBackingOff) => {
+                                                    debug!("queued issuance for {:?} is backing off", &domain);
+                                                }
+                                                Err(e) => {
+                                                    error!(
+                                                        "unable to issue certificate for: {:?} : {}",
+                                                        &domain,
+                                                        e.to_string()
+                                                    );
                                                 }
-                                                error!(
-                                                    "unable to issue certificate for: {:?} : {}",
-                                                    &domains,
-                                                    e.to_string()
-                                                );
-                                                continue;
                                             }
-                                            trace!("load certificate to memory");
-                                            let _ = cm.load_to_memory(&uid, &agent_name, domain).instrument(span.clone()).await;
-                                        }
+                                        }.instrument(span.clone()));
                                     }
                                 },
+                                CertificateServiceMessage::Issued(uid, agent_name, domain) => {
+                                    if loading.contains(&(uid.clone(), agent_name.clone())) {
+                                        trace!("load certificate to memory");
+                                        let _ = cm.load_to_memory(&uid, &agent_name, &domain).await;
+                                    }
+                                }
+                                CertificateServiceMessage::IssuancePending(uid, agent_name, domain) => {
+                                    pendings.insert((uid, agent_name, domain));
+                                }
                                 CertificateServiceMessage::Unload(uid, agent_name) => {
                                     let span = span!(tracing::Level::TRACE, "unload_certificate", uid = %uid, agent_name = %agent_name);
+                                    for info in cm.certificate_info(&uid, &agent_name).await {
+                                        trace!("unload certificate for {:?}, expires at {:?}, staging: {}", info.domains, info.not_after, info.staging);
+                                    }
                                     trace!("unload certificate from memory");
+                                    loading.remove(&(uid.clone(), agent_name.clone()));
                                     cm.unload_from_memory(&uid, &agent_name).instrument(span).await;
+                                    cm.issuance_backoff.write().await.remove(&(uid.clone(), agent_name.clone()));
+                                    cm.agent_key_types.write().await.remove(&(uid.clone(), agent_name.clone()));
+                                    cm.agent_challenge_types.write().await.remove(&(uid.clone(), agent_name.clone()));
+                                    cm.agent_accounts.write().await.remove(&(uid.clone(), agent_name.clone()));
+                                    cm.agent_directories.write().await.remove(&(uid, agent_name));
+                                }
+                                CertificateServiceMessage::UnloadDomains(uid, agent_name, domains) => {
+                                    let span = span!(tracing::Level::TRACE, "unload_certificate", uid = %uid, agent_name = %agent_name, domains = ?domains);
+                                    cm.unload_domains_from_memory(&uid, &agent_name, &domains).instrument(span).await;
+                                }
+                                CertificateServiceMessage::Account(uid, agent_name, email) => {
+                                    if !validator::validate_email(&email) {
+                                        warn!("invalid acme email for agent {}:{}", uid, agent_name);
+                                        continue;
+                                    }
+                                    let mut agent_accounts = cm.agent_accounts.write().await;
+                                    if agent_accounts.get(&(uid.clone(), agent_name.clone())).is_some_and(|agent_account| agent_account.email == email) {
+                                        continue;
+                                    }
+                                    agent_accounts.insert((uid, agent_name), AgentAccount { email, account: None });
+                                }
+                                CertificateServiceMessage::Directory(uid, agent_name, directory_url) => {
+                                    if cm.directory(&directory_url).is_none() {
+                                        warn!("acme directory {} of agent {}:{} is not configured, using the default directory", directory_url, uid, agent_name);
+                                        continue;
+                                    }
+                                    cm.agent_directories.write().await.insert((uid, agent_name), directory_url);
+                                }
+                                CertificateServiceMessage::ChallengeType(uid, agent_name, challenge_type) => {
+                                    cm.agent_challenge_types.write().await.insert((uid, agent_name), challenge_type);
+                                }
+                                CertificateServiceMessage::Shutdown => {
+                                    // waits for the issuances in progress and queued
+                                    let _ = cm.issuance_permits.acquire_many(cm.config.max_concurrent_issuances.max(1) as u32).await;
+                                    debug!("certificate manager stopped");
+                                    break;
+                                }
+                                CertificateServiceMessage::Renew(uid, agent_name) => {
+                                    let span = span!(tracing::Level::TRACE, "renew_certificate", uid = %uid, agent_name = %agent_name);
+                                    let cm = cm.clone();
+                                    tokio::spawn(async move {
+                                        if let Err(e) = cm.force_renew(&uid, &agent_name).await {
+                                            error!("unable to renew certificate for agent {}:{} : {}", uid, agent_name, e);
+                                        }
+                                    }.instrument(span));
                                 }
                             }
                         }
                         _ = pending_interval.tick() =>{
                             for (uid,agent_name,domains) in pendings.drain() {
-                                let _ = sender.send(CertificateServiceMessage::Load(uid,agent_name,vec![domains]));
+                                let _ = sender.send(CertificateServiceMessage::Load(uid,agent_name,vec![domains],None));
+                            }
+                            for (uid,agent_name,domains) in cm.due_retries().await {
+                                debug!("retry issuance for {:?} in agent {}:{}", &domains, uid, agent_name);
+                                let _ = sender.send(CertificateServiceMessage::Load(uid,agent_name,domains,None));
                             }
                         }
                         _ = interval.tick() =>{
-                            for (uid,agent_name,domain) in cm.certificate_store.read().await.renew_needed(){
-                                debug!("renew required for certificate {:?} in agent {}:{}", &domain, uid, agent_name);
-                                let _ = sender.send(CertificateServiceMessage::Load(uid,agent_name,vec![domain]));
-                            }
+                            cm.check_renewals(&sender, &mut expiry_warned).await;
+                        }
+                        _ = ocsp_interval.tick() =>{
+                            let cm = cm.clone();
+                            tokio::spawn(async move { cm.refresh_ocsp_staples().await }.in_current_span());
                         }
                     }
                 }
             }.in_current_span()
-        ));
+        );
+        res.handler = std::sync::Mutex::new(Some(handler));
 
         Ok(res)
     }
+    // stops the background task after the message it is handling, issuances in progress finish first
+    pub async fn shutdown(&self) {
+        let _ = self.sender.send(CertificateServiceMessage::Shutdown);
+        let handler = self
+            .handler
+            .lock()
+            .ok()
+            .and_then(|mut handler| handler.take());
+        if let Some(handler) = handler {
+            let _ = handler.await;
+        }
+    }
+    // readiness as reported by the health service, any storage error makes the gateway unready
+    pub async fn health(&self) -> serde_json::Value {
+        let storage = self.storage.health().await;
+        let acme_account = self
+            .is_acme_enabled()
+            .then_some(self.acme_account.read().await.is_some());
+        serde_json::json!({
+            "ready": storage.is_ok() && acme_account != Some(false),
+            "storage": storage.err().map(|e| e.to_string()).unwrap_or("ok".to_owned()),
+            "acme_account": acme_account,
+            "acme_staging": self.config.staging,
+            "certificates": self.certificate_store.read().await.certificate_count(),
+        })
+    }
+    // for acceptors building their own config around the served certificates
+    pub fn session_resumption(&self) -> &SessionResumption {
+        &self.config.session_resumption
+    }
+    pub fn tls_policy(&self) -> &TlsPolicy {
+        &self.config.tls_policy
+    }
+    // resolves once the certificate of the domain, or the wildcard that may have served it,
+    // renewed and a connection accepted at accepted reached the max connection age, never
+    // without one
+    pub fn drain_on_renewal(
+        &self,
+        domain: &str,
+        accepted: time::Instant,
+    ) -> Option<impl std::future::Future<Output = ()> + Send + 'static> {
+        let max_connection_age = self.config.max_connection_age?;
+        let mut renewals = self.renewals.subscribe();
+        let wildcard = parent_wildcard(domain);
+        let domain = domain.to_owned();
+        Some(async move {
+            loop {
+                match renewals.recv().await {
+                    Ok(renewed) if renewed == domain || Some(&renewed) == wildcard.as_ref() => {
+                        break
+                    }
+                    Ok(_) => continue,
+                    // renewals were missed, one of them might have been of the domain
+                    Err(broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+                }
+            }
+            time::sleep_until(accepted + max_connection_age).await;
+        })
+    }
     pub fn is_acme_enabled(&self) -> bool {
         self.acme_type.is_some()
     }
@@ -259,6 +960,43 @@ impl CertificateManager {
         domain: String,
         suggested_private_key: Option<PrivateKey>,
     ) -> Result<(), GatewayError> {
+        // the agent's override applies only while ACME is enabled
+        let challenge_type = match self.acme_type.clone() {
+            Some(acme_type) => Some(
+                self.agent_challenge_types
+                    .read()
+                    .await
+                    .get(&(uid.to_owned(), agent_name.to_owned()))
+                    .cloned()
+                    .unwrap_or(acme_type),
+            ),
+            None => None,
+        };
+        validate_domain(
+            &domain,
+            matches!(challenge_type, Some(ACMEChallengeType::Dns01)),
+        )?;
+        let Some(challenge_type) = challenge_type else {
+            trace!("acme is disabled");
+            return Err(GatewayError::ACMEIsDisabled);
+        };
+        let _permit = match self.issuance_permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("issuance for {:?} queued", &domain);
+                let permit = self
+                    .issuance_permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| GatewayError::Other("issuance stopped"))?;
+                // a failure of another domain of the agent may have started a backoff meanwhile
+                if self.is_backing_off(uid, agent_name).await {
+                    return Err(GatewayError::ACMEBackingOff);
+                }
+                permit
+            }
+        };
         if self.storage.is_failed(uid, &domain).await {
             return Err(GatewayError::ACMEFailed);
         };
@@ -267,48 +1005,105 @@ impl CertificateManager {
         } else {
             self.storage.set_pending(uid, &domain).await?;
         };
+        #[cfg(feature = "metrics")]
+        super::metrics::issuance_attempted();
         debug!("start to issue acme certificate for {:?}", &domain);
-        // we can create acme account for each agent later
-        let (Some(acme_account), Some(challenge_type)) = (
-            self.storage
-                .get_acme_account(uid, &domain)
-                .await
-                .ok()
-                .or(self.acme_account.clone()),
-            self.acme_type.clone(),
-        ) else {
-            trace!("acme is disabled");
+        // the challenges are withdrawn whatever the outcome, a failure clears the pending flag
+        // and backs off
+        let mut challenge_domains = Vec::new();
+        let issued = self
+            .order(
+                uid,
+                agent_name,
+                &domain,
+                challenge_type,
+                suggested_private_key,
+                &mut challenge_domains,
+            )
+            .in_current_span()
+            .await;
+        self.withdraw_challenges(challenge_domains).await;
+        match issued {
+            Ok(()) => {
+                self.backoff_reset(uid, agent_name, &domain).await;
+                Ok(())
+            }
+            Err(e) => {
+                let retry_after = self.backoff_failure(uid, agent_name, &domain).await;
+                self.storage.set_failed(uid, &domain, retry_after).await?;
+                Err(e)
+            }
+        }
+    }
+
+    // places the order and answers its challenges, the domain of each challenge is pushed to
+    // challenge_domains before it is published
+    async fn order(
+        &self,
+        uid: &str,
+        agent_name: &str,
+        domain: &str,
+        challenge_type: ACMEChallengeType,
+        suggested_private_key: Option<PrivateKey>,
+        challenge_domains: &mut Vec<String>,
+    ) -> Result<(), GatewayError> {
+        let _issuing = self.key_rotation.read().await;
+        // an account already registered for the domain, then the agent's own account, then the
+        // gateway's one, all with the directory the agent selected
+        let directory_url = self.agent_directory(uid, agent_name).await;
+        let (acme_account, account_credentials) = match directory_url.as_deref() {
+            Some(directory_url) => match self.domain_account(uid, domain, directory_url).await {
+                Some(acme_account) => (Some(acme_account), None),
+                None => match self.agent_account(uid, agent_name, directory_url).await {
+                    Some((acme_account, account_credentials)) => {
+                        (Some(acme_account), Some(account_credentials))
+                    }
+                    None => (self.directory_account(directory_url).await, None),
+                },
+            },
+            None => (None, None),
+        };
+        let Some(acme_account) = acme_account else {
+            trace!("no acme account for the directory");
             return Err(GatewayError::ACMEIsDisabled);
         };
 
+        let key_type = self
+            .agent_key_types
+            .read()
+            .await
+            .get(&(uid.to_owned(), agent_name.to_owned()))
+            .copied()
+            .unwrap_or(self.config.key_type);
+
         let mut acme = Acme::from_account(acme_account.clone())?;
         trace!("place order");
-        let new_order = match acme
-            .new_order(vec![domain.clone()], suggested_private_key.as_ref())
+        let new_order = acme
+            .new_order(
+                vec![domain.to_owned()],
+                suggested_private_key.as_ref(),
+                key_type,
+            )
             .in_current_span()
-            .await
-        {
-            Ok(new_order) => new_order,
-            Err(e) => {
-                self.storage.set_failed(uid, &domain).await?;
-                return Err(e);
-            }
-        };
+            .await?;
 
         if let Some(pem) = new_order {
             trace!("order placed, withouth challenge");
-            self.storage.put(uid, &domain, None, pem).await?;
-            return Ok(());
+            return self
+                .storage
+                .put(uid, domain, account_credentials, pem)
+                .await;
         }
         trace!("order placed, require challenge");
 
         let challenges = match challenge_type {
             ACMEChallengeType::Http01 => acme.get_http_01_certificate_challenges()?,
             ACMEChallengeType::TlsAlpn01 => acme.get_tls_alpn_01_certificate_challenges()?,
+            ACMEChallengeType::Dns01 => acme.get_dns_01_certificate_challenges()?,
         };
-        let mut challenge_domains = Vec::new();
 
         for challenge in challenges.iter() {
+            // recorded first, a token published before put_challenge fails is withdrawn too
             {
                 self.acme_configurations
                     .write()
@@ -316,128 +1111,1630 @@ impl CertificateManager {
                     .insert(challenge.domain.clone(), challenge.challenge.clone());
             }
             challenge_domains.push(challenge.domain.clone());
+            match &challenge.challenge {
+                ACMEChallenge::Dns01(name, value) => {
+                    if let Some(dns_provider) = self.dns_provider.as_ref() {
+                        trace!("publish dns challenge record {}", name);
+                        dns_provider.publish_txt_record(name, value).await?;
+                    } else {
+                        warn!(
+                            "no dns provider configured, TXT record {} must be set to {}",
+                            name, value
+                        );
+                    }
+                }
+                ACMEChallenge::Http01(token, key_authorization) => {
+                    if let Some(http_challenge_handler) = self.http_challenge_handler.as_ref() {
+                        trace!("publish http challenge token of {}", challenge.domain);
+                        http_challenge_handler
+                            .publish(&challenge.domain, token, key_authorization)
+                            .await?;
+                    }
+                }
+                ACMEChallenge::TlsAlpn01(..) => {}
+            }
+            self.storage
+                .put_challenge(&challenge.domain, &challenge.challenge)
+                .await?;
         }
 
-        let uid = uid.to_owned();
-        // let agent_name = agent_name.to_owned();
-        let success = 'status: {
-            trace!("check challenge status");
-            let Ok(pem) = acme
-                .check_challenge(challenges, 5, 10 * 1000, suggested_private_key.as_ref())
-                .in_current_span()
-                .await
-            else {
-                break 'status false;
-            };
-            if self.storage.put(&uid, &domain, None, pem).await.is_err() {
-                break 'status false;
-            };
-
-            true
+        trace!("check challenge status");
+        let Ok(pem) = acme
+            .check_challenge(
+                challenges,
+                self.config.challenge_poll_tries,
+                self.config.challenge_poll_interval.as_millis() as u64,
+                suggested_private_key.as_ref(),
+                key_type,
+            )
+            .in_current_span()
+            .await
+        else {
+            #[cfg(feature = "metrics")]
+            super::metrics::challenge_validated(false);
+            return Err(GatewayError::ACMEFailed);
         };
+        #[cfg(feature = "metrics")]
+        super::metrics::challenge_validated(true);
+        self.storage
+            .put(uid, domain, account_credentials, pem)
+            .await
+            .map_err(|_| GatewayError::ACMEFailed)
+    }
 
-        {
-            let mut acme_configurations = self.acme_configurations.write().await;
-            for challenge_domain in challenge_domains {
-                let _acme_challenge = acme_configurations.remove(&challenge_domain);
+    // from the storage, the DNS provider and the HTTP challenge handler
+    async fn withdraw_challenges(&self, challenge_domains: Vec<String>) {
+        let mut acme_configurations = self.acme_configurations.write().await;
+        for challenge_domain in challenge_domains {
+            if let Err(e) = self.storage.remove_challenge(&challenge_domain).await {
+                debug!(
+                    "unable to remove stored challenge {}: {}",
+                    challenge_domain, e
+                );
+            }
+            match (
+                acme_configurations.remove(&challenge_domain),
+                self.dns_provider.as_ref(),
+                self.http_challenge_handler.as_ref(),
+            ) {
+                (Some(ACMEChallenge::Dns01(name, value)), Some(dns_provider), _) => {
+                    trace!("remove dns challenge record {}", name);
+                    if let Err(e) = dns_provider.remove_txt_record(&name, &value).await {
+                        warn!("unable to remove dns challenge record {}: {}", name, e);
+                    }
+                }
+                (
+                    Some(ACMEChallenge::Http01(token, key_authorization)),
+                    _,
+                    Some(http_challenge_handler),
+                ) => {
+                    trace!("remove http challenge token of {}", challenge_domain);
+                    if let Err(e) = http_challenge_handler
+                        .remove(&challenge_domain, &token, &key_authorization)
+                        .await
+                    {
+                        warn!(
+                            "unable to remove http challenge token of {}: {}",
+                            challenge_domain, e
+                        );
+                    }
+                }
+                _ => {}
             }
         }
+    }
 
-        if success {
-            Ok(())
-        } else {
-            self.storage.set_failed(&uid, &domain).await?;
-            Err(GatewayError::ACMEFailed)
+    // the configured directory of the url, the default one or one of those agents may select
+    fn directory(&self, directory_url: &str) -> Option<&Option<(String, String)>> {
+        self.acme_directory
+            .iter()
+            .chain(self.config.directories.iter())
+            .find(|(url, _)| url == directory_url)
+            .map(|(_, eab)| eab)
+    }
+
+    // None while ACME is disabled
+    async fn agent_directory(&self, uid: &str, agent_name: &str) -> Option<String> {
+        let (default_url, _) = self.acme_directory.as_ref()?;
+        Some(
+            self.agent_directories
+                .read()
+                .await
+                .get(&(uid.to_owned(), agent_name.to_owned()))
+                .unwrap_or(default_url)
+                .to_owned(),
+        )
+    }
+
+    // the gateway's account, registered with the other directories on their first issuance
+    async fn directory_account(&self, directory_url: &str) -> Option<Account> {
+        if self
+            .acme_directory
+            .as_ref()
+            .is_some_and(|(url, _)| url == directory_url)
+        {
+            return self.acme_account.read().await.clone();
+        }
+        let mut directory_accounts = self.directory_accounts.write().await;
+        if let Some(account) = directory_accounts.get(directory_url) {
+            return Some(account.clone());
         }
+        let eab = self.directory(directory_url)?;
+        let account = match self.storage.get_account(directory_url).await {
+            Ok(account) => account,
+            Err(_) => {
+                debug!("create ACME account for directory {}", directory_url);
+                match Acme::new(
+                    self.acme_email.as_deref()?,
+                    directory_url,
+                    eab.as_ref()
+                        .map(|(kid, hmac_key)| (kid.as_str(), hmac_key.as_str())),
+                )
+                .await
+                {
+                    Ok((acme, account_credentials)) => {
+                        if let Err(e) = self
+                            .storage
+                            .set_account_credentials(directory_url, account_credentials)
+                            .await
+                        {
+                            warn!(
+                                "unable to store the ACME account of directory {}: {}",
+                                directory_url, e
+                            );
+                        }
+                        acme.account
+                    }
+                    Err(e) => {
+                        warn!(
+                            "unable to create ACME account for directory {}: {}",
+                            directory_url, e
+                        );
+                        return None;
+                    }
+                }
+            }
+        };
+        directory_accounts.insert(directory_url.to_owned(), account.clone());
+        Some(account)
     }
 
-    pub async fn load_to_memory(
+    // accounts registered with another directory are skipped once the agent selects a new one
+    async fn domain_account(
         &self,
         uid: &str,
-        agent_name: &str,
         domain: &str,
-    ) -> Result<(), GatewayError> {
-        let (cert, _) = self.storage.get(uid, domain).await?;
-        if cert.renew_needed() {
-            trace!("certificate renewal required");
-            return Err(GatewayError::CertificateRenewalRequired);
+        directory_url: &str,
+    ) -> Option<Account> {
+        let account_credentials = self
+            .storage
+            .get_acme_account_credentials(uid, domain)
+            .await?;
+        if super::credentials_directory(&account_credentials)
+            .is_some_and(|url| url != directory_url)
+        {
+            debug!("ACME account of {} belongs to another directory", domain);
+            return None;
         }
+        Account::from_credentials(account_credentials).await.ok()
+    }
 
+    async fn agent_account(
+        &self,
+        uid: &str,
+        agent_name: &str,
+        directory_url: &str,
+    ) -> Option<(Account, AccountCredentials)> {
+        let mut agent_accounts = self.agent_accounts.write().await;
+        let agent_account = agent_accounts.get_mut(&(uid.to_owned(), agent_name.to_owned()))?;
+        if agent_account
+            .account
+            .as_ref()
+            .is_some_and(|(_, _, url)| url != directory_url)
         {
-            self.certificate_store.write().await.insert(
-                uid.to_owned(),
-                agent_name.to_owned(),
-                domain,
-                cert,
-            );
+            agent_account.account = None;
         }
-        Ok(())
+        if agent_account.account.is_none() {
+            let eab = self.directory(directory_url)?;
+            debug!("create ACME account for agent {}:{}", uid, agent_name);
+            match Acme::new(
+                &agent_account.email,
+                directory_url,
+                eab.as_ref()
+                    .map(|(kid, hmac_key)| (kid.as_str(), hmac_key.as_str())),
+            )
+            .await
+            {
+                Ok((acme, account_credentials)) => {
+                    let account_credentials = serde_json::to_string(&account_credentials).ok()?;
+                    agent_account.account =
+                        Some((acme.account, account_credentials, directory_url.to_owned()));
+                }
+                Err(e) => {
+                    warn!(
+                        "unable to create ACME account for agent {}:{}, using the default account: {}",
+                        uid, agent_name, e
+                    );
+                    return None;
+                }
+            }
+        }
+        let (account, account_credentials, _) = agent_account.account.as_ref()?;
+        Some((
+            account.clone(),
+            serde_json::from_str(account_credentials).ok()?,
+        ))
     }
 
-    pub async fn unload_from_memory(&self, uid: &str, agent_name: &str) {
-        debug!("unload certificate");
-        self.certificate_store
-            .write()
-            .await
-            .remove(uid.to_owned(), agent_name.to_owned());
+    // doubles the retry delay on every failed attempt, up to the configured cap
+    async fn backoff_failure(&self, uid: &str, agent_name: &str, domain: &str) -> Duration {
+        let mut issuance_backoff = self.issuance_backoff.write().await;
+        let backoff = issuance_backoff
+            .entry((uid.to_owned(), agent_name.to_owned()))
+            .or_insert_with(|| IssuanceBackoff {
+                attempts: 0,
+                retry_at: time::Instant::now(),
+                domains: HashSet::new(),
+            });
+        let retry_after = self
+            .config
+            .issue_retry_base
+            .saturating_mul(2u32.saturating_pow(backoff.attempts))
+            .min(self.config.issue_retry_cap);
+        backoff.attempts = backoff.attempts.saturating_add(1);
+        backoff.retry_at = time::Instant::now() + retry_after;
+        backoff.domains.insert(domain.to_owned());
+        #[cfg(feature = "metrics")]
+        super::metrics::issuance_failed();
+        self.emit(CertificateEvent::IssuanceFailed(
+            uid.to_owned(),
+            agent_name.to_owned(),
+            vec![domain.to_owned()],
+        ));
+        debug!(
+            "issuance for {:?} failed {} time(s), retry in {:?}",
+            domain, backoff.attempts, retry_after
+        );
+        retry_after
     }
 
-    pub async fn get(&self, domain: &str) -> Result<Arc<ServerConfig>, GatewayError> {
-        self.certificate_store
+    async fn backoff_reset(&self, uid: &str, agent_name: &str, domain: &str) {
+        #[cfg(feature = "metrics")]
+        super::metrics::issuance_succeeded();
+        let mut issuance_backoff = self.issuance_backoff.write().await;
+        let key = (uid.to_owned(), agent_name.to_owned());
+        if let Some(backoff) = issuance_backoff.get_mut(&key) {
+            backoff.domains.remove(domain);
+            if backoff.domains.is_empty() {
+                issuance_backoff.remove(&key);
+            }
+        }
+    }
+
+    async fn is_backing_off(&self, uid: &str, agent_name: &str) -> bool {
+        self.issuance_backoff
             .read()
             .await
-            .get_config(domain)
-            .ok_or(GatewayError::CertificateNotFound)
+            .get(&(uid.to_owned(), agent_name.to_owned()))
+            .filter(|backoff| backoff.retry_at > time::Instant::now())
+            .is_some()
     }
-    #[instrument(name = "get_acme_tls_challenge", skip(self))]
-    pub async fn get_acme_tls_challenge(
+
+    async fn due_retries(&self) -> Vec<(String, String, Vec<String>)> {
+        let now = time::Instant::now();
+        self.issuance_backoff
+            .read()
+            .await
+            .iter()
+            .filter(|(_, backoff)| backoff.retry_at <= now)
+            .map(|((uid, agent_name), backoff)| {
+                (
+                    uid.to_owned(),
+                    agent_name.to_owned(),
+                    backoff.domains.iter().cloned().collect(),
+                )
+            })
+            .collect()
+    }
+
+    pub async fn load_to_memory(
         &self,
+        uid: &str,
+        agent_name: &str,
         domain: &str,
-    ) -> Result<Arc<ServerConfig>, GatewayError> {
-        trace!("get acme tls challenge");
-        self.acme_configurations
+    ) -> Result<(), GatewayError> {
+        let (cert, _) = self
+            .storage
+            .get(
+                uid,
+                domain,
+                self.config.clock.now(),
+                self.config.renew_before_expiry,
+            )
+            .await?;
+        if cert.renew_needed_at(self.config.clock.now(), self.config.renew_before_expiry) {
+            if !cert.is_imported() {
+                trace!("certificate renewal required");
+                return Err(GatewayError::CertificateRenewalRequired);
+            }
+            self.imported_expiring(uid, agent_name, domain);
+        }
+        debug!(
+            "certificate loaded, issuer: {}, serial: {}, valid from {:?} until {:?}",
+            cert.info().issuer,
+            cert.info().serial,
+            cert.not_before(),
+            cert.not_after()
+        );
+        if cert.info().staging {
+            warn!(
+                "staging certificate loaded for {}, it is not trusted by clients",
+                domain
+            );
+        }
+
+        #[cfg(feature = "metrics")]
+        super::metrics::set_expiry(domain, cert.not_after());
+        {
+            let mut certificate_store = self.certificate_store.write().await;
+            if certificate_store
+                .agents(uid, domain)
+                .iter()
+                .any(|name| name == agent_name)
+            {
+                trace!("replace loaded certificate");
+                certificate_store.update(uid, domain, cert)?;
+                let _ = self.renewals.send(domain.to_owned());
+                self.emit(CertificateEvent::Renewed(
+                    uid.to_owned(),
+                    agent_name.to_owned(),
+                    vec![domain.to_owned()],
+                ));
+            } else {
+                certificate_store.insert(uid.to_owned(), agent_name.to_owned(), domain, cert)?;
+                self.emit(CertificateEvent::Loaded(
+                    uid.to_owned(),
+                    agent_name.to_owned(),
+                    vec![domain.to_owned()],
+                ));
+            }
+            #[cfg(feature = "metrics")]
+            super::metrics::set_certificates_loaded(certificate_store.certificate_count());
+        }
+        Ok(())
+    }
+
+    // serves stored certificates before their agents connect, expiring ones are left for the
+    // agents to renew
+    async fn preload(&self, certificates: Vec<(String, String, Vec<String>)>) {
+        let loads: Vec<_> = certificates
+            .into_iter()
+            .map(|(uid, domain, _)| (uid, PRELOADED_AGENT_NAME.to_owned(), domain))
+            .collect();
+        let domains: Vec<_> = loads.iter().map(|(_, _, domain)| domain.clone()).collect();
+        let mut loaded = 0;
+        for (domain, result) in domains.iter().zip(self.load_many(loads).await) {
+            match result {
+                Ok(()) => loaded += 1,
+                Err(e) => debug!("certificate for {} not preloaded: {}", domain, e),
+            }
+        }
+        debug!("{} stored certificates preloaded", loaded);
+    }
+
+    // load_to_memory of every (uid, agent_name, domain), at most load_concurrency at once so
+    // the storage is not flooded, the results are in the order of the loads
+    async fn load_many(
+        &self,
+        loads: Vec<(String, String, String)>,
+    ) -> Vec<Result<(), GatewayError>> {
+        let permits = Arc::new(Semaphore::new(self.config.load_concurrency.max(1)));
+        let mut tasks = JoinSet::new();
+        for (index, (uid, agent_name, domain)) in loads.into_iter().enumerate() {
+            let (cm, permits) = (self.clone(), permits.clone());
+            tasks.spawn(
+                async move {
+                    let _permit = permits.acquire_owned().await;
+                    (index, cm.load_to_memory(&uid, &agent_name, &domain).await)
+                }
+                .in_current_span(),
+            );
+        }
+        let mut results: Vec<_> = (0..tasks.len())
+            .map(|_| Err(GatewayError::Other("certificate load failed")))
+            .collect();
+        while let Some(task) = tasks.join_next().await {
+            if let Ok((index, result)) = task {
+                results[index] = result;
+            }
+        }
+        results
+    }
+
+    // stores a certificate issued outside of ACME for every domain it covers and serves it,
+    // it is never renewed, expiry is only reported
+    #[instrument(name = "import", skip(self, cert_pem, key_pem))]
+    pub async fn import(
+        &self,
+        uid: &str,
+        agent_name: &str,
+        domains: Vec<String>,
+        cert_pem: &str,
+        key_pem: &str,
+    ) -> Result<(), GatewayError> {
+        let mut pems = pem::parse_many(cert_pem)?;
+        pems.extend(pem::parse_many(key_pem)?);
+        pems.push(pem::Pem::new(super::IMPORTED_PEM_TAG, Vec::new()));
+        let cert = Certificate::from_pem_vec(pems.clone())?;
+        if cert.renew_needed_at(self.config.clock.now(), Duration::ZERO) {
+            return Err(GatewayError::Invalid("expired certificate"));
+        }
+        if domains.is_empty() {
+            return Err(GatewayError::Invalid("certificate domains"));
+        }
+        for domain in domains.iter() {
+            validate_domain(domain, true)?;
+            if !cert.info().covers(domain) {
+                debug!("imported certificate does not cover {}", domain);
+                return Err(GatewayError::Invalid("certificate domains"));
+            }
+        }
+        for domain in domains.iter() {
+            self.storage.put(uid, domain, None, pems.clone()).await?;
+            self.load_to_memory(uid, agent_name, domain).await?;
+        }
+        debug!(
+            "certificate imported for {:?}, issuer: {}, valid until {:?}",
+            domains,
+            cert.info().issuer,
+            cert.not_after()
+        );
+        Ok(())
+    }
+
+    // queues a load of every certificate due for renewal as seen by the configured clock, the
+    // load issues a new one
+    async fn check_renewals(
+        &self,
+        sender: &UnboundedSender<CertificateServiceMessage>,
+        expiry_warned: &mut HashMap<(String, String, String), u64>,
+    ) {
+        let renew_needed = self
+            .certificate_store
             .read()
             .await
-            .get(domain)
-            .ok_or({
-                trace!("acme http challenge for this domain not found");
-                GatewayError::CertificateNotFound
-            })
-            .and_then(|challenge_info| {
-                if let ACMEChallenge::TlsAlpn01(conf) = challenge_info {
-                    trace!("acme http challenge found");
-                    Ok(conf)
-                } else {
-                    trace!("acme http challenge not found for this challenge not found");
-                    Err(GatewayError::CertificateNotFound)
-                    // improve error
+            .renew_needed(self.config.renew_before_expiry);
+        self.warn_expiry(&renew_needed, expiry_warned).await;
+        for (uid, agent_name, domain) in renew_needed {
+            debug!(
+                "renew required for certificate {:?} in agent {}:{}",
+                &domain, uid, agent_name
+            );
+            let _ = sender.send(CertificateServiceMessage::Load(
+                uid,
+                agent_name,
+                vec![domain],
+                None,
+            ));
+        }
+        let imported_expiring = self
+            .certificate_store
+            .read()
+            .await
+            .imported_expiring(self.config.renew_before_expiry);
+        for (uid, agent_name, domain) in imported_expiring {
+            self.imported_expiring(&uid, &agent_name, &domain);
+        }
+    }
+
+    // warns once per threshold crossed while the renewal is still due, the last one as an
+    // error, renewed certificates leave `warned` (agent, domain -> lowest threshold warned)
+    async fn warn_expiry(
+        &self,
+        renew_needed: &[(String, String, String)],
+        warned: &mut HashMap<(String, String, String), u64>,
+    ) {
+        warned.retain(|agent_domain, _| renew_needed.contains(agent_domain));
+        let Some(&last) = self.config.expiry_warning_days.iter().min() else {
+            return;
+        };
+        for (uid, agent_name, domain) in renew_needed {
+            let Some(days) = self
+                .certificate_store
+                .read()
+                .await
+                .days_until_expiry(uid, domain)
+            else {
+                continue;
+            };
+            let key = (uid.to_owned(), agent_name.to_owned(), domain.to_owned());
+            let Some(threshold) = self
+                .config
+                .expiry_warning_days
+                .iter()
+                .copied()
+                .filter(|threshold| days <= *threshold as i64)
+                .min()
+            else {
+                continue;
+            };
+            if warned.get(&key).is_some_and(|warned| *warned <= threshold) {
+                continue;
+            }
+            warned.insert(key, threshold);
+            if threshold == last {
+                error!(
+                    "certificate for {} in agent {}:{} expires in {} days and is still not renewed",
+                    domain, uid, agent_name, days
+                );
+            } else {
+                warn!(
+                    "certificate for {} in agent {}:{} expires in {} days and is not renewed yet",
+                    domain, uid, agent_name, days
+                );
+            }
+            self.emit(CertificateEvent::ExpiryWarning(
+                uid.to_owned(),
+                agent_name.to_owned(),
+                vec![domain.to_owned()],
+                days,
+            ));
+        }
+    }
+
+    fn imported_expiring(&self, uid: &str, agent_name: &str, domain: &str) {
+        warn!(
+            "imported certificate for {} in agent {}:{} expires soon, it must be replaced manually",
+            domain, uid, agent_name
+        );
+        self.emit(CertificateEvent::ImportedExpiring(
+            uid.to_owned(),
+            agent_name.to_owned(),
+            vec![domain.to_owned()],
+        ));
+    }
+
+    // replaces the key of the account of the default directory, issuances in progress finish
+    // with the current key first, other gateways sharing the storage pick the new key up on restart
+    #[instrument(name = "rotate_acme_account_key", skip(self))]
+    pub async fn rotate_acme_account_key(&self) -> Result<(), GatewayError> {
+        let (Some((directory_url, _)), true) =
+            (self.acme_directory.as_ref(), self.is_acme_enabled())
+        else {
+            return Err(GatewayError::ACMEIsDisabled);
+        };
+        let _rotation = self.key_rotation.write().await;
+        let credentials = self.storage.get_account_credentials(directory_url).await?;
+        let credentials = super::acme::change_account_key(&credentials).await?;
+        *self.acme_account.write().await =
+            Some(Account::from_credentials(super::copy_credentials(&credentials)?).await?);
+        if let Err(e) = self
+            .storage
+            .set_account_credentials(directory_url, credentials)
+            .await
+        {
+            // the CA only accepts the new key now, it is kept in memory until the restart
+            error!("unable to store the rotated ACME account key: {}", e);
+            return Err(e);
+        }
+        debug!("ACME account key rotated");
+        Ok(())
+    }
+
+    // revokes the agent's ACME certificates, then removes them from the storage and from every
+    // agent serving them, expired and imported ones are only removed
+    #[instrument(name = "revoke", skip(self))]
+    pub async fn revoke(
+        &self,
+        uid: &str,
+        agent_name: &str,
+        reason: RevocationReason,
+    ) -> Result<(), GatewayError> {
+        if !self.is_acme_enabled() {
+            return Err(GatewayError::ACMEIsDisabled);
+        }
+        let domains = self.certificate_store.read().await.domains(uid, agent_name);
+        if domains.is_empty() {
+            return Err(GatewayError::CertificateNotFound);
+        }
+        for domain in domains {
+            match self
+                .storage
+                .get(
+                    uid,
+                    &domain,
+                    self.config.clock.now(),
+                    self.config.renew_before_expiry,
+                )
+                .await
+            {
+                Ok((cert, _)) if cert.is_imported() => {
+                    warn!(
+                        "imported certificate for {} is not revoked, only its CA can revoke it",
+                        domain
+                    );
                 }
-            })
-            .cloned()
+                Ok((cert, _)) if cert.renew_needed_at(self.config.clock.now(), Duration::ZERO) => {
+                    debug!("certificate for {} has expired, revocation skipped", domain);
+                }
+                Ok((cert, _)) => {
+                    let Some(leaf) = cert.certificate_chain().first() else {
+                        return Err(GatewayError::Invalid("certificate chain"));
+                    };
+                    // the account that issued the certificate, then the one of the agent's directory
+                    let account = match self.storage.get_acme_account(uid, &domain).await {
+                        Ok(account) => Some(account),
+                        Err(_) => match self.agent_directory(uid, agent_name).await {
+                            Some(directory_url) => self.directory_account(&directory_url).await,
+                            None => None,
+                        },
+                    };
+                    let Some(account) = account else {
+                        return Err(GatewayError::ACMEIsDisabled);
+                    };
+                    Acme::from_account(account)?
+                        .revoke(&leaf.0, reason.clone())
+                        .await?;
+                    debug!("certificate for {} revoked", domain);
+                }
+                Err(e) => debug!("no stored certificate for {}: {}", domain, e),
+            }
+            if let Err(e) = self
+                .storage
+                .delete(uid, agent_name, std::slice::from_ref(&domain))
+                .await
+            {
+                debug!("unable to remove the certificate for {}: {}", domain, e);
+            }
+            self.unload_domain_from_agents(uid, agent_name, &domain)
+                .await;
+        }
+        Ok(())
+    }
+
+    // removes the agent's certificates and their account data from the storage and from every
+    // agent serving them without revoking them, for decommissioned domains
+    #[instrument(name = "purge", skip(self))]
+    pub async fn purge(&self, uid: &str, agent_name: &str) -> Result<(), GatewayError> {
+        let domains = self.certificate_store.read().await.domains(uid, agent_name);
+        if domains.is_empty() {
+            return Err(GatewayError::CertificateNotFound);
+        }
+        debug!("purge certificates for {:?}", domains);
+        match self.storage.delete(uid, agent_name, &domains).await {
+            Ok(()) | Err(GatewayError::CertificateNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        for domain in domains {
+            self.unload_domain_from_agents(uid, agent_name, &domain)
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn unload_domain_from_agents(&self, uid: &str, agent_name: &str, domain: &str) {
+        let agents = self.certificate_store.read().await.agents(uid, domain);
+        for agent_name in agents.iter().map(String::as_str).chain([agent_name]) {
+            self.unload_domains_from_memory(uid, agent_name, &[domain.to_owned()])
+                .await;
+        }
+    }
+
+    // re-issues every certificate loaded for the agent regardless of its remaining validity
+    pub async fn force_renew(&self, uid: &str, agent_name: &str) -> Result<(), GatewayError> {
+        if !self.is_acme_enabled() {
+            return Err(GatewayError::ACMEIsDisabled);
+        }
+        let domains = self.certificate_store.read().await.domains(uid, agent_name);
+        if domains.is_empty() {
+            return Err(GatewayError::CertificateNotFound);
+        }
+        for domain in domains {
+            debug!("force renew certificate for {:?}", &domain);
+            self.issue(uid, agent_name, domain.clone(), None).await?;
+            self.load_to_memory(uid, agent_name, &domain).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn unload_from_memory(&self, uid: &str, agent_name: &str) {
+        let domains = self.certificate_store.read().await.domains(uid, agent_name);
+        self.unload_domains_from_memory(uid, agent_name, &domains)
+            .await;
+    }
+
+    pub async fn unload_domains_from_memory(
+        &self,
+        uid: &str,
+        agent_name: &str,
+        domains: &[String],
+    ) {
+        debug!("unload certificate");
+        let mut certificate_store = self.certificate_store.write().await;
+        let domains = certificate_store
+            .domains(uid, agent_name)
+            .into_iter()
+            .filter(|domain| domains.contains(domain))
+            .collect::<Vec<_>>();
+        certificate_store.remove_domains(uid, agent_name, &domains);
+        #[cfg(feature = "metrics")]
+        {
+            for domain in domains.iter() {
+                if certificate_store.get_config(domain).is_none() {
+                    super::metrics::remove_expiry(domain);
+                }
+            }
+            super::metrics::set_certificates_loaded(certificate_store.certificate_count());
+        }
+        if !domains.is_empty() {
+            self.emit(CertificateEvent::Unloaded(
+                uid.to_owned(),
+                agent_name.to_owned(),
+                domains,
+            ));
+        }
+    }
+
+    fn emit(&self, event: CertificateEvent) {
+        if let Some(event_sender) = self.event_sender.as_ref() {
+            let _ = event_sender.send(event);
+        }
+    }
+
+    pub async fn refresh_ocsp_staples(&self) {
+        let certificates = self.certificate_store.read().await.ocsp_refresh_needed();
+        for (uid, domain, certificate_chain) in certificates {
+            match ocsp::fetch(&certificate_chain, self.config.clock.now()).await {
+                Ok(Some(response)) => {
+                    trace!("ocsp staple refreshed for {}", domain);
+                    if let Err(e) = self.certificate_store.write().await.set_ocsp(
+                        &uid,
+                        &domain,
+                        &certificate_chain,
+                        response.response,
+                        response.next_update,
+                    ) {
+                        debug!("unable to staple ocsp response for {}: {}", domain, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("unable to fetch ocsp response for {}: {}", domain, e);
+                }
+            }
+        }
+    }
+
+    pub async fn certificate_info(&self, uid: &str, agent_name: &str) -> Vec<CertificateInfo> {
+        self.certificate_store
+            .read()
+            .await
+            .certificate_info(uid, agent_name)
+    }
+
+    // for synchronous certificate resolvers, None while the store is being written, an evicted
+    // certificate is reloaded in the background for the next handshake
+    pub fn try_get(&self, domain: &str) -> Option<Arc<ServerConfig>> {
+        let certificate_store = self.certificate_store.try_read().ok()?;
+        if let Some(config) = certificate_store.get_config(domain) {
+            return Some(config);
+        }
+        if !certificate_store.evicted(domain).is_empty() {
+            let (cm, domain) = (self.clone(), domain.to_owned());
+            tokio::spawn(async move { cm.reload_evicted(&domain).await }.in_current_span());
+        }
+        None
+    }
+    pub async fn get(&self, domain: &str) -> Result<Arc<ServerConfig>, GatewayError> {
+        if let Some(config) = self.certificate_store.read().await.get_config(domain) {
+            return Ok(config);
+        }
+        self.reload_evicted(domain).await;
+        self.certificate_store
+            .read()
+            .await
+            .get_config(domain)
+            .ok_or(GatewayError::CertificateNotFound)
+    }
+    async fn reload_evicted(&self, domain: &str) {
+        let evicted = self.certificate_store.read().await.evicted(domain);
+        for (uid, agent_name, domain) in evicted {
+            trace!(
+                "reload evicted certificate {:?} in agent {}:{}",
+                &domain,
+                uid,
+                agent_name
+            );
+            if let Err(e) = self.load_to_memory(&uid, &agent_name, &domain).await {
+                debug!("unable to reload evicted certificate {}: {}", domain, e);
+                // renewal and issuance go through the regular load
+                let _ = self.sender.send(CertificateServiceMessage::Load(
+                    uid,
+                    agent_name,
+                    vec![domain],
+                    None,
+                ));
+            }
+        }
+    }
+    // in-memory challenges first, then the ones persisted by another instance or before a restart
+    async fn challenge(&self, domain: &str) -> Option<ACMEChallenge> {
+        if let Some(challenge) = self.acme_configurations.read().await.get(domain) {
+            return Some(challenge.clone());
+        }
+        let challenge = self.storage.get_challenge(domain).await.ok()?;
+        trace!("acme challenge loaded from storage");
+        self.acme_configurations
+            .write()
+            .await
+            .insert(domain.to_owned(), challenge.clone());
+        Some(challenge)
+    }
+    #[instrument(name = "get_acme_tls_challenge", skip(self))]
+    pub async fn get_acme_tls_challenge(
+        &self,
+        domain: &str,
+    ) -> Result<Arc<ServerConfig>, GatewayError> {
+        trace!("get acme tls challenge");
+        match self.challenge(domain).await {
+            Some(ACMEChallenge::TlsAlpn01(certificate, private_key)) => {
+                trace!("acme tls challenge found");
+                ACMEChallenge::tls_alpn_server_config(
+                    &certificate,
+                    &private_key,
+                    &self.config.tls_policy,
+                )
+            }
+            Some(challenge) => {
+                trace!("acme challenge for this domain is not tls alpn 01");
+                Err(GatewayError::ACMEChallengeTypeMismatch(challenge.kind()))
+            }
+            None => {
+                trace!("acme tls challenge for this domain not found");
+                Err(GatewayError::ACMEChallengeNotFound)
+            }
+        }
     }
+    // unauthenticated, so it is rate limited per ip and only answers for orders in progress
+    // on this gateway, without looking the domain up in the storage
     #[instrument(name = "get_acme_http_challenge", skip(self))]
     pub async fn get_acme_http_challenge(
         &self,
         domain: &str,
+        peer_ip: IpAddr,
     ) -> Result<(String, String), GatewayError> {
         Span::current().record("challenge_domain", domain);
         trace!("get acme http challenge");
-        self.acme_configurations
+        if !self.http_challenge_permitted(peer_ip) {
+            debug!(
+                "acme http challenge requests of {} are rate limited",
+                peer_ip
+            );
+            return Err(GatewayError::ACMEChallengeRateLimited);
+        }
+        let challenge = self.acme_configurations.read().await.get(domain).cloned();
+        match challenge {
+            Some(ACMEChallenge::Http01(token, key_authorization)) => {
+                trace!("acme http challenge found");
+                Ok((token, key_authorization))
+            }
+            Some(challenge) => {
+                trace!("acme challenge for this domain is not http 01");
+                Err(GatewayError::ACMEChallengeTypeMismatch(challenge.kind()))
+            }
+            None => {
+                trace!("acme http challenge for this domain not found");
+                Err(GatewayError::ACMEChallengeNotFound)
+            }
+        }
+    }
+    fn http_challenge_permitted(&self, peer_ip: IpAddr) -> bool {
+        let Ok(mut requests) = self.http_challenge_requests.lock() else {
+            return true;
+        };
+        let now = Instant::now();
+        if requests.len() >= HTTP_CHALLENGE_TRACKED_IPS {
+            requests.retain(|_, (since, _)| now.duration_since(*since) < HTTP_CHALLENGE_WINDOW);
+        }
+        let (since, count) = requests.entry(peer_ip).or_insert((now, 0));
+        if now.duration_since(*since) >= HTTP_CHALLENGE_WINDOW {
+            *since = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= HTTP_CHALLENGE_REQUESTS
+    }
+    #[instrument(name = "get_acme_dns_challenge", skip(self))]
+    pub async fn get_acme_dns_challenge(
+        &self,
+        domain: &str,
+    ) -> Result<(String, String), GatewayError> {
+        trace!("get acme dns challenge");
+        match self.challenge(domain).await {
+            Some(ACMEChallenge::Dns01(name, value)) => {
+                trace!("acme dns challenge found");
+                Ok((name, value))
+            }
+            Some(challenge) => {
+                trace!("acme challenge for this domain is not dns 01");
+                Err(GatewayError::ACMEChallengeTypeMismatch(challenge.kind()))
+            }
+            None => {
+                trace!("acme dns challenge for this domain not found");
+                Err(GatewayError::ACMEChallengeNotFound)
+            }
+        }
+    }
+}
+
+// the wildcard serving the domain without a certificate of its own, of the immediate parent
+// only, `*.example.com` serves `api.example.com` but not `a.b.example.com`
+fn parent_wildcard(domain: &str) -> Option<String> {
+    let (_, parent) = domain.split_once('.')?;
+    if parent.is_empty() || domain.starts_with("*.") {
+        return None;
+    }
+    Some(format!("*.{}", parent))
+}
+
+// rejects empty, malformed and IP literal domains, wildcards are only valid for DNS-01. a domain
+// is at most 253 characters of two or more labels, each of 1 to 63 letters, digits and inner
+// hyphens, internationalized names in their A-label form
+fn validate_domain(domain: &str, allow_wildcard: bool) -> Result<(), GatewayError> {
+    let name = match domain.strip_prefix("*.") {
+        Some(name) if allow_wildcard => name,
+        _ => domain,
+    };
+    let ldh = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    if name.len() > 253
+        || !name.contains('.')
+        || !name.split('.').all(ldh)
+        || validator::validate_ip(name)
+        // the xn-- labels have to decode to a valid internationalized name
+        || !idna::domain_to_ascii_strict(name).is_ok_and(|ascii| ascii.eq_ignore_ascii_case(name))
+    {
+        debug!("invalid domain: {:?}", domain);
+        return Err(GatewayError::Invalid("domain"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::service::certificate::{
+        clock::MockClock, memory_storage::InMemoryCertificateStorage, TlsVersion,
+    };
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn year_2030() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_893_456_000) // 2030-01-01
+    }
+
+    // self-signed, valid for 90 days from 2030-01-01
+    fn certificate(domain: &str) -> Vec<pem::Pem> {
+        certificate_until(domain, 4)
+    }
+
+    // self-signed, valid from 2030-01-01 until the first day of the month
+    fn certificate_until(domain: &str, month: u8) -> Vec<pem::Pem> {
+        let mut params = rcgen::CertificateParams::new(vec![domain.to_owned()]);
+        params.not_before = rcgen::date_time_ymd(2030, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2030, month, 1);
+        let cert = rcgen::Certificate::from_params(params).expect("certificate");
+        let pems =
+            cert.serialize_pem().expect("certificate pem") + &cert.serialize_private_key_pem();
+        pem::parse_many(pems).expect("pems")
+    }
+
+    async fn manager(
+        storage: Arc<dyn CertificateStorage + Sync + Send>,
+        clock: &MockClock,
+    ) -> CertificateManager {
+        let config = CertificateManagerConfig {
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        CertificateManager::new(storage, None, None, None, config, None)
+            .await
+            .expect("certificate manager")
+    }
+
+    #[tokio::test]
+    async fn renewal_is_needed_once_the_clock_reaches_the_threshold() {
+        let clock = MockClock::new(year_2030());
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        storage
+            .put("uid", "example.com", None, certificate("example.com"))
+            .await
+            .expect("put");
+        let cm = manager(storage, &clock).await;
+        cm.load_to_memory("uid", "agent", "example.com")
+            .await
+            .expect("load");
+        assert!(cm
+            .certificate_store
             .read()
             .await
-            .get(domain)
-            .ok_or({
-                trace!("acme http challenge for this domain not found");
-                GatewayError::ACMEChallengeNotFound
+            .renew_needed(DEFAULT_RENEW_BEFORE_EXPIRY)
+            .is_empty());
+
+        // seven days before expiry by default
+        clock.advance(82 * DAY);
+        assert!(cm
+            .certificate_store
+            .read()
+            .await
+            .renew_needed(DEFAULT_RENEW_BEFORE_EXPIRY)
+            .is_empty());
+        // a longer configured threshold is already reached
+        assert!(!cm
+            .certificate_store
+            .read()
+            .await
+            .renew_needed(30 * DAY)
+            .is_empty());
+        clock.advance(DAY);
+        assert_eq!(
+            cm.certificate_store
+                .read()
+                .await
+                .renew_needed(DEFAULT_RENEW_BEFORE_EXPIRY),
+            vec![(
+                "uid".to_owned(),
+                "agent".to_owned(),
+                "example.com".to_owned()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn due_renewals_queue_a_load() {
+        let clock = MockClock::new(year_2030());
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        storage
+            .put("uid", "example.com", None, certificate("example.com"))
+            .await
+            .expect("put");
+        let cm = manager(storage, &clock).await;
+        cm.load_to_memory("uid", "agent", "example.com")
+            .await
+            .expect("load");
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut expiry_warned = HashMap::new();
+
+        cm.check_renewals(&sender, &mut expiry_warned).await;
+        assert!(receiver.try_recv().is_err());
+
+        clock.advance(85 * DAY);
+        cm.check_renewals(&sender, &mut expiry_warned).await;
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(CertificateServiceMessage::Load(uid, agent_name, domains, None))
+                if uid == "uid" && agent_name == "agent" && domains == ["example.com"]
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn load_to_memory_serves_the_stored_certificate() {
+        let clock = MockClock::new(year_2030());
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        storage
+            .put("uid", "example.com", None, certificate("example.com"))
+            .await
+            .expect("put");
+        let cm = manager(storage, &clock).await;
+        assert!(cm.get("example.com").await.is_err());
+
+        cm.load_to_memory("uid", "agent", "example.com")
+            .await
+            .expect("load");
+        assert!(cm.get("example.com").await.is_ok());
+        assert!(matches!(
+            cm.load_to_memory("uid", "agent", "other.example.com").await,
+            Err(GatewayError::CertificateNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn load_to_memory_refuses_a_certificate_due_for_renewal() {
+        let clock = MockClock::new(year_2030() + 85 * DAY);
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        storage
+            .put("uid", "example.com", None, certificate("example.com"))
+            .await
+            .expect("put");
+        let cm = manager(storage, &clock).await;
+        assert!(matches!(
+            cm.load_to_memory("uid", "agent", "example.com").await,
+            Err(GatewayError::CertificateRenewalRequired)
+        ));
+        assert!(cm.get("example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn renewed_certificate_replaces_the_expiring_one() {
+        let clock = MockClock::new(year_2030());
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        storage
+            .put("uid", "example.com", None, certificate("example.com"))
+            .await
+            .expect("put");
+        let cm = manager(storage.clone(), &clock).await;
+        cm.load_to_memory("uid", "agent", "example.com")
+            .await
+            .expect("load");
+        let mut renewals = cm.renewals.subscribe();
+        clock.advance(85 * DAY);
+        assert!(!cm
+            .certificate_store
+            .read()
+            .await
+            .renew_needed(DEFAULT_RENEW_BEFORE_EXPIRY)
+            .is_empty());
+
+        // as put by an issuance
+        storage
+            .put(
+                "uid",
+                "example.com",
+                None,
+                certificate_until("example.com", 7),
+            )
+            .await
+            .expect("put");
+        cm.load_to_memory("uid", "agent", "example.com")
+            .await
+            .expect("load");
+        assert!(cm
+            .certificate_store
+            .read()
+            .await
+            .renew_needed(DEFAULT_RENEW_BEFORE_EXPIRY)
+            .is_empty());
+        assert_eq!(renewals.try_recv().ok().as_deref(), Some("example.com"));
+        let not_after = cm
+            .certificate_info("uid", "agent")
+            .await
+            .iter()
+            .map(|info| info.not_after)
+            .max();
+        assert_eq!(
+            not_after,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_909_094_400)) // 2030-07-01
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_served_by_a_wildcard_drain_once_it_renews() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::service::drain::Draining;
+
+        let clock = MockClock::new(year_2030());
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        storage
+            .put("uid", "*.example.com", None, certificate("*.example.com"))
+            .await
+            .expect("put");
+        let config = CertificateManagerConfig {
+            clock: Arc::new(clock.clone()),
+            max_connection_age: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let cm = CertificateManager::new(storage.clone(), None, None, None, config, None)
+            .await
+            .expect("certificate manager");
+        cm.load_to_memory("uid", "agent", "*.example.com")
+            .await
+            .expect("load");
+        assert!(cm.get("api.example.com").await.is_ok());
+
+        let accepted = time::Instant::now();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut connection =
+            Draining::new(server, cm.drain_on_renewal("api.example.com", accepted));
+        let mut nested = Box::pin(
+            cm.drain_on_renewal("a.b.example.com", accepted)
+                .expect("drain"),
+        );
+        let mut unrelated = Box::pin(cm.drain_on_renewal("example.org", accepted).expect("drain"));
+
+        // older than the max connection age, but its certificate has not renewed
+        time::advance(Duration::from_secs(7200)).await;
+        let mut buf = [0; 16];
+        client.write_all(b"before").await.expect("write");
+        let read = connection.read(&mut buf).await.expect("read");
+        assert_eq!(&buf[..read], b"before");
+
+        clock.advance(85 * DAY);
+        storage
+            .put(
+                "uid",
+                "*.example.com",
+                None,
+                certificate_until("*.example.com", 7),
+            )
+            .await
+            .expect("put");
+        cm.load_to_memory("uid", "agent", "*.example.com")
+            .await
+            .expect("load");
+
+        client.write_all(b"after").await.expect("write");
+        assert_eq!(connection.read(&mut buf).await.expect("read"), 0);
+        assert!(futures_util::FutureExt::now_or_never(nested.as_mut()).is_none());
+        assert!(futures_util::FutureExt::now_or_never(unrelated.as_mut()).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn configs_are_served_throughout_a_renewal() {
+        let clock = MockClock::new(year_2030());
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        storage
+            .put("uid", "example.com", None, certificate("example.com"))
+            .await
+            .expect("put");
+        let cm = manager(storage.clone(), &clock).await;
+        cm.load_to_memory("uid", "agent", "example.com")
+            .await
+            .expect("load");
+        let expiring = cm.get("example.com").await.expect("config");
+        clock.advance(85 * DAY);
+
+        // every reader is served before the renewal starts and keeps being served until it ends
+        let readers_started = Arc::new(tokio::sync::Barrier::new(5));
+        let renewed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers = (0..4)
+            .map(|_| {
+                let (cm, readers_started, renewed) =
+                    (cm.clone(), readers_started.clone(), renewed.clone());
+                tokio::spawn(async move {
+                    cm.get("example.com").await.expect("config");
+                    readers_started.wait().await;
+                    while !renewed.load(Ordering::Relaxed) {
+                        cm.get("example.com").await.expect("config while renewing");
+                        tokio::task::yield_now().await;
+                    }
+                })
             })
-            .and_then(|challenge_info| {
-                if let ACMEChallenge::Http01(token, key_authorization) = challenge_info {
-                    trace!("acme http challenge found");
-                    Ok((token.to_owned(), key_authorization.to_owned()))
-                } else {
-                    trace!("acme http challenge not found for this challenge not found");
-                    Err(GatewayError::ACMEChallengeNotFound)
+            .collect::<Vec<_>>();
+        readers_started.wait().await;
+        storage
+            .put(
+                "uid",
+                "example.com",
+                None,
+                certificate_until("example.com", 7),
+            )
+            .await
+            .expect("put");
+        cm.load_to_memory("uid", "agent", "example.com")
+            .await
+            .expect("load");
+        renewed.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.await.expect("reader");
+        }
+        let renewed = cm.get("example.com").await.expect("config");
+        assert!(!Arc::ptr_eq(&expiring, &renewed));
+    }
+
+    #[tokio::test]
+    async fn purge_deletes_and_unloads_every_domain_of_the_agent() {
+        let clock = MockClock::new(year_2030());
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        for domain in ["example.com", "www.example.com"] {
+            storage
+                .put("uid", domain, None, certificate(domain))
+                .await
+                .expect("put");
+            storage
+                .put("other", domain, None, certificate(domain))
+                .await
+                .expect("put");
+        }
+        let cm = manager(storage.clone(), &clock).await;
+        for domain in ["example.com", "www.example.com"] {
+            cm.load_to_memory("uid", "agent", domain)
+                .await
+                .expect("load");
+        }
+
+        cm.purge("uid", "agent").await.expect("purge");
+        for domain in ["example.com", "www.example.com"] {
+            assert!(cm.get(domain).await.is_err());
+            assert!(matches!(
+                storage
+                    .get("uid", domain, clock.now(), DEFAULT_RENEW_BEFORE_EXPIRY)
+                    .await,
+                Err(GatewayError::CertificateNotFound)
+            ));
+            assert!(storage
+                .get("other", domain, clock.now(), DEFAULT_RENEW_BEFORE_EXPIRY)
+                .await
+                .is_ok());
+        }
+        assert!(matches!(
+            cm.purge("uid", "agent").await,
+            Err(GatewayError::CertificateNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn import_serves_a_certificate_covering_the_domains() {
+        let clock = MockClock::new(year_2030());
+        let storage = Arc::new(InMemoryCertificateStorage::new());
+        let cm = manager(storage, &clock).await;
+        let pems = certificate("example.com");
+        let (cert_pem, key_pem) = (pem::encode(&pems[0]), pem::encode(&pems[1]));
+
+        assert!(matches!(
+            cm.import(
+                "uid",
+                "agent",
+                vec!["other.example.com".to_owned()],
+                &cert_pem,
+                &key_pem
+            )
+            .await,
+            Err(GatewayError::Invalid(_))
+        ));
+        cm.import(
+            "uid",
+            "agent",
+            vec!["example.com".to_owned()],
+            &cert_pem,
+            &key_pem,
+        )
+        .await
+        .expect("import");
+        assert!(cm.get("example.com").await.is_ok());
+    }
+
+    // whether the server answers the hello of a client only offering TLS 1.2
+    fn accepts_tls12(config: Arc<ServerConfig>) -> bool {
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .expect("tls 1.2 client")
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let mut client = rustls::ClientConnection::new(
+            Arc::new(client_config),
+            "example.com".try_into().expect("server name"),
+        )
+        .expect("client");
+        let mut hello = Vec::new();
+        client.write_tls(&mut hello).expect("client hello");
+        let mut server = rustls::ServerConnection::new(config).expect("server");
+        server.read_tls(&mut &hello[..]).expect("read client hello");
+        server.process_new_packets().is_ok()
+    }
+
+    #[test]
+    fn served_configs_follow_the_tls_policy() {
+        let cert = Certificate::from_pem_vec(certificate("example.com")).expect("certificate");
+        let policy =
+            TlsPolicy::new(TlsVersion::Tls13, &[], &["h2".to_owned()]).expect("tls policy");
+        let config = cert.get_config(&policy).expect("config");
+        assert_eq!(config.alpn_protocols, vec![b"h2".to_vec()]);
+        assert!(!accepts_tls12(config));
+        assert!(accepts_tls12(
+            cert.get_config(&TlsPolicy::default()).expect("config")
+        ));
+    }
+
+    // self-signed with a 2048 bit RSA key, optionally marked as imported through the admin service
+    fn rsa_certificate(domain: &str, imported: bool) -> Vec<pem::Pem> {
+        use rsa::pkcs8::EncodePrivateKey;
+
+        let key = rsa::RsaPrivateKey::new(&mut rand_core::OsRng, 2048)
+            .and_then(|key| Ok(key.to_pkcs8_der()?))
+            .expect("rsa key");
+        let mut params = rcgen::CertificateParams::new(vec![domain.to_owned()]);
+        params.not_before = rcgen::date_time_ymd(2030, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2030, 4, 1);
+        params.alg = &rcgen::PKCS_RSA_SHA256;
+        params.key_pair = Some(rcgen::KeyPair::from_der(key.as_bytes()).expect("key pair"));
+        let cert = rcgen::Certificate::from_params(params).expect("certificate");
+        let pems =
+            cert.serialize_pem().expect("certificate pem") + &cert.serialize_private_key_pem();
+        let mut pems = pem::parse_many(pems).expect("pems");
+        if imported {
+            pems.push(pem::Pem::new(super::super::IMPORTED_PEM_TAG, Vec::new()));
+        }
+        pems
+    }
+
+    // the leaf the server answers a TLS 1.2 hello offering only the given signature scheme with
+    fn served_leaf(config: Arc<ServerConfig>, scheme: rustls::SignatureScheme) -> Vec<u8> {
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .expect("tls 1.2 client")
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let mut client = rustls::ClientConnection::new(
+            Arc::new(client_config),
+            "example.com".try_into().expect("server name"),
+        )
+        .expect("client");
+        let mut hello = Vec::new();
+        client.write_tls(&mut hello).expect("client hello");
+        // record and handshake headers, version, random, then the session id, cipher suites and
+        // compression methods before the extensions
+        let mut at = 5 + 4 + 2 + 32;
+        at += 1 + hello[at] as usize;
+        at += 2 + u16::from_be_bytes([hello[at], hello[at + 1]]) as usize;
+        at += 1 + hello[at] as usize;
+        at += 2;
+        while at < hello.len() {
+            let extension = u16::from_be_bytes([hello[at], hello[at + 1]]);
+            let len = u16::from_be_bytes([hello[at + 2], hello[at + 3]]) as usize;
+            if extension == 13 {
+                // signature_algorithms, every offered scheme becomes the given one
+                for offered in hello[at + 6..at + 4 + len].chunks_mut(2) {
+                    offered.copy_from_slice(&scheme.get_u16().to_be_bytes());
                 }
-            })
+            }
+            at += 4 + len;
+        }
+        let mut server = rustls::ServerConnection::new(config).expect("server");
+        server.read_tls(&mut &hello[..]).expect("read client hello");
+        server.process_new_packets().expect("client hello accepted");
+        let mut answer = Vec::new();
+        server.write_tls(&mut answer).expect("server hello");
+        answer
+    }
+
+    #[test]
+    fn the_certificate_of_the_offered_key_type_is_served() {
+        let (session_resumption, tls_policy) = (SessionResumption::default(), TlsPolicy::default());
+        let ecdsa_pems = certificate("example.com");
+        let ecdsa = || Certificate::from_pem_vec(ecdsa_pems.clone()).expect("ecdsa");
+        let rsa = Certificate::from_pem_vec(rsa_certificate("example.com", true)).expect("rsa");
+        let ecdsa_leaf = ecdsa().certified_key.cert[0].0.clone();
+        let rsa_leaf = rsa.certified_key.cert[0].0.clone();
+        let contains = |answer: &[u8], leaf: &[u8]| answer.windows(leaf.len()).any(|w| w == leaf);
+
+        // an imported RSA certificate is served next to the ACME ECDSA one
+        let mut domain_certificates =
+            DomainCertificates::new(ecdsa(), 0, &session_resumption, &tls_policy)
+                .expect("domain certificates");
+        domain_certificates.put(rsa, &session_resumption, &tls_policy);
+        let answer = served_leaf(
+            domain_certificates.config.clone(),
+            rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
+        );
+        assert!(contains(&answer, &ecdsa_leaf) && !contains(&answer, &rsa_leaf));
+        let answer = served_leaf(
+            domain_certificates.config.clone(),
+            rustls::SignatureScheme::RSA_PSS_SHA256,
+        );
+        assert!(contains(&answer, &rsa_leaf) && !contains(&answer, &ecdsa_leaf));
+
+        // ACME issues the configured key type only, a certificate of another one replaces it
+        let mut domain_certificates =
+            DomainCertificates::new(ecdsa(), 0, &session_resumption, &tls_policy)
+                .expect("domain certificates");
+        let acme_rsa =
+            Certificate::from_pem_vec(rsa_certificate("example.com", false)).expect("rsa");
+        domain_certificates.put(acme_rsa, &session_resumption, &tls_policy);
+        assert_eq!(domain_certificates.certificates.len(), 1);
+        assert_eq!(
+            domain_certificates.certificates[0].algorithm(),
+            rustls::SignatureAlgorithm::RSA
+        );
+    }
+
+    fn ca(name: &str) -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(Vec::new());
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).expect("ca")
+    }
+
+    #[test]
+    fn days_until_expiry_count_whole_days_on_both_sides() {
+        let cert = Certificate::from_pem_vec(certificate("a.com")).expect("certificate");
+        let days = |at| cert.days_until_expiry(at);
+        let not_after = cert.not_after();
+        let second = Duration::from_secs(1);
+        assert_eq!(days(not_after - DAY), 1);
+        assert_eq!(days(not_after - second), 0);
+        assert_eq!(days(not_after), 0);
+        assert_eq!(days(not_after + second), -1);
+        assert_eq!(days(not_after + DAY), -1);
+        assert_eq!(days(not_after + DAY + second), -2);
+    }
+
+    #[test]
+    fn broken_chains_are_rejected() {
+        let root = ca("root");
+        let intermediate = ca("intermediate");
+        let leaf = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+            "example.com".to_owned(),
+        ]))
+        .expect("leaf");
+        let other_key = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).expect("key");
+        let leaf_pem = pem::Pem::new(
+            "CERTIFICATE",
+            leaf.serialize_der_with_signer(&intermediate)
+                .expect("leaf der"),
+        );
+        let intermediate_pem = pem::Pem::new(
+            "CERTIFICATE",
+            intermediate
+                .serialize_der_with_signer(&root)
+                .expect("intermediate der"),
+        );
+        let root_pem = pem::Pem::new("CERTIFICATE", root.serialize_der().expect("root der"));
+        let key_pem = pem::Pem::new("PRIVATE KEY", leaf.serialize_private_key_der());
+        let other_key_pem = pem::Pem::new("PRIVATE KEY", other_key.serialize_der());
+        let chain = |pems: &[&pem::Pem]| {
+            Certificate::from_pem_vec(pems.iter().map(|pem| (*pem).clone()).collect())
+        };
+
+        assert!(chain(&[&leaf_pem, &intermediate_pem, &root_pem, &key_pem]).is_ok());
+        assert!(chain(&[&leaf_pem, &intermediate_pem, &key_pem]).is_ok());
+        // wrong order
+        assert!(matches!(
+            chain(&[&intermediate_pem, &leaf_pem, &key_pem]),
+            Err(GatewayError::Invalid("certificate chain"))
+        ));
+        assert!(matches!(
+            chain(&[&leaf_pem, &root_pem, &intermediate_pem, &key_pem]),
+            Err(GatewayError::Invalid("certificate chain"))
+        ));
+        // missing intermediate
+        assert!(matches!(
+            chain(&[&leaf_pem, &root_pem, &key_pem]),
+            Err(GatewayError::Invalid("certificate chain"))
+        ));
+        // key of another certificate
+        assert!(matches!(
+            chain(&[&leaf_pem, &intermediate_pem, &other_key_pem]),
+            Err(GatewayError::Invalid("certificate chain"))
+        ));
+    }
+
+    #[test]
+    fn ocsp_refresh_follows_the_clock() {
+        let cert = Certificate::from_pem_vec(certificate("example.com")).expect("certificate");
+        assert!(cert.ocsp_refresh_needed(year_2030()));
+        let cert = cert
+            .with_ocsp(Vec::new(), year_2030() + 3 * DAY)
+            .expect("stapled certificate");
+        assert!(!cert.ocsp_refresh_needed(year_2030()));
+        assert!(!cert.ocsp_refresh_needed(year_2030() + DAY));
+        assert!(cert.ocsp_refresh_needed(year_2030() + 2 * DAY));
+    }
+
+    #[test]
+    fn domains_follow_the_host_name_rules() {
+        let label = "a".repeat(63);
+        let longest = format!("{0}.{0}.{0}.{1}", label, "a".repeat(61));
+        for domain in [
+            "example.com",
+            "sub-domain.example.com",
+            "xn--bcher-kva.example",
+            "EXAMPLE.com",
+            longest.as_str(),
+        ] {
+            assert!(validate_domain(domain, false).is_ok(), "{}", domain);
+        }
+        assert!(validate_domain("*.example.com", true).is_ok());
+        for domain in [
+            "",
+            "localhost",
+            "*.example.com",
+            "-example.com",
+            "example-.com",
+            "exa_mple.com",
+            "example..com",
+            "bücher.example",
+            "xn--a.example",
+            "192.168.1.1",
+            &format!("{}.com", "a".repeat(64)),
+            &format!("a.{}", longest),
+        ] {
+            assert!(validate_domain(domain, false).is_err(), "{}", domain);
+        }
     }
 }
@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use instant_acme::AccountCredentials;
+use pem::Pem;
+
+use crate::error::GatewayError;
+
+use super::{ACMEChallenge, Certificate, CertificateStorage};
+
+const PENDING_TIMEOUT: Duration = Duration::from_secs(120); // as in the file storage
+
+#[derive(Default)]
+struct Storage {
+    directory_accounts: HashMap<String, Vec<u8>>, // directory -> credentials, kept serialized as they are not Clone
+    certificates: HashMap<(String, String), Vec<Pem>>, // (account, domain) -> pems
+    accounts: HashMap<(String, String), Vec<u8>>, // (account, domain) -> credentials
+    failed: HashMap<(String, String), Instant>,   // (account, domain) -> retry at
+    pending: HashMap<(String, String), Instant>,  // (account, domain) -> pending since
+    challenges: HashMap<String, ACMEChallenge>,
+}
+
+// keeps everything in process and loses it on exit, for tests and throwaway gateways
+#[derive(Default)]
+pub struct InMemoryCertificateStorage {
+    storage: Mutex<Storage>,
+}
+
+impl InMemoryCertificateStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Storage>, GatewayError> {
+        self.storage
+            .lock()
+            .map_err(|_| GatewayError::Other("certificate storage poisoned"))
+    }
+}
+
+fn key(account: &str, domain: &str) -> (String, String) {
+    (account.to_owned(), domain.to_owned())
+}
+
+#[async_trait]
+impl CertificateStorage for InMemoryCertificateStorage {
+    async fn set_account_credentials(
+        &self,
+        directory: &str,
+        account: AccountCredentials,
+    ) -> Result<(), GatewayError> {
+        self.lock()?
+            .directory_accounts
+            .insert(directory.to_owned(), serde_json::to_vec(&account)?);
+        Ok(())
+    }
+    async fn get_account_credentials(
+        &self,
+        directory: &str,
+    ) -> Result<AccountCredentials, GatewayError> {
+        let account = self
+            .lock()?
+            .directory_accounts
+            .get(directory)
+            .cloned()
+            .ok_or(GatewayError::Invalid("No account credentials found"))?;
+        Ok(serde_json::from_slice(&account)?)
+    }
+    async fn put(
+        &self,
+        account: &str,
+        domain: &str,
+        acme_account: Option<AccountCredentials>,
+        pems: Vec<Pem>,
+    ) -> Result<(), GatewayError> {
+        let acme_account = acme_account
+            .map(|acme_account| serde_json::to_vec(&acme_account))
+            .transpose()?;
+        let mut storage = self.lock()?;
+        if let Some(acme_account) = acme_account {
+            storage.accounts.insert(key(account, domain), acme_account);
+        }
+        storage.certificates.insert(key(account, domain), pems);
+        storage.failed.remove(&key(account, domain));
+        storage.pending.remove(&key(account, domain));
+        Ok(())
+    }
+    async fn get_pems(&self, account: &str, domain: &str) -> Result<Vec<Pem>, GatewayError> {
+        self.lock()?
+            .certificates
+            .get(&key(account, domain))
+            .cloned()
+            .ok_or(GatewayError::CertificateNotFound)
+    }
+    async fn delete(
+        &self,
+        uid: &str,
+        _agent_name: &str,
+        domains: &[String],
+    ) -> Result<(), GatewayError> {
+        let mut storage = self.lock()?;
+        let mut found = false;
+        for domain in domains {
+            let key = key(uid, domain);
+            storage.accounts.remove(&key);
+            storage.failed.remove(&key);
+            storage.pending.remove(&key);
+            found |= storage.certificates.remove(&key).is_some();
+        }
+        found.then_some(()).ok_or(GatewayError::CertificateNotFound)
+    }
+    async fn get_acme_account_credentials(
+        &self,
+        account: &str,
+        domain: &str,
+    ) -> Option<AccountCredentials> {
+        let acme_account = self
+            .lock()
+            .ok()?
+            .accounts
+            .get(&key(account, domain))
+            .cloned()?;
+        serde_json::from_slice(&acme_account).ok()
+    }
+    async fn set_failed(
+        &self,
+        account: &str,
+        domain: &str,
+        retry_after: Duration,
+    ) -> Result<(), GatewayError> {
+        let mut storage = self.lock()?;
+        storage
+            .failed
+            .insert(key(account, domain), Instant::now() + retry_after);
+        storage.pending.remove(&key(account, domain));
+        Ok(())
+    }
+    async fn is_failed(&self, account: &str, domain: &str) -> bool {
+        self.lock().is_ok_and(|storage| {
+            storage
+                .failed
+                .get(&key(account, domain))
+                .is_some_and(|retry_at| *retry_at > Instant::now())
+        })
+    }
+    async fn set_pending(&self, account: &str, domain: &str) -> Result<(), GatewayError> {
+        self.lock()?
+            .pending
+            .insert(key(account, domain), Instant::now());
+        Ok(())
+    }
+    async fn is_pending(&self, account: &str, domain: &str) -> bool {
+        self.lock().is_ok_and(|storage| {
+            storage
+                .pending
+                .get(&key(account, domain))
+                .is_some_and(|since| since.elapsed() < PENDING_TIMEOUT)
+        })
+    }
+    async fn put_challenge(
+        &self,
+        domain: &str,
+        challenge: &ACMEChallenge,
+    ) -> Result<(), GatewayError> {
+        self.lock()?
+            .challenges
+            .insert(domain.to_owned(), challenge.clone());
+        Ok(())
+    }
+    async fn get_challenge(&self, domain: &str) -> Result<ACMEChallenge, GatewayError> {
+        self.lock()?
+            .challenges
+            .get(domain)
+            .cloned()
+            .ok_or(GatewayError::ACMEChallengeNotFound)
+    }
+    async fn remove_challenge(&self, domain: &str) -> Result<(), GatewayError> {
+        self.lock()?.challenges.remove(domain);
+        Ok(())
+    }
+    async fn get_challenges(&self) -> Result<Vec<(String, ACMEChallenge)>, GatewayError> {
+        Ok(self
+            .lock()?
+            .challenges
+            .iter()
+            .map(|(domain, challenge)| (domain.to_owned(), challenge.clone()))
+            .collect())
+    }
+    async fn health(&self) -> Result<(), GatewayError> {
+        self.lock().map(|_| ())
+    }
+    async fn list(&self) -> Result<Vec<(String, String, Vec<String>)>, GatewayError> {
+        let certificates = self.lock()?.certificates.clone();
+        Ok(certificates
+            .into_iter()
+            .filter_map(|((account, domain), pems)| {
+                let domains = Certificate::info_from_pem_vec(&pems).ok()?.domains;
+                Some((account, domain, domains))
+            })
+            .collect())
+    }
+}
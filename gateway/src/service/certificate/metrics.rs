@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+static CERTIFICATES_LOADED: AtomicU64 = AtomicU64::new(0);
+static ISSUANCE_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+static ISSUANCE_SUCCEEDED: AtomicU64 = AtomicU64::new(0);
+static ISSUANCE_FAILED: AtomicU64 = AtomicU64::new(0);
+static CHALLENGE_VALID: AtomicU64 = AtomicU64::new(0);
+static CHALLENGE_INVALID: AtomicU64 = AtomicU64::new(0);
+static CERTIFICATE_EXPIRY: Mutex<Option<HashMap<String, SystemTime>>> = Mutex::new(None); // domain -> not after
+
+pub fn set_certificates_loaded(count: usize) {
+    CERTIFICATES_LOADED.store(count as u64, Ordering::Relaxed);
+}
+
+pub fn issuance_attempted() {
+    ISSUANCE_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn issuance_succeeded() {
+    ISSUANCE_SUCCEEDED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn issuance_failed() {
+    ISSUANCE_FAILED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn challenge_validated(valid: bool) {
+    if valid {
+        CHALLENGE_VALID.fetch_add(1, Ordering::Relaxed);
+    } else {
+        CHALLENGE_INVALID.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn set_expiry(domain: &str, not_after: SystemTime) {
+    if let Ok(mut expiry) = CERTIFICATE_EXPIRY.lock() {
+        expiry
+            .get_or_insert_with(HashMap::new)
+            .insert(domain.to_owned(), not_after);
+    }
+}
+
+pub fn remove_expiry(domain: &str) {
+    if let Ok(mut expiry) = CERTIFICATE_EXPIRY.lock() {
+        if let Some(expiry) = expiry.as_mut() {
+            expiry.remove(domain);
+        }
+    }
+}
+
+// Prometheus text exposition format
+pub fn render() -> String {
+    let mut res = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, u64)]| {
+        let _ = writeln!(res, "# HELP {} {}", name, help);
+        let _ = writeln!(res, "# TYPE {} {}", name, kind);
+        for (labels, value) in values {
+            let _ = writeln!(res, "{}{} {}", name, labels, value);
+        }
+    };
+    metric(
+        "narrowlink_certificates_loaded",
+        "gauge",
+        "Number of certificates loaded in memory",
+        &[("", CERTIFICATES_LOADED.load(Ordering::Relaxed))],
+    );
+    metric(
+        "narrowlink_certificate_issuance_attempts_total",
+        "counter",
+        "ACME issuance and renewal attempts",
+        &[("", ISSUANCE_ATTEMPTS.load(Ordering::Relaxed))],
+    );
+    metric(
+        "narrowlink_certificate_issuance_succeeded_total",
+        "counter",
+        "Successful ACME issuances and renewals",
+        &[("", ISSUANCE_SUCCEEDED.load(Ordering::Relaxed))],
+    );
+    metric(
+        "narrowlink_certificate_issuance_failed_total",
+        "counter",
+        "Failed ACME issuances and renewals",
+        &[("", ISSUANCE_FAILED.load(Ordering::Relaxed))],
+    );
+    metric(
+        "narrowlink_acme_challenge_validations_total",
+        "counter",
+        "ACME challenge validations by result",
+        &[
+            (
+                "{result=\"valid\"}",
+                CHALLENGE_VALID.load(Ordering::Relaxed),
+            ),
+            (
+                "{result=\"invalid\"}",
+                CHALLENGE_INVALID.load(Ordering::Relaxed),
+            ),
+        ],
+    );
+
+    let _ = writeln!(
+        res,
+        "# HELP narrowlink_certificate_expiry_seconds Seconds until the certificate expires"
+    );
+    let _ = writeln!(res, "# TYPE narrowlink_certificate_expiry_seconds gauge");
+    if let Ok(expiry) = CERTIFICATE_EXPIRY.lock() {
+        let now = SystemTime::now();
+        for (domain, not_after) in expiry.iter().flatten() {
+            let seconds = match not_after.duration_since(now) {
+                Ok(remaining) => remaining.as_secs() as i64,
+                Err(e) => -(e.duration().as_secs() as i64),
+            };
+            let _ = writeln!(
+                res,
+                "narrowlink_certificate_expiry_seconds{{domain=\"{}\"}} {}",
+                domain.replace('\\', "\\\\").replace('"', "\\\""),
+                seconds
+            );
+        }
+    }
+    res
+}
@@ -1,8 +1,18 @@
 mod acme;
 
+pub mod clock;
+pub mod encrypted_storage;
 pub mod file_storage;
 pub mod manager;
-use std::{sync::Arc, time::Duration};
+pub mod memory_storage;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod ocsp;
+pub mod redis_storage;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use async_trait::async_trait;
 
@@ -10,21 +20,42 @@ use instant_acme::{Account, AccountCredentials};
 
 use pem::Pem;
 
-pub(crate) use acme::ACMEChallengeType;
-use rustls::ServerConfig;
-use x509_parser::prelude::{FromDer, X509Certificate};
+pub(crate) use acme::{ACMEChallenge, ACMEChallengeType, KeyType};
+use rustls::{
+    server::{
+        ClientHello, NoServerSessionStorage, ProducesTickets, ResolvesServerCert,
+        ServerSessionMemoryCache, StoresServerSessions,
+    },
+    sign::CertifiedKey,
+    ConfigBuilder, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion, WantsVerifier,
+};
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
 
 use crate::error::GatewayError;
 
 pub const ACME_TLS_ALPN_NAME: &[u8] = b"acme-tls/1";
+pub const DEFAULT_RENEW_BEFORE_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60); // seven days
+pub const OCSP_REFRESH_BEFORE_NEXT_UPDATE: Duration = Duration::from_secs(24 * 60 * 60); // one day
+pub const IMPORTED_PEM_TAG: &str = "NARROWLINK IMPORTED"; // empty block stored with imported certificates
 
 #[async_trait]
 pub trait CertificateStorage {
-    async fn set_default_account_credentials(
+    // the gateway's account registered with the directory
+    async fn set_account_credentials(
         &self,
+        directory: &str,
         account: AccountCredentials,
     ) -> Result<(), GatewayError>;
-    async fn get_default_account_credentials(&self) -> Result<AccountCredentials, GatewayError>;
+    async fn get_account_credentials(
+        &self,
+        directory: &str,
+    ) -> Result<AccountCredentials, GatewayError>;
+    // the single account stored before accounts were keyed by directory, it is kept for rollbacks
+    async fn get_default_account_credentials(&self) -> Result<AccountCredentials, GatewayError> {
+        Err(GatewayError::Invalid("No account credentials found"))
+    }
     async fn put(
         &self,
         account: &str,
@@ -32,22 +63,83 @@ pub trait CertificateStorage {
         acme_account: Option<AccountCredentials>,
         pems: Vec<Pem>,
     ) -> Result<(), GatewayError>;
+    // the chain and key as they were put
+    async fn get_pems(&self, account: &str, domain: &str) -> Result<Vec<Pem>, GatewayError>;
+    // the ACME account is only returned while the certificate needs a renewal as seen at `now`,
+    // `renew_before` ahead of its expiry
     async fn get(
         &self,
         account: &str,
         domain: &str,
-    ) -> Result<(Certificate, Option<AccountCredentials>), GatewayError>;
+        now: SystemTime,
+        renew_before: Duration,
+    ) -> Result<(Certificate, Option<AccountCredentials>), GatewayError> {
+        let cert = Certificate::from_pem_vec(self.get_pems(account, domain).await?)?;
+        let acme_account = if cert.renew_needed_at(now, renew_before) {
+            self.get_acme_account_credentials(account, domain).await
+        } else {
+            None
+        };
+        Ok((cert, acme_account))
+    }
+    // the certificates of the agent's domains and the account data kept with them, all of them
+    // or none, the storage is keyed by account and domain so the agent's domains are passed,
+    // CertificateNotFound if none of them is stored
+    async fn delete(
+        &self,
+        uid: &str,
+        agent_name: &str,
+        domains: &[String],
+    ) -> Result<(), GatewayError>;
     async fn get_acme_account_credentials(
         &self,
         account: &str,
         domain: &str,
     ) -> Option<AccountCredentials>;
-    async fn set_failed(&self, account: &str, domain: &str) -> Result<(), GatewayError>;
+    async fn set_failed(
+        &self,
+        account: &str,
+        domain: &str,
+        retry_after: Duration,
+    ) -> Result<(), GatewayError>;
     async fn is_failed(&self, account: &str, domain: &str) -> bool;
     async fn set_pending(&self, account: &str, domain: &str) -> Result<(), GatewayError>;
     async fn is_pending(&self, account: &str, domain: &str) -> bool;
-    async fn get_default_account(&self) -> Result<Account, GatewayError> {
-        let account_credentials = self.get_default_account_credentials().await?;
+    async fn put_challenge(
+        &self,
+        domain: &str,
+        challenge: &ACMEChallenge,
+    ) -> Result<(), GatewayError>;
+    async fn get_challenge(&self, domain: &str) -> Result<ACMEChallenge, GatewayError>;
+    async fn remove_challenge(&self, domain: &str) -> Result<(), GatewayError>;
+    async fn get_challenges(&self) -> Result<Vec<(String, ACMEChallenge)>, GatewayError>;
+    // probes the backend, errors while it is unreachable
+    async fn health(&self) -> Result<(), GatewayError>;
+    // (account, domain, certificate domains) of every stored certificate, the storage is keyed
+    // by account and domain so agent names are unknown to it
+    async fn list(&self) -> Result<Vec<(String, String, Vec<String>)>, GatewayError> {
+        Err(GatewayError::Unsupported("listing certificates"))
+    }
+    // (account, domain) of certificates put by other gateways sharing the storage
+    async fn watch(&self) -> Option<UnboundedReceiver<(String, String)>> {
+        None
+    }
+    // the default account is moved to the directory it was registered with on first use
+    async fn get_account(&self, directory: &str) -> Result<Account, GatewayError> {
+        let account_credentials = match self.get_account_credentials(directory).await {
+            Ok(account_credentials) => account_credentials,
+            Err(e) => {
+                let Ok(default) = self.get_default_account_credentials().await else {
+                    return Err(e);
+                };
+                if credentials_directory(&default).is_some_and(|d| d != directory) {
+                    return Err(e);
+                }
+                self.set_account_credentials(directory, copy_credentials(&default)?)
+                    .await?;
+                default
+            }
+        };
         Ok(Account::from_credentials(account_credentials).await?)
     }
     async fn get_acme_account(&self, account: &str, domain: &str) -> Result<Account, GatewayError> {
@@ -60,24 +152,294 @@ pub trait CertificateStorage {
     }
 }
 
+// directory url the account was registered with, unknown for credentials of old versions
+pub fn credentials_directory(account: &AccountCredentials) -> Option<String> {
+    serde_json::to_value(account)
+        .ok()?
+        .get("directory")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+// the credentials are not Clone
+pub fn copy_credentials(account: &AccountCredentials) -> Result<AccountCredentials, GatewayError> {
+    Ok(serde_json::from_value(serde_json::to_value(account)?)?)
+}
+
+#[async_trait]
+pub trait DnsProvider {
+    async fn publish_txt_record(&self, name: &str, value: &str) -> Result<(), GatewayError>;
+    async fn remove_txt_record(&self, name: &str, value: &str) -> Result<(), GatewayError>;
+}
+
+// publishes HTTP-01 tokens outside the gateway, e.g. on a CDN in front of it, the built-in
+// responder keeps answering the requests that reach the gateway
+#[async_trait]
+pub trait HttpChallengeHandler {
+    async fn publish(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), GatewayError>;
+    async fn remove(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), GatewayError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct CertificateInfo {
+    pub domains: Vec<String>,
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    pub staging: bool,  // issued by a staging CA, untrusted
+    pub imported: bool, // issued outside of ACME, never renewed automatically
+}
+
+impl CertificateInfo {
+    // same matching as the certificate store, a wildcard covers a single label
+    pub fn covers(&self, domain: &str) -> bool {
+        self.domains.iter().any(|name| {
+            name == domain
+                || name.strip_prefix("*.").is_some_and(|parent| {
+                    domain
+                        .split_once('.')
+                        .is_some_and(|(label, rest)| !label.is_empty() && rest == parent)
+                })
+        })
+    }
+}
+
+// how clients resume their sessions, the cache and the ticket keys are shared by every config
+// it is applied to
+#[derive(Clone)]
+pub struct SessionResumption {
+    session_storage: Arc<dyn StoresServerSessions + Send + Sync>,
+    ticketer: Option<Arc<dyn ProducesTickets>>, // stateless tickets, their keys rotate every six hours
+    tls13_tickets: usize,                       // sent after every full TLS 1.3 handshake
+}
+
+impl SessionResumption {
+    // a zero cache size disables stateful resumption, without tickets too none is offered
+    pub fn new(
+        tickets: bool,
+        cache_size: usize,
+        tls13_tickets: usize,
+    ) -> Result<Self, GatewayError> {
+        let session_storage: Arc<dyn StoresServerSessions + Send + Sync> = if cache_size == 0 {
+            Arc::new(NoServerSessionStorage {})
+        } else {
+            ServerSessionMemoryCache::new(cache_size)
+        };
+        Ok(Self {
+            session_storage,
+            ticketer: tickets.then(rustls::Ticketer::new).transpose()?,
+            tls13_tickets,
+        })
+    }
+
+    pub fn apply(&self, config: &mut ServerConfig) {
+        config.session_storage = self.session_storage.clone();
+        if let Some(ticketer) = self.ticketer.as_ref() {
+            config.ticketer = ticketer.clone();
+        }
+        config.send_tls13_tickets = self.tls13_tickets;
+    }
+
+    pub fn applied(&self, config: Arc<ServerConfig>) -> Arc<ServerConfig> {
+        let mut config = ServerConfig::clone(&config);
+        self.apply(&mut config);
+        Arc::new(config)
+    }
+}
+
+// the rustls defaults, a session cache of 256 entries and no tickets
+impl Default for SessionResumption {
+    fn default() -> Self {
+        Self {
+            session_storage: ServerSessionMemoryCache::new(256),
+            ticketer: None,
+            tls13_tickets: 4,
+        }
+    }
+}
+
+impl std::fmt::Debug for SessionResumption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionResumption")
+            .field("tickets", &self.ticketer.is_some())
+            .field("tls13_tickets", &self.tls13_tickets)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+// the suites a policy may name, by their IANA registry names
+pub fn cipher_suite(name: &str) -> Option<SupportedCipherSuite> {
+    use rustls::cipher_suite::*;
+    Some(match name {
+        "TLS13_AES_256_GCM_SHA384" => TLS13_AES_256_GCM_SHA384,
+        "TLS13_AES_128_GCM_SHA256" => TLS13_AES_128_GCM_SHA256,
+        "TLS13_CHACHA20_POLY1305_SHA256" => TLS13_CHACHA20_POLY1305_SHA256,
+        "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384" => TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256" => TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256" => {
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+        }
+        "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384" => TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256" => TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256" => {
+            TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256
+        }
+        _ => return None,
+    })
+}
+
+// versions, cipher suites and protocols of the served configs, the TLS-ALPN-01 challenge config
+// only advertises its own protocol
+#[derive(Debug, Clone)]
+pub struct TlsPolicy {
+    versions: Vec<&'static SupportedProtocolVersion>,
+    cipher_suites: Vec<SupportedCipherSuite>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl TlsPolicy {
+    // suites are named as in the IANA registry, e.g. TLS13_AES_256_GCM_SHA384, all the rustls
+    // ones if empty, a suite of a version below the minimum is rejected
+    pub fn new(
+        min_version: TlsVersion,
+        cipher_suites: &[String],
+        alpn: &[String],
+    ) -> Result<Self, GatewayError> {
+        let versions: Vec<&'static SupportedProtocolVersion> = match min_version {
+            TlsVersion::Tls12 => vec![&rustls::version::TLS13, &rustls::version::TLS12],
+            TlsVersion::Tls13 => vec![&rustls::version::TLS13],
+        };
+        let cipher_suites = if cipher_suites.is_empty() {
+            rustls::DEFAULT_CIPHER_SUITES
+                .iter()
+                .filter(|suite| {
+                    versions
+                        .iter()
+                        .any(|version| version.version == suite.version().version)
+                })
+                .copied()
+                .collect()
+        } else {
+            cipher_suites
+                .iter()
+                .map(|name| {
+                    cipher_suite(name)
+                        .filter(|suite| {
+                            versions
+                                .iter()
+                                .any(|version| version.version == suite.version().version)
+                        })
+                        .ok_or(GatewayError::Invalid("TLS cipher suite"))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let policy = Self {
+            versions,
+            cipher_suites,
+            alpn: alpn
+                .iter()
+                .map(|protocol| protocol.as_bytes().to_vec())
+                .collect(),
+        };
+        policy.builder()?;
+        Ok(policy)
+    }
+
+    pub fn builder(&self) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, GatewayError> {
+        Ok(ServerConfig::builder()
+            .with_cipher_suites(&self.cipher_suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&self.versions)?)
+    }
+
+    // QUIC only runs TLS 1.3, a policy without any TLS 1.3 suite is rejected
+    pub fn quic_builder(&self) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, GatewayError> {
+        let cipher_suites = self
+            .cipher_suites
+            .iter()
+            .filter(|suite| suite.version().version == rustls::version::TLS13.version)
+            .copied()
+            .collect::<Vec<_>>();
+        Ok(ServerConfig::builder()
+            .with_cipher_suites(&cipher_suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?)
+    }
+
+    pub fn alpn(&self) -> Vec<Vec<u8>> {
+        self.alpn.clone()
+    }
+}
+
+// the rustls defaults with h2 and http/1.1
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        Self {
+            versions: rustls::DEFAULT_VERSIONS.to_vec(),
+            cipher_suites: rustls::DEFAULT_CIPHER_SUITES.to_vec(),
+            alpn: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        }
+    }
+}
+
 pub struct Certificate {
     certificate_chain: Vec<rustls::Certificate>,
-    // private_key: rustls::PrivateKey,
-    config: Arc<ServerConfig>,
+    private_key: rustls::PrivateKey,
+    info: CertificateInfo,
+    ocsp_next_update: Option<SystemTime>,
+    certified_key: Arc<CertifiedKey>,
+}
+
+// serves the certificate whose key type the client supports, the first one that does wins
+struct KeyTypeResolver(Vec<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for KeyTypeResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.0
+            .iter()
+            .find(|certified_key| {
+                certified_key
+                    .key
+                    .choose_scheme(client_hello.signature_schemes())
+                    .is_some()
+            })
+            .or(self.0.first())
+            .cloned()
+    }
 }
 
 impl Certificate {
     pub fn from_pem_vec(v: Vec<Pem>) -> Result<Self, GatewayError> {
         let mut certificate_chain = Vec::new();
         let mut private_key = None;
+        let mut imported = false;
         for i in v {
             match i.tag() {
                 "CERTIFICATE" => {
                     certificate_chain.push(rustls::Certificate(i.contents().to_vec()));
                 }
-                "PRIVATE KEY" => {
+                "PRIVATE KEY" | "RSA PRIVATE KEY" | "EC PRIVATE KEY" => {
                     private_key.replace(rustls::PrivateKey(i.contents().to_vec()));
                 }
+                IMPORTED_PEM_TAG => imported = true,
                 _ => continue,
             }
         }
@@ -89,64 +451,221 @@ impl Certificate {
         if certificate_chain.is_empty() {
             return Err(GatewayError::Invalid("Invalid Pem FIle"));
         }
-        let mut config = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certificate_chain.clone(), private_key.clone())?;
-        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Self::validate_chain(&certificate_chain, &private_key)?;
+        let mut info = Self::leaf_info(&certificate_chain)?;
+        info.imported = imported;
+        let certified_key = Self::certified_key(&certificate_chain, &private_key, Vec::new())?;
 
         Ok(Certificate {
             certificate_chain,
-            // private_key,
-            config: Arc::new(config),
+            private_key,
+            info,
+            ocsp_next_update: None,
+            certified_key,
         })
     }
 
-    pub fn renew_needed(&self) -> bool {
-        for certificate in self.certificate_chain.iter() {
+    // the leaf of the chain, the private key is neither required nor read
+    pub fn info_from_pem_vec(v: &[Pem]) -> Result<CertificateInfo, GatewayError> {
+        let certificate_chain = v
+            .iter()
+            .filter(|i| i.tag() == "CERTIFICATE")
+            .map(|i| rustls::Certificate(i.contents().to_vec()))
+            .collect::<Vec<_>>();
+        let mut info = Self::leaf_info(&certificate_chain)?;
+        info.imported = v.iter().any(|i| i.tag() == IMPORTED_PEM_TAG);
+        Ok(info)
+    }
+
+    fn certified_key(
+        certificate_chain: &[rustls::Certificate],
+        private_key: &rustls::PrivateKey,
+        ocsp: Vec<u8>,
+    ) -> Result<Arc<CertifiedKey>, GatewayError> {
+        let key = rustls::sign::any_supported_type(private_key)
+            .map_err(|_| GatewayError::Invalid("private key"))?;
+        let mut certified_key = CertifiedKey::new(certificate_chain.to_vec(), key);
+        certified_key.ocsp = Some(ocsp).filter(|ocsp| !ocsp.is_empty());
+        Ok(Arc::new(certified_key))
+    }
+
+    // the certificate is chosen per handshake among several
+    pub fn negotiated_config(
+        certificates: &[Certificate],
+        policy: &TlsPolicy,
+    ) -> Result<Arc<ServerConfig>, GatewayError> {
+        if certificates.is_empty() {
+            return Err(GatewayError::CertificateNotFound);
+        }
+        let mut config = policy
+            .builder()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(KeyTypeResolver(
+                certificates
+                    .iter()
+                    .map(|certificate| certificate.certified_key.clone())
+                    .collect(),
+            )));
+        config.alpn_protocols = policy.alpn();
+        Ok(Arc::new(config))
+    }
+
+    pub fn algorithm(&self) -> rustls::SignatureAlgorithm {
+        self.certified_key.key.algorithm()
+    }
+
+    // the leaf comes first, each certificate is followed by its issuer and the private key
+    // belongs to the leaf, otherwise clients fail the handshake
+    fn validate_chain(
+        certificate_chain: &[rustls::Certificate],
+        private_key: &rustls::PrivateKey,
+    ) -> Result<(), GatewayError> {
+        let mut parsed = Vec::new();
+        for certificate in certificate_chain.iter() {
             let Ok((_, cert)) = X509Certificate::from_der(certificate.as_ref()) else {
-                return true;
+                return Err(GatewayError::Invalid("certificate chain"));
+            };
+            parsed.push(cert);
+        }
+        let Some(leaf) = parsed.first().filter(|leaf| !leaf.is_ca()) else {
+            return Err(GatewayError::Invalid("certificate chain"));
+        };
+        if parsed
+            .windows(2)
+            .any(|pair| pair[0].issuer().as_raw() != pair[1].subject().as_raw())
+        {
+            return Err(GatewayError::Invalid("certificate chain"));
+        }
+
+        // proves the key by signing with it and verifying against the leaf public key
+        let schemes = [
+            (
+                rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
+                &ring::signature::ECDSA_P256_SHA256_ASN1
+                    as &dyn ring::signature::VerificationAlgorithm,
+            ),
+            (
+                rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
+                &ring::signature::ECDSA_P384_SHA384_ASN1,
+            ),
+            (
+                rustls::SignatureScheme::RSA_PSS_SHA256,
+                &ring::signature::RSA_PSS_2048_8192_SHA256,
+            ),
+            (rustls::SignatureScheme::ED25519, &ring::signature::ED25519),
+        ];
+        let signer = rustls::sign::any_supported_type(private_key)
+            .ok()
+            .and_then(|key| key.choose_scheme(&schemes.map(|(scheme, _)| scheme)))
+            .ok_or(GatewayError::Invalid("certificate chain"))?;
+        let Some((_, algorithm)) = schemes
+            .iter()
+            .find(|(scheme, _)| *scheme == signer.scheme())
+        else {
+            return Err(GatewayError::Invalid("certificate chain"));
+        };
+        let message = leaf.tbs_certificate.as_ref();
+        let signature = signer
+            .sign(message)
+            .map_err(|_| GatewayError::Invalid("certificate chain"))?;
+        ring::signature::UnparsedPublicKey::new(
+            *algorithm,
+            leaf.public_key().subject_public_key.data.as_ref(),
+        )
+        .verify(message, &signature)
+        .map_err(|_| GatewayError::Invalid("certificate chain"))
+    }
+
+    pub fn with_ocsp(
+        &self,
+        response: Vec<u8>,
+        next_update: SystemTime,
+    ) -> Result<Self, GatewayError> {
+        Ok(Certificate {
+            certificate_chain: self.certificate_chain.clone(),
+            private_key: self.private_key.clone(),
+            info: self.info.clone(),
+            ocsp_next_update: Some(next_update),
+            certified_key: Self::certified_key(
+                &self.certificate_chain,
+                &self.private_key,
+                response,
+            )?,
+        })
+    }
+
+    pub fn ocsp_refresh_needed(&self, now: SystemTime) -> bool {
+        self.ocsp_next_update
+            .map(|next_update| now + OCSP_REFRESH_BEFORE_NEXT_UPDATE >= next_update)
+            .unwrap_or(true)
+    }
+
+    pub fn certificate_chain(&self) -> &[rustls::Certificate] {
+        &self.certificate_chain
+    }
+
+    fn leaf_info(
+        certificate_chain: &[rustls::Certificate],
+    ) -> Result<CertificateInfo, GatewayError> {
+        for certificate in certificate_chain.iter() {
+            let Ok((_, cert)) = X509Certificate::from_der(certificate.as_ref()) else {
+                return Err(GatewayError::Invalid("certificate"));
             };
             if cert.is_ca() {
                 continue;
             }
-
-            if cert
-                .validity()
-                .time_to_expiration()
-                .and_then(|d| {
-                    d.unsigned_abs()
-                        .checked_sub(Duration::from_secs(7 * 24 * 60 * 60))
-                })
-                .is_none()
-            {
-                return true;
+            let mut domains = Vec::new();
+            if let Ok(Some(san)) = cert.subject_alternative_name() {
+                for name in &san.value.general_names {
+                    if let GeneralName::DNSName(domain_name) = name {
+                        domains.push(domain_name.to_string());
+                    }
+                }
             }
+            let to_system_time = |timestamp: i64| {
+                SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+            };
+            let issuer = cert.issuer().to_string();
+            return Ok(CertificateInfo {
+                domains,
+                // Let's Encrypt staging intermediates are named "(STAGING) ..." and formerly "Fake LE ..."
+                staging: issuer.contains("(STAGING)") || issuer.contains("Fake LE"),
+                issuer,
+                imported: false,
+                serial: cert.raw_serial_as_string(),
+                not_before: to_system_time(cert.validity().not_before.timestamp()),
+                not_after: to_system_time(cert.validity().not_after.timestamp()),
+            });
+        }
+        Err(GatewayError::Invalid("leaf certificate"))
+    }
+
+    // as seen at `now`, the leaf has expired or expires within the threshold
+    pub fn renew_needed_at(&self, now: SystemTime, threshold: Duration) -> bool {
+        now + threshold >= self.info.not_after
+    }
+    // whole days left as seen at `now`, negative once expired
+    pub fn days_until_expiry(&self, now: SystemTime) -> i64 {
+        match self.info.not_after.duration_since(now) {
+            Ok(left) => (left.as_secs() / (24 * 60 * 60)) as i64,
+            Err(e) => -(e.duration().as_secs().div_ceil(24 * 60 * 60) as i64),
         }
-        false
-    }
-    // pub fn domains(&self) -> Option<Vec<String>> {
-    //     let mut domains = Vec::new();
-    //     for certificate in self.certificate_chain.iter() {
-    //         let (_, cert) = X509Certificate::from_der(certificate.as_ref()).ok()?;
-    //         if cert.is_ca() {
-    //             continue;
-    //         }
-    //         if let Ok(Some(san)) = cert.subject_alternative_name() {
-    //             for name in &san.value.general_names {
-    //                 if let GeneralName::DNSName(domain_name) = name {
-    //                     domains.push(domain_name.to_string());
-    //                 }
-    //             }
-    //         }
-    //     }
-    //     if domains.is_empty() {
-    //         return None;
-    //     }
-    //     Some(domains)
-    // }
-    pub fn get_config(&self) -> Arc<ServerConfig> {
-        self.config.clone()
+    }
+    pub fn not_before(&self) -> SystemTime {
+        self.info.not_before
+    }
+    pub fn not_after(&self) -> SystemTime {
+        self.info.not_after
+    }
+    pub fn info(&self) -> CertificateInfo {
+        self.info.clone()
+    }
+    pub fn is_imported(&self) -> bool {
+        self.info.imported
+    }
+    // the certificate alone, with the versions, suites and protocols of the policy
+    pub fn get_config(&self, policy: &TlsPolicy) -> Result<Arc<ServerConfig>, GatewayError> {
+        Self::negotiated_config(std::slice::from_ref(self), policy)
     }
 }
 
@@ -0,0 +1,599 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
+use async_trait::async_trait;
+use instant_acme::AccountCredentials;
+use pem::Pem;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        Mutex,
+    },
+    time::timeout,
+};
+use tracing::{debug, trace, warn};
+
+use crate::error::GatewayError;
+
+use super::{ACMEChallenge, Certificate, CertificateStorage};
+
+const PENDING_TIMEOUT: Duration = Duration::from_secs(120);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5); // connecting included
+const MAX_BULK_SIZE: usize = 16 * 1024 * 1024;
+
+// Keys are laid out as `{prefix}:{account}:{domain}:{kind}`, every put is published on
+// `{prefix}:certificates` as `{account}\n{domain}` so the other gateways can reload it.
+pub struct RedisCertificateStorage {
+    address: String,
+    password: Option<String>,
+    prefix: String,
+    connection: Mutex<Option<RedisConnection>>,
+}
+
+impl RedisCertificateStorage {
+    pub fn new(address: &str, password: Option<String>, prefix: &str) -> Self {
+        Self {
+            address: address.into(),
+            password,
+            prefix: prefix.into(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    async fn command(&self, args: &[&[u8]]) -> Result<RespValue, GatewayError> {
+        self.transaction(&[args]).await
+    }
+
+    // the commands on one connection, more than one run in MULTI/EXEC and the reply of EXEC
+    // holds theirs
+    async fn transaction(&self, commands: &[&[&[u8]]]) -> Result<RespValue, GatewayError> {
+        let mut connection = self.connection.lock().await;
+        let mut redis_connection = match connection.take() {
+            Some(redis_connection) => redis_connection,
+            None => RedisConnection::connect(&self.address, self.password.as_deref()).await?,
+        };
+        let res = timeout(COMMAND_TIMEOUT, async {
+            let [command] = commands else {
+                redis_connection.command(&[b"MULTI"]).await?;
+                for command in commands {
+                    redis_connection.command(command).await?; // QUEUED
+                }
+                return redis_connection.command(&[b"EXEC"]).await;
+            };
+            redis_connection.command(command).await
+        })
+        .await
+        .unwrap_or_else(|_| Err(timed_out()));
+        // after any error the connection may hold an unread reply or an open transaction, the
+        // next command reconnects
+        if res.is_ok() {
+            connection.replace(redis_connection);
+        }
+        res
+    }
+
+    fn key(&self, account: &str, domain: &str, kind: &str) -> String {
+        format!("{}:{}:{}:{}", self.prefix, account, domain, kind)
+    }
+
+    fn updates_channel(&self) -> String {
+        format!("{}:certificates", self.prefix)
+    }
+}
+
+#[async_trait]
+impl CertificateStorage for RedisCertificateStorage {
+    async fn get_default_account_credentials(&self) -> Result<AccountCredentials, GatewayError> {
+        let key = format!("{}:default.account", self.prefix);
+        let account = self
+            .command(&[b"GET", key.as_bytes()])
+            .await?
+            .into_bytes()
+            .ok_or(GatewayError::Invalid("No account credentials found"))?;
+        Ok(serde_json::from_slice(&account)?)
+    }
+    async fn get_account_credentials(
+        &self,
+        directory: &str,
+    ) -> Result<AccountCredentials, GatewayError> {
+        let key = format!("{}:accounts:{}", self.prefix, directory);
+        let account = self
+            .command(&[b"GET", key.as_bytes()])
+            .await?
+            .into_bytes()
+            .ok_or(GatewayError::Invalid("No account credentials found"))?;
+        Ok(serde_json::from_slice(&account)?)
+    }
+    async fn set_account_credentials(
+        &self,
+        directory: &str,
+        account: AccountCredentials,
+    ) -> Result<(), GatewayError> {
+        let key = format!("{}:accounts:{}", self.prefix, directory);
+        self.command(&[b"SET", key.as_bytes(), &serde_json::to_vec(&account)?])
+            .await?;
+        Ok(())
+    }
+    async fn put(
+        &self,
+        account: &str,
+        domain: &str,
+        acme_account_credentials: Option<AccountCredentials>,
+        cert: Vec<Pem>,
+    ) -> Result<(), GatewayError> {
+        if let Some(acme_account_credentials) = acme_account_credentials {
+            let account_key = self.key(account, domain, "account");
+            self.command(&[
+                b"SET",
+                account_key.as_bytes(),
+                &serde_json::to_vec(&acme_account_credentials)?,
+            ])
+            .await?;
+        }
+        let pem_key = self.key(account, domain, "pem");
+        self.command(&[
+            b"SET",
+            pem_key.as_bytes(),
+            pem::encode_many(&cert).as_bytes(),
+        ])
+        .await?;
+
+        let failed_key = self.key(account, domain, "failed");
+        let pending_key = self.key(account, domain, "pending");
+        self.command(&[b"DEL", failed_key.as_bytes(), pending_key.as_bytes()])
+            .await?;
+
+        let update = format!("{}\n{}", account, domain);
+        if let Err(e) = self
+            .command(&[
+                b"PUBLISH",
+                self.updates_channel().as_bytes(),
+                update.as_bytes(),
+            ])
+            .await
+        {
+            warn!("unable to publish certificate update: {}", e);
+        }
+        Ok(())
+    }
+    async fn get_pems(&self, account: &str, domain: &str) -> Result<Vec<Pem>, GatewayError> {
+        let pem_key = self.key(account, domain, "pem");
+        let cert = self
+            .command(&[b"GET", pem_key.as_bytes()])
+            .await?
+            .into_bytes()
+            .ok_or(GatewayError::CertificateNotFound)?;
+        Ok(pem::parse_many(cert)?)
+    }
+    async fn delete(
+        &self,
+        uid: &str,
+        _agent_name: &str,
+        domains: &[String],
+    ) -> Result<(), GatewayError> {
+        let pem_keys = domains
+            .iter()
+            .map(|domain| self.key(uid, domain, "pem"))
+            .collect::<Vec<_>>();
+        let other_keys = domains
+            .iter()
+            .flat_map(|domain| {
+                ["account", "failed", "pending"].map(|kind| self.key(uid, domain, kind))
+            })
+            .collect::<Vec<_>>();
+        // a transaction so no other gateway reads a certificate without its account data
+        let mut exists: Vec<&[u8]> = vec![b"EXISTS"];
+        exists.extend(pem_keys.iter().map(|key| key.as_bytes()));
+        let mut del: Vec<&[u8]> = vec![b"DEL"];
+        del.extend(pem_keys.iter().chain(&other_keys).map(|key| key.as_bytes()));
+        let RespValue::Array(replies) = self.transaction(&[&exists, &del]).await? else {
+            return Err(GatewayError::RedisError("Transaction aborted".to_owned()));
+        };
+        match replies.first() {
+            Some(RespValue::Integer(0)) => Err(GatewayError::CertificateNotFound),
+            Some(RespValue::Integer(_)) => Ok(()),
+            _ => Err(GatewayError::RedisError(
+                "Unexpected EXISTS reply".to_owned(),
+            )),
+        }
+    }
+    async fn get_acme_account_credentials(
+        &self,
+        account: &str,
+        domain: &str,
+    ) -> Option<AccountCredentials> {
+        let account_key = self.key(account, domain, "account");
+        self.command(&[b"GET", account_key.as_bytes()])
+            .await
+            .ok()?
+            .into_bytes()
+            .and_then(|account| serde_json::from_slice(&account).ok())
+    }
+    async fn set_failed(
+        &self,
+        account: &str,
+        domain: &str,
+        retry_after: Duration,
+    ) -> Result<(), GatewayError> {
+        let failed_key = self.key(account, domain, "failed");
+        let pending_key = self.key(account, domain, "pending");
+        let retry_after = retry_after.as_secs().max(1).to_string();
+        self.command(&[
+            b"SET",
+            failed_key.as_bytes(),
+            b"1",
+            b"EX",
+            retry_after.as_bytes(),
+        ])
+        .await?;
+        self.command(&[b"DEL", pending_key.as_bytes()]).await?;
+        Ok(())
+    }
+    async fn is_failed(&self, account: &str, domain: &str) -> bool {
+        let failed_key = self.key(account, domain, "failed");
+        matches!(
+            self.command(&[b"EXISTS", failed_key.as_bytes()]).await,
+            Ok(RespValue::Integer(1))
+        )
+    }
+    async fn set_pending(&self, account: &str, domain: &str) -> Result<(), GatewayError> {
+        let pending_key = self.key(account, domain, "pending");
+        let timeout = PENDING_TIMEOUT.as_secs().to_string();
+        self.command(&[
+            b"SET",
+            pending_key.as_bytes(),
+            b"1",
+            b"EX",
+            timeout.as_bytes(),
+        ])
+        .await?;
+        Ok(())
+    }
+    async fn is_pending(&self, account: &str, domain: &str) -> bool {
+        let pending_key = self.key(account, domain, "pending");
+        matches!(
+            self.command(&[b"EXISTS", pending_key.as_bytes()]).await,
+            Ok(RespValue::Integer(1))
+        )
+    }
+    async fn put_challenge(
+        &self,
+        domain: &str,
+        challenge: &ACMEChallenge,
+    ) -> Result<(), GatewayError> {
+        let key = format!("{}:challenges", self.prefix);
+        self.command(&[
+            b"HSET",
+            key.as_bytes(),
+            domain.as_bytes(),
+            &serde_json::to_vec(challenge)?,
+        ])
+        .await?;
+        Ok(())
+    }
+    async fn get_challenge(&self, domain: &str) -> Result<ACMEChallenge, GatewayError> {
+        let key = format!("{}:challenges", self.prefix);
+        let challenge = self
+            .command(&[b"HGET", key.as_bytes(), domain.as_bytes()])
+            .await?
+            .into_bytes()
+            .ok_or(GatewayError::ACMEChallengeNotFound)?;
+        Ok(serde_json::from_slice(&challenge)?)
+    }
+    async fn remove_challenge(&self, domain: &str) -> Result<(), GatewayError> {
+        let key = format!("{}:challenges", self.prefix);
+        self.command(&[b"HDEL", key.as_bytes(), domain.as_bytes()])
+            .await?;
+        Ok(())
+    }
+    async fn get_challenges(&self) -> Result<Vec<(String, ACMEChallenge)>, GatewayError> {
+        let key = format!("{}:challenges", self.prefix);
+        let RespValue::Array(fields) = self.command(&[b"HGETALL", key.as_bytes()]).await? else {
+            return Ok(Vec::new());
+        };
+        let mut challenges = Vec::new();
+        let mut fields = fields.into_iter().map(RespValue::into_bytes);
+        while let (Some(Some(domain)), Some(Some(challenge))) = (fields.next(), fields.next()) {
+            if let (Ok(domain), Ok(challenge)) = (
+                String::from_utf8(domain),
+                serde_json::from_slice(&challenge),
+            ) {
+                challenges.push((domain, challenge));
+            }
+        }
+        Ok(challenges)
+    }
+    async fn list(&self) -> Result<Vec<(String, String, Vec<String>)>, GatewayError> {
+        let pattern = format!("{}:*:pem", self.prefix);
+        let mut keys = Vec::new();
+        let mut cursor = b"0".to_vec();
+        loop {
+            let RespValue::Array(reply) = self
+                .command(&[
+                    b"SCAN",
+                    &cursor,
+                    b"MATCH",
+                    pattern.as_bytes(),
+                    b"COUNT",
+                    b"100",
+                ])
+                .await?
+            else {
+                return Err(GatewayError::RedisError("Unexpected SCAN reply".to_owned()));
+            };
+            let mut reply = reply.into_iter();
+            let (Some(Some(next_cursor)), Some(RespValue::Array(batch))) =
+                (reply.next().map(RespValue::into_bytes), reply.next())
+            else {
+                return Err(GatewayError::RedisError("Unexpected SCAN reply".to_owned()));
+            };
+            keys.extend(batch.into_iter().filter_map(RespValue::into_bytes));
+            if next_cursor == b"0" {
+                break;
+            }
+            cursor = next_cursor;
+        }
+        let mut certificates = Vec::new();
+        for key in keys {
+            let Some((account, domain)) = String::from_utf8(key).ok().and_then(|key| {
+                let (account, domain) = key
+                    .strip_prefix(&format!("{}:", self.prefix))?
+                    .strip_suffix(":pem")?
+                    .split_once(':')?;
+                Some((account.to_owned(), domain.to_owned()))
+            }) else {
+                continue;
+            };
+            match self
+                .get_pems(&account, &domain)
+                .await
+                .and_then(|pems| Certificate::info_from_pem_vec(&pems))
+            {
+                Ok(info) => certificates.push((account, domain, info.domains)),
+                Err(e) => debug!("unable to read stored certificate {}: {}", domain, e),
+            }
+        }
+        Ok(certificates)
+    }
+    async fn health(&self) -> Result<(), GatewayError> {
+        self.command(&[b"PING"]).await?;
+        Ok(())
+    }
+    async fn watch(&self) -> Option<UnboundedReceiver<(String, String)>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let address = self.address.clone();
+        let password = self.password.clone();
+        let channel = self.updates_channel();
+        tokio::spawn(async move {
+            loop {
+                let subscription = async {
+                    let mut redis_connection =
+                        RedisConnection::connect(&address, password.as_deref()).await?;
+                    timeout(
+                        COMMAND_TIMEOUT,
+                        redis_connection.command(&[b"SUBSCRIBE", channel.as_bytes()]),
+                    )
+                    .await
+                    .unwrap_or_else(|_| Err(timed_out()))?;
+                    trace!("subscribed to certificate updates");
+                    loop {
+                        let RespValue::Array(message) = redis_connection.read().await? else {
+                            continue;
+                        };
+                        let Some(update) = message
+                            .into_iter()
+                            .nth(2)
+                            .and_then(RespValue::into_bytes)
+                            .and_then(|update| String::from_utf8(update).ok())
+                        else {
+                            continue;
+                        };
+                        if let Some((account, domain)) = update.split_once('\n') {
+                            if sender
+                                .send((account.to_owned(), domain.to_owned()))
+                                .is_err()
+                            {
+                                return Ok::<(), GatewayError>(());
+                            }
+                        }
+                    }
+                };
+                match subscription.await {
+                    Ok(()) => break,
+                    Err(e) => {
+                        debug!("certificate update subscription lost: {}", e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+        Some(receiver)
+    }
+}
+
+enum RespValue {
+    Nil,
+    Status(String),
+    Error(String), // only as an element of an array, a top level error reply is an Err
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<RespValue>),
+}
+
+impl RespValue {
+    fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            RespValue::Bulk(value) => Some(value),
+            RespValue::Status(value) => Some(value.into_bytes()),
+            _ => None,
+        }
+    }
+}
+
+fn timed_out() -> GatewayError {
+    std::io::Error::from(std::io::ErrorKind::TimedOut).into()
+}
+
+// A minimal RESP2 connection, enough for the commands used by the storage
+struct RedisConnection {
+    stream: BufStream<TcpStream>,
+}
+
+impl RedisConnection {
+    async fn connect(address: &str, password: Option<&str>) -> Result<Self, GatewayError> {
+        trace!("connecting to redis");
+        timeout(COMMAND_TIMEOUT, async {
+            let mut redis_connection = Self {
+                stream: BufStream::new(TcpStream::connect(address).await?),
+            };
+            if let Some(password) = password {
+                redis_connection
+                    .command(&[b"AUTH", password.as_bytes()])
+                    .await?;
+            }
+            Ok(redis_connection)
+        })
+        .await
+        .unwrap_or_else(|_| Err(timed_out()))
+    }
+
+    async fn command(&mut self, args: &[&[u8]]) -> Result<RespValue, GatewayError> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        self.stream.write_all(&request).await?;
+        self.stream.flush().await?;
+        self.read().await
+    }
+
+    async fn read(&mut self) -> Result<RespValue, GatewayError> {
+        match self.read_value().await? {
+            RespValue::Error(e) => Err(GatewayError::RedisError(e)),
+            value => Ok(value),
+        }
+    }
+
+    // an error reply is a value so the rest of an array is still read
+    fn read_value(
+        &mut self,
+    ) -> Pin<Box<dyn Future<Output = Result<RespValue, GatewayError>> + Send + '_>> {
+        Box::pin(async move {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            let line = line.trim_end_matches("\r\n");
+            let (kind, value) = line.split_at(line.len().min(1));
+            let invalid = || {
+                GatewayError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "invalid redis response",
+                ))
+            };
+            match kind {
+                "+" => Ok(RespValue::Status(value.to_owned())),
+                "-" => Ok(RespValue::Error(value.to_owned())),
+                ":" => Ok(RespValue::Integer(value.parse().map_err(|_| invalid())?)),
+                "$" => {
+                    let Ok(len) = usize::try_from(value.parse::<i64>().map_err(|_| invalid())?)
+                    else {
+                        return Ok(RespValue::Nil);
+                    };
+                    if len > MAX_BULK_SIZE {
+                        return Err(invalid());
+                    }
+                    let mut bulk = vec![0; len + 2];
+                    self.stream.read_exact(&mut bulk).await?;
+                    bulk.truncate(len);
+                    Ok(RespValue::Bulk(bulk))
+                }
+                "*" => {
+                    let Ok(len) = usize::try_from(value.parse::<i64>().map_err(|_| invalid())?)
+                    else {
+                        return Ok(RespValue::Nil);
+                    };
+                    let mut array = Vec::with_capacity(len.min(1024));
+                    for _ in 0..len {
+                        array.push(self.read_value().await?);
+                    }
+                    Ok(RespValue::Array(array))
+                }
+                _ => Err(invalid()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+
+    // answers every request with the next reply, stops reading after the last one
+    async fn server(replies: &'static [&'static [u8]]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let address = listener.local_addr().expect("address").to_string();
+        tokio::spawn(async move {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                return;
+            };
+            let mut buf = [0; 1024];
+            for reply in replies {
+                if stream.read(&mut buf).await.unwrap_or(0) == 0
+                    || stream.write_all(reply).await.is_err()
+                {
+                    return;
+                }
+            }
+            std::future::pending::<()>().await;
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn error_replies_in_an_array_are_read_to_the_end() {
+        let address = server(&[b"*2\r\n-ERR wrong type\r\n:1\r\n", b"+PONG\r\n"]).await;
+        let mut connection = RedisConnection::connect(&address, None)
+            .await
+            .expect("connect");
+        let Ok(RespValue::Array(replies)) = connection.command(&[b"EXEC"]).await else {
+            panic!("expected an array");
+        };
+        assert!(matches!(
+            replies[..],
+            [RespValue::Error(_), RespValue::Integer(1)]
+        ));
+        assert!(matches!(
+            connection.command(&[b"PING"]).await,
+            Ok(RespValue::Status(status)) if status == "PONG"
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_failed_command_drops_the_connection() {
+        let address = server(&[b"-ERR unknown command\r\n"]).await;
+        let storage = RedisCertificateStorage::new(&address, None, "narrowlink");
+        assert!(matches!(
+            storage.command(&[b"NOPE"]).await,
+            Err(GatewayError::RedisError(_))
+        ));
+        assert!(storage.connection.lock().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_unanswered_command_times_out() {
+        let address = server(&[]).await;
+        let storage = RedisCertificateStorage::new(&address, None, "narrowlink");
+        assert!(matches!(
+            storage.command(&[b"PING"]).await,
+            Err(GatewayError::IoError(e)) if e.kind() == std::io::ErrorKind::TimedOut
+        ));
+        assert!(storage.connection.lock().await.is_none());
+    }
+}
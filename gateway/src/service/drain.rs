@@ -0,0 +1,66 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+type Drain = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// ends the reading side of a TLS stream once drain resolves, what is being written is still
+// sent before the server closes, so the peer reconnects and gets the renewed certificate
+pub struct Draining<S> {
+    stream: S,
+    drain: Option<Drain>,
+    drained: bool,
+}
+
+impl<S> Draining<S> {
+    pub fn new(stream: S, drain: Option<impl Future<Output = ()> + Send + 'static>) -> Self {
+        Self {
+            stream,
+            drain: drain.map(|drain| Box::pin(drain) as Drain),
+            drained: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Draining<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let Some(drain) = self.drain.as_mut() {
+            if drain.as_mut().poll(cx).is_ready() {
+                debug!("draining the connection after its certificate renewed");
+                self.drain = None;
+                self.drained = true;
+            }
+        }
+        if self.drained {
+            return Poll::Ready(Ok(())); // end of stream
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Draining<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,88 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use hyper::{header, server::conn::Http, service::service_fn, Body, Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tracing::{debug, span, trace, warn, Instrument};
+
+use crate::error::GatewayError;
+
+use super::{certificate::manager::CertificateManager, Service};
+
+pub struct Health {
+    listen_addr: SocketAddr,
+    cm: Option<Arc<CertificateManager>>,
+}
+
+impl Health {
+    pub fn from(
+        health: &crate::config::HealthService,
+        cm: Option<Arc<CertificateManager>>,
+    ) -> Self {
+        Self {
+            listen_addr: health.listen_addr,
+            cm,
+        }
+    }
+}
+
+#[async_trait]
+impl Service for Health {
+    async fn run(self) -> Result<(), GatewayError> {
+        let span = span!(tracing::Level::TRACE, "health", listen_addr = %self.listen_addr);
+        let tcp_listener: TcpListener = TcpListener::bind(&self.listen_addr).await?;
+        span.in_scope(|| trace!("tcp listener successfully bound"));
+        loop {
+            let Ok((tcp_stream, peer_addr)) = tcp_listener.accept().await else {
+                span.in_scope(|| warn!("failed to accept tcp connection"));
+                continue;
+            };
+            span.in_scope(|| debug!("new health connection from {}", peer_addr));
+            let cm = self.cm.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(http_err) = Http::new()
+                        .serve_connection(
+                            tcp_stream,
+                            service_fn(|req| health_response(req, cm.clone())),
+                        )
+                        .await
+                    {
+                        warn!("{}", http_err);
+                    }
+                }
+                .instrument(span.clone()),
+            );
+        }
+    }
+}
+
+// /live answers while the gateway runs, /ready only once it can serve certificates
+async fn health_response(
+    req: Request<Body>,
+    cm: Option<Arc<CertificateManager>>,
+) -> Result<Response<Body>, Infallible> {
+    let body = match req.uri().path() {
+        "/live" => serde_json::json!({ "live": true }),
+        "/ready" => match cm {
+            Some(cm) => cm.health().await,
+            None => serde_json::json!({ "ready": true }),
+        },
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap_or_default())
+        }
+    };
+    let status = if body["ready"] == false {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap_or_default())
+}
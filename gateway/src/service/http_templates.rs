@@ -10,6 +10,7 @@ pub enum HttpErrors {
     NotFound(Option<&'static str>),
     NotAcceptable(Option<&'static str>),
     Conflict,
+    MisdirectedRequest,
     InternalServerError,
     ServiceUnavailable,
     WenServerIsDown,
@@ -25,6 +26,7 @@ impl HttpErrors {
             HttpErrors::NotFound(e) => ("404 Not Found",e.unwrap_or("The requested resource could not be found.")),
             HttpErrors::NotAcceptable(e) => ("406 Not Acceptable",e.unwrap_or("The resource you requested is not available in the format you requested.")),
             HttpErrors::Conflict => ("409 Conflict","A conflict has occurred, please check your inputs and try again."),
+            HttpErrors::MisdirectedRequest => ("421 - Misdirected Request","This gateway does not serve the requested host, make sure its agent is connected and publishing it."),
             HttpErrors::InternalServerError => ("500 Internal Server Error","An error occurred on the server while processing the request."),
             HttpErrors::ServiceUnavailable => ("503 - Service Unavailable", "The server is currently unable to handle the request due to maintenance or overloading."),
             HttpErrors::WenServerIsDown => ("521 - Web Server Is Down", "The agent is available, but its hosted web server is refusing connections from the agent. Make sure the agent can reach the web server."),
@@ -49,6 +51,7 @@ impl From<HttpErrors> for u16 {
             HttpErrors::NotFound(_) => 404,
             HttpErrors::NotAcceptable(_) => 406,
             HttpErrors::Conflict => 409,
+            HttpErrors::MisdirectedRequest => 421,
             HttpErrors::InternalServerError => 500,
             HttpErrors::ServiceUnavailable => 503,
             HttpErrors::WenServerIsDown => 521,
@@ -0,0 +1,97 @@
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use async_trait::async_trait;
+use hyper::{header, server::conn::Http, service::service_fn, Body, Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tracing::{debug, span, trace, warn, Instrument};
+
+use crate::{error::GatewayError, state::LimitExceeded};
+
+use super::{certificate::metrics, Service};
+
+static CONNECTIONS_REJECTED_AGENT: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_REJECTED_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+pub fn connection_rejected(exceeded: LimitExceeded) {
+    match exceeded {
+        LimitExceeded::Agent(_) => CONNECTIONS_REJECTED_AGENT.fetch_add(1, Ordering::Relaxed),
+        LimitExceeded::Total(_) => CONNECTIONS_REJECTED_TOTAL.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+fn render() -> String {
+    let mut res = metrics::render();
+    let _ = writeln!(
+        res,
+        "# HELP narrowlink_connections_rejected_total Connections rejected by the connection limits"
+    );
+    let _ = writeln!(res, "# TYPE narrowlink_connections_rejected_total counter");
+    let _ = writeln!(
+        res,
+        "narrowlink_connections_rejected_total{{limit=\"per_agent\"}} {}",
+        CONNECTIONS_REJECTED_AGENT.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        res,
+        "narrowlink_connections_rejected_total{{limit=\"total\"}} {}",
+        CONNECTIONS_REJECTED_TOTAL.load(Ordering::Relaxed)
+    );
+    res
+}
+
+pub struct Metrics {
+    listen_addr: SocketAddr,
+}
+
+impl Metrics {
+    pub fn from(metrics: &crate::config::MetricsService) -> Self {
+        Self {
+            listen_addr: metrics.listen_addr,
+        }
+    }
+}
+
+#[async_trait]
+impl Service for Metrics {
+    async fn run(self) -> Result<(), GatewayError> {
+        let span = span!(tracing::Level::TRACE, "metrics", listen_addr = %self.listen_addr);
+        let tcp_listener: TcpListener = TcpListener::bind(&self.listen_addr).await?;
+        span.in_scope(|| trace!("tcp listener successfully bound"));
+        loop {
+            let Ok((tcp_stream, peer_addr)) = tcp_listener.accept().await else {
+                span.in_scope(|| warn!("failed to accept tcp connection"));
+                continue;
+            };
+            span.in_scope(|| debug!("new metrics connection from {}", peer_addr));
+            tokio::spawn(
+                async move {
+                    if let Err(http_err) = Http::new()
+                        .serve_connection(tcp_stream, service_fn(metrics_response))
+                        .await
+                    {
+                        warn!("{}", http_err);
+                    }
+                }
+                .instrument(span.clone()),
+            );
+        }
+    }
+}
+
+async fn metrics_response(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = if req.uri().path() == "/metrics" {
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(render()))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+    };
+    Ok(response.unwrap_or_default())
+}
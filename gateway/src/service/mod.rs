@@ -4,22 +4,65 @@ use async_trait::async_trait;
 
 use crate::error::GatewayError;
 
+pub mod admin;
 pub mod certificate;
+pub mod drain;
+pub mod health;
 pub mod http_templates;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod quic;
+pub mod tls;
+pub mod udp;
 pub mod ws;
 pub mod wss;
+// the TLS client certificate of an agent request, checked in addition to the token, which still
+// identifies the agent; clients never need one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientCert {
+    NotRequired,
+    Verified,
+    Missing,
+}
+
+impl ClientCert {
+    pub fn from_presented(required: bool, presented: bool) -> Self {
+        match (required, presented) {
+            (false, _) => Self::NotRequired,
+            (true, true) => Self::Verified,
+            (true, false) => Self::Missing,
+        }
+    }
+}
+
 pub struct ServiceEventRequest {
     pub(crate) token: String,
+    pub(crate) client_cert: ClientCert,
     pub(crate) acl: Option<String>,
     pub(crate) publish: Option<String>,
+    pub(crate) acme_email: Option<String>,
+    pub(crate) acme_challenge: Option<String>, // Http01, TlsAlpn01 or Dns01
+    pub(crate) acme_directory: Option<String>, // one of the directories of the gateway
+    pub(crate) client_acl: Option<String>,     // JSON list of service access lists
+    pub(crate) capabilities: Option<String>,   // comma separated, see Capability
+    pub(crate) version: Option<String>,
 }
 pub struct ServiceDataRequest {
     pub(crate) token: String,
+    pub(crate) client_cert: ClientCert,
     pub(crate) command: Option<String>,
     pub(crate) session: Option<String>,
     pub(crate) connection: Option<String>,
     pub(crate) connecting_address: Option<String>,
 }
+// an HTTP CONNECT of a client, authenticated with its token instead of an event connection
+pub struct ServiceConnectRequest {
+    pub(crate) token: String,
+    pub(crate) agent_name: String,
+    pub(crate) acl: Option<String>, // JSON list of policy tokens, as sent on event connections
+    pub(crate) host: String,
+    pub(crate) port: u16,
+}
 
 #[derive(Debug, Clone)]
 pub enum RequestProtocol {
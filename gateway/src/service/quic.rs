@@ -0,0 +1,109 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use hyper::server::conn::Http;
+use narrowlink_network::quic::QuicListener;
+use rustls::server::ClientCertVerifier;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, span, trace, warn, Instrument};
+
+use crate::{error::GatewayError, state::InBound};
+
+use super::{
+    ws::WsService,
+    wss::{CertificateResolver, TlsEngine},
+    ClientCert, RequestProtocol, Service,
+};
+
+// agents speaking QUIC, each bidirectional stream carries one HTTP upgrade like a TLS connection
+// of the Wss service, with the certificates of its TLS engine
+#[derive(Clone)]
+pub struct Quic {
+    listen_addr: SocketAddr,
+    domains: Vec<String>,
+    status_sender: UnboundedSender<InBound>,
+    cm: TlsEngine,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+}
+
+impl Quic {
+    pub fn from(
+        quic: &crate::config::QuicService,
+        status_sender: UnboundedSender<InBound>,
+        cm: TlsEngine,
+        client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    ) -> Self {
+        Self {
+            listen_addr: quic.listen_addr,
+            domains: quic.domains.to_owned(),
+            status_sender,
+            cm,
+            client_verifier,
+        }
+    }
+}
+
+#[async_trait]
+impl Service for Quic {
+    async fn run(self) -> Result<(), GatewayError> {
+        let span = span!(tracing::Level::TRACE, "quic", listen_addr = %self.listen_addr, domains = ?self.domains);
+        let builder = self.cm.tls_policy().quic_builder()?;
+        let builder = match &self.client_verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier.clone()),
+            None => builder.with_no_client_auth(),
+        };
+        let mut tls = builder.with_cert_resolver(Arc::new(CertificateResolver(self.cm.clone())));
+        if let TlsEngine::Acme(acme) = &self.cm {
+            acme.session_resumption().apply(&mut tls);
+        }
+        let listener = QuicListener::bind(self.listen_addr, tls)?;
+        span.in_scope(|| trace!("quic endpoint successfully bound"));
+        while let Some(connecting) = listener.accept().await {
+            let peer_addr = connecting.peer_addr();
+            let span_connection = span
+                .in_scope(|| span!(tracing::Level::TRACE, "connection", peer_addr = %peer_addr));
+            let quic = self.clone();
+            tokio::spawn(async move {
+                let peer = match connecting.established().await {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        span_connection.in_scope(|| debug!("quic handshake failed: {}", e));
+                        return;
+                    }
+                };
+                let sni = peer.sni();
+                let client_cert = ClientCert::from_presented(
+                    quic.client_verifier.is_some(),
+                    peer.has_client_cert(),
+                );
+                span_connection.in_scope(|| trace!("quic connection established, sni: {:?}", sni));
+                while let Ok(stream) = peer.accept().await {
+                    let service = WsService {
+                        listen_addr: RequestProtocol::Https(quic.listen_addr),
+                        domains: quic.domains.clone(),
+                        sni: sni.clone(),
+                        status_sender: quic.status_sender.clone(),
+                        peer_addr,
+                        cm: None,
+                        client_cert,
+                        http_proxy: false, // agents only
+                        idle_timeout: None,
+                    };
+                    let span_stream = span_connection.clone();
+                    tokio::spawn(async move {
+                        if let Err(http_err) = Http::new()
+                            .serve_connection(stream, service)
+                            .with_upgrades()
+                            .instrument(span_stream.clone())
+                            .await
+                        {
+                            span_stream.in_scope(|| warn!("{}", http_err));
+                        }
+                    });
+                }
+                span_connection.in_scope(|| trace!("quic connection closed"));
+            });
+        }
+        Err(GatewayError::Other("QUIC endpoint closed"))
+    }
+}
@@ -0,0 +1,124 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{net::TcpListener, sync::mpsc::UnboundedSender};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, span, trace, warn, Instrument};
+
+use crate::{error::GatewayError, state::InBound};
+
+use super::{
+    certificate::manager::{CertificateServiceMessage, GATEWAY_AGENT_NAME, GATEWAY_UID},
+    drain::Draining,
+    wss::{CertificateResolver, TlsEngine},
+    Service,
+};
+
+// TLS termination for protocols other than HTTP, the certificate is chosen by SNI from the TLS
+// engine of the Wss service and the decrypted stream is tunnelled to the agent publishing the SNI
+#[derive(Clone)]
+pub struct Tls {
+    listen_addr: SocketAddr,
+    domains: Vec<String>,
+    alpn: Vec<Vec<u8>>,
+    idle_timeout: Option<Duration>,
+    status_sender: UnboundedSender<InBound>,
+    cm: TlsEngine,
+}
+
+impl Tls {
+    pub fn from(
+        tls: &crate::config::TlsService,
+        status_sender: UnboundedSender<InBound>,
+        cm: TlsEngine,
+    ) -> Self {
+        Self {
+            listen_addr: tls.listen_addr,
+            domains: tls.domains.to_owned(),
+            alpn: tls.alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+            idle_timeout: tls.idle_timeout.map(Duration::from_secs),
+            status_sender,
+            cm,
+        }
+    }
+}
+
+#[async_trait]
+impl Service for Tls {
+    async fn run(self) -> Result<(), GatewayError> {
+        let span = span!(tracing::Level::TRACE, "tls", listen_addr = %self.listen_addr, domains = ?self.domains);
+        if let (TlsEngine::Acme(acme), false) = (&self.cm, self.domains.is_empty()) {
+            let _ = acme
+                .get_service_sender()
+                .send(CertificateServiceMessage::Load(
+                    GATEWAY_UID.to_owned(),
+                    GATEWAY_AGENT_NAME.to_owned(),
+                    self.domains.clone(),
+                    None,
+                ));
+        }
+        let mut config = self
+            .cm
+            .tls_policy()
+            .builder()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(CertificateResolver(self.cm.clone())));
+        config.alpn_protocols = self.alpn.clone();
+        if let TlsEngine::Acme(acme) = &self.cm {
+            acme.session_resumption().apply(&mut config);
+        }
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let tcp_listener = TcpListener::bind(&self.listen_addr).await?;
+        loop {
+            let Ok((tcp_stream, peer_addr)) = tcp_listener.accept().await else {
+                span.in_scope(|| warn!("failed to accept tcp connection"));
+                continue;
+            };
+            let accepted = tokio::time::Instant::now();
+            let local_addr = tcp_stream.local_addr().unwrap_or(self.listen_addr);
+            let span_connection = span
+                .in_scope(|| span!(tracing::Level::TRACE, "connection", peer_addr = %peer_addr));
+            let tls = self.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let secure_stream = match acceptor
+                    .accept(tcp_stream)
+                    .instrument(span_connection.clone())
+                    .await
+                {
+                    Ok(secure_stream) => secure_stream,
+                    Err(e) => {
+                        span_connection.in_scope(|| debug!("tls handshake failed: {}", e));
+                        return;
+                    }
+                };
+                let Some(sni) = secure_stream
+                    .get_ref()
+                    .1
+                    .server_name()
+                    .map(|sni| sni.to_owned())
+                else {
+                    span_connection.in_scope(|| debug!("tls connection without sni"));
+                    return;
+                };
+                if !tls.domains.is_empty() && !tls.domains.contains(&sni) {
+                    span_connection.in_scope(|| debug!("{} is not served by this service", sni));
+                    return;
+                }
+                span_connection.in_scope(|| trace!("tls terminated for {}", sni));
+                let drain = match &tls.cm {
+                    TlsEngine::Acme(acme) => acme.drain_on_renewal(&sni, accepted),
+                    TlsEngine::File(_) => None,
+                };
+                let _ = tls.status_sender.send(InBound::TlsTerminated(
+                    sni,
+                    Box::new(Draining::new(secure_stream, drain)),
+                    local_addr,
+                    peer_addr,
+                    tls.idle_timeout,
+                ));
+            });
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, error::TrySendError, UnboundedSender},
+        oneshot,
+    },
+    time,
+};
+use tracing::{debug, span, trace, warn};
+
+use crate::{error::GatewayError, state::InBound};
+
+use super::Service;
+
+// the agent forwards UDP through 8 KiB copy buffers, larger datagrams are truncated in both
+// directions and each truncation is logged as a warning with the peer address
+pub const MAX_DATAGRAM_SIZE: usize = 8192;
+const SESSION_QUEUE_LEN: usize = 64; // datagrams queued while the agent connects, later ones are dropped
+
+pub struct Udp {
+    listen_addr: SocketAddr,
+    domain: String,
+    idle_timeout: Duration,
+    max_sessions: usize,
+    max_sessions_per_prefix: usize,
+    status_sender: UnboundedSender<InBound>,
+}
+
+impl Udp {
+    pub fn from(udp: &crate::config::UdpService, status_sender: UnboundedSender<InBound>) -> Self {
+        Self {
+            listen_addr: udp.listen_addr,
+            domain: udp.domain.to_owned(),
+            idle_timeout: Duration::from_secs(udp.idle_timeout),
+            max_sessions: udp.max_sessions,
+            max_sessions_per_prefix: udp.max_sessions_per_prefix,
+            status_sender,
+        }
+    }
+}
+
+struct SessionHandle {
+    id: u64, // tells a session from a later one of the same peer
+    sender: mpsc::Sender<Vec<u8>>,
+    last_activity: Arc<AtomicU64>,
+}
+
+// the sessions of a service, capped in total and per source prefix so a flood of spoofed sources
+// can't open unbounded tunnels, a session counts as soon as it is sent to the state
+struct Sessions {
+    max: usize,
+    max_per_prefix: usize,
+    handles: HashMap<SocketAddr, SessionHandle>,
+    prefixes: HashMap<IpAddr, usize>, // sessions per source prefix
+}
+
+// the /24 of IPv4 sources and the /64 of IPv6 ones
+fn prefix(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & 0xffff_ff00)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+    }
+}
+
+impl Sessions {
+    fn new(max: usize, max_per_prefix: usize) -> Self {
+        Self {
+            max,
+            max_per_prefix,
+            handles: HashMap::new(),
+            prefixes: HashMap::new(),
+        }
+    }
+
+    fn get(&self, peer_addr: &SocketAddr) -> Option<&SessionHandle> {
+        self.handles.get(peer_addr)
+    }
+
+    fn has_room(&self, peer_addr: &SocketAddr) -> bool {
+        self.handles.len() < self.max
+            && self
+                .prefixes
+                .get(&prefix(peer_addr.ip()))
+                .map_or(true, |sessions| *sessions < self.max_per_prefix)
+    }
+
+    fn insert(&mut self, peer_addr: SocketAddr, handle: SessionHandle) {
+        if self.handles.insert(peer_addr, handle).is_none() {
+            *self.prefixes.entry(prefix(peer_addr.ip())).or_default() += 1;
+        }
+    }
+
+    fn remove(&mut self, peer_addr: &SocketAddr) {
+        if self.handles.remove(peer_addr).is_none() {
+            return;
+        }
+        let prefix = prefix(peer_addr.ip());
+        if let Some(sessions) = self.prefixes.get_mut(&prefix) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                self.prefixes.remove(&prefix);
+            }
+        }
+    }
+
+    // a session the state rejected, unless the peer has a newer one
+    fn reject(&mut self, peer_addr: &SocketAddr, id: u64) {
+        if self
+            .handles
+            .get(peer_addr)
+            .is_some_and(|handle| handle.id == id)
+        {
+            self.remove(peer_addr);
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&SocketAddr, &SessionHandle) -> bool) {
+        let removed = self
+            .handles
+            .iter()
+            .filter(|(peer_addr, handle)| !keep(peer_addr, handle))
+            .map(|(peer_addr, _)| *peer_addr)
+            .collect::<Vec<_>>();
+        for peer_addr in removed {
+            self.remove(&peer_addr);
+        }
+    }
+}
+
+#[async_trait]
+impl Service for Udp {
+    async fn run(self) -> Result<(), GatewayError> {
+        let span = span!(tracing::Level::TRACE, "udp", listen_addr = %self.listen_addr, domain = %self.domain);
+        let socket = Arc::new(UdpSocket::bind(&self.listen_addr).await?);
+        span.in_scope(|| trace!("udp socket successfully bound"));
+        let epoch = Instant::now();
+        let mut sessions = Sessions::new(self.max_sessions, self.max_sessions_per_prefix);
+        let mut admissions = FuturesUnordered::new();
+        let mut next_id = 0;
+        let mut reaper = time::interval(self.idle_timeout.max(Duration::from_secs(1)) / 2);
+        let mut buf = vec![0u8; 65536];
+        loop {
+            tokio::select! {
+                res = socket.recv_from(&mut buf) => {
+                    let Ok((len, peer_addr)) = res else {
+                        span.in_scope(|| warn!("failed to receive udp datagram"));
+                        continue;
+                    };
+                    let mut datagram = buf[..len].to_vec();
+                    if datagram.len() > MAX_DATAGRAM_SIZE {
+                        span.in_scope(|| warn!("datagram of {} bytes from {} truncated to {} bytes", len, peer_addr, MAX_DATAGRAM_SIZE));
+                        datagram.truncate(MAX_DATAGRAM_SIZE);
+                    }
+                    if let Some(session) = sessions.get(&peer_addr) {
+                        session.last_activity.store(epoch.elapsed().as_secs(), Ordering::Relaxed);
+                        match session.sender.try_send(datagram) {
+                            Ok(()) => continue,
+                            Err(TrySendError::Full(_)) => {
+                                span.in_scope(|| trace!("udp session queue of {} is full, datagram dropped", peer_addr));
+                                continue;
+                            }
+                            Err(TrySendError::Closed(d)) => {
+                                // the tunnel is gone, the datagram opens a new session
+                                sessions.remove(&peer_addr);
+                                datagram = d;
+                            }
+                        }
+                    }
+                    if !sessions.has_room(&peer_addr) {
+                        span.in_scope(|| trace!("udp session limit reached, datagram from {} dropped", peer_addr));
+                        continue;
+                    }
+                    span.in_scope(|| debug!("new udp session from {}", peer_addr));
+                    let (sender, receiver) = mpsc::channel(SESSION_QUEUE_LEN);
+                    let last_activity = Arc::new(AtomicU64::new(epoch.elapsed().as_secs()));
+                    let _ = sender.try_send(datagram);
+                    let session = UdpSession {
+                        socket: socket.clone(),
+                        peer_addr,
+                        receiver,
+                        epoch,
+                        last_activity: last_activity.clone(),
+                    };
+                    let (admitted, admission) = oneshot::channel();
+                    if self.status_sender.send(InBound::UdpTransparent(self.domain.clone(), session, self.listen_addr, admitted)).is_err() {
+                        return Err(GatewayError::Other("State is gone"));
+                    }
+                    let id = next_id;
+                    next_id += 1;
+                    sessions.insert(peer_addr, SessionHandle { id, sender, last_activity });
+                    // the agent or the connection limit may refuse it, its slot is freed right away
+                    admissions.push(async move { (peer_addr, id, admission.await.is_ok()) });
+                }
+                Some((peer_addr, id, admitted)) = admissions.next() => {
+                    if !admitted {
+                        span.in_scope(|| debug!("udp session from {} rejected", peer_addr));
+                        sessions.reject(&peer_addr, id);
+                    }
+                }
+                _ = reaper.tick() => {
+                    let now = epoch.elapsed().as_secs();
+                    sessions.retain(|peer_addr, session| {
+                        let idle = now.saturating_sub(session.last_activity.load(Ordering::Relaxed));
+                        let alive = idle < self.idle_timeout.as_secs() && !session.sender.is_closed();
+                        if !alive {
+                            span.in_scope(|| debug!("udp session from {} reaped", peer_addr));
+                        }
+                        alive
+                    });
+                }
+            }
+        }
+    }
+}
+
+// one remote peer of a UDP service, every read and write is a single datagram
+pub struct UdpSession {
+    socket: Arc<UdpSocket>,
+    peer_addr: SocketAddr,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    epoch: Instant,
+    last_activity: Arc<AtomicU64>,
+}
+
+impl UdpSession {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncRead for UdpSession {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(datagram)) => {
+                let len = datagram.len().min(buf.remaining());
+                buf.put_slice(&datagram[..len]);
+                Poll::Ready(Ok(()))
+            }
+            // reaped after the idle timeout
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for UdpSession {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let datagram = if buf.len() > MAX_DATAGRAM_SIZE {
+            warn!(
+                "datagram of {} bytes to {} truncated to {} bytes",
+                buf.len(),
+                self.peer_addr,
+                MAX_DATAGRAM_SIZE
+            );
+            &buf[..MAX_DATAGRAM_SIZE]
+        } else {
+            buf
+        };
+        match self.socket.poll_send_to(cx, datagram, self.peer_addr) {
+            Poll::Ready(Ok(_)) => {
+                self.last_activity
+                    .store(self.epoch.elapsed().as_secs(), Ordering::Relaxed);
+                // the whole buffer is consumed, so the rest is never sent as another datagram
+                Poll::Ready(Ok(buf.len()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(id: u64) -> SessionHandle {
+        let (sender, _) = mpsc::channel(1);
+        SessionHandle {
+            id,
+            sender,
+            last_activity: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().expect("socket address")
+    }
+
+    #[test]
+    fn sessions_are_capped_per_prefix_and_in_total() {
+        let mut sessions = Sessions::new(3, 2);
+        sessions.insert(addr("192.0.2.1:53"), handle(0));
+        sessions.insert(addr("192.0.2.2:53"), handle(1));
+        // same /24, the IPv4-mapped form included
+        assert!(!sessions.has_room(&addr("192.0.2.3:53")));
+        assert!(!sessions.has_room(&addr("[::ffff:192.0.2.3]:53")));
+        assert!(sessions.has_room(&addr("198.51.100.1:53")));
+        sessions.insert(addr("[2001:db8::1]:53"), handle(2));
+        assert!(!sessions.has_room(&addr("198.51.100.1:53")));
+
+        sessions.remove(&addr("192.0.2.1:53"));
+        assert!(sessions.has_room(&addr("192.0.2.3:53")));
+        sessions.insert(addr("[2001:db8::2]:53"), handle(3));
+        // a later session of the peer is kept
+        sessions.reject(&addr("[2001:db8::2]:53"), 2);
+        assert_eq!(sessions.handles.len(), 3);
+        sessions.reject(&addr("[2001:db8::2]:53"), 3);
+        sessions.retain(|peer_addr, _| peer_addr.is_ipv4());
+        assert_eq!(sessions.handles.len(), 1);
+        assert_eq!(sessions.prefixes.len(), 1);
+        assert!(sessions.has_room(&addr("[2001:db8::3]:53")));
+    }
+}
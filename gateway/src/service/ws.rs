@@ -1,6 +1,10 @@
-use std::{collections::HashMap, net::SocketAddr, pin::Pin, sync::Arc, task::Poll};
+use std::{
+    collections::HashMap, net::SocketAddr, pin::Pin, str::FromStr, sync::Arc, task::Poll,
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use base64::Engine;
 use either::Either::{Left, Right};
 use futures_util::Future;
 use hyper::{
@@ -8,8 +12,9 @@ use hyper::{
     http::{self, HeaderValue},
     server::conn::Http,
     service::Service as HyperService,
-    upgrade, Body, Request, Response, StatusCode,
+    upgrade, Body, Method, Request, Response, StatusCode,
 };
+use narrowlink_network::{mux::MuxListener, AsyncSocketCompress, AsyncToStream, Compression};
 use tokio::{
     net::TcpListener,
     sync::{mpsc::UnboundedSender, oneshot},
@@ -18,11 +23,16 @@ use tracing::{debug, span, trace, warn, Instrument};
 
 use crate::{
     error::GatewayError,
-    service::{ServiceDataRequest, ServiceEventRequest},
+    service::{ServiceConnectRequest, ServiceDataRequest, ServiceEventRequest},
     state::{InBound, ResponseHeaders},
 };
 
-use super::{certificate::manager::CertificateManager, wss::TlsEngine, RequestProtocol, Service};
+use super::{
+    certificate::manager::CertificateManager,
+    http_templates::{response_error, ErrorFormat, HttpErrors},
+    wss::TlsEngine,
+    ClientCert, RequestProtocol, Service,
+};
 
 const INDEX_HTML: &str = include_str!("../../templates/index.html");
 
@@ -32,6 +42,9 @@ pub struct Ws {
     domains: Vec<String>,
     status_sender: UnboundedSender<InBound>,
     cm: Option<Arc<CertificateManager>>,
+    mtls: bool, // plain connections carry no certificate, agents are rejected
+    http_proxy: bool,
+    idle_timeout: Option<Duration>,
 }
 
 impl Ws {
@@ -39,6 +52,7 @@ impl Ws {
         ws: &crate::config::WsService,
         status_sender: UnboundedSender<InBound>,
         tls_engine: Option<TlsEngine>,
+        mtls: bool,
     ) -> Self {
         let cm = tls_engine.and_then(|e| match e {
             TlsEngine::Acme(cm) => Some(cm),
@@ -49,6 +63,9 @@ impl Ws {
             domains: ws.domains.to_owned(),
             status_sender,
             cm,
+            mtls,
+            http_proxy: ws.http_proxy,
+            idle_timeout: ws.idle_timeout.map(Duration::from_secs),
         }
     }
 }
@@ -60,11 +77,12 @@ impl Service for Ws {
         let tcp_listener: TcpListener = TcpListener::bind(&self.listen_addr).await?;
         span.in_scope(|| trace!("tcp listener successfully bound"));
         loop {
-            let listen_addr = self.listen_addr;
             let Ok((tcp_stream, peer_addr)) = tcp_listener.accept().await else {
                 span.in_scope(|| warn!("failed to accept tcp connection"));
                 continue;
             };
+            // the interface the connection arrived on when listening on an unspecified address
+            let listen_addr = tcp_stream.local_addr().unwrap_or(self.listen_addr);
             let span_connection = span
                 .in_scope(|| span!(tracing::Level::TRACE, "connection", peer_addr = %peer_addr));
 
@@ -82,6 +100,9 @@ impl Service for Ws {
                             status_sender: ws.status_sender,
                             peer_addr,
                             cm: ws.cm,
+                            client_cert: ClientCert::from_presented(ws.mtls, false),
+                            http_proxy: ws.http_proxy,
+                            idle_timeout: ws.idle_timeout,
                         },
                     )
                     .with_upgrades()
@@ -97,6 +118,7 @@ impl Service for Ws {
 }
 
 //response header
+#[derive(Clone)]
 pub struct WsService {
     pub listen_addr: RequestProtocol,
     pub domains: Vec<String>,
@@ -104,6 +126,9 @@ pub struct WsService {
     pub status_sender: UnboundedSender<InBound>,
     pub peer_addr: SocketAddr,
     pub cm: Option<Arc<CertificateManager>>,
+    pub client_cert: ClientCert,
+    pub http_proxy: bool,
+    pub idle_timeout: Option<Duration>, // of the published services reached through it
 }
 
 impl HyperService<Request<Body>> for WsService {
@@ -121,6 +146,11 @@ impl HyperService<Request<Body>> for WsService {
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let span = span!(tracing::Level::INFO, "service", peer_addr = %self.peer_addr);
         span.in_scope(|| debug!("request: {:?}", req));
+        if self.http_proxy && req.method() == Method::CONNECT {
+            return Box::pin(
+                http_connect(req, self.status_sender.clone(), self.peer_addr).instrument(span),
+            );
+        }
         let Some(host) = req
             .uri()
             .host()
@@ -129,10 +159,7 @@ impl HyperService<Request<Body>> for WsService {
         else {
             //inconsistency:port number
             return Box::pin(async {
-                Ok(crate::service::http_templates::response_error(
-                    crate::service::http_templates::ErrorFormat::Html,
-                    crate::service::http_templates::HttpErrors::BadRequest,
-                ))
+                Ok(response_error(ErrorFormat::Html, HttpErrors::BadRequest))
             });
         };
 
@@ -145,23 +172,88 @@ impl HyperService<Request<Body>> for WsService {
         let status_sender = self.status_sender.clone();
         let peer_addr = self.peer_addr;
         let listen_addr = self.listen_addr.clone();
+        let client_cert = self.client_cert;
+        let idle_timeout = self.idle_timeout;
+        // every stream of a multiplexed connection carries one request like a connection of its own
+        let mux = (tunnel_permit
+            && req.headers().get(header::UPGRADE).is_some_and(|upgrade| {
+                upgrade.as_bytes() == narrowlink_network::mux::UPGRADE.as_bytes()
+            }))
+        .then(|| WsService {
+            cm: None,
+            ..self.clone()
+        });
 
         let handler = async move {
+            if let Some(service) = mux {
+                let req_version = req.version();
+                tokio::spawn(
+                    async move {
+                        let Ok(upgraded) = upgrade::on(req).await else {
+                            return;
+                        };
+                        trace!("multiplexed connection established");
+                        let mut listener = MuxListener::new(upgraded);
+                        while let Some(stream) = listener.accept().await {
+                            let service = service.clone();
+                            tokio::spawn(
+                                async move {
+                                    if let Err(http_err) = Http::new()
+                                        .serve_connection(stream, service)
+                                        .with_upgrades()
+                                        .await
+                                    {
+                                        warn!("{}", http_err);
+                                    }
+                                }
+                                .in_current_span(),
+                            );
+                        }
+                        trace!("multiplexed connection closed");
+                    }
+                    .in_current_span(),
+                );
+                return Response::builder()
+                    .version(req_version)
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header(header::CONNECTION, "Upgrade")
+                    .header(header::UPGRADE, narrowlink_network::mux::UPGRADE)
+                    .body::<Body>("".into());
+            }
             let req_version = req.version();
             if let Some(acme) = cm.as_ref() {
                 if req.uri().path().starts_with("/.well-known/acme-challenge/") {
                     trace!("acme challenge");
-                    if let Ok((token, key_authorization)) =
-                        acme.get_acme_http_challenge(&host).in_current_span().await
+                    match acme
+                        .get_acme_http_challenge(&host, peer_addr.ip())
+                        .in_current_span()
+                        .await
                     {
-                        if req.uri().path() == format!("/.well-known/acme-challenge/{}", token) {
+                        Ok((token, key_authorization))
+                            if req.uri().path()
+                                == format!("/.well-known/acme-challenge/{}", token) =>
+                        {
                             return Response::builder()
                                 .version(req_version)
                                 .status(StatusCode::OK)
                                 .body::<Body>(key_authorization.into());
                         }
-                    } else {
-                        trace!("acme challenge not found for {}", host);
+                        Err(GatewayError::ACMEChallengeRateLimited) => {
+                            return Response::builder()
+                                .version(req_version)
+                                .status(StatusCode::TOO_MANY_REQUESTS)
+                                .body::<Body>("".into());
+                        }
+                        result => {
+                            match result {
+                                Ok(_) => trace!("acme challenge token mismatch for {}", host),
+                                Err(e) => trace!("acme challenge for {}: {}", host, e),
+                            }
+                            return Ok(response_error(
+                                ErrorFormat::Html,
+                                HttpErrors::NotFound(None),
+                            ));
+                        }
                     }
                 }
                 // return Response::builder()
@@ -174,6 +266,35 @@ impl HyperService<Request<Body>> for WsService {
                 //     .body("".into());
             }
 
+            // a plain request of an agent, answered with a fresh agent token as the body
+            if let Some(refresh_token) = req
+                .headers()
+                .get("NL-REFRESH-TOKEN")
+                .filter(|_| tunnel_permit)
+                .and_then(|t| t.to_str().ok())
+                .map(|t| t.to_owned())
+            {
+                trace!("agent token refresh request found");
+                let (response_sender, response_receiver) = oneshot::channel();
+                let _ = status_sender.send(InBound::TokenRefresh(
+                    refresh_token,
+                    peer_addr,
+                    response_sender,
+                ));
+                return match response_receiver.await {
+                    Ok(Ok(token)) => Response::builder()
+                        .version(req_version)
+                        .status(StatusCode::OK)
+                        .header(header::CONTENT_TYPE, "text/plain")
+                        .body::<Body>(token.into()),
+                    Ok(Err(error)) => Ok(response_error(ErrorFormat::Json, error.into())),
+                    Err(_) => Ok(response_error(
+                        ErrorFormat::Json,
+                        HttpErrors::InternalServerError,
+                    )),
+                };
+            }
+
             if let Some(token) = req
                 .headers()
                 .get("NL-TOKEN")
@@ -194,7 +315,6 @@ impl HyperService<Request<Body>> for WsService {
                     .map(|t| narrowlink_network::ws::WsConnection::drive_key(t.as_bytes()))
                 else {
                     trace!("invalid websocket key or key header not found");
-                    use crate::service::http_templates::{response_error, ErrorFormat, HttpErrors};
                     return Ok(response_error(ErrorFormat::Html, HttpErrors::BadRequest));
                 };
                 let publish = req
@@ -203,6 +323,30 @@ impl HyperService<Request<Body>> for WsService {
                     .and_then(|t| t.to_str().ok())
                     .map(|t| t.to_owned());
 
+                let acme_email = req
+                    .headers()
+                    .get("NL-ACME-EMAIL")
+                    .and_then(|t| t.to_str().ok())
+                    .map(|t| t.to_owned());
+
+                let acme_challenge = req
+                    .headers()
+                    .get("NL-ACME-CHALLENGE")
+                    .and_then(|t| t.to_str().ok())
+                    .map(|t| t.to_owned());
+
+                let acme_directory = req
+                    .headers()
+                    .get("NL-ACME-DIRECTORY")
+                    .and_then(|t| t.to_str().ok())
+                    .map(|t| t.to_owned());
+
+                let client_acl = req
+                    .headers()
+                    .get("NL-CLIENT-ACL")
+                    .and_then(|t| t.to_str().ok())
+                    .map(|t| t.to_owned());
+
                 let connection = req
                     .headers()
                     .get("NL-CONNECTION")
@@ -215,6 +359,18 @@ impl HyperService<Request<Body>> for WsService {
                     .and_then(|t| t.to_str().ok())
                     .map(|t| t.to_owned());
 
+                let capabilities = req
+                    .headers()
+                    .get("NL-CAPABILITIES")
+                    .and_then(|t| t.to_str().ok())
+                    .map(|t| t.to_owned());
+
+                let version = req
+                    .headers()
+                    .get("NL-VERSION")
+                    .and_then(|t| t.to_str().ok())
+                    .map(|t| t.to_owned());
+
                 let session = req
                     .headers()
                     .get("NL-SESSION")
@@ -238,6 +394,14 @@ impl HyperService<Request<Body>> for WsService {
                     .and_then(|t| t.to_str().ok())
                     .map(|t| t.to_owned());
 
+                // answered only when supported, agents without an answer send uncompressed
+                let compression = req
+                    .headers()
+                    .get("NL-COMPRESSION")
+                    .and_then(|t| t.to_str().ok())
+                    .and_then(|t| Compression::from_str(t).ok())
+                    .filter(|c| c != &Compression::None && connection.is_some());
+
                 let (response_sender, response_receiver) = oneshot::channel();
                 let (request, sender) = if command.is_some() ^ connection.is_some() {
                     trace!("data request found");
@@ -246,6 +410,7 @@ impl HyperService<Request<Body>> for WsService {
                         InBound::DataRequest(
                             ServiceDataRequest {
                                 token,
+                                client_cert,
                                 command,
                                 session,
                                 connection,
@@ -265,8 +430,15 @@ impl HyperService<Request<Body>> for WsService {
                         InBound::EventRequest(
                             ServiceEventRequest {
                                 token,
+                                client_cert,
                                 acl,
                                 publish,
+                                acme_email,
+                                acme_challenge,
+                                acme_directory,
+                                client_acl,
+                                capabilities,
+                                version,
                             },
                             stream_receiver,
                             peer_addr,
@@ -295,7 +467,12 @@ impl HyperService<Request<Body>> for WsService {
                                                 )
                                                 .await,
                                             );
-                                            let _ = s.send(ws_connection);
+                                            let _ = match compression {
+                                                Some(_) => s.send(Box::new(AsyncToStream::new(
+                                                    AsyncSocketCompress::new(ws_connection),
+                                                ))),
+                                                None => s.send(ws_connection),
+                                            };
                                         }
                                     }
                                     Right(s) => {
@@ -327,21 +504,24 @@ impl HyperService<Request<Body>> for WsService {
                                 for (k, v) in headers {
                                     r.headers_mut().append(k, v);
                                 }
+                                if let Some(compression) = compression {
+                                    r.headers_mut().append(
+                                        "NL-COMPRESSION",
+                                        HeaderValue::from_static(compression.as_str()),
+                                    );
+                                }
                                 r
                             })
                     }
                     Ok(Err(error)) => {
                         debug!("an expected response error received: {:?}", error);
-                        Ok(crate::service::http_templates::response_error(
-                            crate::service::http_templates::ErrorFormat::Json,
-                            error.into(),
-                        ))
+                        Ok(response_error(ErrorFormat::Json, error.into()))
                     }
                     Err(e) => {
                         debug!("unexpected response error: {}", e);
-                        Ok(crate::service::http_templates::response_error(
-                            crate::service::http_templates::ErrorFormat::Html,
-                            super::http_templates::HttpErrors::InternalServerError,
+                        Ok(response_error(
+                            ErrorFormat::Html,
+                            HttpErrors::InternalServerError,
                         ))
                     }
                 }
@@ -354,6 +534,7 @@ impl HyperService<Request<Body>> for WsService {
                     peer_addr,
                     response_sender,
                     listen_addr,
+                    idle_timeout,
                 ));
                 trace!("http transparent request found and sent and waiting for response");
                 match response_receiver.await {
@@ -369,16 +550,13 @@ impl HyperService<Request<Body>> for WsService {
                                 .body::<Body>(INDEX_HTML.into());
                         }
                         debug!("an expected response error received: {:?}", e);
-                        Ok(crate::service::http_templates::response_error(
-                            crate::service::http_templates::ErrorFormat::Html,
-                            e.into(),
-                        ))
+                        Ok(response_error(ErrorFormat::Html, e.into()))
                     }
                     Err(e) => {
                         trace!("unexpected response error: {:?}", e);
-                        Ok(crate::service::http_templates::response_error(
-                            crate::service::http_templates::ErrorFormat::Html,
-                            super::http_templates::HttpErrors::ServiceUnavailable,
+                        Ok(response_error(
+                            ErrorFormat::Html,
+                            HttpErrors::ServiceUnavailable,
                         ))
                     }
                 }
@@ -389,11 +567,108 @@ impl HyperService<Request<Body>> for WsService {
     }
 }
 
+// the agent name and client token are the Basic credentials of Proxy-Authorization, the stream
+// is tunnelled like a client data connection once the gateway accepts the target
+async fn http_connect(
+    req: Request<Body>,
+    status_sender: UnboundedSender<InBound>,
+    peer_addr: SocketAddr,
+) -> Result<Response<Body>, http::Error> {
+    let req_version = req.version();
+    let proxy_unauthorized = || {
+        Response::builder()
+            .version(req_version)
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header(header::PROXY_AUTHENTICATE, "Basic realm=\"narrowlink\"")
+            .body::<Body>("".into())
+    };
+    let Some((host, port)) = req
+        .uri()
+        .authority()
+        .and_then(|authority| Some((authority.host().to_owned(), authority.port_u16()?)))
+    else {
+        trace!("connect request without a target port");
+        return Ok(response_error(ErrorFormat::Html, HttpErrors::BadRequest));
+    };
+    let Some((agent_name, token)) = req
+        .headers()
+        .get(header::PROXY_AUTHORIZATION)
+        .and_then(|t| t.to_str().ok())
+        .and_then(|t| t.strip_prefix("Basic "))
+        .and_then(|t| base64::engine::general_purpose::STANDARD.decode(t).ok())
+        .and_then(|t| String::from_utf8(t).ok())
+        .and_then(|t| {
+            t.split_once(':')
+                .map(|(agent_name, token)| (agent_name.to_owned(), token.to_owned()))
+        })
+    else {
+        trace!("connect request without proxy credentials");
+        return proxy_unauthorized();
+    };
+    let acl = req
+        .headers()
+        .get("NL-ACL")
+        .and_then(|t| t.to_str().ok())
+        .map(|t| t.to_owned());
+
+    let (response_sender, response_receiver) = oneshot::channel();
+    let (socket_sender, socket_receiver) = oneshot::channel();
+    let _ = status_sender.send(InBound::HttpConnect(
+        ServiceConnectRequest {
+            token,
+            agent_name,
+            acl,
+            host,
+            port,
+        },
+        socket_receiver,
+        peer_addr,
+        response_sender,
+    ));
+    match response_receiver.await {
+        Ok(Ok(response_headers)) => {
+            tokio::spawn(
+                async move {
+                    if let Ok(upgraded) = upgrade::on(req).await {
+                        trace!("connect tunnel established");
+                        let _ = socket_sender.send(Box::new(AsyncToStream::new(upgraded)));
+                    }
+                }
+                .in_current_span(),
+            );
+            Response::builder()
+                .version(req_version)
+                .status(StatusCode::OK)
+                .body::<Body>("".into())
+                .map(|mut r| {
+                    let headers: HashMap<&str, HeaderValue> = response_headers.into();
+                    for (k, v) in headers {
+                        r.headers_mut().append(k, v);
+                    }
+                    r
+                })
+        }
+        Ok(Err(crate::state::ResponseErrors::Unauthorized)) => proxy_unauthorized(),
+        Ok(Err(error)) => {
+            debug!("an expected response error received: {:?}", error);
+            Ok(response_error(ErrorFormat::Html, error.into()))
+        }
+        Err(e) => {
+            debug!("unexpected response error: {}", e);
+            Ok(response_error(
+                ErrorFormat::Html,
+                HttpErrors::InternalServerError,
+            ))
+        }
+    }
+}
+
 impl From<ResponseHeaders> for HashMap<&str, HeaderValue> {
     fn from(value: ResponseHeaders) -> Self {
         let ResponseHeaders {
             session,
             connection,
+            capabilities,
         } = value;
         let mut map = HashMap::new();
         if let Some(session) = session.and_then(|s| HeaderValue::from_str(&s.to_string()).ok()) {
@@ -404,23 +679,27 @@ impl From<ResponseHeaders> for HashMap<&str, HeaderValue> {
         {
             map.insert("NL-CONNECTION", connection);
         }
+        if let Some(capabilities) =
+            capabilities.and_then(|c| HeaderValue::from_str(&c.to_string()).ok())
+        {
+            map.insert("NL-CAPABILITIES", capabilities);
+            map.insert(
+                "NL-VERSION",
+                HeaderValue::from_static(env!("CARGO_PKG_VERSION")),
+            );
+        }
         map
     }
 }
 
-impl From<crate::state::ResponseErrors> for super::http_templates::HttpErrors {
+impl From<crate::state::ResponseErrors> for HttpErrors {
     fn from(v: crate::state::ResponseErrors) -> Self {
         match v {
-            crate::state::ResponseErrors::Unauthorized => {
-                super::http_templates::HttpErrors::Unauthorized
-            }
-            crate::state::ResponseErrors::NotAcceptable(e) => {
-                super::http_templates::HttpErrors::NotAcceptable(e)
-            }
-            crate::state::ResponseErrors::NotFound(e) => {
-                super::http_templates::HttpErrors::NotFound(e)
-            }
-            crate::state::ResponseErrors::Forbidden => super::http_templates::HttpErrors::Forbidden,
+            crate::state::ResponseErrors::Unauthorized => HttpErrors::Unauthorized,
+            crate::state::ResponseErrors::NotAcceptable(e) => HttpErrors::NotAcceptable(e),
+            crate::state::ResponseErrors::NotFound(e) => HttpErrors::NotFound(e),
+            crate::state::ResponseErrors::Forbidden => HttpErrors::Forbidden,
+            crate::state::ResponseErrors::ServiceUnavailable => HttpErrors::ServiceUnavailable,
         }
     }
 }
@@ -1,44 +1,290 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use crate::{config::TlsConfig, error::GatewayError, state::InBound};
+use crate::{
+    config::{TlsConfig, UnknownSni},
+    error::GatewayError,
+    state::InBound,
+};
 
 use async_trait::async_trait;
-use hyper::server::conn::Http;
-use rustls::{internal::msgs::codec::Codec, ServerConfig};
-use tokio::{net::TcpListener, sync::mpsc::UnboundedSender};
+use hyper::{server::conn::Http, service::service_fn};
+use rustls::{
+    internal::msgs::codec::Codec,
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, ClientCertVerifier, ClientHello, ResolvesServerCert,
+    },
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+};
 use tokio_rustls::TlsAcceptor;
-use tracing::{debug, instrument, span, trace, warn, Instrument};
+use tracing::{debug, info, instrument, span, trace, warn, Instrument};
 
-use super::{certificate::manager::CertificateManager, ws::WsService, RequestProtocol, Service};
+use super::{
+    certificate::{
+        manager::{
+            CertificateEvent, CertificateManager, CertificateManagerConfig,
+            CertificateServiceMessage, GATEWAY_AGENT_NAME, GATEWAY_UID,
+        },
+        CertificateStorage, SessionResumption, TlsPolicy,
+    },
+    drain::Draining,
+    ws::WsService,
+    ClientCert, RequestProtocol, Service,
+};
 
 #[derive(Clone)]
 pub struct Wss {
     listen_addr: SocketAddr,
     domains: Vec<String>,
+    alpn: Option<Vec<Vec<u8>>>,
     status_sender: UnboundedSender<InBound>,
     cm: TlsEngine,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    http_proxy: bool,
+    idle_timeout: Option<Duration>,
+    default_cert: Option<PathBuf>,
+    unknown_sni: UnknownSni,
+}
+
+// certificates are optional in the handshake so browsers and clients keep working, a presented
+// one has to chain to the CA
+pub async fn client_verifier(
+    client_ca: &std::path::Path,
+) -> Result<Arc<dyn ClientCertVerifier>, GatewayError> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut &tokio::fs::read(client_ca).await?[..])? {
+        roots.add(&rustls::Certificate(cert))?;
+    }
+    if roots.is_empty() {
+        return Err(GatewayError::Invalid("client CA, no certificate found"));
+    }
+    Ok(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+}
+
+// the same certificates, with client certificates requested and verified
+pub fn with_client_verifier(
+    config: &ServerConfig,
+    verifier: Arc<dyn ClientCertVerifier>,
+    tls_policy: &TlsPolicy,
+) -> Result<Arc<ServerConfig>, GatewayError> {
+    let mut mtls = tls_policy
+        .builder()?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(config.cert_resolver.clone());
+    mtls.alpn_protocols = config.alpn_protocols.clone();
+    mtls.session_storage = config.session_storage.clone();
+    mtls.ticketer = config.ticketer.clone();
+    mtls.send_tls13_tickets = config.send_tls13_tickets;
+    Ok(Arc::new(mtls))
+}
+
+// the same certificates, advertising other protocols than the default h2 and http/1.1
+pub fn with_alpn(config: Arc<ServerConfig>, alpn: &Option<Vec<Vec<u8>>>) -> Arc<ServerConfig> {
+    let Some(alpn) = alpn else {
+        return config;
+    };
+    let mut config = ServerConfig::clone(&config);
+    config.alpn_protocols = alpn.clone();
+    Arc::new(config)
+}
+
+// completes the handshake with the default certificate so clients see why the host failed
+async fn serve_fallback(config: Arc<ServerConfig>, unknown_sni: UnknownSni, tcp_stream: TcpStream) {
+    let Ok(mut stream) = TlsAcceptor::from(config).accept(tcp_stream).await else {
+        trace!("handshake with the default certificate failed");
+        return;
+    };
+    match unknown_sni {
+        UnknownSni::Reject => {
+            let _ = stream.shutdown().await;
+        }
+        UnknownSni::Respond => {
+            use super::http_templates::{response_error, ErrorFormat, HttpErrors};
+            let _ = Http::new()
+                .serve_connection(
+                    stream,
+                    service_fn(|_| async {
+                        Ok::<_, Infallible>(response_error(
+                            ErrorFormat::Html,
+                            HttpErrors::MisdirectedRequest,
+                        ))
+                    }),
+                )
+                .await;
+        }
+    }
+}
+
+// picks the certificate by SNI during the handshake, for acceptors outside of the Wss service
+// the gateway's own sink of the certificate events, failures are already warned about where they happen
+async fn log_certificate_events(mut events: UnboundedReceiver<CertificateEvent>) {
+    while let Some(event) = events.recv().await {
+        match event {
+            CertificateEvent::Loaded(uid, agent_name, domains) => {
+                info!(
+                    "certificates of {} loaded for {}/{}",
+                    domains.join(", "),
+                    uid,
+                    agent_name
+                )
+            }
+            CertificateEvent::Renewed(uid, agent_name, domains) => {
+                info!(
+                    "certificates of {} renewed for {}/{}",
+                    domains.join(", "),
+                    uid,
+                    agent_name
+                )
+            }
+            CertificateEvent::Unloaded(uid, agent_name, domains) => {
+                info!(
+                    "certificates of {} unloaded for {}/{}",
+                    domains.join(", "),
+                    uid,
+                    agent_name
+                )
+            }
+            CertificateEvent::IssuanceFailed(uid, agent_name, domains)
+            | CertificateEvent::ImportedExpiring(uid, agent_name, domains) => {
+                debug!(
+                    "certificates of {} need attention for {}/{}",
+                    domains.join(", "),
+                    uid,
+                    agent_name
+                )
+            }
+            CertificateEvent::ExpiryWarning(uid, agent_name, domains, days) => debug!(
+                "certificates of {} expire in {} days for {}/{}",
+                domains.join(", "),
+                days,
+                uid,
+                agent_name
+            ),
+        }
+    }
+}
+
+pub struct CertificateResolver(pub TlsEngine);
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let sni = client_hello.server_name()?;
+        let config = match &self.0 {
+            TlsEngine::Acme(acme) => acme.try_get(sni)?,
+            TlsEngine::File((domains, config, _)) => domains
+                .iter()
+                .any(|domain| domain == sni)
+                .then(|| config.clone())?,
+        };
+        config.cert_resolver.resolve(client_hello)
+    }
 }
 #[derive(Clone)]
 pub enum TlsEngine {
     Acme(Arc<CertificateManager>),
-    File((Vec<String>, Arc<ServerConfig>)),
+    File((Vec<String>, Arc<ServerConfig>, TlsPolicy)),
 }
 
 impl TlsEngine {
+    pub fn tls_policy(&self) -> TlsPolicy {
+        match self {
+            Self::Acme(acme) => acme.tls_policy().clone(),
+            Self::File((_, _, tls_policy)) => tls_policy.clone(),
+        }
+    }
     #[instrument(name = "tls_engine::new", skip(conf))]
     pub async fn new(conf: TlsConfig) -> Result<Self, GatewayError> {
         debug!("tls config: {:?}", conf);
         match conf {
             TlsConfig::Acme(acme) => {
                 trace!("setting up acme tls engine");
-                let certificate_file_storage = Arc::new(
-                    crate::service::certificate::file_storage::CertificateFileStorage::new(
-                        "./certificates",
+                let certificate_storage: Arc<dyn CertificateStorage + Sync + Send> = match &acme
+                    .storage
+                {
+                    crate::config::CertificateStorage::File { path } => Arc::new(
+                        crate::service::certificate::file_storage::CertificateFileStorage::new(
+                            path,
+                        ),
                     ),
-                );
+                    crate::config::CertificateStorage::Redis {
+                        address,
+                        password,
+                        prefix,
+                    } => Arc::new(
+                        crate::service::certificate::redis_storage::RedisCertificateStorage::new(
+                            address,
+                            password.clone(),
+                            prefix,
+                        ),
+                    ),
+                    crate::config::CertificateStorage::Memory => Arc::new(
+                        crate::service::certificate::memory_storage::InMemoryCertificateStorage::new(),
+                    ),
+                };
+                let certificate_storage = match &acme.storage_encryption {
+                    Some(encryption) => Arc::new(
+                        crate::service::certificate::encrypted_storage::EncryptedCertificateStorage::from_env(
+                            certificate_storage,
+                            &encryption.key_env,
+                            &encryption.previous_key_envs,
+                        )?,
+                    ),
+                    None => certificate_storage,
+                };
+                let config = CertificateManagerConfig {
+                    renew_check_interval: Duration::from_secs(acme.renew_check_interval),
+                    renew_before_expiry: Duration::from_secs(acme.renew_before_days * 24 * 60 * 60),
+                    challenge_poll_tries: acme.challenge_poll_tries,
+                    challenge_poll_interval: Duration::from_secs(acme.challenge_poll_interval),
+                    max_concurrent_issuances: acme.max_concurrent_issuances,
+                    key_type: acme.key_type,
+                    staging: acme.staging,
+                    preload: acme.preload,
+                    max_cached: acme.max_cached_certificates,
+                    load_concurrency: acme.load_concurrency,
+                    agent_selection: acme.agent_selection,
+                    session_resumption: SessionResumption::new(
+                        acme.session_resumption.tickets,
+                        acme.session_resumption.cache_size,
+                        acme.session_resumption.tls13_tickets,
+                    )?,
+                    tls_policy: TlsPolicy::new(
+                        acme.tls_policy.min_version,
+                        &acme.tls_policy.cipher_suites,
+                        &acme.tls_policy.alpn,
+                    )?,
+                    expiry_warning_days: acme.expiry_warning_days.clone(),
+                    max_connection_age: acme.max_connection_age.map(Duration::from_secs),
+                    directories: acme
+                        .directories
+                        .iter()
+                        .map(|d| (d.url.clone(), d.eab_kid.clone().zip(d.eab_hmac_key.clone())))
+                        .collect(),
+                    ..Default::default()
+                };
+                let directory_url = acme.directory();
+                let (event_sender, events) = mpsc::unbounded_channel();
+                tokio::spawn(log_certificate_events(events).in_current_span());
                 let certificate_manager = CertificateManager::new(
-                    certificate_file_storage,
-                    Some((acme.email, acme.challenge_type, acme.directory_url)),
+                    certificate_storage,
+                    Some((
+                        acme.email,
+                        acme.challenge_type,
+                        directory_url,
+                        acme.eab_kid.zip(acme.eab_hmac_key),
+                    )),
+                    None,
+                    None,
+                    config,
+                    Some(event_sender),
                 )
                 .in_current_span()
                 .await?;
@@ -47,12 +293,17 @@ impl TlsEngine {
             }
             TlsConfig::File(file) => {
                 trace!("setting up file tls engine");
+                let tls_policy = TlsPolicy::new(
+                    file.tls_policy.min_version,
+                    &file.tls_policy.cipher_suites,
+                    &file.tls_policy.alpn,
+                )?;
                 let cert = super::certificate::Certificate::from_pem_vec(pem::parse_many(
                     tokio::fs::read_to_string(file.cert_path).await?,
                 )?)?
-                .get_config();
+                .get_config(&tls_policy)?;
                 trace!("file tls engine successfully created");
-                Ok(Self::File((file.domains, cert)))
+                Ok(Self::File((file.domains, cert, tls_policy)))
             }
         }
     }
@@ -63,12 +314,22 @@ impl Wss {
         ws: &crate::config::WsSecureService,
         status_sender: UnboundedSender<InBound>,
         cm: TlsEngine,
+        client_verifier: Option<Arc<dyn ClientCertVerifier>>,
     ) -> Self {
         Self {
             listen_addr: ws.listen_addr,
             domains: ws.domains.to_owned(),
+            alpn: ws
+                .alpn
+                .as_ref()
+                .map(|alpn| alpn.iter().map(|p| p.as_bytes().to_vec()).collect()),
             status_sender,
             cm,
+            client_verifier,
+            http_proxy: ws.http_proxy,
+            idle_timeout: ws.idle_timeout.map(Duration::from_secs),
+            default_cert: ws.default_cert.clone(),
+            unknown_sni: ws.unknown_sni,
         }
     }
     // buf is the first 1024 bytes of the tcp stream, which is the client hello
@@ -113,14 +374,27 @@ impl Service for Wss {
         let wss = self.clone();
         let tls_engine = self.cm.clone();
         if let TlsEngine::Acme(acme) = &tls_engine {
-            let _ = acme.clone().get_service_sender().send(
-                crate::service::certificate::manager::CertificateServiceMessage::Load(
-                    "main".to_owned(),
-                    "self".to_owned(),
+            let _ = acme
+                .clone()
+                .get_service_sender()
+                .send(CertificateServiceMessage::Load(
+                    GATEWAY_UID.to_owned(),
+                    GATEWAY_AGENT_NAME.to_owned(),
                     self.domains,
-                ),
-            );
+                    None,
+                ));
         }
+        // the versions and suites of the engine, unknown hosts are not weaker than the known ones
+        let fallback = match &self.default_cert {
+            Some(default_cert) => Some(with_alpn(
+                super::certificate::Certificate::from_pem_vec(pem::parse_many(
+                    tokio::fs::read_to_string(default_cert).await?,
+                )?)?
+                .get_config(&tls_engine.tls_policy())?,
+                &self.alpn,
+            )),
+            None => None,
+        };
         span.in_scope(|| trace!("binding tcp listener"));
 
         let tcp_listener = TcpListener::bind(&self.listen_addr).await?;
@@ -129,11 +403,14 @@ impl Service for Wss {
                 span.in_scope(|| warn!("failed to accept tcp connection"));
                 continue;
             };
+            let accepted = tokio::time::Instant::now();
+            let local_addr = tcp_stream.local_addr().unwrap_or(self.listen_addr);
             let span_connection = span
                 .in_scope(|| span!(tracing::Level::TRACE, "connection", peer_addr = %peer_addr));
 
             let wss = wss.clone();
             let tls_engine = tls_engine.clone();
+            let fallback = fallback.clone();
             tokio::spawn(async move {
                 let mut buf = vec![0; 1024];
                 tcp_stream
@@ -147,10 +424,22 @@ impl Service for Wss {
                 let Some((sni, alpns)) =
                     span_connection.in_scope(|| Self::peek_sni_and_alpns(&buf))
                 else {
+                    if let Some(fallback) = fallback {
+                        span_connection
+                            .in_scope(|| debug!("no sni, serving the default certificate"));
+                        serve_fallback(fallback, wss.unknown_sni, tcp_stream)
+                            .instrument(span_connection.clone())
+                            .await;
+                        return Ok(());
+                    }
                     span_connection.in_scope(|| warn!("failed to peek sni and alpns"));
                     return Err::<(), ()>(());
                 };
                 span_connection.record("sni", &sni);
+                let drain = match &tls_engine {
+                    TlsEngine::Acme(acme) => acme.drain_on_renewal(&sni, accepted),
+                    TlsEngine::File(_) => None,
+                };
                 let Some(server_config) = (match tls_engine {
                     TlsEngine::Acme(acme) => {
                         if acme.acme_type().is_some()
@@ -160,6 +449,14 @@ impl Service for Wss {
                             acme.get_acme_tls_challenge(&sni)
                                 .instrument(span_connection.clone())
                                 .await
+                                .map_err(|e| {
+                                    span_connection.in_scope(|| {
+                                        debug!(
+                                            "tls alpn 01 challenge for {} unavailable: {}",
+                                            sni, e
+                                        )
+                                    })
+                                })
                                 .ok()
                         } else {
                             span_connection.in_scope(|| trace!("get certificate from acme"));
@@ -167,12 +464,29 @@ impl Service for Wss {
                                 .instrument(span_connection.clone())
                                 .await
                                 .ok()
+                                .map(|config| with_alpn(config, &wss.alpn))
+                                .and_then(|config| match &wss.client_verifier {
+                                    Some(verifier) => with_client_verifier(
+                                        &config,
+                                        verifier.clone(),
+                                        acme.tls_policy(),
+                                    )
+                                    .ok(),
+                                    None => Some(config),
+                                })
                         }
                     }
-                    TlsEngine::File((domains, acceptor)) => {
+                    TlsEngine::File((domains, acceptor, tls_policy)) => {
                         if domains.contains(&sni) {
                             span_connection.in_scope(|| trace!("get certificate from file"));
-                            Some(acceptor)
+                            let acceptor = with_alpn(acceptor, &wss.alpn);
+                            match &wss.client_verifier {
+                                Some(verifier) => {
+                                    with_client_verifier(&acceptor, verifier.clone(), &tls_policy)
+                                        .ok()
+                                }
+                                None => Some(acceptor),
+                            }
                         } else {
                             span_connection.in_scope(|| {
                                 trace!("no certificate found for this domain in file")
@@ -182,11 +496,31 @@ impl Service for Wss {
                     }
                 }) else {
                     span_connection.in_scope(|| trace!("certificate not found, act as SNI proxy"));
+                    let (unknown, unoccupied) = match fallback {
+                        Some(_) => {
+                            let (unknown, unoccupied) = oneshot::channel();
+                            (Some(unknown), Some(unoccupied))
+                        }
+                        None => (None, None),
+                    };
                     let _ = wss.status_sender.send(InBound::TlsTransparent(
-                        sni,
+                        sni.clone(),
                         tcp_stream,
-                        self.listen_addr.port(),
+                        local_addr,
+                        wss.idle_timeout,
+                        unknown,
                     ));
+                    // the state hands the stream back if no agent publishes the sni either
+                    if let (Some(fallback), Some(unoccupied)) = (fallback, unoccupied) {
+                        if let Ok(tcp_stream) = unoccupied.await {
+                            span_connection.in_scope(|| {
+                                debug!("no agent serves {}, using the default certificate", sni)
+                            });
+                            serve_fallback(fallback, wss.unknown_sni, tcp_stream)
+                                .instrument(span_connection.clone())
+                                .await;
+                        }
+                    }
                     return Ok::<(), ()>(());
                 };
                 span_connection.in_scope(|| trace!("setting up tls acceptor"));
@@ -196,16 +530,23 @@ impl Service for Wss {
                     .await
                     .map_err(|_| ())?;
                 span_connection.in_scope(|| trace!("tls acceptor successfully created"));
+                let client_cert = ClientCert::from_presented(
+                    wss.client_verifier.is_some(),
+                    secure_stream.get_ref().1.peer_certificates().is_some(),
+                );
                 if let Err(http_err) = Http::new()
                     .serve_connection(
-                        secure_stream,
+                        Draining::new(secure_stream, drain),
                         WsService {
-                            listen_addr: RequestProtocol::Https(self.listen_addr),
+                            listen_addr: RequestProtocol::Https(local_addr),
                             domains: wss.domains,
                             sni: Some(sni),
                             status_sender: wss.status_sender,
                             peer_addr,
                             cm: None,
+                            client_cert,
+                            http_proxy: wss.http_proxy,
+                            idle_timeout: wss.idle_timeout,
                         },
                     )
                     .with_upgrades()
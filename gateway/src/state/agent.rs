@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
     str::FromStr,
 };
@@ -8,19 +8,25 @@ use futures_util::{stream::SplitSink, SinkExt};
 use narrowlink_network::{error::NetworkError, event::NarrowEvent};
 use narrowlink_types::{
     agent::{ConstSystemInfo, DynSystemInfo, EventInBound, EventOutBound, SystemInfo},
-    generic::Connect,
-    publish::PublishHost,
+    capability::{Capabilities, Capability},
+    generic::{Connect, Protocol},
+    publish::{PublishHost, ServiceAccess},
     NatType,
 };
 
+// service port -> published services, each restricted to connections arriving at its bind address
+type PublishPorts = HashMap<u16, Vec<(Option<SocketAddr>, Connect)>>;
+
 pub struct Agent {
     pub name: String,
-    pub publish_map: HashMap<String, HashMap<u16, Connect>>,
+    pub publish_map: HashMap<String, PublishPorts>,
     pub socket_addr: SocketAddr,
     pub forward_addr: Option<String>,
     pub system_info: Option<SystemInfo>,
     pub ping: u16,
     pub since: u64,
+    pub client_access: Vec<ServiceAccess>,
+    capabilities: Option<Capabilities>, // None for agents predating the exchange
     sender: SplitSink<NarrowEvent<EventInBound, EventOutBound>, EventInBound>,
 }
 
@@ -30,15 +36,11 @@ impl Agent {
         publishes: Vec<PublishHost>,
         socket_addr: SocketAddr,
         forward_addr: Option<String>,
+        client_access: Vec<ServiceAccess>,
+        capabilities: Option<Capabilities>,
         sender: SplitSink<NarrowEvent<EventInBound, EventOutBound>, EventInBound>,
     ) -> Self {
-        let mut publish_map = HashMap::new();
-        for publish in publishes {
-            publish_map
-                .entry(publish.host)
-                .or_insert_with(HashMap::new)
-                .insert(publish.port, publish.connect);
-        }
+        let publish_map = publish_map(publishes);
         let since = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .map(|d| d.as_secs())
@@ -51,9 +53,16 @@ impl Agent {
             system_info: None,
             ping: 0,
             since,
+            client_access,
+            capabilities,
             sender,
         }
     }
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_some_and(|capabilities| capabilities.contains(capability))
+    }
 
     pub fn name(&self) -> String {
         self.name.clone()
@@ -61,12 +70,74 @@ impl Agent {
     pub async fn send(&mut self, msg: EventInBound) -> Result<(), NetworkError> {
         self.sender.send(msg).await
     }
-    pub fn domain(&self, domain: &str, port: u16) -> Option<Connect> {
+    // matched on the port alone, the same service is reachable under more than one host
+    pub fn permit_client(&self, client: &str, connect: &Connect) -> bool {
+        self.client_access
+            .iter()
+            .filter(|service| service.port == connect.port)
+            .all(|service| service.access.permit(client))
+    }
+    // peer to peer connections bypass the gateway, only clients allowed everywhere get them
+    pub fn permit_peer_to_peer(&self, client: &str) -> bool {
+        self.client_access
+            .iter()
+            .all(|service| service.access.permit(client))
+    }
+    pub fn set_publish_hosts(&mut self, publishes: Vec<PublishHost>) {
+        self.publish_map = publish_map(publishes);
+    }
+    pub fn is_published(&self, publish: &PublishHost) -> bool {
+        self.publish_map
+            .get(&publish.host)
+            .and_then(|ports| ports.get(&publish.port))
+            .is_some_and(|services| {
+                services.iter().any(|(bind, connect)| {
+                    bind == &publish.bind
+                        && connect.host == publish.connect.host
+                        && connect.port == publish.connect.port
+                        && connect.protocol == publish.connect.protocol
+                })
+            })
+    }
+    // published hosts served over TLS by the gateway, certificates are only issued for literal hosts
+    pub fn certificate_hosts(&self) -> HashSet<String> {
+        self.publish_map
+            .iter()
+            .filter(|(host, _)| !is_pattern(host))
+            .filter(|(_, ports)| {
+                ports.values().flatten().any(|(_, connect)| {
+                    matches!(
+                        connect.protocol,
+                        Protocol::HTTP | Protocol::HTTPS | Protocol::QUIC
+                    )
+                })
+            })
+            .map(|(host, _)| host.to_owned())
+            .collect()
+    }
+    // literal hosts take precedence over patterns, then the most specific pattern wins,
+    // `port` is the published port or 0 and `local_addr` the address the connection arrived on
+    pub fn domain(&self, domain: &str, port: u16, local_addr: SocketAddr) -> Option<Connect> {
+        let serve = |map| serving(map, port, local_addr);
         self.publish_map
             .get(domain)
-            .and_then(|map| map.get(&port))
+            .and_then(serve)
+            .or_else(|| {
+                self.publish_map
+                    .iter()
+                    .filter(|(pattern, map)| {
+                        is_pattern(pattern) && serve(map).is_some() && glob_match(pattern, domain)
+                    })
+                    .max_by_key(|(pattern, _)| pattern.len())
+                    .and_then(|(_, map)| serve(map))
+            })
             .cloned()
     }
+    pub fn publishes(&self, domain: &str, port: u16) -> bool {
+        self.publish_map
+            .get(domain)
+            .is_some_and(|map| map.contains_key(&port))
+    }
     pub fn dyn_sys_update(&mut self, dyn_sys_info: DynSystemInfo) {
         if let Some(sys_info) = &mut self.system_info {
             sys_info.dynamic = dyn_sys_info;
@@ -120,3 +191,122 @@ impl Drop for Agent {
         drop(self.sender.close());
     }
 }
+
+fn publish_map(publishes: Vec<PublishHost>) -> HashMap<String, PublishPorts> {
+    let mut publish_map: HashMap<String, PublishPorts> = HashMap::new();
+    for publish in publishes {
+        publish_map
+            .entry(publish.host)
+            .or_default()
+            .entry(publish.port)
+            .or_default()
+            .push((publish.bind, publish.connect));
+    }
+    publish_map
+}
+
+// a service bound to the local address takes precedence over the unbound one
+fn serving(map: &PublishPorts, port: u16, local_addr: SocketAddr) -> Option<&Connect> {
+    map.get(&port)?
+        .iter()
+        .filter(|(bind, _)| bind.map_or(true, |bind| binds(bind, local_addr)))
+        .max_by_key(|(bind, _)| bind.is_some())
+        .map(|(_, connect)| connect)
+}
+
+// an unspecified ip or a zero port in the bind address matches any
+pub fn binds(bind: SocketAddr, local_addr: SocketAddr) -> bool {
+    (bind.ip().is_unspecified() || bind.ip() == local_addr.ip())
+        && (bind.port() == 0 || bind.port() == local_addr.port())
+}
+
+pub fn is_pattern(host: &str) -> bool {
+    host.contains(['*', '?', '\\'])
+}
+
+// anchored and case-sensitive, `*` matches any run of characters, `?` exactly one and `\` escapes the next one
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None; // pattern index after the last `*`, name index it resumes from
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            Some('\\') if pattern.get(p + 1) == Some(&name[n]) => {
+                p += 2;
+                n += 1;
+                continue;
+            }
+            Some(c) if *c != '\\' && *c == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let Some((resume_p, resume_n)) = backtrack else {
+            return false;
+        };
+        p = resume_p;
+        n = resume_n + 1;
+        backtrack = Some((resume_p, resume_n + 1));
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_matches_any_run() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "api.example.com"));
+        assert!(glob_match("*.example.com", "api.example.com"));
+        assert!(glob_match("*.example.com", "a.b.example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("api-**", "api-"));
+    }
+
+    #[test]
+    fn question_mark_matches_exactly_one_character() {
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("a?c", "aéc"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(!glob_match("a?c", "abbc"));
+        assert!(glob_match("??*", "ab"));
+        assert!(!glob_match("??*", "a"));
+    }
+
+    #[test]
+    fn backslash_escapes_the_next_character() {
+        assert!(glob_match(r"a\*b", "a*b"));
+        assert!(!glob_match(r"a\*b", "aXb"));
+        assert!(glob_match(r"a\?b", "a?b"));
+        assert!(!glob_match(r"a\?b", "aXb"));
+        assert!(glob_match(r"a\\b", r"a\b"));
+        assert!(glob_match(r"*\*", "any*"));
+        // a trailing backslash escapes nothing and never matches
+        assert!(!glob_match(r"a\", r"a\"));
+        assert!(!glob_match(r"a\", "a"));
+    }
+
+    #[test]
+    fn matching_is_anchored_and_case_sensitive() {
+        assert!(!glob_match("example.com", "api.example.com"));
+        assert!(!glob_match("api.*", "my.api.example.com"));
+        assert!(!glob_match("API.example.com", "api.example.com"));
+    }
+}
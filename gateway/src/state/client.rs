@@ -1,11 +1,12 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
     str::FromStr,
 };
 
 use futures_util::{stream::SplitSink, SinkExt};
 use narrowlink_types::{
-    client::{ConstSystemInfo, EventInBound, EventOutBound, SystemInfo},
+    client::{ConstSystemInfo, EventInBound, EventOutBound, ReverseBindRequest, SystemInfo},
     policy::Policy,
     NatType,
 };
@@ -13,6 +14,15 @@ use narrowlink_types::{
 use narrowlink_network::{error::NetworkError, event::NarrowEvent};
 use uuid::Uuid;
 
+// the part of the client policies applying to one agent
+pub fn agent_policies(policies: &[Policy], agent_name: &str) -> Vec<Policy> {
+    policies
+        .iter()
+        .map(|p| p.agent_policies(agent_name))
+        .filter(|p| p.policies.is_empty())
+        .collect()
+}
+
 pub struct Client {
     pub name: String,
     session_id: Uuid,
@@ -20,6 +30,7 @@ pub struct Client {
     socket_addr: SocketAddr,
     forward_addr: Option<String>,
     system_info: Option<SystemInfo>,
+    reverse_binds: HashMap<Uuid, ReverseBindRequest>, // bind id -> listener on the agent
     sender: SplitSink<NarrowEvent<EventInBound, EventOutBound>, EventInBound>,
 }
 
@@ -39,6 +50,7 @@ impl Client {
             socket_addr,
             forward_addr,
             system_info: None,
+            reverse_binds: HashMap::new(),
             sender,
         }
     }
@@ -59,12 +71,16 @@ impl Client {
     //     self.policies.clone()
     // }
     pub fn get_agent_policy(&self, agent_name: &str) -> Vec<Policy> {
-        self.policies
-            .clone()
-            .into_iter()
-            .map(|p| p.agent_policies(agent_name))
-            .filter(|p| p.policies.is_empty())
-            .collect()
+        agent_policies(&self.policies, agent_name)
+    }
+    pub fn add_reverse_bind(&mut self, bind: ReverseBindRequest) {
+        self.reverse_binds.insert(bind.id, bind);
+    }
+    pub fn has_reverse_bind(&self, id: Uuid) -> bool {
+        self.reverse_binds.contains_key(&id)
+    }
+    pub fn reverse_binds(&self) -> impl Iterator<Item = &ReverseBindRequest> {
+        self.reverse_binds.values()
     }
     pub fn get_real_ip(&self) -> IpAddr {
         if let Some(addr) = self
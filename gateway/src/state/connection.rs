@@ -1,15 +1,19 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use hyper::{client::conn, http::HeaderValue, Body, Request, Response};
-use narrowlink_network::{error::NetworkError, UniversalStream};
+use narrowlink_network::{error::NetworkError, AsyncSocket, IdleTimeout, UniversalStream};
 // use narrowlink_types::policy::Policy;
-use tokio::{net::TcpStream, sync::oneshot};
+use tokio::sync::oneshot;
 use tracing::{debug, Instrument};
 use uuid::Uuid;
 
-use crate::{error::GatewayError, service::RequestProtocol};
+use crate::{
+    audit::Tunnel,
+    error::GatewayError,
+    service::{udp::UdpSession, RequestProtocol},
+};
 
-use super::{ResponseErrors, ResponseHeaders};
+use super::{limit::ConnectionPermit, ResponseErrors, ResponseHeaders};
 
 // #[derive(Debug)]
 pub struct Connection {
@@ -17,17 +21,19 @@ pub struct Connection {
     pub session_id: Option<Uuid>,
     pub data: ConnectionData,
     // pub policies: Vec<Policy>,
+    _permit: ConnectionPermit,
 }
 
 // #[derive(Debug)]
 pub enum ClientConnection {
     HttpTransparent(
-        Request<Body>,
+        Box<Request<Body>>, // boxed, the other variants are a fraction of its size
         SocketAddr,
         oneshot::Sender<Result<Response<Body>, ResponseErrors>>,
         RequestProtocol,
     ),
-    TlsTransparent(TcpStream),
+    TlsTransparent(Box<dyn AsyncSocket>), // raw TLS, or the plain stream of a terminated one
+    UdpTransparent(UdpSession),
     Client(
         Option<oneshot::Sender<Result<ResponseHeaders, ResponseErrors>>>,
         oneshot::Receiver<Box<dyn UniversalStream<Vec<u8>, NetworkError>>>,
@@ -48,18 +54,37 @@ impl Connection {
         client_socket: Option<ClientConnection>,
         agent_socket: Option<AgentConnection>,
         // policies: Vec<Policy>,
+        permit: ConnectionPermit,
     ) -> Self {
         Self {
             id,
             session_id,
             data: ConnectionData::new(id, session_id, client_socket, agent_socket),
             // policies,
+            _permit: permit,
         }
     }
 
+    // recorded in the audit log when the connection is dropped, if there is one
+    pub fn set_audit(&mut self, tunnel: impl FnOnce(Uuid) -> Tunnel) {
+        if crate::audit::enabled() {
+            self.data.audit = Some(tunnel(self.id));
+        }
+    }
+    pub fn set_close_reason(&mut self, reason: String) {
+        if let Some(tunnel) = self.data.audit.as_mut() {
+            tunnel.set_reason(reason);
+        }
+    }
     pub fn set_agent_socket(&mut self, socket: AgentConnection) {
         self.data.agent_socket = Some(socket);
     }
+    // the receiver gets a value if the connection is closed for being idle
+    pub fn set_idle_timeout(&mut self, timeout: Duration) -> oneshot::Receiver<()> {
+        let (expired_sender, expired) = oneshot::channel();
+        self.data.idle = Some((timeout, expired_sender));
+        expired
+    }
     // pub fn take_client_socket(
     //     &mut self,
     // ) -> Option<oneshot::Sender<Result<ResponseHeaders, ResponseErrors>>> {
@@ -82,6 +107,8 @@ pub struct ConnectionData {
     pub session_id: Option<Uuid>,
     pub client_socket: Option<ClientConnection>,
     pub agent_socket: Option<AgentConnection>,
+    idle: Option<(Duration, oneshot::Sender<()>)>,
+    audit: Option<Tunnel>,
 }
 
 impl ConnectionData {
@@ -96,10 +123,36 @@ impl ConnectionData {
             session_id,
             client_socket,
             agent_socket,
+            idle: None,
+            audit: None,
+        }
+    }
+    // the idle timeout and the traffic of the audit log are both taken on the agent side
+    fn wrap_agent_socket(
+        &mut self,
+        socket: Box<dyn UniversalStream<Vec<u8>, NetworkError>>,
+    ) -> Box<dyn UniversalStream<Vec<u8>, NetworkError>> {
+        let socket = match self.idle.take() {
+            Some((timeout, expired)) => Box::new(IdleTimeout::new(socket, timeout, expired)),
+            None => socket,
+        };
+        match &self.audit {
+            Some(tunnel) => tunnel.count(socket),
+            None => socket,
         }
     }
     #[tracing::instrument(name = "connection_serve", skip(self))]
     pub async fn serve(&mut self) -> Result<(), GatewayError> {
+        let result = self.forward().await;
+        if let Some(tunnel) = self.audit.as_mut() {
+            tunnel.set_reason(match &result {
+                Ok(()) => "Closed".to_owned(),
+                Err(e) => e.to_string(),
+            });
+        }
+        result
+    }
+    async fn forward(&mut self) -> Result<(), GatewayError> {
         let (Some(client_connection), Some(agent_connection)) =
             (self.client_socket.take(), self.agent_socket.take())
         else {
@@ -113,6 +166,7 @@ impl ConnectionData {
                         res.send(Ok(ResponseHeaders {
                             session: self.session_id,
                             connection: Some(self.id),
+                            capabilities: None,
                         }))
                         .ok()
                     })
@@ -123,6 +177,7 @@ impl ConnectionData {
                         res.send(Ok(ResponseHeaders {
                             session: self.session_id,
                             connection: Some(self.id),
+                            capabilities: None,
                         }))
                         .ok()
                     })
@@ -132,9 +187,11 @@ impl ConnectionData {
                 let client_socket = client_socket_receiver
                     .await
                     .map_err(|_| GatewayError::Other("Client Connection gone"))?;
-                let agent_socket = agent_socket_receiver
-                    .await
-                    .map_err(|_| GatewayError::Other("Agent Connection gone"))?;
+                let agent_socket = self.wrap_agent_socket(
+                    agent_socket_receiver
+                        .await
+                        .map_err(|_| GatewayError::Other("Agent Connection gone"))?,
+                );
                 narrowlink_network::stream_forward(client_socket, agent_socket)
                     .await
                     .map_err(|e| e.into())
@@ -145,15 +202,18 @@ impl ConnectionData {
                         res.send(Ok(ResponseHeaders {
                             session: self.session_id,
                             connection: Some(self.id),
+                            capabilities: None,
                         }))
                         .ok()
                     })
                     .is_none()
                 {};
 
-                let agent_socket = agent_socket_receiver
-                    .await
-                    .map_err(|_| GatewayError::Other("Agent Connection gone"))?;
+                let agent_socket = self.wrap_agent_socket(
+                    agent_socket_receiver
+                        .await
+                        .map_err(|_| GatewayError::Other("Agent Connection gone"))?,
+                );
                 narrowlink_network::stream_forward(
                     narrowlink_network::AsyncToStream::new(tcp_stream),
                     agent_socket,
@@ -161,10 +221,38 @@ impl ConnectionData {
                 .await
                 .map_err(|e| e.into())
             }
-            ClientConnection::HttpTransparent(mut request, peer_addr, replay, service_protocol) => {
-                let agent_stream = agent_socket_receiver
-                    .await
-                    .map_err(|_| GatewayError::Other("Agent Connection gone"))?;
+            ClientConnection::UdpTransparent(session) => {
+                if agent_response
+                    .and_then(|res| {
+                        res.send(Ok(ResponseHeaders {
+                            session: self.session_id,
+                            connection: Some(self.id),
+                            capabilities: None,
+                        }))
+                        .ok()
+                    })
+                    .is_none()
+                {};
+
+                let agent_socket = self.wrap_agent_socket(
+                    agent_socket_receiver
+                        .await
+                        .map_err(|_| GatewayError::Other("Agent Connection gone"))?,
+                );
+                narrowlink_network::stream_forward(
+                    narrowlink_network::AsyncToStream::new(session),
+                    agent_socket,
+                )
+                .await
+                .map_err(|e| e.into())
+            }
+            ClientConnection::HttpTransparent(request, peer_addr, replay, service_protocol) => {
+                let mut request = *request;
+                let agent_stream = self.wrap_agent_socket(
+                    agent_socket_receiver
+                        .await
+                        .map_err(|_| GatewayError::Other("Agent Connection gone"))?,
+                );
                 let agent_socket = narrowlink_network::StreamToAsync::new(agent_stream);
                 let (mut request_sender, connection) = conn::handshake(agent_socket).await?;
                 tokio::spawn(
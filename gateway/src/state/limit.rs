@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::config::ConnectionLimit;
+
+#[derive(Error, Debug, Clone, Copy)]
+pub enum LimitExceeded {
+    #[error("per-agent connection limit of {0} reached")]
+    Agent(usize),
+    #[error("gateway connection limit of {0} reached")]
+    Total(usize),
+}
+
+// counts the connections of every agent, from the connect request until the tunnel is closed
+pub struct ConnectionLimiter {
+    limit: ConnectionLimit,
+    total: Arc<AtomicUsize>,
+    agents: HashMap<(Uuid, String), Arc<AtomicUsize>>, // survives agent reconnects
+}
+
+impl ConnectionLimiter {
+    pub fn new(limit: ConnectionLimit, total: Arc<AtomicUsize>) -> Self {
+        Self {
+            limit,
+            total,
+            agents: HashMap::new(),
+        }
+    }
+
+    pub fn acquire(
+        &mut self,
+        uid: Uuid,
+        agent_name: &str,
+    ) -> Result<ConnectionPermit, LimitExceeded> {
+        let key = (uid, agent_name.to_owned());
+        if !self.agents.contains_key(&key) {
+            // drop the counters of agents without connections left
+            self.agents.retain(|_, count| Arc::strong_count(count) > 1);
+        }
+        let agent = self.agents.entry(key).or_default().clone();
+        // the slots are taken first and released on drop if the connection is rejected
+        let permit = ConnectionPermit {
+            agent,
+            total: self.total.clone(),
+        };
+        let agent_count = permit.agent.fetch_add(1, Ordering::Relaxed) + 1;
+        let total_count = permit.total.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(limit) = self.limit.per_agent.filter(|limit| agent_count > *limit) {
+            return Err(LimitExceeded::Agent(limit));
+        }
+        if let Some(limit) = self.limit.total.filter(|limit| total_count > *limit) {
+            return Err(LimitExceeded::Total(limit));
+        }
+        Ok(permit)
+    }
+}
+
+// one connection slot of an agent, freed on drop
+pub struct ConnectionPermit {
+    agent: Arc<AtomicUsize>,
+    total: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.agent.fetch_sub(1, Ordering::Relaxed);
+        self.total.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -1,16 +1,26 @@
 use futures_util::StreamExt;
 use rcgen::{CertificateParams, DistinguishedName};
-use std::{net::SocketAddr, str::FromStr};
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
+};
 use uuid::Uuid;
 mod agent;
 mod client;
 mod connection;
+mod limit;
 mod users;
 use crate::{
-    service::{RequestProtocol, ServiceDataRequest, ServiceEventRequest},
+    audit::{self, Tunnel},
+    service::{
+        ClientCert, RequestProtocol, ServiceConnectRequest, ServiceDataRequest, ServiceEventRequest,
+    },
     state::connection::AgentConnection,
     CONNECTION_ORIANTED,
 };
+pub use limit::LimitExceeded;
 use narrowlink_network::{error::NetworkError, event::NarrowEvent, UniversalStream};
 use narrowlink_types::{
     agent::{
@@ -18,15 +28,18 @@ use narrowlink_types::{
         EventRequest as AgentEventRequest, EventResponse as AgentEventResponse,
         Peer2PeerInstruction as AgentPeer2PeerInstruction,
     },
+    capability::{Capabilities, Capability},
     client::{
         DataOutBound as ClientDataOutBound, EventInBound as ClientEventInBound,
         EventOutBound as ClientEventOutBound, EventRequest as ClientEventRequest,
         EventResponse as ClientEventResponse, Peer2PeerInstruction as ClientPeer2PeerInstruction,
     },
+    policy::Policy,
     token::PolicyToken,
 };
 use narrowlink_types::{
-    token::{AgentPublishToken, AgentToken, ClientToken},
+    publish::{PublishHost, ServiceAccess},
+    token::{AgentPublishToken, AgentRefreshToken, AgentToken, ClientToken},
     NatType,
 };
 use tokio::{
@@ -45,6 +58,12 @@ pub struct State {
     name: String,
     client_token: Vec<u8>,
     agent_token: Vec<u8>,
+    agent_token_ttl: Duration, // of the agent tokens issued for refresh tokens
+    listen_addrs: Vec<SocketAddr>,
+    connection_limit: crate::config::ConnectionLimit,
+    publish_limit: Option<usize>,
+    connections: Arc<AtomicUsize>,
+    capabilities: Capabilities, // answered to peers, intersected with theirs
     message_receiver: UnboundedReceiver<InBound>,
     message_sender: UnboundedSender<InBound>,
     certificate_manager: std::option::Option<
@@ -67,22 +86,56 @@ pub enum InBound {
         Option<String>, // Forward address
         oneshot::Sender<Result<ResponseHeaders, ResponseErrors>>,
     ),
+    HttpConnect(
+        ServiceConnectRequest,
+        oneshot::Receiver<Box<dyn UniversalStream<Vec<u8>, NetworkError>>>,
+        SocketAddr,
+        oneshot::Sender<Result<ResponseHeaders, ResponseErrors>>,
+    ),
     HttpTransparent(
         String,                                                                //domain_name
         hyper::Request<hyper::Body>,                                           //request
         SocketAddr,                                                            //peer_addr
         oneshot::Sender<Result<hyper::Response<hyper::Body>, ResponseErrors>>, //response
         RequestProtocol,                                                       //service_protocol
+        Option<Duration>,                                                      // idle timeout
+    ),
+    TokenRefresh(
+        String,                                          // agent refresh token
+        SocketAddr,                                      // peer address
+        oneshot::Sender<Result<String, ResponseErrors>>, // agent token
     ),
     TlsTransparent(
-        String,    //sni
-        TcpStream, //stream
-        u16,       // service port
+        String,                             //sni
+        TcpStream,                          //stream
+        SocketAddr,                         // local address
+        Option<Duration>,                   // idle timeout
+        Option<oneshot::Sender<TcpStream>>, // gets the stream back if no agent serves the sni
+    ),
+    TlsTerminated(
+        String,                                   //sni
+        Box<dyn narrowlink_network::AsyncSocket>, //decrypted stream
+        SocketAddr,                               // local address
+        SocketAddr,                               // peer address
+        Option<Duration>,                         // idle timeout
+    ),
+    UdpTransparent(
+        String,                          //domain_name
+        crate::service::udp::UdpSession, //session
+        SocketAddr,                      // local address
+        oneshot::Sender<()>,             // the session is tunnelled, dropped if it is rejected
+    ),
+    ConnectionIdle(
+        Uuid,     // user id
+        String,   // agent name
+        Uuid,     // connection id
+        Duration, // idle timeout
     ),
 }
 pub struct ResponseHeaders {
     pub(crate) session: Option<Uuid>,
     pub(crate) connection: Option<Uuid>,
+    pub(crate) capabilities: Option<Capabilities>, // negotiated on event connections
 }
 
 #[derive(Debug)]
@@ -91,6 +144,7 @@ pub enum ResponseErrors {
     Forbidden,
     NotAcceptable(Option<&'static str>),
     NotFound(Option<&'static str>),
+    ServiceUnavailable,
 }
 
 impl State {
@@ -98,9 +152,12 @@ impl State {
     pub async fn run(&mut self) {
         trace!("state running");
         let mut users = users::Users::new();
+        let mut limiter =
+            limit::ConnectionLimiter::new(self.connection_limit, self.connections.clone());
         let mut client_types = futures_util::stream::SelectAll::new();
         let mut agent_types = futures_util::stream::SelectAll::new();
         let certificate_manager = self.certificate_manager.take();
+        let idle_notices = self.message_sender.clone();
         loop {
             select! (
                 Some(client_types) = client_types.next()=>{
@@ -144,6 +201,12 @@ impl State {
                                             }
                                             continue
                                         };
+                                        if !agent.permit_peer_to_peer(&client.name) {
+                                            warn!("Client {}:{} ({}) denied peer to peer access to agent {} by its client access list",uid,client.name,client.get_real_ip(),req.agent_name);
+                                            audit::denied(uid,&client.name,client.get_real_ip(),&req.agent_name,"peer to peer","Client access list");
+                                            client.send(ClientEventInBound::Response(request_id,ClientEventResponse::Failed)).await.ok();
+                                            continue
+                                        }
                                         let mut client_ip = client.get_real_ip();
                                         let mut agent_ip = agent.get_real_ip();
                                         let mut seq = if (c == NatType::Hard && a == NatType::Hard) && client_ip != agent_ip {
@@ -197,10 +260,52 @@ impl State {
                                     }
 
                                 }
+                                ClientEventRequest::ReverseBind(bind) =>{
+                                    let connect = narrowlink_types::generic::Connect {
+                                        host: bind.host.clone(),
+                                        port: bind.port,
+                                        protocol: narrowlink_types::generic::Protocol::TCP,
+                                        cryptography: None,
+                                        sign: None,
+                                        kdf: None,
+                                        exchange: None,
+                                        reverse: None,
+                                        source: None,
+                                    };
+                                    // the data connections of the bind pass the same checks as the ones the client dials
+                                    let permitted = users.get_client_policy_for_agent(uid,session,&bind.agent_name).is_some_and(|policy|policy.is_empty() || policy.iter().any(|p|p.permit(&connect)));
+                                    let Some((client,agent)) = users.get_mut_user(uid).and_then(|u|u.get_mut_pair(session,&bind.agent_name)) else {
+                                        if let Some(client) = users.get_mut_client(uid,session){
+                                            let _ = client.send(ClientEventInBound::Response(request_id,ClientEventResponse::Failed)).await;
+                                        }
+                                        continue
+                                    };
+                                    // older agents can not read reverse binds
+                                    if !agent.supports(Capability::ReverseForward) {
+                                        warn!("Agent {}:{} does not support reverse binds, requested by client {}:{} ({})",uid,bind.agent_name,uid,client.name,client.get_real_ip());
+                                        let _ = client.send(ClientEventInBound::Response(request_id,ClientEventResponse::Failed)).await;
+                                        continue
+                                    }
+                                    if !permitted || !agent.permit_client(&client.name, &connect) {
+                                        warn!("Client {}:{} ({}) denied reverse bind of {}:{} on agent {}",uid,client.name,client.get_real_ip(),bind.host,bind.port,bind.agent_name);
+                                        audit::denied(uid,&client.name,client.get_real_ip(),&bind.agent_name,&format!("reverse bind {}:{}",bind.host,bind.port),if permitted {"Client access list"} else {"Client policy"});
+                                        let _ = client.send(ClientEventInBound::Response(request_id,ClientEventResponse::Failed)).await;
+                                        continue
+                                    }
+                                    let _ = agent.send(AgentEventInBound::ReverseBind(bind.id,bind.host.clone(),bind.port)).await;
+                                    client.add_reverse_bind(bind);
+                                    let _ = client.send(ClientEventInBound::Response(request_id,ClientEventResponse::Ok)).await;
+                                }
                             }
                         }
                         Err(_e)=>{
-                            users.del_client(uid,session);
+                            if let Some(client) = users.del_client(uid,session){
+                                for bind in client.reverse_binds() {
+                                    if let Some(agent) = users.get_mut_agent(uid,bind.agent_name.clone()){
+                                        let _ = agent.send(AgentEventInBound::ReverseUnbind(bind.id)).await;
+                                    }
+                                }
+                            }
                             info!("Client {}:{} disconnected", uid, session);
                             // dbg!((e as NetworkError).to_string());
                         }
@@ -215,12 +320,26 @@ impl State {
                         Ok(AgentEventOutBound::Ready(_id))=>{},
                         Ok(AgentEventOutBound::NotSure(_id))=>{},
                         Ok(AgentEventOutBound::Error(id, err))=>{
-                            if let Some(client) = users.del_connection(uid,id).and_then(|c|c.session_id).and_then(|s|users.get_mut_client(uid, s)) {
+                            let session = users.del_connection(uid,id).and_then(|mut c|{
+                                c.set_close_reason(format!("Agent error: {}", err));
+                                c.session_id
+                            });
+                            if let Some(client) = session.and_then(|s|users.get_mut_client(uid, s)) {
+                                client.send(ClientEventInBound::ConnectionError(id,err.to_string())).await.ok();
+                            } else if let Some(client) = users.get_mut_user(uid).and_then(|u|u.get_mut_client_by_reverse_bind(id)) {
+                                // the agent could not listen on a reverse bind
                                 client.send(ClientEventInBound::ConnectionError(id,err.to_string())).await.ok();
                             }
                         },
+                        Ok(AgentEventOutBound::ReverseConnection(bind_id, connection))=>{
+                            if let Some(client) = users.get_mut_user(uid).and_then(|u|u.get_mut_client_by_reverse_bind(bind_id)) {
+                                client.send(ClientEventInBound::ReverseConnection(bind_id,connection)).await.ok();
+                            } else if let Some(agent) = users.get_mut_agent(uid,name.clone()) {
+                                let _ = agent.send(AgentEventInBound::ReverseUnbind(bind_id)).await;
+                            }
+                        },
                         Ok(AgentEventOutBound::Request(request_id, request))=>{
-                            if let Some(agent) = users.get_mut_agent(uid,name){
+                            if let Some(agent) = users.get_mut_agent(uid,name.clone()){
                                 match request{
                                     AgentEventRequest::UpdateDynamicSysInfo(load)=>{
                                         if let Ok(ts) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH){
@@ -235,6 +354,29 @@ impl State {
                                         let _ = agent.send(AgentEventInBound::Response(request_id,AgentEventResponse::Ok)).await;
                                         continue
                                     }
+                                    AgentEventRequest::UpdatePublish(publish)=>{
+                                        let previous_hosts = agent.certificate_hosts();
+                                        let (publish_hosts, rejected) = publish_quota(verified_publish_hosts(publish, uid, &name, &self.agent_token, &self.listen_addrs), self.publish_limit, |p|agent.is_published(p));
+                                        let Some(agent) = users.set_publish_hosts(uid, &name, publish_hosts) else {
+                                            continue
+                                        };
+                                        reject_publish_hosts(agent, uid, rejected, self.publish_limit).await;
+                                        let hosts = agent.certificate_hosts();
+                                        info!("Agent {}:{} publish list updated",uid,name);
+                                        if let Some(cm_sender) = certificate_manager.as_ref() {
+                                            let removed_hosts = Vec::from_iter(previous_hosts.difference(&hosts).cloned());
+                                            if !removed_hosts.is_empty() {
+                                                let _ = cm_sender.send(crate::service::certificate::manager::CertificateServiceMessage::UnloadDomains(uid.to_string(),name.clone(),removed_hosts));
+                                            }
+                                            let added_hosts = Vec::from_iter(hosts.difference(&previous_hosts).cloned());
+                                            if !added_hosts.is_empty() {
+                                                info!("Loading new certificate for {:?}",added_hosts);
+                                                let _ = cm_sender.send(crate::service::certificate::manager::CertificateServiceMessage::Load(uid.to_string(),name.clone(),added_hosts,None));
+                                            }
+                                        }
+                                        let _ = agent.send(AgentEventInBound::Response(request_id,AgentEventResponse::Ok)).await;
+                                        continue
+                                    }
                                 }
                             }
                         },
@@ -266,8 +408,15 @@ impl State {
                         Some(InBound::EventRequest(
                             ServiceEventRequest {
                                 token,
+                                client_cert,
                                 acl,
                                 publish,
+                                acme_email,
+                                acme_challenge,
+                                acme_directory,
+                                client_acl,
+                                capabilities,
+                                version,
                             },
                             stream_receiver,
                             peer_socket_addr,
@@ -277,6 +426,9 @@ impl State {
                             let event_span = tracing::span!(tracing::Level::TRACE, "event", peer_addr = %peer_socket_addr, peer_forward_addr = ?peer_forward_addr);
                             let _event_gaurd = event_span.enter();
                             trace!("Event Request Received");
+                            let peer_capabilities = capabilities.as_deref().and_then(|c|Capabilities::from_str(c).ok());
+                            let capabilities = peer_capabilities.as_ref().map(|c|self.capabilities.intersection(c)).unwrap_or(self.capabilities.clone());
+                            debug!("Peer version {:?}, negotiated capabilities: {}", version, capabilities);
                             // debug!("Client Event Received");
                             //Client Event
                             //todo client request acl
@@ -284,18 +436,7 @@ impl State {
                                 let client_event_span = tracing::span!(tracing::Level::TRACE, "client", user_id = %client_token.uid, client_name = %client_token.name);
                                 let _client_event_gaurd = client_event_span.enter();
                                 trace!("Client Token Verification Success");
-                                // if client_token.policies.
-                                let mut policies = Vec::new();
-
-                                let p = acl.and_then(|a|serde_json::from_str::<Vec<String>>(&a).ok()).and_then(|a|a.into_iter().map(|p|PolicyToken::from_str(&p, &self.client_token).ok()).collect::<Option<Vec<PolicyToken>>>());
-                                for pid in &client_token.policies{ // important to keep order
-                                    for policy_token in p.as_ref().unwrap_or(&Vec::new()){
-                                        if &policy_token.pid == pid && policy_token.name == client_token.name && policy_token.uid == client_token.uid {
-                                            policies.push(policy_token.policy.clone());
-                                        }
-                                    }
-                                }
-                                if client_token.policies.len() != policies.len(){
+                                let Some(policies) = verified_client_policies(&client_token, acl, &self.client_token) else {
                                     trace!("Client {}:{} policies not match",client_token.uid,client_token.name);
                                     let _ = response.send(Err(ResponseErrors::Unauthorized));
                                     continue
@@ -303,7 +444,7 @@ impl State {
 
                                 let session = Uuid::new_v4();
                                 client_event_span.record("session_id", session.to_string());
-                                if response.send(Ok(ResponseHeaders{session:Some(session),connection:None})).is_err(){
+                                if response.send(Ok(ResponseHeaders{session:Some(session),connection:None,capabilities:Some(capabilities)})).is_err(){
                                     continue
                                 }
                                 trace!("Waiting for client event stream");
@@ -332,10 +473,25 @@ impl State {
                                     let _ = response.send(Err(ResponseErrors::Unauthorized));
                                     continue
                                 };
+                                if client_cert == ClientCert::Missing {
+                                    debug!("Agent {}:{} has no verified client certificate", agent_token.uid, agent_token.name);
+                                    let _ = response.send(Err(ResponseErrors::Unauthorized));
+                                    continue
+                                }
                                 let agent_event_span = tracing::span!(tracing::Level::TRACE, "agent", user_id = %agent_token.uid, agent_name = %agent_token.name);
                                 let _agent_event_gaurd = agent_event_span.enter();
+                                // refused rather than ignored, the agent relies on it to keep services private
+                                let client_access = match client_acl.as_deref().map(serde_json::from_str::<Vec<ServiceAccess>>) {
+                                    None => Vec::new(),
+                                    Some(Ok(client_access)) => client_access,
+                                    Some(Err(e)) => {
+                                        warn!("Agent {}:{} sent an invalid client access list: {}", agent_token.uid, agent_token.name, e);
+                                        let _ = response.send(Err(ResponseErrors::NotAcceptable(Some("Invalid client access list"))));
+                                        continue
+                                    }
+                                };
 
-                                if response.send(Ok(ResponseHeaders{session:None,connection:None})).is_err(){
+                                if response.send(Ok(ResponseHeaders{session:None,connection:None,capabilities:Some(capabilities.clone())})).is_err(){
                                     continue
                                 }
                                 trace!("Waiting for agent event stream");
@@ -346,13 +502,7 @@ impl State {
                                 let (sender, receiver) = stream.split();
 
 
-                                let p = publish.and_then(|a|serde_json::from_str::<Vec<String>>(&a).ok()).and_then(|a|a.into_iter().map(|p|AgentPublishToken::from_str(&p, &self.agent_token).ok()).collect::<Option<Vec<AgentPublishToken>>>());
-                                let mut publish_hosts = Vec::new();
-                                for publish_token in p.unwrap_or(Vec::new()){
-                                    if publish_token.name == agent_token.name && publish_token.uid == agent_token.uid {
-                                        publish_hosts.extend(publish_token.publish_hosts);
-                                    }
-                                }
+                                let (publish_hosts, rejected_publish_hosts) = publish_quota(verified_publish_hosts(publish.and_then(|a|serde_json::from_str::<Vec<String>>(&a).ok()).unwrap_or_default(), agent_token.uid, &agent_token.name, &self.agent_token, &self.listen_addrs), self.publish_limit, |_|false);
 
                                 // if let Some(publish_token) = publish.and_then(|publish_token| {
                                 //     AgentPublishToken::from_str(&publish_token, &self.agent_token).ok()
@@ -370,26 +520,61 @@ impl State {
                                         // cert_required_connect.map(|ph|ph.host);
                                         let hosts = cert_required_connect.map(|ph|ph.host.clone());
                                         info!("Loading new certificate for {:?}",hosts);
+                                        if let Some(acme_email) = acme_email {
+                                            let _ = cm_sender.send(crate::service::certificate::manager::CertificateServiceMessage::Account(
+                                                agent_token.uid.to_string(),
+                                                agent_token.name.to_owned(),
+                                                acme_email,
+                                            ));
+                                        }
+                                        if let Some(acme_directory) = acme_directory {
+                                            let _ = cm_sender.send(crate::service::certificate::manager::CertificateServiceMessage::Directory(
+                                                agent_token.uid.to_string(),
+                                                agent_token.name.to_owned(),
+                                                acme_directory,
+                                            ));
+                                        }
+                                        match acme_challenge.as_deref().map(str::parse::<crate::service::certificate::ACMEChallengeType>) {
+                                            Some(Ok(challenge_type)) => {
+                                                let _ = cm_sender.send(crate::service::certificate::manager::CertificateServiceMessage::ChallengeType(
+                                                    agent_token.uid.to_string(),
+                                                    agent_token.name.to_owned(),
+                                                    challenge_type,
+                                                ));
+                                            }
+                                            Some(Err(_)) => warn!("invalid acme challenge type for agent {}:{}", agent_token.uid, agent_token.name),
+                                            None => {}
+                                        }
                                         let _ = cm_sender.send(crate::service::certificate::manager::CertificateServiceMessage::Load(
                                             agent_token.uid.to_string(),
                                             agent_token.name.to_owned(),
                                             Vec::from_iter(hosts),
+                                            None,
                                         ));
                                     }
                                 }
                                 let agent_name = agent_token.name.clone();
                                 agent_types.push(receiver.map(move |f| (agent_token.uid, agent_name.to_owned(), f,peer_socket_addr)));
-                                if let Some(mut privous_agent) = users.add_agent(agent_token.uid,agent::Agent::new(agent_token.name.to_owned(),publish_hosts,peer_socket_addr,peer_forward_addr,sender)) {
+                                if let Some(mut privous_agent) = users.add_agent(agent_token.uid,agent::Agent::new(agent_token.name.to_owned(),publish_hosts,peer_socket_addr,peer_forward_addr,client_access,peer_capabilities.map(|_|capabilities),sender)) {
                                     info!("Previous agent {}:{} ({}) disconnected",agent_token.uid,privous_agent.name,peer_socket_addr);
                                     let _ = privous_agent.send(AgentEventInBound::Shutdown).await;
                                 }
+                                let reverse_binds = users.get_mut_user(agent_token.uid).map(|u|u.reverse_binds(&agent_token.name)).unwrap_or_default();
+                                if let Some(agent) = users.get_mut_agent(agent_token.uid,agent_token.name.clone()) {
+                                    reject_publish_hosts(agent, agent_token.uid, rejected_publish_hosts, self.publish_limit).await;
+                                    let reverse_binds = if agent.supports(Capability::ReverseForward) { reverse_binds } else { Vec::new() };
+                                    for (id,host,port) in reverse_binds {
+                                        let _ = agent.send(AgentEventInBound::ReverseBind(id,host,port)).await;
+                                    }
+                                }
 
-                                info!("Agent {}:{} ({}) added",agent_token.uid,agent_token.name,peer_socket_addr);
+                                info!("Agent {}:{} ({}) added, version {}",agent_token.uid,agent_token.name,peer_socket_addr,version.as_deref().unwrap_or("unknown"));
                             }
                         }
                         Some(InBound::DataRequest(
                             ServiceDataRequest {
                                 token,
+                                client_cert,
                                 command,
                                 session,
                                 connection,
@@ -418,7 +603,7 @@ impl State {
                                     continue
                                 };
                                 client_data_span.record("session_id", session.to_string());
-                                let Ok(ClientDataOutBound::Connect(agent_name, connect)) = ClientDataOutBound::from_str(&command) else {
+                                let Ok(ClientDataOutBound::Connect(agent_name, mut connect)) = ClientDataOutBound::from_str(&command) else {
                                     debug!("command is not valid: {}",command);
                                     let _ = response.send(Err(ResponseErrors::NotAcceptable(None)));
                                     continue
@@ -434,6 +619,7 @@ impl State {
                                 if !client_policy.is_empty() && !client_policy.iter().any(|p|p.permit(&connect)){
                                     debug!("Client {}:{} connect to {}:{:?} forbidden",client_token.uid,session,agent_name,connect);
                                     debug!("{:?}",&client_policy);
+                                    audit::denied(client_token.uid,&client_token.name,peer_socket_addr.ip(),&agent_name,&format!("{}:{}",connect.host,connect.port),"Client policy");
                                     let _ = response.send(Err(ResponseErrors::Forbidden));
                                     continue
                                 };
@@ -445,9 +631,23 @@ impl State {
                                     let _ = response.send(Err(ResponseErrors::NotFound(Some("The requested agent could not be found"))));
                                     continue
                                 };
+                                if !agent.permit_client(&client_token.name, &connect) {
+                                    warn!("Client {}:{} ({}) denied access to {}:{} on agent {} by its client access list",client_token.uid,client_token.name,peer_socket_addr,connect.host,connect.port,agent_name);
+                                    audit::denied(client_token.uid,&client_token.name,peer_socket_addr.ip(),&agent_name,&format!("{}:{}",connect.host,connect.port),"Client access list");
+                                    let _ = response.send(Err(ResponseErrors::Forbidden));
+                                    continue
+                                }
 
+                                let permit = match limiter.acquire(client_token.uid, &agent_name) {
+                                    Ok(permit) => permit,
+                                    Err(e) => {
+                                        reject_connection(agent, client_token.uid, connection_id, e).await;
+                                        let _ = response.send(Err(ResponseErrors::ServiceUnavailable));
+                                        continue
+                                    }
+                                };
                                 let response = if CONNECTION_ORIANTED {
-                                    if response.send(Ok(ResponseHeaders{session:Some(session),connection:Some(connection_id)})).is_err(){
+                                    if response.send(Ok(ResponseHeaders{session:Some(session),connection:Some(connection_id),capabilities:None})).is_err(){
                                             continue
                                         }
                                     None
@@ -456,7 +656,9 @@ impl State {
                                 };
 
 
-                                let connection = connection::Connection::new(connection_id, Some(session), Some(connection::ClientConnection::Client(response,socket_receiver)), None);
+                                let mut connection = connection::Connection::new(connection_id, Some(session), Some(connection::ClientConnection::Client(response,socket_receiver)), None, permit);
+                                connection.set_audit(|id| Tunnel::new(id, client_token.uid, Some(client_token.name.clone()), Some(peer_socket_addr), agent_name.clone(), format!("{}:{}", connect.host, connect.port)));
+                                connect.source = Some(peer_socket_addr);
 
                                 debug!("Connection to {}:{} with agent {} added to pool",connect.host,connect.port, agent_name);
                                 let _ = agent.send(AgentEventInBound::Connect(connection_id, connect, client_policy)).await;
@@ -469,7 +671,12 @@ impl State {
                                     let _ = response.send(Err(ResponseErrors::Unauthorized));
                                     continue
                                 };
-                                let Some(connected_address) = connecting_address.as_ref().and_then(|addr|narrowlink_types::generic::Connect::from_schemaed_string(addr)) else {
+                                if client_cert == ClientCert::Missing {
+                                    debug!("Agent {}:{} has no verified client certificate", agent_token.uid, agent_token.name);
+                                    let _ = response.send(Err(ResponseErrors::Unauthorized));
+                                    continue
+                                }
+                                let Some(connected_address) = connecting_address.as_ref().and_then(|addr|narrowlink_types::generic::ConnectedAddress::from_schemaed_string(addr)) else {
                                     debug!("connecting address is not valid: {:?}",connecting_address);
                                     let _ = response.send(Err(ResponseErrors::NotAcceptable(None)));
                                     continue
@@ -490,7 +697,7 @@ impl State {
                                     continue
                                 };
                                 let response = if CONNECTION_ORIANTED {
-                                    if response.send(Ok(ResponseHeaders{session:requested_connection.session_id,connection:Some(connection)})).is_err(){
+                                    if response.send(Ok(ResponseHeaders{session:requested_connection.session_id,connection:Some(connection),capabilities:None})).is_err(){
                                             continue
                                         }
                                     None
@@ -508,13 +715,121 @@ impl State {
                                 }.in_current_span());
                             }
                         }
-                        Some(InBound::HttpTransparent(domain_name,request,peer_addr,response,service_protocol))=>{
-                            match users.get_mut_agent_by_domain(&domain_name,service_protocol.get_address().port()){ //todo
-                                Some(Ok((user_id,agent,connect)))=>{
+                        Some(InBound::HttpConnect(
+                            ServiceConnectRequest {
+                                token,
+                                agent_name,
+                                acl,
+                                host,
+                                port,
+                            },
+                            socket_receiver,
+                            peer_socket_addr,
+                            response
+                        )) => {
+                            let Ok(client_token) = ClientToken::from_str(&token, &self.client_token) else {
+                                let _ = response.send(Err(ResponseErrors::Unauthorized));
+                                continue
+                            };
+                            let connect_span = tracing::span!(tracing::Level::TRACE, "connect", peer_addr = %peer_socket_addr, user_id = %client_token.uid, client_name = %client_token.name);
+                            let _connect_gaurd = connect_span.enter();
+                            let Some(policies) = verified_client_policies(&client_token, acl, &self.client_token) else {
+                                trace!("Client {}:{} policies not match",client_token.uid,client_token.name);
+                                let _ = response.send(Err(ResponseErrors::Unauthorized));
+                                continue
+                            };
+                            let client_policy = client::agent_policies(&policies, &agent_name);
+                            let connect = narrowlink_types::generic::Connect {
+                                host,
+                                port,
+                                protocol: narrowlink_types::generic::Protocol::TCP,
+                                cryptography: None,
+                                sign: None,
+                                kdf: None,
+                                exchange: None,
+                                reverse: None,
+                                source: Some(peer_socket_addr),
+                            };
+                            if !client_policy.is_empty() && !client_policy.iter().any(|p|p.permit(&connect)){
+                                debug!("Client {} connect to {}:{:?} forbidden",client_token.uid,agent_name,connect);
+                                audit::denied(client_token.uid,&client_token.name,peer_socket_addr.ip(),&agent_name,&format!("{}:{}",connect.host,connect.port),"Client policy");
+                                let _ = response.send(Err(ResponseErrors::Forbidden));
+                                continue
+                            };
+                            let Some(agent) = users.get_mut_agent(client_token.uid,agent_name.clone()) else{
+                                debug!("Agent {}:{} not found",client_token.uid,agent_name);
+                                let _ = response.send(Err(ResponseErrors::NotFound(Some("The requested agent could not be found"))));
+                                continue
+                            };
+                            if !agent.permit_client(&client_token.name, &connect) {
+                                warn!("Client {}:{} ({}) denied access to {}:{} on agent {} by its client access list",client_token.uid,client_token.name,peer_socket_addr,connect.host,connect.port,agent_name);
+                                audit::denied(client_token.uid,&client_token.name,peer_socket_addr.ip(),&agent_name,&format!("{}:{}",connect.host,connect.port),"Client access list");
+                                let _ = response.send(Err(ResponseErrors::Forbidden));
+                                continue
+                            }
+                            let connection_id = Uuid::new_v4();
+                            let permit = match limiter.acquire(client_token.uid, &agent_name) {
+                                Ok(permit) => permit,
+                                Err(e) => {
+                                    reject_connection(agent, client_token.uid, connection_id, e).await;
+                                    let _ = response.send(Err(ResponseErrors::ServiceUnavailable));
+                                    continue
+                                }
+                            };
+                            // answered before the agent dials, like client data connections
+                            if response.send(Ok(ResponseHeaders{session:None,connection:Some(connection_id),capabilities:None})).is_err(){
+                                continue
+                            }
+                            info!("Client {}:{} ({}) HTTP CONNECT to {}:{} through agent {}",client_token.uid,client_token.name,peer_socket_addr,connect.host,connect.port,agent_name);
+                            let mut connection = connection::Connection::new(connection_id, None, Some(connection::ClientConnection::Client(None,socket_receiver)), None, permit);
+                            connection.set_audit(|id| Tunnel::new(id, client_token.uid, Some(client_token.name.clone()), Some(peer_socket_addr), agent_name.clone(), format!("{}:{}", connect.host, connect.port)));
+                            let _ = agent.send(AgentEventInBound::Connect(connection_id, connect, client_policy)).await;
+                            users.add_connection(client_token.uid,connection);
+                        }
+                        Some(InBound::TokenRefresh(refresh_token,peer_addr,response))=>{
+                            let Ok(refresh_token) = AgentRefreshToken::from_str(&refresh_token, &self.agent_token) else {
+                                debug!("Invalid agent refresh token from {}", peer_addr);
+                                let _ = response.send(Err(ResponseErrors::Unauthorized));
+                                continue
+                            };
+                            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|now| now.as_secs() as usize).unwrap_or_default();
+                            let agent_token = AgentToken {
+                                uid: refresh_token.uid,
+                                name: refresh_token.name,
+                                exp: (now + self.agent_token_ttl.as_secs() as usize).min(refresh_token.exp),
+                            };
+                            match agent_token.to_string(&self.agent_token) {
+                                Ok(token) => {
+                                    info!("Agent {}:{} ({}) token refreshed, valid for {}s", agent_token.uid, agent_token.name, peer_addr, agent_token.exp.saturating_sub(now));
+                                    let _ = response.send(Ok(token));
+                                }
+                                Err(e) => {
+                                    warn!("Unable to issue a token for agent {}:{}: {}", agent_token.uid, agent_token.name, e);
+                                    let _ = response.send(Err(ResponseErrors::ServiceUnavailable));
+                                }
+                            }
+                        }
+                        Some(InBound::HttpTransparent(domain_name,request,peer_addr,response,service_protocol,idle_timeout))=>{
+                            match users.get_mut_agent_by_domain(&domain_name,service_protocol.get_address()){
+                                Some(Ok((user_id,agent,mut connect)))=>{
+                                    connect.source = Some(peer_addr);
                                     let connection = Uuid::new_v4();
+                                    let permit = match limiter.acquire(user_id, &agent.name) {
+                                        Ok(permit) => permit,
+                                        Err(e) => {
+                                            reject_connection(agent, user_id, connection, e).await;
+                                            let _ = response.send(Err(ResponseErrors::ServiceUnavailable));
+                                            continue
+                                        }
+                                    };
                                     debug!("HttpTransparent Connection ({}) Request to {} with {} address Received", connection,domain_name,peer_addr);
+                                    let destination = format!("{}:{}", connect.host, connect.port);
+                                    let source = connect.source;
                                     let _ = agent.send(AgentEventInBound::Connect(connection, connect, vec![])).await;
-                                    users.add_connection(user_id, connection::Connection::new(connection,None,Some(connection::ClientConnection::HttpTransparent(request,peer_addr,response,service_protocol)),None));
+                                    let mut connection = connection::Connection::new(connection,None,Some(connection::ClientConnection::HttpTransparent(Box::new(request),peer_addr,response,service_protocol)),None,permit);
+                                    connection.set_audit(|id| Tunnel::new(id, user_id, None, source, agent.name.clone(), destination));
+                                    watch_idle(&mut connection, idle_timeout, &idle_notices, user_id, &agent.name);
+                                    users.add_connection(user_id, connection);
                                 }
                                 None | Some(Err(()))=>{
                                     debug!("Unoccupied HttpTransparent Connection Request to {} with {} address Rejected", domain_name,peer_addr);
@@ -522,19 +837,95 @@ impl State {
                                 }
                             }
                         }
-                        Some(InBound::TlsTransparent(sni,mut stream,service_port))  =>{
-                            if let Some(Ok((user_id,agent,connect))) = users.get_mut_agent_by_domain(&sni,service_port){ //todo
+                        Some(InBound::TlsTransparent(sni,mut stream,local_addr,idle_timeout,unknown))  =>{
+                            if let Some(Ok((user_id,agent,mut connect))) = users.get_mut_agent_by_domain(&sni,local_addr){
                                 if connect.protocol == narrowlink_types::generic::Protocol::TCP{
+                                    connect.source = stream.peer_addr().ok();
                                     let connection = Uuid::new_v4();
+                                    let permit = match limiter.acquire(user_id, &agent.name) {
+                                        Ok(permit) => permit,
+                                        Err(e) => {
+                                            reject_connection(agent, user_id, connection, e).await;
+                                            stream.shutdown().await.ok();
+                                            continue
+                                        }
+                                    };
                                     debug!("TlsTransparent Connection ({}) Request to {} with {:?} address Received", connection,sni,stream.peer_addr());
+                                    let destination = format!("{}:{}", connect.host, connect.port);
+                                    let source = connect.source;
                                     let _ = agent.send(AgentEventInBound::Connect(connection, connect, vec![])).await;
-                                    users.add_connection(user_id, connection::Connection::new(connection,None,Some(connection::ClientConnection::TlsTransparent(stream)),None));
+                                    let mut connection = connection::Connection::new(connection,None,Some(connection::ClientConnection::TlsTransparent(Box::new(stream))),None,permit);
+                                    connection.set_audit(|id| Tunnel::new(id, user_id, None, source, agent.name.clone(), destination));
+                                    watch_idle(&mut connection, idle_timeout, &idle_notices, user_id, &agent.name);
+                                    users.add_connection(user_id, connection);
                                     continue
                                 }
                             }
                             debug!("Unoccupied TlsTransparent Connection Request to {} with {:?} address Rejected", sni,stream.peer_addr());
+                            if let Some(unknown) = unknown {
+                                let _ = unknown.send(stream);
+                            } else {
+                                stream.shutdown().await.ok();
+                            }
+                        }
+                        Some(InBound::TlsTerminated(sni,mut stream,local_addr,peer_addr,idle_timeout))  =>{
+                            if let Some(Ok((user_id,agent,mut connect))) = users.get_mut_agent_by_domain(&sni,local_addr){
+                                if connect.protocol == narrowlink_types::generic::Protocol::TCP{
+                                    connect.source = Some(peer_addr);
+                                    let connection = Uuid::new_v4();
+                                    let permit = match limiter.acquire(user_id, &agent.name) {
+                                        Ok(permit) => permit,
+                                        Err(e) => {
+                                            reject_connection(agent, user_id, connection, e).await;
+                                            stream.shutdown().await.ok();
+                                            continue
+                                        }
+                                    };
+                                    debug!("TlsTerminated Connection ({}) Request to {} with {} address Received", connection,sni,peer_addr);
+                                    let destination = format!("{}:{}", connect.host, connect.port);
+                                    let source = connect.source;
+                                    let _ = agent.send(AgentEventInBound::Connect(connection, connect, vec![])).await;
+                                    let mut connection = connection::Connection::new(connection,None,Some(connection::ClientConnection::TlsTransparent(stream)),None,permit);
+                                    connection.set_audit(|id| Tunnel::new(id, user_id, None, source, agent.name.clone(), destination));
+                                    watch_idle(&mut connection, idle_timeout, &idle_notices, user_id, &agent.name);
+                                    users.add_connection(user_id, connection);
+                                    continue
+                                }
+                            }
+                            debug!("Unoccupied TlsTerminated Connection Request to {} with {} address Rejected", sni,peer_addr);
                             stream.shutdown().await.ok();
                         }
+                        Some(InBound::UdpTransparent(domain_name,session,local_addr,admitted))  =>{
+                            if let Some(Ok((user_id,agent,mut connect))) = users.get_mut_agent_by_domain(&domain_name,local_addr){
+                                if connect.protocol == narrowlink_types::generic::Protocol::UDP{
+                                    connect.source = Some(session.peer_addr());
+                                    let connection = Uuid::new_v4();
+                                    let permit = match limiter.acquire(user_id, &agent.name) {
+                                        Ok(permit) => permit,
+                                        Err(e) => {
+                                            reject_connection(agent, user_id, connection, e).await;
+                                            continue
+                                        }
+                                    };
+                                    debug!("UdpTransparent Connection ({}) Request to {} with {} address Received", connection,domain_name,session.peer_addr());
+                                    let destination = format!("{}:{}", connect.host, connect.port);
+                                    let source = connect.source;
+                                    let _ = agent.send(AgentEventInBound::Connect(connection, connect, vec![])).await;
+                                    let mut connection = connection::Connection::new(connection,None,Some(connection::ClientConnection::UdpTransparent(session)),None,permit);
+                                    connection.set_audit(|id| Tunnel::new(id, user_id, None, source, agent.name.clone(), destination));
+                                    users.add_connection(user_id, connection);
+                                    let _ = admitted.send(());
+                                    continue
+                                }
+                            }
+                            debug!("Unoccupied UdpTransparent Connection Request to {} with {} address Rejected", domain_name,session.peer_addr());
+                        }
+                        Some(InBound::ConnectionIdle(user_id,agent_name,connection,idle_timeout)) => {
+                            info!("Connection {} to agent {}:{} closed after {}s without traffic",connection,user_id,agent_name,idle_timeout.as_secs());
+                            if let Some(agent) = users.get_mut_agent(user_id,agent_name).filter(|agent|agent.supports(Capability::ConnectionEvents)){
+                                let _ = agent.send(AgentEventInBound::ConnectionClosed(connection, format!("idle for {}s", idle_timeout.as_secs()))).await;
+                            }
+                        }
                         None => todo!(),
 
                     }
@@ -542,6 +933,10 @@ impl State {
             );
         }
     }
+    // tunnels requested or being served, they outlive the state
+    pub fn active_connections(&self) -> Arc<AtomicUsize> {
+        self.connections.clone()
+    }
     pub fn get_sender(&self) -> UnboundedSender<InBound> {
         self.message_sender.clone()
     }
@@ -556,6 +951,12 @@ impl State {
             name: conf.name.to_owned(),
             client_token: conf.secret.clone(),
             agent_token: conf.secret.clone().into_iter().rev().collect::<Vec<u8>>(),
+            agent_token_ttl: Duration::from_secs(conf.agent_token_ttl),
+            listen_addrs: conf.listen_addrs(),
+            connection_limit: conf.connection_limit,
+            publish_limit: conf.publish_limit,
+            connections: Arc::new(AtomicUsize::new(0)),
+            capabilities: gateway_capabilities(conf, certificate_manager.is_some()),
             message_receiver,
             message_sender,
             certificate_manager,
@@ -601,3 +1002,193 @@ impl State {
 //         }
 //     }
 // }
+
+// the agent logs why a connection it serves was not tunnelled
+fn gateway_capabilities(conf: &crate::config::Config, acme: bool) -> Capabilities {
+    let mut capabilities = Capabilities::new(&[
+        Capability::Compression,
+        Capability::Multiplex,
+        Capability::ClientAcl,
+        Capability::ReverseForward,
+        Capability::ConnectionEvents,
+        Capability::PublishEvents,
+    ]);
+    if conf
+        .services()
+        .iter()
+        .any(|service| matches!(service, crate::config::Service::Quic(_)))
+    {
+        capabilities.insert(Capability::Quic);
+    }
+    if acme {
+        capabilities.insert(Capability::AcmeAccount);
+        capabilities.insert(Capability::AcmeDirectory);
+    }
+    capabilities
+}
+
+// the idle stream closes the tunnel itself, the state only hears about it to log and tell the agent
+fn watch_idle(
+    connection: &mut connection::Connection,
+    idle_timeout: Option<Duration>,
+    idle_notices: &UnboundedSender<InBound>,
+    user_id: Uuid,
+    agent_name: &str,
+) {
+    let Some(idle_timeout) = idle_timeout else {
+        return;
+    };
+    let expired = connection.set_idle_timeout(idle_timeout);
+    let notice = InBound::ConnectionIdle(
+        user_id,
+        agent_name.to_owned(),
+        connection.get_id(),
+        idle_timeout,
+    );
+    let idle_notices = idle_notices.clone();
+    tokio::spawn(async move {
+        if expired.await.is_ok() {
+            let _ = idle_notices.send(notice);
+        }
+    });
+}
+
+async fn reject_connection(
+    agent: &mut agent::Agent,
+    uid: Uuid,
+    connection: Uuid,
+    exceeded: LimitExceeded,
+) {
+    warn!(
+        "Connection {} to agent {}:{} rejected: {}",
+        connection, uid, agent.name, exceeded
+    );
+    #[cfg(feature = "metrics")]
+    crate::service::metrics::connection_rejected(exceeded);
+    if !agent.supports(Capability::ConnectionEvents) {
+        return;
+    }
+    let _ = agent
+        .send(AgentEventInBound::ConnectionRejected(
+            connection,
+            exceeded.to_string(),
+        ))
+        .await;
+}
+
+// services already published keep their place, new ones are accepted in order until the quota
+// is reached and the rest are rejected
+fn publish_quota(
+    publish_hosts: Vec<PublishHost>,
+    publish_limit: Option<usize>,
+    is_published: impl Fn(&PublishHost) -> bool,
+) -> (Vec<PublishHost>, Vec<PublishHost>) {
+    let Some(publish_limit) = publish_limit.filter(|limit| publish_hosts.len() > *limit) else {
+        return (publish_hosts, Vec::new());
+    };
+    let (mut accepted, mut rejected): (Vec<_>, Vec<_>) =
+        publish_hosts.into_iter().partition(|p| is_published(p));
+    accepted.append(&mut rejected);
+    let rejected = accepted.split_off(publish_limit.min(accepted.len()));
+    (accepted, rejected)
+}
+
+async fn reject_publish_hosts(
+    agent: &mut agent::Agent,
+    uid: Uuid,
+    rejected: Vec<PublishHost>,
+    publish_limit: Option<usize>,
+) {
+    if rejected.is_empty() {
+        return;
+    }
+    let rejected = rejected
+        .iter()
+        .map(|p| format!("{}:{}", p.host, p.port))
+        .collect::<Vec<_>>();
+    let reason = format!(
+        "publish quota of {} services reached",
+        publish_limit.unwrap_or_default()
+    );
+    warn!(
+        "Agent {}:{} services {} not published: {}",
+        uid,
+        agent.name,
+        rejected.join(", "),
+        reason
+    );
+    if agent.supports(Capability::PublishEvents) {
+        let _ = agent
+            .send(AgentEventInBound::PublishRejected(rejected, reason))
+            .await;
+    }
+}
+
+// the policies of the client token, in its order, none unless a valid policy token was sent
+// for each of them
+fn verified_client_policies(
+    client_token: &ClientToken,
+    acl: Option<String>,
+    key: &[u8],
+) -> Option<Vec<Policy>> {
+    let mut policies = Vec::new();
+    let p = acl
+        .and_then(|a| serde_json::from_str::<Vec<String>>(&a).ok())
+        .and_then(|a| {
+            a.into_iter()
+                .map(|p| PolicyToken::from_str(&p, key).ok())
+                .collect::<Option<Vec<PolicyToken>>>()
+        });
+    for pid in &client_token.policies {
+        // important to keep order
+        for policy_token in p.as_ref().unwrap_or(&Vec::new()) {
+            if &policy_token.pid == pid
+                && policy_token.name == client_token.name
+                && policy_token.uid == client_token.uid
+            {
+                policies.push(policy_token.policy.clone());
+            }
+        }
+    }
+    (client_token.policies.len() == policies.len()).then_some(policies)
+}
+
+// publish hosts of the tokens issued to the agent, none if any token is invalid
+fn verified_publish_hosts(
+    publish: Vec<String>,
+    uid: Uuid,
+    agent_name: &str,
+    agent_token: &[u8],
+    listen_addrs: &[SocketAddr],
+) -> Vec<PublishHost> {
+    let publish_tokens = publish
+        .into_iter()
+        .map(|p| AgentPublishToken::from_str(&p, agent_token).ok())
+        .collect::<Option<Vec<AgentPublishToken>>>();
+    let mut publish_hosts = Vec::new();
+    for publish_token in publish_tokens.unwrap_or_default() {
+        if publish_token.name == agent_name && publish_token.uid == uid {
+            publish_hosts.extend(publish_token.publish_hosts);
+        }
+    }
+    // a bind address no service listens on would never be served
+    publish_hosts.retain(|publish_host| {
+        let Some(bind) = publish_host.bind else {
+            return true;
+        };
+        let reachable = listen_addrs.iter().any(|listen_addr| {
+            (listen_addr.ip().is_unspecified()
+                || bind.ip().is_unspecified()
+                || listen_addr.ip() == bind.ip())
+                && (bind.port() == 0 || listen_addr.port() == bind.port())
+        });
+        if !reachable {
+            warn!(
+                "Publish host {}:{} of agent {} is not served, no service listens on {}",
+                publish_host.host, publish_host.port, agent_name, bind
+            );
+        }
+        reachable
+    });
+    publish_hosts
+}
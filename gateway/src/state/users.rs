@@ -1,14 +1,19 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::SocketAddr};
 
 use narrowlink_types::{
     agent::AgentPublishInfo,
     generic::{AgentInfo, Connect},
     policy::Policy,
+    publish::PublishHost,
     NatType,
 };
 use uuid::Uuid;
 
-use super::{agent::Agent, client::Client, connection::Connection};
+use super::{
+    agent::{glob_match, is_pattern, Agent},
+    client::Client,
+    connection::Connection,
+};
 
 pub struct User {
     agents: HashMap<String, Agent>,
@@ -60,6 +65,20 @@ impl User {
         let agent = self.agents.get_mut(agent_name)?;
         Some((client, agent))
     }
+    pub fn get_mut_client_by_reverse_bind(&mut self, bind_id: Uuid) -> Option<&mut Client> {
+        self.clients
+            .values_mut()
+            .find(|c| c.has_reverse_bind(bind_id))
+    }
+    // the reverse binds clients registered on an agent, to restore them when it reconnects
+    pub fn reverse_binds(&self, agent_name: &str) -> Vec<(Uuid, String, u16)> {
+        self.clients
+            .values()
+            .flat_map(|c| c.reverse_binds())
+            .filter(|b| b.agent_name == agent_name)
+            .map(|b| (b.id, b.host.clone(), b.port))
+            .collect()
+    }
     pub fn get_mut_connection(&mut self, connection_id: Uuid) -> Option<Connection> {
         self.connections.remove(&connection_id)
     }
@@ -81,12 +100,13 @@ impl User {
     pub fn get_mut_agent_by_domain(
         &mut self,
         domain_name: &str,
-        port: u16,
+        local_addr: SocketAddr,
     ) -> Option<(&mut Agent, Connect)> {
+        let port = local_addr.port();
         let port = if self
             .agents
             .values()
-            .any(|agent| agent.domain(domain_name, port).is_some())
+            .any(|agent| agent.domain(domain_name, port, local_addr).is_some())
         {
             port
         } else {
@@ -95,7 +115,7 @@ impl User {
 
         self.agents
             .values_mut()
-            .filter(|agent| agent.domain(domain_name, port).is_some())
+            .filter(|agent| agent.domain(domain_name, port, local_addr).is_some())
             .min_by(|x, y| {
                 if let (Some(l), Some(r)) = (x.system_info.as_ref(), y.system_info.as_ref()) {
                     (l.dynamic.loadavg / l.constant.cpus as f64)
@@ -107,8 +127,8 @@ impl User {
             })
             .and_then(|agent| {
                 let connect = agent
-                    .domain(domain_name, port)
-                    .or(agent.domain(domain_name, 0))?;
+                    .domain(domain_name, port, local_addr)
+                    .or(agent.domain(domain_name, 0, local_addr))?;
                 Some((agent, connect))
             })
     }
@@ -183,14 +203,28 @@ impl Users {
     pub fn get_mut_agent_by_domain(
         &mut self,
         domain_name: &str,
-        port: u16,
+        local_addr: SocketAddr,
     ) -> Option<Result<(Uuid, &mut Agent, Connect), ()>> {
-        let agent_pairs = self.domains.get(domain_name)?;
-        let user_id = agent_pairs.get(&port).or(agent_pairs.get(&0))?; // 0 is default port
+        let port = local_addr.port();
+        let user_id = self
+            .domains
+            .get(domain_name)
+            .and_then(|agent_pairs| agent_pairs.get(&port).or(agent_pairs.get(&0))) // 0 is default port
+            .or_else(|| {
+                self.domains
+                    .iter()
+                    .filter(|(pattern, agent_pairs)| {
+                        is_pattern(pattern)
+                            && (agent_pairs.contains_key(&port) || agent_pairs.contains_key(&0))
+                            && glob_match(pattern, domain_name)
+                    })
+                    .max_by_key(|(pattern, _)| pattern.len())
+                    .and_then(|(_, agent_pairs)| agent_pairs.get(&port).or(agent_pairs.get(&0)))
+            })?;
         let user = self.users.get_mut(user_id)?;
 
         Some(
-            if let Some((agent, connect)) = user.get_mut_agent_by_domain(domain_name, port) {
+            if let Some((agent, connect)) = user.get_mut_agent_by_domain(domain_name, local_addr) {
                 Ok((*user_id, agent, connect))
             } else {
                 Err(())
@@ -224,11 +258,16 @@ impl Users {
         let agent = user.del_agent(agent_name)?;
         for (domain_name, pub_info) in &agent.publish_map {
             for port in pub_info.keys() {
-                if user.get_mut_agent_by_domain(domain_name, *port).is_none() {
-                    let agent_pairs = self.domains.get_mut(domain_name)?;
-                    let _ = agent_pairs.remove(port);
-                    if agent_pairs.is_empty() {
-                        let _ = self.domains.remove(domain_name);
+                if !user
+                    .agents
+                    .values()
+                    .any(|agent| agent.publishes(domain_name, *port))
+                {
+                    if let Some(agent_pairs) = self.domains.get_mut(domain_name) {
+                        let _ = agent_pairs.remove(port);
+                        if agent_pairs.is_empty() {
+                            let _ = self.domains.remove(domain_name);
+                        }
                     }
                     // let _ = self.domains.remove(&(domain_name.clone(), *port));
                 }
@@ -245,6 +284,19 @@ impl Users {
         Some(agent)
     }
 
+    // re-indexes the published hosts of a connected agent
+    pub fn set_publish_hosts(
+        &mut self,
+        user_id: Uuid,
+        agent_name: &str,
+        publishes: Vec<PublishHost>,
+    ) -> Option<&mut Agent> {
+        let mut agent = self.del_agent(user_id, agent_name)?;
+        agent.set_publish_hosts(publishes);
+        self.add_agent(user_id, agent);
+        self.get_mut_agent(user_id, agent_name.to_owned())
+    }
+
     pub fn del_client(&mut self, user_id: Uuid, client_id: Uuid) -> Option<Client> {
         let user = self.users.get_mut(&user_id)?;
         let client = user.del_client(client_id)?;
@@ -275,11 +327,13 @@ impl Users {
                 let mut publish_info = Vec::new();
                 for (host, connect) in agent.publish_map.iter() {
                     for (src_port, connect) in connect.iter() {
-                        publish_info.push(AgentPublishInfo::from_connect(
-                            host.clone(),
-                            *src_port,
-                            connect,
-                        ));
+                        for (_, connect) in connect {
+                            publish_info.push(AgentPublishInfo::from_connect(
+                                host.clone(),
+                                *src_port,
+                                connect,
+                            ));
+                        }
                     }
                 }
                 ret.push(AgentInfo {
@@ -52,13 +52,20 @@ impl Sink<Vec<u8>> for AsyncToStream {
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
         if let Some((mut len, buffer)) = self.buffer.take() {
-            loop {
-                len = match Pin::new(&mut self.socket).poll_write(cx, &buffer)? {
-                    Poll::Ready(written) => written,
-                    Poll::Pending => return Poll::Pending,
-                };
-                if len == buffer.len() {
-                    break;
+            // len is what is already written, the rest waits for the next poll if pending
+            while len < buffer.len() {
+                match Pin::new(&mut self.socket).poll_write(cx, &buffer[len..])? {
+                    Poll::Ready(0) => {
+                        return Poll::Ready(Err(std::io::Error::from(
+                            std::io::ErrorKind::WriteZero,
+                        )
+                        .into()))
+                    }
+                    Poll::Ready(written) => len += written,
+                    Poll::Pending => {
+                        self.buffer = Some((len, buffer));
+                        return Poll::Pending;
+                    }
                 }
             }
         }
@@ -75,7 +82,9 @@ impl Sink<Vec<u8>> for AsyncToStream {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        let _ = Pin::new(&mut self).poll_ready(cx)?;
+        if Pin::new(&mut self).poll_ready(cx)?.is_pending() {
+            return Poll::Pending;
+        }
         Pin::new(&mut self.socket)
             .poll_flush(cx)
             .map_err(|e| e.into())
@@ -85,7 +94,9 @@ impl Sink<Vec<u8>> for AsyncToStream {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        let _ = Pin::new(&mut self).poll_ready(cx)?;
+        if Pin::new(&mut self).poll_ready(cx)?.is_pending() {
+            return Poll::Pending;
+        }
         Pin::new(&mut self.socket)
             .poll_shutdown(cx)
             .map_err(|e| e.into())
@@ -128,10 +139,7 @@ impl AsyncRead for StreamToAsync {
                     continue;
                 }
                 Poll::Ready(Some(Err(e))) => {
-                    return Poll::Ready(Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        e.to_string(),
-                    )))
+                    return Poll::Ready(Err(std::io::Error::other(e.to_string())))
                 }
                 Poll::Ready(None) => return Poll::Ready(Ok(())),
                 Poll::Pending => return Poll::Pending,
@@ -148,7 +156,7 @@ impl AsyncWrite for StreamToAsync {
     ) -> Poll<Result<usize, std::io::Error>> {
         match Pin::new(&mut self.stream.send(buf.to_vec()))
             .poll(cx)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?
+            .map_err(|e| std::io::Error::other(e.to_string()))?
         {
             Poll::Ready(_) => Poll::Ready(Ok(buf.len())),
             Poll::Pending => Poll::Pending,
@@ -173,3 +181,26 @@ impl AsyncWrite for StreamToAsync {
             .map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_survive_partial_writes() {
+        // a pipe far smaller than the item forces many short writes
+        let (a, mut b) = tokio::io::duplex(64);
+        let item = (0..10_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let sent = item.clone();
+        let sender = tokio::spawn(async move {
+            let mut stream = AsyncToStream::new(a);
+            stream.send(sent).await.is_ok() && stream.close().await.is_ok()
+        });
+        let mut received = Vec::new();
+        assert!(b.read_to_end(&mut received).await.is_ok());
+        assert!(matches!(sender.await, Ok(true)));
+        assert_eq!(received, item);
+    }
+}
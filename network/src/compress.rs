@@ -0,0 +1,403 @@
+use std::{
+    io,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use chunkio::ChunkIO;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::AsyncSocket;
+
+const STORED: u8 = 0;
+const LZ4: u8 = 1;
+const MIN_COMPRESS: usize = 64; // smaller chunks are sent as they are
+const MAX_CHUNK: usize = 1 << 20; // refuse to inflate beyond this
+const HASH_LOG: u32 = 12;
+const MIN_MATCH: usize = 4;
+const MF_LIMIT: usize = 12; // the last match starts at least this far from the end
+const LAST_LITERALS: usize = 5; // the block always ends with this many literals
+
+// compression of a data channel, negotiated with the NL-COMPRESSION header
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "None",
+            Compression::Lz4 => "Lz4",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "None" => Ok(Compression::None),
+            "Lz4" => Ok(Compression::Lz4),
+            _ => Err(()),
+        }
+    }
+}
+
+// every write becomes one chunk, a flag byte then the raw bytes or the
+// original length and an LZ4 block; incompressible chunks are stored
+pub struct AsyncSocketCompress {
+    inner: ChunkIO<Box<dyn AsyncSocket>>,
+    read_buffer: Option<(usize, Vec<u8>)>,
+}
+
+impl AsyncSocketCompress {
+    pub fn new(inner: Box<dyn AsyncSocket>) -> Self {
+        Self {
+            inner: ChunkIO::new(inner),
+            read_buffer: None,
+        }
+    }
+}
+
+fn other(e: impl ToString) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+fn encode(item: &[u8]) -> Vec<u8> {
+    // larger items come from the sink as they are, the reader refuses to inflate them
+    if (MIN_COMPRESS..=MAX_CHUNK).contains(&item.len()) {
+        let compressed = lz4_compress(item);
+        if compressed.len() + 4 < item.len() {
+            let mut chunk = Vec::with_capacity(compressed.len() + 5);
+            chunk.push(LZ4);
+            chunk.extend_from_slice(&(item.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(&compressed);
+            return chunk;
+        }
+    }
+    let mut chunk = Vec::with_capacity(item.len() + 1);
+    chunk.push(STORED);
+    chunk.extend_from_slice(item);
+    chunk
+}
+
+fn decode(mut chunk: Vec<u8>) -> io::Result<Vec<u8>> {
+    match chunk.first() {
+        Some(&STORED) => {
+            chunk.remove(0);
+            Ok(chunk)
+        }
+        Some(&LZ4) if chunk.len() >= 5 => {
+            let size = u32::from_le_bytes([chunk[1], chunk[2], chunk[3], chunk[4]]) as usize;
+            if size > MAX_CHUNK {
+                return Err(other("Compressed chunk too large"));
+            }
+            lz4_decompress(&chunk[5..], size).ok_or(other("Invalid compressed chunk"))
+        }
+        _ => Err(other("Invalid compressed chunk")),
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+// LZ4 block format with a single pass greedy matcher
+fn lz4_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut table = vec![0usize; 1 << HASH_LOG]; // position + 1, zero is empty
+    let (mut anchor, mut i) = (0, 0);
+    let match_limit = input.len().saturating_sub(MF_LIMIT);
+    while i < match_limit {
+        let sequence = u32::from_le_bytes([input[i], input[i + 1], input[i + 2], input[i + 3]]);
+        let hash = (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize;
+        let candidate = std::mem::replace(&mut table[hash], i + 1);
+        if candidate == 0
+            || i - (candidate - 1) > u16::MAX as usize
+            || input[candidate - 1..candidate - 1 + MIN_MATCH] != input[i..i + MIN_MATCH]
+        {
+            i += 1;
+            continue;
+        }
+        let candidate = candidate - 1;
+        let mut len = MIN_MATCH;
+        while i + len < input.len() - LAST_LITERALS && input[candidate + len] == input[i + len] {
+            len += 1;
+        }
+        let literals = i - anchor;
+        let match_len = len - MIN_MATCH;
+        out.push(((literals.min(15) as u8) << 4) | match_len.min(15) as u8);
+        if literals >= 15 {
+            write_length(&mut out, literals - 15);
+        }
+        out.extend_from_slice(&input[anchor..i]);
+        out.extend_from_slice(&((i - candidate) as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(&mut out, match_len - 15);
+        }
+        i += len;
+        anchor = i;
+    }
+    let literals = input.len() - anchor;
+    out.push((literals.min(15) as u8) << 4);
+    if literals >= 15 {
+        write_length(&mut out, literals - 15);
+    }
+    out.extend_from_slice(&input[anchor..]);
+    out
+}
+
+fn read_length(input: &[u8], i: &mut usize, mut len: usize) -> Option<usize> {
+    loop {
+        let b = *input.get(*i)?;
+        *i += 1;
+        len = len.checked_add(b as usize)?;
+        if b != 255 {
+            return Some(len);
+        }
+    }
+}
+
+fn lz4_decompress(input: &[u8], size: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(size.min(MAX_CHUNK));
+    let mut i = 0;
+    loop {
+        let token = *input.get(i)?;
+        i += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = read_length(input, &mut i, literals)?;
+        }
+        if literals > size - out.len() {
+            return None;
+        }
+        out.extend_from_slice(input.get(i..i.checked_add(literals)?)?);
+        i += literals;
+        if i == input.len() {
+            break;
+        }
+        let offset = u16::from_le_bytes([*input.get(i)?, *input.get(i + 1)?]) as usize;
+        i += 2;
+        if offset == 0 || offset > out.len() {
+            return None;
+        }
+        let mut len = (token & 0xf) as usize;
+        if len == 15 {
+            len = read_length(input, &mut i, len)?;
+        }
+        len = len.checked_add(MIN_MATCH)?;
+        if len > size - out.len() {
+            return None;
+        }
+        let start = out.len() - offset;
+        for k in start..start + len {
+            out.push(out[k]); // the match may overlap what it copies
+        }
+    }
+    (out.len() == size).then_some(out)
+}
+
+impl Stream for AsyncSocketCompress {
+    type Item = Result<Vec<u8>, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.inner.poll_next_unpin(cx).map_err(other)? {
+            Poll::Ready(Some(chunk)) => Poll::Ready(Some(decode(chunk))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for AsyncSocketCompress {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx).map_err(other)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner.start_send_unpin(encode(&item)).map_err(other)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx).map_err(other)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx).map_err(other)
+    }
+}
+
+impl AsyncRead for AsyncSocketCompress {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let (offset, data) = match self.read_buffer.take() {
+            Some(buffered) => buffered,
+            None => match self.as_mut().poll_next(cx)? {
+                Poll::Ready(Some(data)) => (0, data),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+        let len = buf.remaining().min(data.len() - offset);
+        buf.put_slice(&data[offset..offset + len]);
+        if offset + len < data.len() {
+            self.read_buffer = Some((offset + len, data));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncSocketCompress {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let buf = &buf[..buf.len().min(MAX_CHUNK)];
+        match self.as_mut().poll_ready(cx) {
+            Poll::Ready(Ok(())) => match self.start_send_unpin(buf.to_vec()) {
+                Ok(()) => Poll::Ready(Ok(buf.len())),
+                Err(e) => Poll::Ready(Err(e)),
+            },
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Sink::<Vec<u8>>::poll_flush(self, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    // deterministic bytes that do not compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        let text = b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n".repeat(40);
+        let mut mixed = noise(300);
+        mixed.extend_from_slice(&[7; 1000]);
+        mixed.extend(noise(20));
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            vec![0; MIN_COMPRESS],
+            vec![0; MF_LIMIT + LAST_LITERALS],
+            vec![b'x'; 15 + 255 + 4 + 100], // length bytes of 255 for the match
+            [noise(15 + 255 + 10), vec![1; 64]].concat(), // and for the literals
+            text,
+            noise(4096),
+            mixed,
+            vec![3; MAX_CHUNK],
+        ]
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        for sample in samples() {
+            let compressed = lz4_compress(&sample);
+            assert_eq!(
+                lz4_decompress(&compressed, sample.len()).as_deref(),
+                Some(&sample[..]),
+                "sample of {} bytes",
+                sample.len()
+            );
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        for sample in samples() {
+            let chunk = encode(&sample);
+            assert_eq!(decode(chunk).expect("decode"), sample);
+        }
+        // incompressible and oversized items stay stored
+        assert_eq!(encode(&noise(4096))[0], STORED);
+        assert_eq!(encode(&vec![0; MAX_CHUNK + 1])[0], STORED);
+        assert_eq!(encode(&vec![0; 4096])[0], LZ4);
+    }
+
+    #[test]
+    fn malformed_blocks_are_refused() {
+        let sample = b"narrowlink ".repeat(100);
+        let compressed = lz4_compress(&sample);
+        for len in 0..compressed.len() {
+            assert_eq!(lz4_decompress(&compressed[..len], sample.len()), None);
+        }
+        // the declared size must be exact
+        assert_eq!(lz4_decompress(&compressed, sample.len() - 1), None);
+        assert_eq!(lz4_decompress(&compressed, sample.len() + 1), None);
+        // a literal then a match at offset 0, or behind the start of the output
+        assert_eq!(lz4_decompress(&[0x10, b'a', 0, 0, 0x00], 5), None);
+        assert_eq!(lz4_decompress(&[0x10, b'a', 2, 0, 0x00], 5), None);
+        // endless length bytes
+        assert_eq!(lz4_decompress(&[0xf0; 64], 1 << 16), None);
+        assert_eq!(lz4_decompress(&[0xf0, 255, 255, 255], usize::MAX), None);
+    }
+
+    #[test]
+    fn malformed_chunks_are_refused() {
+        assert!(decode(Vec::new()).is_err());
+        assert!(decode(vec![2, 0]).is_err());
+        assert!(decode(vec![LZ4, 0, 0]).is_err());
+        let mut oversized = vec![LZ4];
+        oversized.extend_from_slice(&(MAX_CHUNK as u32 + 1).to_le_bytes());
+        oversized.extend_from_slice(&lz4_compress(&[0; 64]));
+        assert!(decode(oversized).is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_round_trip() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let (mut a, mut b) = (
+            AsyncSocketCompress::new(Box::new(a)),
+            AsyncSocketCompress::new(Box::new(b)),
+        );
+        let data = [vec![9; 3 * MAX_CHUNK], noise(5000)].concat();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&data).await.expect("write");
+            a.shutdown().await.expect("shutdown");
+        });
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).await.expect("read");
+        writer.await.expect("writer");
+        assert_eq!(received.len(), expected.len());
+        assert!(received == expected);
+    }
+}
@@ -9,15 +9,23 @@ pub enum NetworkError {
     #[error("Network Error: {0}")]
     HyperError(#[from] hyper::Error),
     #[error("Network Error: {0}")]
-    Tungstenite(#[from] tungstenite::Error),
+    Tungstenite(Box<tungstenite::Error>), // boxed, it outweighs all the other variants
     #[error("TLS Error")]
     TlsError,
+    #[error("Certificate Pin Mismatch")]
+    CertificatePinMismatch,
     #[error("Unable To Upgrade: {0}")]
     UnableToUpgrade(u16),
     #[error("Request Canceled")]
     RequestCanceled,
     #[error("Quic Error")]
     QuicError,
+    #[error("Multiplexed Connection Closed")]
+    MuxClosed,
+    #[error("Idle Timeout")]
+    IdleTimeout,
+    #[error("Quic Connection Error: {0}")]
+    QuicConnection(#[from] quinn::ConnectionError),
     #[error("P2P Invalid Command")]
     P2PInvalidCommand,
     #[error("P2P Invalid Domain")]
@@ -30,8 +38,16 @@ pub enum NetworkError {
     P2PFailed,
     #[error("Json Serialization Error: {0}")]
     JsonSerializationError(#[from] serde_json::Error),
+    #[error("E2EE Integrity Check Failed, the key likely differs")]
+    IntegrityCheckFailed,
+    #[error("E2EE Key Exchange Failed")]
+    KeyExchangeFailed,
     #[error("Cryptography Failure: {0}")]
     XChaCha20Poly1305(chacha20poly1305::Error),
+    #[error("Proxy Unreachable: {0}")]
+    ProxyUnreachable(std::io::Error),
+    #[error("Proxy Error: {0}")]
+    ProxyError(&'static str),
     #[error("Invalid: {0}")]
     Invalid(&'static str),
 }
@@ -41,3 +57,9 @@ impl From<chacha20poly1305::Error> for NetworkError {
         Self::XChaCha20Poly1305(err)
     }
 }
+
+impl From<tungstenite::Error> for NetworkError {
+    fn from(err: tungstenite::Error) -> Self {
+        Self::Tungstenite(Box::new(err))
+    }
+}
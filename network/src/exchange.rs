@@ -0,0 +1,112 @@
+use narrowlink_types::generic::KeyExchange;
+use ring::{
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    hmac,
+    rand::SystemRandom,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::NetworkError;
+
+const PROOF_LABEL: &[u8] = b"narrowlink x25519 agent";
+const SESSION_LABEL: &[u8] = b"narrowlink x25519 session";
+
+// one side of the X25519 exchange of a connection, the passphrase key authenticates both
+// public keys, the client's through the sign of its request and the agent's through a proof
+pub struct EphemeralKey {
+    private_key: EphemeralPrivateKey,
+    public_key: [u8; 32],
+}
+
+impl EphemeralKey {
+    pub fn generate() -> Result<Self, NetworkError> {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| NetworkError::KeyExchangeFailed)?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| NetworkError::KeyExchangeFailed)?
+            .as_ref()
+            .try_into()
+            .map_err(|_| NetworkError::KeyExchangeFailed)?;
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
+    pub fn exchange(&self) -> KeyExchange {
+        KeyExchange::X25519(self.public_key)
+    }
+
+    // the agent's side, sends its public key and proof before any data and returns the session key
+    pub async fn respond(
+        self,
+        mut writer: impl AsyncWrite + Unpin,
+        key: &[u8; 32],
+        client: &KeyExchange,
+    ) -> Result<[u8; 32], NetworkError> {
+        let KeyExchange::X25519(client) = client;
+        let proof = proof(key, client, &self.public_key);
+        writer
+            .write_all(&[&self.public_key[..], proof.as_ref()].concat())
+            .await?;
+        writer.flush().await?;
+        let agent = self.public_key;
+        self.agree(key, client, client, &agent)
+    }
+
+    // the client's side, reads the agent's public key and returns the session key once the proof holds
+    pub async fn accept(
+        self,
+        mut reader: impl AsyncRead + Unpin,
+        key: &[u8; 32],
+    ) -> Result<[u8; 32], NetworkError> {
+        let mut answer = [0; 64];
+        reader
+            .read_exact(&mut answer)
+            .await
+            .map_err(|_| NetworkError::KeyExchangeFailed)?;
+        let (agent, tag) = answer.split_at(32);
+        let agent: [u8; 32] = agent
+            .try_into()
+            .map_err(|_| NetworkError::KeyExchangeFailed)?;
+        hmac::verify(
+            &hmac::Key::new(hmac::HMAC_SHA256, key),
+            &[PROOF_LABEL, &self.public_key, &agent].concat(),
+            tag,
+        )
+        .map_err(|_| NetworkError::KeyExchangeFailed)?;
+        let client = self.public_key;
+        self.agree(key, &agent, &client, &agent)
+    }
+
+    fn agree(
+        self,
+        key: &[u8; 32],
+        peer: &[u8; 32],
+        client: &[u8; 32],
+        agent: &[u8; 32],
+    ) -> Result<[u8; 32], NetworkError> {
+        agreement::agree_ephemeral(
+            self.private_key,
+            &UnparsedPublicKey::new(&X25519, peer),
+            |shared| {
+                let tag = hmac::sign(
+                    &hmac::Key::new(hmac::HMAC_SHA256, key),
+                    &[SESSION_LABEL, shared, client, agent].concat(),
+                );
+                tag.as_ref().try_into().ok()
+            },
+        )
+        .ok()
+        .flatten()
+        .ok_or(NetworkError::KeyExchangeFailed)
+    }
+}
+
+fn proof(key: &[u8; 32], client: &[u8; 32], agent: &[u8; 32]) -> hmac::Tag {
+    hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, key),
+        &[PROOF_LABEL, client, agent].concat(),
+    )
+}
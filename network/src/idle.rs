@@ -0,0 +1,84 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{Future, Sink, Stream};
+use tokio::{
+    sync::oneshot,
+    time::{self, Instant, Sleep},
+};
+
+use crate::{error::NetworkError, UniversalStream};
+
+// fails the stream once nothing was read or written for the timeout, expired is sent when it
+// does so the owner can tell it apart from a close of either peer
+pub struct IdleTimeout {
+    stream: Box<dyn UniversalStream<Vec<u8>, NetworkError>>,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+    expired: Option<oneshot::Sender<()>>,
+}
+
+impl IdleTimeout {
+    pub fn new(
+        stream: impl UniversalStream<Vec<u8>, NetworkError>,
+        timeout: Duration,
+        expired: oneshot::Sender<()>,
+    ) -> Self {
+        Self {
+            stream: Box::new(stream),
+            timeout,
+            deadline: Box::pin(time::sleep(timeout)),
+            expired: Some(expired),
+        }
+    }
+    fn active(&mut self) {
+        let deadline = Instant::now() + self.timeout;
+        self.deadline.as_mut().reset(deadline);
+    }
+}
+
+impl Stream for IdleTimeout {
+    type Item = Result<Vec<u8>, NetworkError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.stream).poll_next(cx) {
+            Poll::Ready(item) => {
+                self.active();
+                Poll::Ready(item)
+            }
+            Poll::Pending => {
+                if self.deadline.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                if let Some(expired) = self.expired.take() {
+                    let _ = expired.send(());
+                }
+                Poll::Ready(Some(Err(NetworkError::IdleTimeout)))
+            }
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for IdleTimeout {
+    type Error = NetworkError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.stream).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.active();
+        Pin::new(&mut self.stream).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
@@ -1,16 +1,35 @@
 pub use async_tools::{AsyncToStream, StreamToAsync};
 use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305};
 use chunkio::ChunkIO;
-use std::{io, pin::Pin, task::Poll};
+pub use compress::{AsyncSocketCompress, Compression};
+pub use exchange::EphemeralKey;
+pub use idle::IdleTimeout;
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
 mod async_tools;
+mod compress;
 pub mod error;
 pub mod event;
+mod exchange;
+mod idle;
+pub mod mux;
 pub mod p2p;
+pub mod quic;
 pub mod transport;
 pub mod ws;
 use error::NetworkError;
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
 
 pub trait AsyncSocket: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
 impl<T> AsyncSocket for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
@@ -83,10 +102,92 @@ pub async fn async_forward(
     }
 }
 
+static INTEGRITY_FAILURES: AtomicU64 = AtomicU64::new(0);
+static KEY_MISMATCHES: AtomicU64 = AtomicU64::new(0);
+type IntegrityReports = HashMap<(String, bool), (Instant, u64)>; // (label, key mismatch) -> logged at, failures since
+static INTEGRITY_LOGGED: Mutex<Option<IntegrityReports>> = Mutex::new(None);
+const INTEGRITY_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+// chunks of every E2EE stream that failed AEAD verification
+pub fn integrity_failures() -> u64 {
+    INTEGRITY_FAILURES.load(Ordering::Relaxed)
+}
+
+// E2EE requests signed with another key
+pub fn key_mismatches() -> u64 {
+    KEY_MISMATCHES.load(Ordering::Relaxed)
+}
+
+// tells a peer with another passphrase, whose signed request fails to verify, from an
+// established stream whose chunks fail AEAD verification, which is corruption or tampering
+pub struct IntegrityMonitor {
+    label: String, // service and peer, for the log
+    failures: Option<Arc<AtomicU64>>,
+    mismatches: Option<Arc<AtomicU64>>,
+}
+
+impl IntegrityMonitor {
+    pub fn new(label: String) -> Self {
+        Self {
+            label,
+            failures: None,
+            mismatches: None,
+        }
+    }
+    pub fn counters(mut self, failures: Arc<AtomicU64>, mismatches: Arc<AtomicU64>) -> Self {
+        self.failures = Some(failures);
+        self.mismatches = Some(mismatches);
+        self
+    }
+    pub fn key_mismatch(&self) {
+        KEY_MISMATCHES.fetch_add(1, Ordering::Relaxed);
+        if let Some(mismatches) = &self.mismatches {
+            mismatches.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(suppressed) = self.report(true) {
+            warn!(
+                "E2EE key mismatch for {}, the passphrase or key derivation differs ({} more since the last report)",
+                self.label, suppressed
+            );
+        }
+    }
+    fn integrity_failure(&self) {
+        INTEGRITY_FAILURES.fetch_add(1, Ordering::Relaxed);
+        if let Some(failures) = &self.failures {
+            failures.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(suppressed) = self.report(false) {
+            warn!(
+                "E2EE integrity check failed for {}, the data was corrupted or altered ({} more since the last report)",
+                self.label, suppressed
+            );
+        }
+    }
+    // once a minute per label and kind, with the failures left unreported since
+    fn report(&self, key_mismatch: bool) -> Option<u64> {
+        let mut logged = INTEGRITY_LOGGED.lock().ok()?;
+        let logged = logged.get_or_insert_with(HashMap::new);
+        let key = (self.label.clone(), key_mismatch);
+        let suppressed = match logged.get_mut(&key) {
+            Some((at, since)) if at.elapsed() < INTEGRITY_LOG_INTERVAL => {
+                *since += 1;
+                return None;
+            }
+            Some((_, since)) => *since,
+            None => 0,
+        };
+        logged.retain(|_, (at, _)| at.elapsed() < INTEGRITY_LOG_INTERVAL * 2);
+        logged.insert(key, (Instant::now(), 0));
+        Some(suppressed)
+    }
+}
+
 pub struct AsyncSocketCrypt {
     inner: ChunkIO<Box<dyn AsyncSocket>>,
     cipher: XChaCha20Poly1305,
     nonce: [u8; 24],
+    monitor: IntegrityMonitor,
+    read_buffer: Option<(usize, Vec<u8>)>,
 }
 
 impl AsyncSocketCrypt {
@@ -96,8 +197,14 @@ impl AsyncSocketCrypt {
             inner: ChunkIO::new(inner),
             cipher,
             nonce,
+            monitor: IntegrityMonitor::new("an E2EE peer".to_owned()),
+            read_buffer: None,
         }
     }
+    pub fn with_monitor(mut self, monitor: IntegrityMonitor) -> Self {
+        self.monitor = monitor;
+        self
+    }
 }
 
 impl Stream for AsyncSocketCrypt {
@@ -107,18 +214,25 @@ impl Stream for AsyncSocketCrypt {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        match self
+        let chunk = match self
             .inner
             .poll_next_unpin(cx)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?
+            .map_err(|e| std::io::Error::other(e.to_string()))?
         {
-            Poll::Ready(Some(chunk)) => Poll::Ready(Some(
-                self.cipher
-                    .decrypt(&self.nonce.into(), chunk.as_ref())
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
-            )),
-            Poll::Pending => Poll::Pending,
-            _ => Poll::Ready(None),
+            Poll::Ready(Some(chunk)) => chunk,
+            Poll::Pending => return Poll::Pending,
+            _ => return Poll::Ready(None),
+        };
+        // a failed tag is the only way decryption fails, framing errors are returned above
+        match self.cipher.decrypt(&self.nonce.into(), chunk.as_ref()) {
+            Ok(plain) => Poll::Ready(Some(Ok(plain))),
+            Err(_) => {
+                self.monitor.integrity_failure();
+                Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    NetworkError::IntegrityCheckFailed,
+                ))))
+            }
         }
     }
 }
@@ -131,17 +245,17 @@ impl Sink<Vec<u8>> for AsyncSocketCrypt {
     ) -> Poll<Result<(), Self::Error>> {
         self.inner
             .poll_ready_unpin(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::other(e.to_string()))
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
         let buf = self
             .cipher
             .encrypt(&self.nonce.into(), item.as_ref())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| io::Error::other(e.to_string()))?;
         self.inner
             .start_send_unpin(buf)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::other(e.to_string()))
     }
 
     fn poll_flush(
@@ -150,7 +264,7 @@ impl Sink<Vec<u8>> for AsyncSocketCrypt {
     ) -> Poll<Result<(), Self::Error>> {
         self.inner
             .poll_flush_unpin(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::other(e.to_string()))
     }
 
     fn poll_close(
@@ -159,7 +273,7 @@ impl Sink<Vec<u8>> for AsyncSocketCrypt {
     ) -> Poll<Result<(), Self::Error>> {
         self.inner
             .poll_close_unpin(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::other(e.to_string()))
     }
 }
 
@@ -169,18 +283,32 @@ impl AsyncRead for AsyncSocketCrypt {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        match Pin::new(&mut self).poll_next(cx)? {
-            Poll::Ready(Some(item)) => {
-                let b = self
-                    .cipher
-                    .decrypt(&self.nonce.into(), item.as_slice())
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
-                buf.put_slice(&b); // todo: fix
-                Poll::Ready(Ok(()))
-            }
-            Poll::Ready(None) => Poll::Ready(Ok(())),
-            Poll::Pending => Poll::Pending,
+        let (offset, data) = match self.read_buffer.take() {
+            Some(buffered) => buffered,
+            None => match self.as_mut().poll_next(cx)? {
+                // poll_write encrypts before the sink does, the stream removes one layer
+                Poll::Ready(Some(data)) => {
+                    match self.cipher.decrypt(&self.nonce.into(), data.as_slice()) {
+                        Ok(data) => (0, data),
+                        Err(_) => {
+                            self.monitor.integrity_failure();
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                NetworkError::IntegrityCheckFailed,
+                            )));
+                        }
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+        let len = buf.remaining().min(data.len() - offset);
+        buf.put_slice(&data[offset..offset + len]);
+        if offset + len < data.len() {
+            self.read_buffer = Some((offset + len, data));
         }
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -195,7 +323,7 @@ impl AsyncWrite for AsyncSocketCrypt {
                 let cipher_text = self
                     .cipher
                     .encrypt(&self.nonce.into(), buf)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+                    .map_err(|e| std::io::Error::other(e.to_string()))?;
                 match self.start_send_unpin(cipher_text) {
                     Ok(()) => Poll::Ready(Ok(buf.len())),
                     Err(e) => Poll::Ready(Err(e)),
@@ -220,3 +348,37 @@ impl AsyncWrite for AsyncSocketCrypt {
         self.poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn chunks_failing_verification_end_the_stream() {
+        let (key, nonce) = ([1; 32], [2; 24]);
+        let (writer, reader) = tokio::io::duplex(1024);
+        let failures = Arc::new(AtomicU64::new(0));
+        let mut reader = AsyncSocketCrypt::new(key, nonce, Box::new(reader))
+            .await
+            .with_monitor(
+                IntegrityMonitor::new("a test peer".to_owned())
+                    .counters(failures.clone(), Arc::new(AtomicU64::new(0))),
+            );
+        let mut writer = ChunkIO::new(writer);
+        let valid = XChaCha20Poly1305::new(&key.into())
+            .encrypt(&nonce.into(), b"valid".as_ref())
+            .expect("encrypted");
+        let mut tampered = valid.clone();
+        tampered[0] ^= 1;
+        writer.send(tampered).await.expect("sent");
+        writer.send(valid).await.expect("sent");
+
+        let error = reader
+            .next()
+            .await
+            .expect("chunk")
+            .expect_err("the tampered chunk is not skipped");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(failures.load(Ordering::Relaxed), 1);
+    }
+}
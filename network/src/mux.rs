@@ -0,0 +1,655 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use hyper::{client::conn, Body, Request, StatusCode};
+use narrowlink_types::ServiceType;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    error::NetworkError,
+    transport::{DialOptions, StreamType, TlsConfiguration, UnifiedSocket},
+    AsyncSocket,
+};
+
+// the HTTP upgrade gateways answer when they serve every stream of the connection like a
+// connection of its own
+pub const UPGRADE: &str = "narrowlink-mux";
+
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const WINDOW: u8 = 2; // the length field carries the credit
+const FIN: u8 = 3; // no more data in this direction
+const RST: u8 = 4; // the stream is gone in both directions
+const HEADER_LEN: usize = 9; // kind, stream id and length
+const MAX_FRAME: usize = 16 * 1024;
+const INITIAL_WINDOW: u32 = 256 * 1024; // bytes a stream may receive before it is read
+const MAX_STREAMS: usize = 1024;
+
+struct Frame {
+    kind: u8,
+    id: u32,
+    len: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, id: u32, len: u32) -> Self {
+        Self {
+            kind,
+            id,
+            len,
+            payload: Vec::new(),
+        }
+    }
+    fn data(id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            kind: DATA,
+            id,
+            len: payload.len() as u32,
+            payload,
+        }
+    }
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push(self.kind);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.len.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    send_window: u32,
+    recv_window: u32,
+    write_waker: Option<Waker>,
+    reset: bool,
+}
+
+fn reset(shared: &Mutex<Shared>) {
+    if let Ok(mut shared) = shared.lock() {
+        shared.reset = true;
+        if let Some(waker) = shared.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Entry {
+    shared: Arc<Mutex<Shared>>,
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>, // None once the peer sent FIN
+}
+
+struct Session {
+    streams: Mutex<HashMap<u32, Entry>>,
+    frames: mpsc::UnboundedSender<Frame>,
+    closed: AtomicBool,
+}
+
+impl Session {
+    fn send(&self, frame: Frame) {
+        let _ = self.frames.send(frame);
+    }
+    fn register(self: &Arc<Self>, id: u32) -> MuxStream {
+        let shared = Arc::new(Mutex::new(Shared {
+            send_window: INITIAL_WINDOW,
+            recv_window: INITIAL_WINDOW,
+            ..Default::default()
+        }));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        if let Ok(mut streams) = self.streams.lock() {
+            streams.insert(
+                id,
+                Entry {
+                    shared: shared.clone(),
+                    inbound: Some(sender),
+                },
+            );
+        }
+        MuxStream {
+            id,
+            session: self.clone(),
+            shared,
+            inbound: receiver,
+            buffer: None,
+            consumed: 0,
+            fin_sent: false,
+            eof: false,
+        }
+    }
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Ok(mut streams) = self.streams.lock() {
+            for (_, entry) in streams.drain() {
+                reset(&entry.shared);
+            }
+        }
+    }
+    // false when the peer broke the protocol and the connection has to go
+    fn receive(
+        self: &Arc<Self>,
+        frame: Frame,
+        incoming: Option<&mpsc::UnboundedSender<MuxStream>>,
+    ) -> bool {
+        let Ok(mut streams) = self.streams.lock() else {
+            return false;
+        };
+        match frame.kind {
+            DATA => {
+                let Some(entry) = streams.get(&frame.id) else {
+                    return true; // reset on this side while it was in flight
+                };
+                let len = frame.payload.len() as u32;
+                let within_window = entry.shared.lock().is_ok_and(|mut shared| {
+                    let fits = len <= shared.recv_window;
+                    if fits {
+                        shared.recv_window -= len;
+                    }
+                    fits
+                });
+                let delivered = within_window
+                    && entry
+                        .inbound
+                        .as_ref()
+                        .is_some_and(|inbound| inbound.send(frame.payload).is_ok());
+                if !delivered {
+                    if let Some(entry) = streams.remove(&frame.id) {
+                        reset(&entry.shared);
+                    }
+                    self.send(Frame::new(RST, frame.id, 0));
+                }
+            }
+            WINDOW => {
+                if let Some(mut shared) = streams.get(&frame.id).and_then(|e| e.shared.lock().ok())
+                {
+                    shared.send_window = shared.send_window.saturating_add(frame.len);
+                    if let Some(waker) = shared.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            FIN => {
+                if let Some(entry) = streams.get_mut(&frame.id) {
+                    entry.inbound = None;
+                }
+            }
+            RST => {
+                if let Some(entry) = streams.remove(&frame.id) {
+                    reset(&entry.shared);
+                }
+            }
+            OPEN => {
+                // only the side that dialed opens streams, with odd ids
+                let Some(incoming) = incoming.filter(|_| frame.id % 2 == 1) else {
+                    return false;
+                };
+                if streams.contains_key(&frame.id) || streams.len() >= MAX_STREAMS {
+                    self.send(Frame::new(RST, frame.id, 0));
+                    return true;
+                }
+                drop(streams);
+                let _ = incoming.send(self.register(frame.id));
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+// reads and writes the frames of every stream until the connection ends
+fn drive(
+    io: Box<dyn AsyncSocket>,
+    incoming: Option<mpsc::UnboundedSender<MuxStream>>,
+) -> (Arc<Session>, JoinHandle<()>) {
+    let (frames, mut outbound) = mpsc::unbounded_channel::<Frame>();
+    let session = Arc::new(Session {
+        streams: Mutex::new(HashMap::new()),
+        frames,
+        closed: AtomicBool::new(false),
+    });
+    let (mut reader, mut writer) = tokio::io::split(io);
+    let driver = tokio::spawn({
+        let session = session.clone();
+        async move {
+            let read = async {
+                loop {
+                    let mut header = [0; HEADER_LEN];
+                    reader.read_exact(&mut header).await?;
+                    let kind = header[0];
+                    let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+                    let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+                    let mut payload = Vec::new();
+                    if kind == DATA {
+                        if len as usize > MAX_FRAME {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "multiplexed frame too large",
+                            ));
+                        }
+                        payload = vec![0; len as usize];
+                        reader.read_exact(&mut payload).await?;
+                    }
+                    let frame = Frame {
+                        kind,
+                        id,
+                        len,
+                        payload,
+                    };
+                    if !session.receive(frame, incoming.as_ref()) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "invalid multiplexed frame",
+                        ));
+                    }
+                }
+            };
+            let write = async {
+                while let Some(frame) = outbound.recv().await {
+                    writer.write_all(&frame.encode()).await?;
+                    if outbound.is_empty() {
+                        writer.flush().await?;
+                    }
+                }
+                Ok::<_, io::Error>(())
+            };
+            let res = tokio::select! {
+                res = read => res,
+                res = write => res,
+            };
+            if let Err(e) = res {
+                debug!("multiplexed connection closed: {}", e);
+            }
+            session.close();
+        }
+    });
+    (session, driver)
+}
+
+// one logical connection of a multiplexed connection
+pub struct MuxStream {
+    id: u32,
+    session: Arc<Session>,
+    shared: Arc<Mutex<Shared>>,
+    inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    buffer: Option<(usize, Vec<u8>)>,
+    consumed: u32, // read since the last window update
+    fin_sent: bool,
+    eof: bool,
+}
+
+impl MuxStream {
+    // the peer may send as much again once half of the window is read
+    fn credit(&mut self, len: usize) {
+        self.consumed += len as u32;
+        if self.consumed >= INITIAL_WINDOW / 2 {
+            if let Ok(mut shared) = self.shared.lock() {
+                shared.recv_window += self.consumed;
+            }
+            self.session
+                .send(Frame::new(WINDOW, self.id, self.consumed));
+            self.consumed = 0;
+        }
+    }
+    fn is_reset(&self) -> bool {
+        self.shared.lock().map_or(true, |shared| shared.reset)
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if let Some((offset, data)) = self.buffer.take() {
+                let len = buf.remaining().min(data.len() - offset);
+                buf.put_slice(&data[offset..offset + len]);
+                if offset + len < data.len() {
+                    self.buffer = Some((offset + len, data));
+                }
+                self.credit(len);
+                return Poll::Ready(Ok(()));
+            }
+            if self.eof {
+                return Poll::Ready(Ok(()));
+            }
+            match self.inbound.poll_recv(cx) {
+                Poll::Ready(Some(data)) => self.buffer = Some((0, data)),
+                Poll::Ready(None) if self.is_reset() => {
+                    return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+                }
+                Poll::Ready(None) => {
+                    self.eof = true;
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let Ok(mut shared) = self.shared.lock() else {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        };
+        if shared.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if shared.send_window == 0 {
+            shared.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = buf.len().min(MAX_FRAME).min(shared.send_window as usize);
+        shared.send_window -= len as u32;
+        drop(shared);
+        self.session.send(Frame::data(self.id, buf[..len].to_vec()));
+        Poll::Ready(Ok(len))
+    }
+
+    // frames are flushed by the driver as soon as nothing else is queued
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.fin_sent {
+            self.fin_sent = true;
+            self.session.send(Frame::new(FIN, self.id, 0));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = self.session.streams.lock() {
+            streams.remove(&self.id);
+        }
+        // a stream closed in both directions is already gone on the other side
+        if !(self.is_reset() || self.fin_sent && self.eof) {
+            self.session.send(Frame::new(RST, self.id, 0));
+        }
+    }
+}
+
+// the agent's side, every tunnel opens a stream of its own on one Ws or Wss connection
+pub struct MuxTransport {
+    session: Arc<Session>,
+    driver: JoinHandle<()>,
+    next_id: AtomicU32,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl MuxTransport {
+    pub async fn connect(
+        host: &str,
+        service_type: &ServiceType,
+        options: &DialOptions,
+    ) -> Result<Self, NetworkError> {
+        let sni = host.split(':').next().unwrap_or(host);
+        let transport_type = if let ServiceType::Wss | ServiceType::Quic = service_type {
+            StreamType::Tls(TlsConfiguration {
+                sni: sni.to_owned(),
+            })
+        } else {
+            StreamType::Tcp
+        };
+        let stream = UnifiedSocket::with_options(host, transport_type, options).await?;
+        let local_addr = stream.local_addr();
+        let peer_addr = stream.peer_addr();
+        let (mut request_sender, connection) = conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Error in connection: {}", e);
+            }
+        });
+        let request = Request::builder()
+            .header(
+                "Host",
+                host.strip_suffix(":443")
+                    .or(host.strip_suffix(":80"))
+                    .unwrap_or(host),
+            )
+            .header("Connection", "Upgrade")
+            .header("Upgrade", UPGRADE)
+            .header("NL-VERSION", env!("CARGO_PKG_VERSION"))
+            .method("GET")
+            .body(Body::from(""))?;
+        let response = request_sender.send_request(request).await?;
+        trace!("multiplex response status: {}", response.status());
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(NetworkError::UnableToUpgrade(response.status().as_u16()));
+        }
+        let upgraded = hyper::upgrade::on(response).await?;
+        let (session, driver) = drive(Box::new(upgraded), None);
+        Ok(Self {
+            session,
+            driver,
+            next_id: AtomicU32::new(1),
+            local_addr,
+            peer_addr,
+        })
+    }
+    pub async fn open(&self) -> Result<UnifiedSocket, NetworkError> {
+        if self.is_closed() {
+            return Err(NetworkError::MuxClosed);
+        }
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.session.register(id);
+        self.session.send(Frame::new(OPEN, id, 0));
+        Ok(UnifiedSocket::from_parts(
+            Box::new(stream),
+            self.local_addr,
+            self.peer_addr,
+        ))
+    }
+    pub fn is_closed(&self) -> bool {
+        self.session.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MuxTransport {
+    fn drop(&mut self) {
+        self.driver.abort();
+        self.session.close();
+    }
+}
+
+// the gateway's side of an upgraded connection
+pub struct MuxListener {
+    incoming: mpsc::UnboundedReceiver<MuxStream>,
+    driver: JoinHandle<()>,
+}
+
+impl MuxListener {
+    pub fn new(io: impl AsyncSocket) -> Self {
+        let (sender, incoming) = mpsc::unbounded_channel();
+        let (_, driver) = drive(Box::new(io), Some(sender));
+        Self { incoming, driver }
+    }
+    // None once the connection is closed
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.incoming.recv().await
+    }
+}
+
+impl Drop for MuxListener {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    const STALL: Duration = Duration::from_millis(100);
+
+    // the dialing side drives one end of the pipe, a listener the other
+    fn pair() -> (Arc<Session>, JoinHandle<()>, MuxListener) {
+        let (dialer, listener) = tokio::io::duplex(1 << 20);
+        let (session, driver) = drive(Box::new(dialer), None);
+        (session, driver, MuxListener::new(listener))
+    }
+
+    fn open(session: &Arc<Session>, id: u32) -> MuxStream {
+        let stream = session.register(id);
+        session.send(Frame::new(OPEN, id, 0));
+        stream
+    }
+
+    #[tokio::test]
+    async fn streams_carry_data_both_ways() {
+        let (session, _driver, mut listener) = pair();
+        let (mut first, mut second) = (open(&session, 1), open(&session, 3));
+        let mut accepted_first = listener.accept().await.expect("first stream");
+        let mut accepted_second = listener.accept().await.expect("second stream");
+
+        first.write_all(b"ping 1").await.expect("write");
+        second.write_all(b"ping 3").await.expect("write");
+        let mut buf = [0; 6];
+        accepted_second.read_exact(&mut buf).await.expect("read");
+        assert_eq!(&buf, b"ping 3");
+        accepted_first.read_exact(&mut buf).await.expect("read");
+        assert_eq!(&buf, b"ping 1");
+        accepted_first.write_all(b"pong 1").await.expect("write");
+        first.read_exact(&mut buf).await.expect("read");
+        assert_eq!(&buf, b"pong 1");
+    }
+
+    #[tokio::test]
+    async fn writes_stall_on_an_exhausted_window_until_it_is_read() {
+        let (session, _driver, mut listener) = pair();
+        let mut stream = open(&session, 1);
+        let mut accepted = listener.accept().await.expect("stream");
+
+        let window = vec![7; INITIAL_WINDOW as usize];
+        stream.write_all(&window).await.expect("write the window");
+        assert!(timeout(STALL, stream.write_all(b"more")).await.is_err());
+
+        // half of the window read is credited back
+        let mut buf = vec![0; INITIAL_WINDOW as usize / 2];
+        accepted.read_exact(&mut buf).await.expect("read");
+        timeout(STALL, stream.write_all(b"more"))
+            .await
+            .expect("write after the refill")
+            .expect("write");
+        let mut rest = vec![0; INITIAL_WINDOW as usize / 2 + 4];
+        accepted.read_exact(&mut rest).await.expect("read");
+        assert_eq!(&rest[rest.len() - 4..], b"more");
+        assert!(rest[..rest.len() - 4].iter().all(|b| *b == 7));
+    }
+
+    #[tokio::test]
+    async fn data_beyond_the_window_resets_the_stream() {
+        let (frames, mut outbound) = mpsc::unbounded_channel();
+        let session = Arc::new(Session {
+            streams: Mutex::new(HashMap::new()),
+            frames,
+            closed: AtomicBool::new(false),
+        });
+        let mut stream = session.register(2);
+        for _ in 0..INITIAL_WINDOW as usize / MAX_FRAME {
+            assert!(session.receive(Frame::data(2, vec![0; MAX_FRAME]), None));
+        }
+        assert!(outbound.try_recv().is_err());
+        assert!(session.receive(Frame::data(2, vec![0]), None));
+        assert!(matches!(
+            outbound.try_recv(),
+            Ok(Frame {
+                kind: RST,
+                id: 2,
+                ..
+            })
+        ));
+        assert!(stream.is_reset());
+        assert!(stream.write_all(b"late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn half_closed_streams_keep_the_other_direction() {
+        let (session, _driver, mut listener) = pair();
+        let mut stream = open(&session, 1);
+        let mut accepted = listener.accept().await.expect("stream");
+
+        stream.write_all(b"request").await.expect("write");
+        stream.shutdown().await.expect("shutdown");
+        assert!(stream.write_all(b"more").await.is_err());
+        let mut request = Vec::new();
+        accepted.read_to_end(&mut request).await.expect("read");
+        assert_eq!(request, b"request");
+
+        accepted.write_all(b"response").await.expect("write");
+        accepted.shutdown().await.expect("shutdown");
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.expect("read");
+        assert_eq!(response, b"response");
+
+        // closed both ways, the connection stays up for the other streams
+        drop(stream);
+        drop(accepted);
+        assert!(!session.closed.load(Ordering::Relaxed));
+        assert!(session.streams.lock().expect("streams").is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropping_a_stream_resets_it_on_the_peer() {
+        let (session, _driver, mut listener) = pair();
+        let mut stream = open(&session, 1);
+        let mut accepted = listener.accept().await.expect("stream");
+        stream.write_all(b"partial").await.expect("write");
+        let mut buf = [0; 7];
+        accepted.read_exact(&mut buf).await.expect("read");
+        drop(stream);
+
+        let mut rest = Vec::new();
+        let err = accepted.read_to_end(&mut rest).await.expect_err("reset");
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            accepted.write_all(b"late").await.expect_err("reset").kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_opened_by_the_listener_close_the_connection() {
+        let (dialer, listener) = tokio::io::duplex(1 << 16);
+        let (session, driver) = drive(Box::new(dialer), None);
+        let mut listener_stream = Box::new(listener) as Box<dyn AsyncSocket>;
+        listener_stream
+            .write_all(&Frame::new(OPEN, 2, 0).encode())
+            .await
+            .expect("write");
+        driver.await.expect("driver");
+        assert!(session.closed.load(Ordering::Relaxed));
+    }
+}
@@ -8,7 +8,9 @@ use std::{
 
 use async_recursion::async_recursion;
 use narrowlink_types::{
-    generic::{Connect, CryptographicAlgorithm, Protocol, SigningAlgorithm},
+    generic::{
+        Connect, CryptographicAlgorithm, KeyDerivation, KeyExchange, Protocol, SigningAlgorithm,
+    },
     NatType, Peer2PeerInstruction,
 };
 use quinn::{ClientConfig, Connection, Endpoint, EndpointConfig, RecvStream, SendStream};
@@ -43,19 +45,57 @@ impl Command {
     }
 }
 
+pub type Crypt = (
+    CryptographicAlgorithm,
+    SigningAlgorithm,
+    KeyDerivation,
+    Option<KeyExchange>,
+);
+
 pub enum Request {
     // Todo: Add signature and salt
-    Ip(
-        SocketAddr,
-        bool,
-        Option<(CryptographicAlgorithm, SigningAlgorithm)>,
-    ), // bool is UDP
-    Dns(
-        String,
-        u16,
-        bool,
-        Option<(CryptographicAlgorithm, SigningAlgorithm)>,
-    ), // bool is UDP
+    Ip(SocketAddr, bool, Option<Crypt>),   // bool is UDP
+    Dns(String, u16, bool, Option<Crypt>), // bool is UDP
+}
+
+// 0 for plain, 1 for the first key derivation, 2 followed by the key derivation version,
+// 3 as 2 followed by the key exchange
+async fn write_crypt(
+    mut writer: impl AsyncWrite + Unpin,
+    crypt: &Option<Crypt>,
+) -> Result<(), NetworkError> {
+    let Some((
+        CryptographicAlgorithm::XChaCha20Poly1305(iv),
+        SigningAlgorithm::HmacSha256(key),
+        kdf,
+        exchange,
+    )) = crypt
+    else {
+        writer.write_u8(0).await?;
+        return Ok(());
+    };
+    writer
+        .write_u8(match (kdf, exchange) {
+            (_, Some(_)) => 3,
+            (KeyDerivation::Sha3Xor, None) => 1,
+            _ => 2,
+        })
+        .await?;
+    writer.write_all(iv).await?;
+    writer.write_all(key).await?;
+    match kdf {
+        KeyDerivation::Sha3Xor if exchange.is_none() => {}
+        KeyDerivation::Sha3Xor => writer.write_u8(kdf.version()).await?,
+        KeyDerivation::Pbkdf2Sha3 { iterations } => {
+            writer.write_u8(kdf.version()).await?;
+            writer.write_u32(*iterations).await?;
+        }
+    }
+    if let Some(KeyExchange::X25519(public_key)) = exchange {
+        writer.write_u8(1).await?;
+        writer.write_all(public_key).await?;
+    }
+    Ok(())
 }
 
 impl Request {
@@ -103,7 +143,8 @@ impl Request {
                 )
             }
         };
-        if reader.read_u8().await? == 1 {
+        let crypt = reader.read_u8().await?;
+        if (1..=3).contains(&crypt) {
             let mut buf = vec![0; 24 + 32];
             reader.read_exact(&mut buf).await?;
             let crypto = CryptographicAlgorithm::XChaCha20Poly1305(
@@ -116,10 +157,31 @@ impl Request {
                     .try_into()
                     .map_err(|_| NetworkError::P2PInvalidCrypto)?,
             );
+            let kdf = if crypt == 1 {
+                KeyDerivation::Sha3Xor
+            } else {
+                match reader.read_u8().await? {
+                    1 => KeyDerivation::Sha3Xor,
+                    2 => KeyDerivation::Pbkdf2Sha3 {
+                        iterations: reader.read_u32().await?,
+                    },
+                    _ => return Err(NetworkError::P2PInvalidCrypto),
+                }
+            };
+            let exchange = if crypt == 3 {
+                if reader.read_u8().await? != 1 {
+                    return Err(NetworkError::P2PInvalidCrypto);
+                }
+                let mut public_key = [0; 32];
+                reader.read_exact(&mut public_key).await?;
+                Some(KeyExchange::X25519(public_key))
+            } else {
+                None
+            };
             let req = match req {
-                Self::Ip(ip, udp, _) => Self::Ip(ip, udp, Some((crypto, sign))),
+                Self::Ip(ip, udp, _) => Self::Ip(ip, udp, Some((crypto, sign, kdf, exchange))),
                 Self::Dns(domain, port, udp, _) => {
-                    Self::Dns(domain, port, udp, Some((crypto, sign)))
+                    Self::Dns(domain, port, udp, Some((crypto, sign, kdf, exchange)))
                 }
             };
             Ok(req)
@@ -151,20 +213,7 @@ impl Request {
                     }
                 }
                 writer.write_u16(ip.port()).await?;
-                if let Some(c) = crypt {
-                    writer.write_u8(1).await?;
-                    match c {
-                        (
-                            CryptographicAlgorithm::XChaCha20Poly1305(iv),
-                            SigningAlgorithm::HmacSha256(key),
-                        ) => {
-                            writer.write_all(iv).await?;
-                            writer.write_all(key).await?;
-                        }
-                    }
-                } else {
-                    writer.write_u8(0).await?;
-                }
+                write_crypt(&mut writer, crypt).await?;
             }
             Request::Dns(domain, port, udp, crypt) => {
                 let cmd = if *udp {
@@ -176,20 +225,7 @@ impl Request {
                 writer.write_u8(domain.len() as u8).await?;
                 writer.write_all(domain.as_bytes()).await?;
                 writer.write_u16(*port).await?;
-                if let Some(c) = crypt {
-                    writer.write_u8(1).await?;
-                    match c {
-                        (
-                            CryptographicAlgorithm::XChaCha20Poly1305(iv),
-                            SigningAlgorithm::HmacSha256(key),
-                        ) => {
-                            writer.write_all(iv).await?;
-                            writer.write_all(key).await?;
-                        }
-                    }
-                } else {
-                    writer.write_u8(0).await?;
-                }
+                write_crypt(&mut writer, crypt).await?;
             }
         }
         Ok(())
@@ -240,10 +276,10 @@ impl From<&Request> for Connect {
             Request::Ip(ip, udp, crypt) => (ip.ip().to_string(), ip.port(), udp, crypt),
             Request::Dns(domain, port, udp, crypt) => (domain.to_owned(), *port, udp, crypt),
         };
-        let (cryptography, sign) = if let Some((c, s)) = crypt {
-            (Some(c.clone()), Some(s.clone()))
+        let (cryptography, sign, kdf, exchange) = if let Some((c, s, k, e)) = crypt {
+            (Some(c.clone()), Some(s.clone()), Some(*k), *e)
         } else {
-            (None, None)
+            (None, None, None, None)
         };
         Connect {
             host,
@@ -255,6 +291,10 @@ impl From<&Request> for Connect {
             },
             cryptography,
             sign,
+            kdf: kdf.filter(|kdf| kdf != &KeyDerivation::Sha3Xor),
+            exchange,
+            reverse: None, // reverse forwards always go through the relay
+            source: None,
         }
     }
 }
@@ -262,7 +302,12 @@ impl From<&Request> for Connect {
 impl From<&Connect> for Request {
     fn from(connect: &Connect) -> Self {
         let crypt = if let (Some(c), Some(s)) = (&connect.cryptography, &connect.sign) {
-            Some((c.clone(), s.clone()))
+            Some((
+                c.clone(),
+                s.clone(),
+                connect.get_key_derivation(),
+                connect.exchange,
+            ))
         } else {
             None
         };
@@ -385,6 +430,12 @@ pub struct QuicBiSocket {
     // number_of_streams: Arc<AtomicU32>,
 }
 
+impl QuicBiSocket {
+    pub(crate) fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self { send, recv }
+    }
+}
+
 impl AsyncRead for QuicBiSocket {
     fn poll_read(
         mut self: std::pin::Pin<&mut Self>,
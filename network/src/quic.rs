@@ -0,0 +1,136 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use quinn::{ClientConfig, Connecting, Connection, Endpoint, ServerConfig, TransportConfig};
+use tokio::net::lookup_host;
+use tracing::debug;
+
+use crate::{
+    error::NetworkError,
+    p2p::QuicBiSocket,
+    transport::{DialOptions, UnifiedSocket},
+};
+
+// negotiated in the TLS handshake, peers without it are rejected before any stream is opened
+pub const ALPN: &[u8] = b"narrowlink";
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_STREAMS: u16 = 1024;
+
+fn transport_config() -> TransportConfig {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    transport.max_idle_timeout(MAX_IDLE_TIMEOUT.try_into().ok());
+    transport.max_concurrent_uni_streams(0_u8.into());
+    transport.max_concurrent_bidi_streams(MAX_STREAMS.into());
+    transport
+}
+
+// one QUIC connection to the gateway, every tunnel gets its own bidirectional stream so a slow
+// service only stalls its own stream
+pub struct QuicTransport {
+    endpoint: Endpoint,
+    connection: Connection,
+}
+
+impl QuicTransport {
+    pub async fn connect(
+        addr: &str,
+        sni: &str,
+        options: &DialOptions,
+    ) -> Result<Self, NetworkError> {
+        let peer_addr = lookup_host(addr)
+            .await?
+            .find(|a| options.ip_family.matches(a))
+            .ok_or(NetworkError::Invalid("unable to resolve gateway address"))?;
+        let bind_addr: SocketAddr = if peer_addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let mut tls = crate::transport::tls_client_config(options)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut config = ClientConfig::new(Arc::new(tls));
+        config.transport_config(Arc::new(transport_config()));
+        let endpoint = Endpoint::client(bind_addr)?;
+        debug!("connecting to {} over quic", peer_addr);
+        let connection = endpoint
+            .connect_with(config, peer_addr, sni)
+            .map_err(|_| NetworkError::QuicError)?
+            .await?;
+        Ok(Self {
+            endpoint,
+            connection,
+        })
+    }
+    pub async fn open(&self) -> Result<UnifiedSocket, NetworkError> {
+        let (send, recv) = self.connection.open_bi().await?;
+        Ok(UnifiedSocket::from_parts(
+            Box::new(QuicBiSocket::new(send, recv)),
+            self.endpoint.local_addr()?,
+            self.connection.remote_address(),
+        ))
+    }
+    pub fn is_closed(&self) -> bool {
+        self.connection.close_reason().is_some()
+    }
+}
+
+impl Drop for QuicTransport {
+    fn drop(&mut self) {
+        self.connection.close(0_u8.into(), b"");
+    }
+}
+
+pub struct QuicListener {
+    endpoint: Endpoint,
+}
+
+impl QuicListener {
+    // the TLS config decides which certificates are served, the ALPN is set here
+    pub fn bind(addr: SocketAddr, mut tls: rustls::ServerConfig) -> Result<Self, NetworkError> {
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut config = ServerConfig::with_crypto(Arc::new(tls));
+        config.transport_config(Arc::new(transport_config()));
+        Ok(Self {
+            endpoint: Endpoint::server(config, addr)?,
+        })
+    }
+    pub async fn accept(&self) -> Option<QuicConnecting> {
+        self.endpoint.accept().await.map(QuicConnecting)
+    }
+}
+
+pub struct QuicConnecting(Connecting);
+
+impl QuicConnecting {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.0.remote_address()
+    }
+    pub async fn established(self) -> Result<QuicPeer, NetworkError> {
+        Ok(QuicPeer(self.0.await?))
+    }
+}
+
+pub struct QuicPeer(Connection);
+
+impl QuicPeer {
+    pub fn sni(&self) -> Option<String> {
+        self.0
+            .handshake_data()?
+            .downcast::<quinn::crypto::rustls::HandshakeData>()
+            .ok()?
+            .server_name
+    }
+    // verified by the server config, so presenting one is enough
+    pub fn has_client_cert(&self) -> bool {
+        self.0.peer_identity().is_some()
+    }
+    pub async fn accept(&self) -> Result<QuicBiSocket, NetworkError> {
+        let (send, recv) = self.0.accept_bi().await?;
+        Ok(QuicBiSocket::new(send, recv))
+    }
+}
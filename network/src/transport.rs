@@ -2,14 +2,23 @@ use std::{
     io,
     net::SocketAddr,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, SystemTime},
 };
 
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, CertificateError, PrivateKey, ServerName,
+};
+
+use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{lookup_host, TcpStream},
+    task::JoinSet,
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{error::NetworkError, AsyncSocket};
 
@@ -21,6 +30,355 @@ pub enum StreamType {
     Tls(TlsConfiguration),
 }
 
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250); // RFC 8305 recommendation
+const PIN_MISMATCH: &str = "certificate pin mismatch";
+
+// address families tried when a host resolves to both
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum IpFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DialOptions {
+    pub proxy: Option<Socks5Proxy>,
+    pub ip_family: IpFamily,
+    pub cert_pin: Option<[u8; 32]>, // SHA-256 of the server's SubjectPublicKeyInfo
+    pub client_identity: Option<ClientIdentity>, // presented when the server asks for a client certificate
+    pub trust_roots: Option<TrustRoots>, // the server is verified against them instead of the webpki roots
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrustRoots {
+    certs: Vec<Certificate>,
+    with_webpki_roots: bool, // the bundle extends the webpki roots instead of replacing them
+}
+
+impl TrustRoots {
+    // every certificate of the bundle, each has to be usable as a trust anchor
+    pub fn from_pem(bundle: &[u8], with_webpki_roots: bool) -> Result<Self, NetworkError> {
+        let certs: Vec<Certificate> = rustls_pemfile::certs(&mut &bundle[..])?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(NetworkError::Invalid("no certificate found"));
+        }
+        let mut root_store = rustls::RootCertStore::empty();
+        for cert in certs.iter() {
+            root_store
+                .add(cert)
+                .map_err(|_| NetworkError::Invalid("certificate is not a valid trust anchor"))?;
+        }
+        Ok(Self {
+            certs,
+            with_webpki_roots,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity {
+    certs: Vec<Certificate>,
+    key: PrivateKey,
+}
+
+impl ClientIdentity {
+    // the certificate chain and the first PKCS#8, RSA or SEC1 key of the key file
+    pub fn from_pem(certs: &[u8], key: &[u8]) -> Result<Self, NetworkError> {
+        let certs: Vec<Certificate> = rustls_pemfile::certs(&mut &certs[..])?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(NetworkError::Invalid("no certificate found"));
+        }
+        let key = rustls_pemfile::read_all(&mut &key[..])?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or(NetworkError::Invalid("no private key found"))?;
+        Ok(Self { certs, key })
+    }
+}
+
+// happy eyeballs (RFC 8305): the addresses alternate between families starting with IPv6, each
+// attempt gets a head start before the next one is raced against it, a failure starts the next
+// one right away and the first connection wins
+pub async fn connect_tcp(addr: &str, ip_family: IpFamily) -> io::Result<TcpStream> {
+    let addresses = lookup_host(addr)
+        .await?
+        .filter(|a| ip_family.matches(a))
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no {:?} address found for {}", ip_family, addr),
+        ));
+    }
+    connect_tcp_addrs(addresses).await
+}
+
+// the happy eyeballs race over addresses resolved elsewhere
+pub async fn connect_tcp_addrs(addresses: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addresses.into_iter().partition(|a| a.is_ipv6());
+    let mut candidates = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    while v6.len() + v4.len() > 0 {
+        candidates.extend(v6.next());
+        candidates.extend(v4.next());
+    }
+    let mut candidates = candidates.into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+    let mut next = candidates.next();
+    loop {
+        if let Some(candidate) = next.take() {
+            debug!("connecting to {}", candidate);
+            attempts.spawn(async move { (candidate, TcpStream::connect(candidate).await) });
+        }
+        if attempts.is_empty() {
+            return Err(last_error);
+        }
+        tokio::select! {
+            Some(res) = attempts.join_next() => match res {
+                Ok((_, Ok(stream))) => return Ok(stream),
+                Ok((candidate, Err(e))) => {
+                    debug!("connecting to {} failed: {}", candidate, e);
+                    last_error = e;
+                    next = candidates.next();
+                }
+                Err(e) => {
+                    last_error = e.into();
+                    next = candidates.next();
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if candidates.len() > 0 => {
+                next = candidates.next();
+            }
+        }
+    }
+}
+
+// socks5://[user:pass@]host:port
+#[derive(Clone, Debug, PartialEq)]
+pub struct Socks5Proxy {
+    pub addr: String,
+    pub auth: Option<(String, String)>,
+    pub direct_fallback: bool, // connect directly when the proxy itself is unreachable
+}
+
+impl Socks5Proxy {
+    pub fn from_url(url: &str, direct_fallback: bool) -> Option<Self> {
+        let rest = url.strip_prefix("socks5://")?;
+        let (auth, addr) = match rest.rsplit_once('@') {
+            Some((auth, addr)) => {
+                let (user, pass) = auth.split_once(':')?;
+                if user.len() > 255 || pass.len() > 255 {
+                    return None;
+                }
+                (Some((user.to_owned(), pass.to_owned())), addr)
+            }
+            None => (None, rest),
+        };
+        let (host, port) = addr.rsplit_once(':')?;
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return None;
+        }
+        Some(Self {
+            addr: addr.to_owned(),
+            auth,
+            direct_fallback,
+        })
+    }
+
+    async fn connect(&self, target: &str) -> Result<TcpStream, NetworkError> {
+        let mut stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(NetworkError::ProxyUnreachable)?;
+        let method = if self.auth.is_some() { 0x02 } else { 0x00 };
+        stream.write_all(&[0x05, 0x01, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 || reply[1] != method {
+            return Err(NetworkError::ProxyError("authentication method rejected"));
+        }
+        if let Some((user, pass)) = &self.auth {
+            let mut request = vec![0x01, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(NetworkError::ProxyError("authentication failed"));
+            }
+        }
+
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or(NetworkError::Invalid("target address"))?;
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.trim_matches(|c| c == '[' || c == ']').parse() {
+            Ok(std::net::IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(std::net::IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(NetworkError::Invalid("target address"));
+                }
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(NetworkError::ProxyError(match reply[1] {
+                0x02 => "connection not allowed by ruleset",
+                0x03 => "network unreachable",
+                0x04 => "host unreachable",
+                0x05 => "connection refused",
+                _ => "general failure",
+            }));
+        }
+        // skip the bound address
+        let len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await? as usize,
+            _ => return Err(NetworkError::ProxyError("invalid reply")),
+        };
+        let mut bound = vec![0u8; len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(stream)
+    }
+}
+
+// the usual CA validation, then the SPKI of the leaf certificate has to match the pin
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pin: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let (_, cert) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let spki_hash = ring::digest::digest(&ring::digest::SHA256, cert.public_key().raw);
+        if spki_hash.as_ref() != self.pin {
+            warn!(
+                "certificate of {:?} does not match the pinned key",
+                server_name
+            );
+            return Err(rustls::Error::General(PIN_MISMATCH.to_owned()));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// webpki roots unless a CA bundle replaces them, shared by the TLS and QUIC transports
+pub(crate) fn tls_client_config(
+    options: &DialOptions,
+) -> Result<rustls::ClientConfig, NetworkError> {
+    let mut root_store = rustls::RootCertStore::empty();
+    if options
+        .trust_roots
+        .as_ref()
+        .map_or(true, |trust_roots| trust_roots.with_webpki_roots)
+    {
+        root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+    }
+    if let Some(trust_roots) = options.trust_roots.as_ref() {
+        for cert in trust_roots.certs.iter() {
+            root_store
+                .add(cert)
+                .map_err(|_| NetworkError::Invalid("CA bundle"))?;
+        }
+    }
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_safe_default_protocol_versions()
+        .or(Err(NetworkError::TlsError))?;
+    let verifier = WebPkiVerifier::new(root_store, None);
+    let builder = match options.cert_pin {
+        Some(pin) => builder.with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+            inner: verifier,
+            pin,
+        })),
+        None => builder.with_custom_certificate_verifier(Arc::new(verifier)),
+    };
+    match &options.client_identity {
+        Some(identity) => builder
+            .with_client_auth_cert(identity.certs.clone(), identity.key.clone())
+            .map_err(|_| NetworkError::Invalid("client certificate does not match its key")),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn tls_error(e: io::Error) -> NetworkError {
+    let pin_mismatch = e
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<rustls::Error>())
+        .is_some_and(|e| matches!(e, rustls::Error::General(msg) if msg == PIN_MISMATCH));
+    if pin_mismatch {
+        NetworkError::CertificatePinMismatch
+    } else {
+        NetworkError::IoError(e)
+    }
+}
+
 pub struct UnifiedSocket {
     io: Box<dyn AsyncSocket>,
     local_addr: SocketAddr,
@@ -29,57 +387,81 @@ pub struct UnifiedSocket {
 
 impl UnifiedSocket {
     pub async fn new(addr: &str, transport_type: StreamType) -> Result<Self, NetworkError> {
+        Self::with_options(addr, transport_type, &DialOptions::default()).await
+    }
+    pub async fn with_options(
+        addr: &str,
+        transport_type: StreamType,
+        options: &DialOptions,
+    ) -> Result<Self, NetworkError> {
         match transport_type {
             StreamType::Tcp | StreamType::Tls(_) => {
-                let tcp_stream = TcpStream::connect(addr).await?;
-                let local_addr = tcp_stream.local_addr()?;
-                let peer_addr = tcp_stream.peer_addr()?;
-                let mut stream: Box<dyn AsyncSocket> = Box::new(tcp_stream);
-                if let StreamType::Tls(conf) = transport_type {
-                    {
-                        debug!("using rustls to connect to {}", peer_addr.to_string());
-                        use std::sync::Arc;
-                        use tokio_rustls::{
-                            rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
-                            TlsConnector,
-                        };
-
-                        let mut root_store = RootCertStore::empty();
-                        root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(
-                            |ta| {
-                                OwnedTrustAnchor::from_subject_spki_name_constraints(
-                                    ta.subject,
-                                    ta.spki,
-                                    ta.name_constraints,
-                                )
-                            },
-                        ));
-
-                        let config = ClientConfig::builder()
-                            .with_safe_default_cipher_suites()
-                            .with_safe_default_kx_groups()
-                            .with_safe_default_protocol_versions()
-                            .or(Err(NetworkError::TlsError))?
-                            .with_root_certificates(root_store)
-                            .with_no_client_auth();
-
-                        let config = TlsConnector::from(Arc::new(config));
-
-                        let dnsname = ServerName::try_from(conf.sni.as_str()).or(Err(
-                            io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"),
-                        ))?;
-                        stream = Box::new(config.connect(dnsname, stream).await?);
-                    }
+                let tcp_stream = match &options.proxy {
+                    None => connect_tcp(addr, options.ip_family).await?,
+                    Some(proxy) => match proxy.connect(addr).await {
+                        Ok(stream) => stream,
+                        Err(NetworkError::ProxyUnreachable(e)) if proxy.direct_fallback => {
+                            warn!(
+                                "proxy {} is unreachable ({}), connecting directly to {}",
+                                proxy.addr, e, addr
+                            );
+                            connect_tcp(addr, options.ip_family).await?
+                        }
+                        Err(e) => return Err(e),
+                    },
+                };
+                match transport_type {
+                    StreamType::Tls(conf) => Self::with_tls(tcp_stream, &conf, options).await,
+                    StreamType::Tcp => Ok(Self {
+                        local_addr: tcp_stream.local_addr()?,
+                        peer_addr: tcp_stream.peer_addr()?,
+                        io: Box::new(tcp_stream),
+                    }),
                 }
-
-                Ok(Self {
-                    io: stream,
-                    local_addr,
-                    peer_addr,
-                })
             }
         }
     }
+    // the handshake over a connected stream, for addresses resolved and checked by the caller
+    pub async fn with_tls(
+        tcp_stream: TcpStream,
+        conf: &TlsConfiguration,
+        options: &DialOptions,
+    ) -> Result<Self, NetworkError> {
+        let local_addr = tcp_stream.local_addr()?;
+        let peer_addr = tcp_stream.peer_addr()?;
+        debug!("using rustls to connect to {}", peer_addr.to_string());
+        use tokio_rustls::TlsConnector;
+
+        let config = TlsConnector::from(Arc::new(tls_client_config(options)?));
+
+        let dnsname = ServerName::try_from(conf.sni.as_str()).or(Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid dnsname",
+        )))?;
+        let stream: Box<dyn AsyncSocket> = Box::new(
+            config
+                .connect(dnsname, tcp_stream)
+                .await
+                .map_err(tls_error)?,
+        );
+
+        Ok(Self {
+            io: stream,
+            local_addr,
+            peer_addr,
+        })
+    }
+    pub(crate) fn from_parts(
+        io: Box<dyn AsyncSocket>,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    ) -> Self {
+        Self {
+            io,
+            local_addr,
+            peer_addr,
+        }
+    }
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
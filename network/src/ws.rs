@@ -4,7 +4,7 @@ use hyper::{client::conn, http::HeaderValue, Body, HeaderMap, Request, StatusCod
 use narrowlink_types::ServiceType;
 use std::{
     collections::HashMap,
-    io::{self, Error, ErrorKind},
+    io::{self, Error},
     net::{SocketAddr, SocketAddrV4},
     pin::Pin,
     task::{Context, Poll},
@@ -19,7 +19,9 @@ use tungstenite::Message;
 
 use crate::{
     error::NetworkError,
-    transport::{StreamType, TlsConfiguration, UnifiedSocket},
+    mux::MuxTransport,
+    quic::QuicTransport,
+    transport::{DialOptions, StreamType, TlsConfiguration, UnifiedSocket},
     AsyncSocket,
 };
 
@@ -62,20 +64,52 @@ impl WsConnection {
         host: &str,
         headers: &HashMap<&'static str, String>,
         service_type: &ServiceType,
+    ) -> Result<Self, NetworkError> {
+        Self::with_options(host, headers, service_type, &DialOptions::default()).await
+    }
+    pub async fn with_options(
+        host: &str,
+        headers: &HashMap<&'static str, String>,
+        service_type: &ServiceType,
+        options: &DialOptions,
     ) -> Result<Self, NetworkError> {
         let sni = if let Some(sni) = host.split(':').next() {
             sni
         } else {
             host
         };
-        let transport_type = if let ServiceType::Wss = service_type {
+        // QUIC is negotiated by the caller, the TCP fallback runs over TLS
+        let transport_type = if let ServiceType::Wss | ServiceType::Quic = service_type {
             StreamType::Tls(TlsConfiguration {
                 sni: sni.to_owned(),
             })
         } else {
             StreamType::Tcp
         };
-        let stream = UnifiedSocket::new(host, transport_type).await?;
+        let stream = UnifiedSocket::with_options(host, transport_type, options).await?;
+        Self::handshake(stream, host, headers).await
+    }
+    // the upgrade runs on its own QUIC stream, the gateway serves it like a TLS connection
+    pub async fn with_quic(
+        quic: &QuicTransport,
+        host: &str,
+        headers: &HashMap<&'static str, String>,
+    ) -> Result<Self, NetworkError> {
+        Self::handshake(quic.open().await?, host, headers).await
+    }
+    // a stream of the multiplexed Ws or Wss connection, served like a connection of its own
+    pub async fn with_mux(
+        mux: &MuxTransport,
+        host: &str,
+        headers: &HashMap<&'static str, String>,
+    ) -> Result<Self, NetworkError> {
+        Self::handshake(mux.open().await?, host, headers).await
+    }
+    async fn handshake(
+        stream: UnifiedSocket,
+        host: &str,
+        headers: &HashMap<&'static str, String>,
+    ) -> Result<Self, NetworkError> {
         let local_addr = stream.local_addr();
         let peer_addr = stream.peer_addr();
         let (mut request_sender, connection) = conn::handshake(stream).await?;
@@ -204,10 +238,7 @@ impl AsyncRead for WsConnection {
                                 match self.ws_stream.send(Message::Ping(vec![0])).poll_unpin(cx) {
                                     Poll::Ready(Ok(_)) => continue,
                                     Poll::Ready(Err(_e)) => {
-                                        return Poll::Ready(Err(Error::new(
-                                            ErrorKind::Other,
-                                            "Ping Error!",
-                                        )))
+                                        return Poll::Ready(Err(Error::other("Ping Error!")))
                                     }
                                     Poll::Pending => return Poll::Pending,
                                 }
@@ -231,7 +262,7 @@ impl AsyncWrite for WsConnection {
     ) -> Poll<Result<usize, io::Error>> {
         match Pin::new(&mut self.ws_stream.send(Message::binary(buf)))
             .poll(cx)
-            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?
+            .map_err(|e| Error::other(e.to_string()))?
         {
             Poll::Ready(_) => Poll::Ready(Ok(buf.len())),
             Poll::Pending => Poll::Pending,
@@ -355,21 +386,50 @@ impl WsConnectionBinary {
         // uri: &str,
         headers: HashMap<&'static str, String>,
         service_type: &ServiceType,
+    ) -> Result<Self, NetworkError> {
+        Self::with_options(host, headers, service_type, &DialOptions::default()).await
+    }
+    pub async fn with_options(
+        host: &str,
+        headers: HashMap<&'static str, String>,
+        service_type: &ServiceType,
+        options: &DialOptions,
     ) -> Result<Self, NetworkError> {
         let sni = if let Some(sni) = host.split(':').next() {
             sni
         } else {
             host
         };
-        let transport_type = if let ServiceType::Wss = service_type {
+        // QUIC is negotiated by the caller, the TCP fallback runs over TLS
+        let transport_type = if let ServiceType::Wss | ServiceType::Quic = service_type {
             StreamType::Tls(TlsConfiguration {
                 sni: sni.to_owned(),
             })
         } else {
             StreamType::Tcp
         };
-        let stream = UnifiedSocket::new(host, transport_type).await?;
-
+        let stream = UnifiedSocket::with_options(host, transport_type, options).await?;
+        Self::handshake(stream, host, headers).await
+    }
+    pub async fn with_quic(
+        quic: &QuicTransport,
+        host: &str,
+        headers: HashMap<&'static str, String>,
+    ) -> Result<Self, NetworkError> {
+        Self::handshake(quic.open().await?, host, headers).await
+    }
+    pub async fn with_mux(
+        mux: &MuxTransport,
+        host: &str,
+        headers: HashMap<&'static str, String>,
+    ) -> Result<Self, NetworkError> {
+        Self::handshake(mux.open().await?, host, headers).await
+    }
+    async fn handshake(
+        stream: UnifiedSocket,
+        host: &str,
+        headers: HashMap<&'static str, String>,
+    ) -> Result<Self, NetworkError> {
         let (mut request_sender, connection) = conn::handshake(stream).await?;
         let conn_handler = tokio::spawn(async move {
             if let Err(e) = connection.await {
@@ -541,7 +601,7 @@ impl AsyncRead for WsConnectionBinary {
                     }
                 }
                 Poll::Ready(Some(Err(e))) => {
-                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e.to_string())))
+                    return Poll::Ready(Err(io::Error::other(e.to_string())))
                 }
                 Poll::Ready(None) => return Poll::Ready(Ok(())),
                 Poll::Pending => {
@@ -551,10 +611,7 @@ impl AsyncRead for WsConnectionBinary {
                                 match self.ws_stream.send(Message::Ping(vec![0])).poll_unpin(cx) {
                                     Poll::Ready(Ok(_)) => continue,
                                     Poll::Ready(Err(e)) => {
-                                        return Poll::Ready(Err(io::Error::new(
-                                            io::ErrorKind::Other,
-                                            e.to_string(),
-                                        )))
+                                        return Poll::Ready(Err(io::Error::other(e.to_string())))
                                     }
                                     Poll::Pending => return Poll::Pending,
                                 }
@@ -579,12 +636,12 @@ impl AsyncWrite for WsConnectionBinary {
         match self
             .ws_stream
             .poll_ready_unpin(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+            .map_err(|e| io::Error::other(e.to_string()))?
         {
             Poll::Ready(()) => {
                 self.ws_stream
                     .start_send_unpin(Message::binary(buf))
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                    .map_err(|e| io::Error::other(e.to_string()))?;
                 Poll::Ready(Ok(buf.len()))
             }
             Poll::Pending => Poll::Pending,
@@ -594,7 +651,7 @@ impl AsyncWrite for WsConnectionBinary {
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         self.ws_stream
             .poll_flush_unpin(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::other(e.to_string()))
     }
 
     fn poll_shutdown(
@@ -603,6 +660,6 @@ impl AsyncWrite for WsConnectionBinary {
     ) -> Poll<Result<(), io::Error>> {
         self.ws_stream
             .poll_close_unpin(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .map_err(|e| io::Error::other(e.to_string()))
     }
 }
@@ -1,4 +1,6 @@
-use narrowlink_types::token::{AgentPublishToken, AgentToken, ClientToken, PolicyToken};
+use narrowlink_types::token::{
+    AgentPublishToken, AgentRefreshToken, AgentToken, ClientToken, PolicyToken,
+};
 use serde::{Deserialize, Serialize};
 use std::{env, fs::File, io::Read, path::PathBuf};
 
@@ -10,6 +12,7 @@ pub enum TokenType {
     ClientPolicy(PolicyToken),
     Agent(AgentToken),
     AgentPublish(AgentPublishToken),
+    AgentRefresh(AgentRefreshToken),
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -68,6 +68,17 @@ fn main() -> Result<(), TokenGeneratorError> {
                     publish_token.uid, publish_token.name, pt
                 );
             }
+            TokenType::AgentRefresh(refresh_token) => {
+                let Ok(rt) = refresh_token
+                    .to_string(&config.secret.clone().into_iter().rev().collect::<Vec<u8>>())
+                else {
+                    return Err(TokenGeneratorError::TokenGenerationError); // unreachable
+                };
+                println!(
+                    "Agent Refresh Token: {}:{}\r\n{}",
+                    refresh_token.uid, refresh_token.name, rt
+                );
+            }
         }
     }
     Ok(())
@@ -15,10 +15,15 @@ use super::{ConstSystemInfo, DynSystemInfo};
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum InBound {
     Connect(Uuid, Connect, Vec<Policy>),
+    ConnectionRejected(Uuid, String), // not tunnelled, e.g. the connection limit was reached
+    ConnectionClosed(Uuid, String),   // closed by the gateway, e.g. after the idle timeout
+    PublishRejected(Vec<String>, String), // host:port of the services not published, reason
     IsReachable(Uuid, Connect),
     Response(usize, Response),
     Ping(u64),
     Peer2Peer(Peer2PeerInstruction),
+    ReverseBind(Uuid, String, u16), // bind id, host and port to listen on
+    ReverseUnbind(Uuid),            // the client that registered the bind is gone
     Shutdown,
 }
 
@@ -28,6 +33,7 @@ pub enum OutBound {
     Ready(Uuid),
     NotSure(Uuid),
     Error(Uuid, String),
+    ReverseConnection(Uuid, Uuid), // bind id, accepted connection waiting for the client
     Request(usize, Request),
 }
 
@@ -35,6 +41,7 @@ pub enum OutBound {
 pub enum Request {
     UpdateDynamicSysInfo(DynSystemInfo),
     UpdateConstantSysInfo(ConstSystemInfo),
+    UpdatePublish(Vec<String>), // publish tokens, replaces the ones sent on connect
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
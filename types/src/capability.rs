@@ -0,0 +1,115 @@
+use std::{fmt::Display, str::FromStr};
+
+// features sent in the NL-CAPABILITIES header of event connections, the gateway answers with the
+// ones both sides support; peers without the header predate the exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Quic,
+    Compression,
+    Multiplex,
+    ClientAcl,        // the access lists of published services are enforced
+    AcmeAccount,      // agents register their own ACME account
+    ReverseForward,   // clients bind listeners on agents
+    ConnectionEvents, // agents are told why the gateway rejected or closed a connection
+    PublishEvents,    // agents are told which of their published services were rejected
+    AcmeDirectory,    // agents select the ACME directory of their certificates
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Quic => "quic",
+            Capability::Compression => "compression",
+            Capability::Multiplex => "multiplex",
+            Capability::ClientAcl => "client-acl",
+            Capability::AcmeAccount => "acme-account",
+            Capability::ReverseForward => "reverse-forward",
+            Capability::ConnectionEvents => "connection-events",
+            Capability::PublishEvents => "publish-events",
+            Capability::AcmeDirectory => "acme-directory",
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "quic" => Ok(Capability::Quic),
+            "compression" => Ok(Capability::Compression),
+            "multiplex" => Ok(Capability::Multiplex),
+            "client-acl" => Ok(Capability::ClientAcl),
+            "acme-account" => Ok(Capability::AcmeAccount),
+            "reverse-forward" => Ok(Capability::ReverseForward),
+            "connection-events" => Ok(Capability::ConnectionEvents),
+            "publish-events" => Ok(Capability::PublishEvents),
+            "acme-directory" => Ok(Capability::AcmeDirectory),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Capabilities(Vec<Capability>);
+
+impl Capabilities {
+    pub fn new(capabilities: &[Capability]) -> Self {
+        let mut set = Self::default();
+        for capability in capabilities {
+            set.insert(*capability);
+        }
+        set
+    }
+    pub fn insert(&mut self, capability: Capability) {
+        if !self.contains(capability) {
+            self.0.push(capability);
+        }
+    }
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.0.contains(&capability)
+    }
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self(
+            self.0
+                .iter()
+                .filter(|capability| other.contains(**capability))
+                .copied()
+                .collect(),
+        )
+    }
+    // the first of the required ones that is not supported
+    pub fn missing(&self, required: &Self) -> Option<Capability> {
+        required
+            .0
+            .iter()
+            .find(|capability| !self.contains(**capability))
+            .copied()
+    }
+}
+
+// names of unknown capabilities are skipped, they belong to newer peers
+impl FromStr for Capabilities {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(
+            &s.split(',')
+                .filter_map(|capability| Capability::from_str(capability.trim()).ok())
+                .collect::<Vec<_>>(),
+        ))
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self.0.iter().map(|c| c.as_str()).collect::<Vec<_>>();
+        f.write_str(&names.join(","))
+    }
+}
@@ -17,6 +17,7 @@ pub enum InBound {
     Response(usize, Response),
     ConnectionError(Uuid, String),
     Peer2Peer(Peer2PeerInstruction),
+    ReverseConnection(Uuid, Uuid), // bind id, connection waiting on the agent
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,6 +25,7 @@ pub enum Request {
     ListOfAgents,
     UpdateConstantSysInfo(ConstSystemInfo),
     Peer2Peer(Peer2PeerRequest), // agent_name
+    ReverseBind(ReverseBindRequest),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -68,6 +70,15 @@ pub struct Peer2PeerRequest {
     pub hard_seq: u16,
 }
 
+// a listener on the agent whose connections are relayed back to the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReverseBindRequest {
+    pub agent_name: String,
+    pub id: Uuid,
+    pub host: String,
+    pub port: u16,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Peer2PeerInstruction {
     pub peer_ip: IpAddr,
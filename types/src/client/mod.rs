@@ -10,6 +10,7 @@ pub use event::Peer2PeerInstruction;
 pub use event::Peer2PeerRequest;
 pub use event::Request as EventRequest;
 pub use event::Response as EventResponse;
+pub use event::ReverseBindRequest;
 
 use serde::Deserialize;
 use serde::Serialize;
@@ -7,3 +7,11 @@ pub enum MessageError {
     #[error("SerdeJson Error: {0}")]
     SerdeJsonError(#[from] serde_json::Error),
 }
+
+#[derive(Error, Debug)]
+pub enum PreSharedKeyError {
+    #[error("expected 64 hex digits or base64")]
+    Encoding,
+    #[error("decodes to {0} bytes, expected 32")]
+    Length(usize),
+}
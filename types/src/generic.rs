@@ -1,10 +1,14 @@
 use core::fmt::Display;
-use hmac::Hmac;
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
-use sha3::Sha3_256;
+use sha3::{Digest, Sha3_256};
 use std::{fmt::Debug, net::SocketAddr, str::FromStr};
+use uuid::Uuid;
 
-use crate::agent::{AgentPublishInfo, SystemInfo};
+use crate::{
+    agent::{AgentPublishInfo, SystemInfo},
+    error::PreSharedKeyError,
+};
 
 pub type HmacSha256 = Hmac<Sha3_256>;
 
@@ -17,6 +21,87 @@ pub enum SigningAlgorithm {
     HmacSha256([u8; 32]), //IV
 }
 
+// ephemeral public key of the client, the agent answers with its own and both sides
+// derive the session key from the shared secret and the passphrase key
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyExchange {
+    X25519([u8; 32]),
+}
+
+// raw E2EE key used without key derivation, as 64 hex digits or standard or URL safe base64
+pub fn decode_pre_shared_key(encoded: &str) -> Result<[u8; 32], PreSharedKeyError> {
+    use base64::{engine::general_purpose, Engine};
+    let encoded = encoded.trim();
+    let key = if encoded.len() == 64 && encoded.bytes().all(|b| b.is_ascii_hexdigit()) {
+        (0..32)
+            .map(|i| u8::from_str_radix(&encoded[i * 2..i * 2 + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| PreSharedKeyError::Encoding)?
+    } else {
+        let unpadded = encoded.trim_end_matches('=');
+        general_purpose::STANDARD_NO_PAD
+            .decode(unpadded)
+            .or_else(|_| general_purpose::URL_SAFE_NO_PAD.decode(unpadded))
+            .map_err(|_| PreSharedKeyError::Encoding)?
+    };
+    let len = key.len();
+    key.try_into().map_err(|_| PreSharedKeyError::Length(len))
+}
+
+// how the E2EE key is derived from a passphrase, the nonce of the connection is the salt;
+// the variant is the version peers agree on, carried as the `kdf` field of `Connect` rather
+// than a version byte of the handshake; new ones must not change existing ones
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum KeyDerivation {
+    // version 1, SHA3-256 of the phrase bytes xored with the repeated nonce
+    #[default]
+    Sha3Xor,
+    // version 2, PBKDF2 with HMAC-SHA3-256, a single 32 byte block
+    Pbkdf2Sha3 {
+        iterations: u32,
+    },
+}
+
+impl KeyDerivation {
+    pub fn version(&self) -> u8 {
+        match self {
+            KeyDerivation::Sha3Xor => 1,
+            KeyDerivation::Pbkdf2Sha3 { .. } => 2,
+        }
+    }
+    pub fn derive(&self, phrase: &str, nonce: &[u8; 24]) -> Option<[u8; 32]> {
+        match self {
+            KeyDerivation::Sha3Xor => Some(
+                Sha3_256::digest(
+                    phrase
+                        .as_bytes()
+                        .iter()
+                        .zip(nonce.iter().cycle())
+                        .map(|(n, s)| n ^ s)
+                        .collect::<Vec<u8>>(),
+                )
+                .into(),
+            ),
+            KeyDerivation::Pbkdf2Sha3 { iterations } => {
+                let prf = HmacSha256::new_from_slice(phrase.as_bytes()).ok()?;
+                let mut u: [u8; 32] = prf
+                    .clone()
+                    .chain_update(nonce)
+                    .chain_update(1u32.to_be_bytes())
+                    .finalize()
+                    .into_bytes()
+                    .into();
+                let mut key = u;
+                for _ in 1..*iterations {
+                    u = prf.clone().chain_update(u).finalize().into_bytes().into();
+                    key.iter_mut().zip(u.iter()).for_each(|(k, u)| *k ^= u);
+                }
+                Some(key)
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Connect {
     pub host: String,
@@ -24,8 +109,44 @@ pub struct Connect {
     pub protocol: Protocol,
     pub cryptography: Option<CryptographicAlgorithm>,
     pub sign: Option<SigningAlgorithm>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KeyDerivation>, // missing is version 1 (Sha3Xor), which older peers assume
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exchange: Option<KeyExchange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverse: Option<Uuid>, // a connection accepted by a reverse forward, used instead of dialing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SocketAddr>, // client address as seen by the gateway, not covered by the sign
 }
 impl Connect {
+    pub fn set_key_exchange(&mut self, public_key: [u8; 32]) {
+        self.exchange = Some(KeyExchange::X25519(public_key));
+    }
+    pub fn get_key_exchange(&self) -> Option<[u8; 32]> {
+        self.exchange
+            .map(|KeyExchange::X25519(public_key)| public_key)
+    }
+    // what the sign covers, the public key of the exchange is left out when there is none
+    pub fn signed_data(&self, nonce: &[u8; 24]) -> Vec<u8> {
+        [
+            format!(
+                "{}:{}:{}",
+                &self.host,
+                &self.port,
+                self.protocol.clone() as u32
+            )
+            .as_bytes(),
+            nonce,
+            self.get_key_exchange().as_ref().map_or(&[][..], |k| &k[..]),
+        ]
+        .concat()
+    }
+    pub fn set_key_derivation(&mut self, kdf: KeyDerivation) {
+        self.kdf = Some(kdf).filter(|kdf| kdf != &KeyDerivation::Sha3Xor);
+    }
+    pub fn get_key_derivation(&self) -> KeyDerivation {
+        self.kdf.unwrap_or_default()
+    }
     pub fn set_cryptography_nonce(&mut self, nonce: [u8; 24]) {
         self.cryptography = Some(CryptographicAlgorithm::XChaCha20Poly1305(nonce));
     }
@@ -56,10 +177,33 @@ impl Connect {
             protocol,
             cryptography: None,
             sign: None,
+            kdf: None,
+            exchange: None,
+            reverse: None,
+            source: None,
         })
     }
 }
 
+// the address an agent dialed for a data connection, sent as scheme://address, a unix socket is
+// sent as unix://path since it has no socket address
+#[derive(Debug, Clone)]
+pub enum ConnectedAddress {
+    Socket(Connect),
+    Unix(String),
+}
+
+impl ConnectedAddress {
+    pub fn from_schemaed_string(addr: &str) -> Option<Self> {
+        match addr.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("unix://") => {
+                Some(Self::Unix(addr[7..].to_owned())).filter(|_| addr.len() > 7)
+            }
+            _ => Connect::from_schemaed_string(addr).map(Self::Socket),
+        }
+    }
+}
+
 impl Debug for Connect {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut debug = f.debug_struct("Connect");
@@ -72,6 +216,12 @@ impl Debug for Connect {
         if self.sign.is_some() {
             debug.field("sign", &"XXXXXX");
         }
+        if let Some(reverse) = &self.reverse {
+            debug.field("reverse", reverse);
+        }
+        if let Some(source) = &self.source {
+            debug.field("source", source);
+        }
         debug.finish()
     }
 }
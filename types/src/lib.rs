@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 
 pub mod agent;
 // pub mod api;
+pub mod capability;
 pub mod client;
 pub mod error;
 pub mod generic;
@@ -16,11 +17,12 @@ pub trait GetResponse {
     fn response(&self) -> Option<Self::Item>;
 }
 
-#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+#[derive(Deserialize, Debug, Clone, Serialize, Default, PartialEq)]
 pub enum ServiceType {
     Ws,
     #[default]
     Wss,
+    Quic, // falls back to Wss when UDP to the gateway is blocked
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use serde::{Deserialize, Serialize};
 
 use crate::generic::Connect;
@@ -7,4 +9,31 @@ pub struct PublishHost {
     pub host: String,
     pub port: u16,
     pub connect: Connect,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind: Option<SocketAddr>, // gateway address the service is served on, any if not set
+}
+
+// clients, by the name in their token, allowed to reach a published service; deny wins over allow
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientAccess {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>, // only these clients if not empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+}
+
+impl ClientAccess {
+    pub fn permit(&self, client: &str) -> bool {
+        !self.deny.iter().any(|c| c == client)
+            && (self.allow.is_empty() || self.allow.iter().any(|c| c == client))
+    }
+}
+
+// the access list of one service the agent connects to, sent by the agent on connect
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceAccess {
+    pub host: String,
+    pub port: u16,
+    #[serde(flatten)]
+    pub access: ClientAccess,
 }
@@ -112,3 +112,35 @@ impl AgentPublishToken {
         )?)
     }
 }
+
+// exchanged at the gateway for short-lived agent tokens, it is signed with a key of its own so
+// it never authenticates an agent and agent tokens never refresh themselves
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentRefreshToken {
+    pub uid: Uuid,
+    pub name: String,
+    pub exp: usize,
+}
+
+impl AgentRefreshToken {
+    fn key(token: &[u8]) -> Vec<u8> {
+        [token, b"refresh"].concat()
+    }
+
+    pub fn from_str(s: &str, token: &[u8]) -> Result<AgentRefreshToken, MessageError> {
+        Ok(jsonwebtoken::decode::<AgentRefreshToken>(
+            s,
+            &DecodingKey::from_secret(&Self::key(token)),
+            &Validation::new(Algorithm::default()),
+        )?
+        .claims)
+    }
+
+    pub fn to_string(&self, token: &[u8]) -> Result<String, MessageError> {
+        Ok(jsonwebtoken::encode(
+            &Header::new(Algorithm::default()),
+            self,
+            &EncodingKey::from_secret(&Self::key(token)),
+        )?)
+    }
+}